@@ -0,0 +1,119 @@
+//! Latency of watch-commit against the design targets: a single changed
+//! file committed within 120ms and a batch of 1000 files within 2s, both
+//! measured from the write to the commit and including the debounce.
+
+use cofer::git::commit::{commit_changes, CommitOptions};
+use cofer::git::watcher::{WatchConfig, WorktreeWatcher};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use git2::{IndexAddOption, Repository, Signature};
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// End-to-end targets for a single changed file and a batch of 1000
+const SINGLE_FILE_TARGET: Duration = Duration::from_millis(120);
+const BATCH_TARGET: Duration = Duration::from_secs(2);
+
+/// Create a repository with one commit containing `files` small text files
+fn init_repo(dir: &Path, files: usize) -> Repository {
+    let repo = Repository::init(dir).unwrap();
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    for i in 0..files {
+        std::fs::write(dir.join("src").join(format!("file{}.txt", i)), "initial\n").unwrap();
+    }
+    std::fs::write(dir.join("README.md"), "bench\n").unwrap();
+
+    let mut index = repo.index().unwrap();
+    index.add_all(["*"], IndexAddOption::DEFAULT, None).unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = Signature::now("Bench", "bench@example.com").unwrap();
+    repo.commit(Some("HEAD"), &signature, &signature, "Initial commit", &tree, &[]).unwrap();
+    drop(tree);
+    repo
+}
+
+/// Rewrite the first `count` files with content unique to `round`
+fn touch_files(dir: &Path, count: usize, round: u64) {
+    for i in 0..count {
+        std::fs::write(dir.join("src").join(format!("file{}.txt", i)), format!("round {}\n", round)).unwrap();
+    }
+}
+
+fn bench_commit_changes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("commit_changes");
+    group.sample_size(10);
+    let options = CommitOptions::default();
+
+    for files in [1, 1000] {
+        let dir = tempfile::tempdir().unwrap();
+        init_repo(dir.path(), files);
+        let mut round = 0;
+
+        group.bench_function(format!("{}_files", files), |b| {
+            b.iter_batched(
+                || {
+                    round += 1;
+                    touch_files(dir.path(), files, round);
+                },
+                |()| {
+                    let summary = commit_changes(dir.path(), &options).unwrap();
+                    assert_eq!(summary.changes.len(), files);
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_watch_commit(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("watch_commit");
+    group.sample_size(10);
+
+    for (files, target) in [(1, SINGLE_FILE_TARGET), (1000, BATCH_TARGET)] {
+        let dir = tempfile::tempdir().unwrap();
+        init_repo(dir.path(), files);
+
+        // The shipped default, since the targets include the debounce
+        let (tx, rx) = mpsc::channel();
+        let _watcher = runtime.block_on(async {
+            WorktreeWatcher::start(dir.path(), WatchConfig::default(), Box::new(move |result| {
+                let _ = tx.send(result.map(|summary| summary.changes.len()));
+            }))
+            .unwrap()
+        });
+        let mut round = 0;
+        let mut measured = Duration::ZERO;
+        let mut runs = 0;
+
+        group.bench_function(format!("{}_files", files), |b| {
+            b.iter_custom(|iterations| {
+                let mut total = Duration::ZERO;
+                for _ in 0..iterations {
+                    round += 1;
+                    let start = Instant::now();
+                    touch_files(dir.path(), files, round);
+
+                    // Events for a large batch may be split over several commits
+                    let mut committed = 0;
+                    while committed < files {
+                        committed += rx.recv_timeout(Duration::from_secs(30)).unwrap().unwrap();
+                    }
+                    total += start.elapsed();
+                }
+                measured += total;
+                runs += iterations;
+                total
+            })
+        });
+
+        let mean = measured / runs.max(1) as u32;
+        assert!(mean <= target, "{} files took {:?} on average, over the {:?} target", files, mean, target);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_commit_changes, bench_watch_commit);
+criterion_main!(benches);
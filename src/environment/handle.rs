@@ -2,6 +2,9 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+use crate::git::EnvironmentWorktree;
+use crate::podman::ports::{PortMapping, PortSpec};
+
 /// Status of an environment
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -13,18 +16,35 @@ pub enum EnvironmentStatus {
     Error(String),
 }
 
+/// Snapshot of an environment that it can be restored to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Name of the checkpoint, unique within the environment
+    pub label: String,
+    /// Worktree commit, if the environment has a worktree
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// Image committed from the container filesystem
+    pub image: String,
+    pub created_at: DateTime<Utc>,
+}
+
 /// Handle to a container environment
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EnvironmentHandle {
     /// Unique environment identifier
     pub env_id: String,
 
-    /// Container ID from Podman
+    /// Container ID from Podman, empty once `down` removed the container
     pub container_id: String,
 
     /// Project root path on host
     pub project_root: PathBuf,
 
+    /// Worktree mounted instead of the project root, if the project is a git repository
+    #[serde(default)]
+    pub worktree: Option<EnvironmentWorktree>,
+
     /// Mount path in container (always /workdir)
     pub mount_path: String,
 
@@ -40,6 +60,53 @@ pub struct EnvironmentHandle {
     /// Environment variables
     #[serde(default)]
     pub env_vars: std::collections::HashMap<String, String>,
+
+    /// Seconds after creation the environment is destroyed, if limited
+    #[serde(default)]
+    pub ttl_seconds: Option<u64>,
+
+    /// Seconds without activity after which the environment is destroyed, if limited
+    #[serde(default)]
+    pub idle_timeout_seconds: Option<u64>,
+
+    /// Last time a command ran in the environment or it was brought up
+    #[serde(default = "Utc::now")]
+    pub last_activity: DateTime<Utc>,
+
+    /// Checkpoints taken of the environment, oldest first
+    #[serde(default)]
+    pub checkpoints: Vec<Checkpoint>,
+
+    /// Ports published when the container is created
+    #[serde(default)]
+    pub ports: Vec<PortSpec>,
+
+    /// Where the ports were bound on the host when the container last started
+    #[serde(default)]
+    pub host_mappings: Vec<PortMapping>,
+
+    /// Environment this one was forked from, if any
+    #[serde(default)]
+    pub parent: Option<String>,
+}
+
+/// Why an environment outlived its limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Expiry {
+    /// Older than its `ttl_seconds`
+    Ttl,
+    /// Unused for longer than its `idle_timeout_seconds`
+    Idle,
+}
+
+impl std::fmt::Display for Expiry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expiry::Ttl => write!(f, "TTL expired"),
+            Expiry::Idle => write!(f, "idle timeout reached"),
+        }
+    }
 }
 
 impl EnvironmentHandle {
@@ -50,15 +117,24 @@ impl EnvironmentHandle {
         project_root: PathBuf,
         image: impl Into<String>,
     ) -> Self {
+        let now = Utc::now();
         Self {
             env_id: env_id.into(),
             container_id: container_id.into(),
             project_root,
+            worktree: None,
             mount_path: "/workdir".to_string(),
-            created_at: Utc::now(),
+            created_at: now,
             status: EnvironmentStatus::Creating,
             image: image.into(),
             env_vars: std::collections::HashMap::new(),
+            ttl_seconds: None,
+            idle_timeout_seconds: None,
+            last_activity: now,
+            checkpoints: Vec::new(),
+            ports: Vec::new(),
+            host_mappings: Vec::new(),
+            parent: None,
         }
     }
 
@@ -81,6 +157,40 @@ impl EnvironmentHandle {
     pub fn is_error(&self) -> bool {
         matches!(self.status, EnvironmentStatus::Error(_))
     }
+
+    /// Host directory bind-mounted into the container
+    pub fn mount_source(&self) -> PathBuf {
+        match &self.worktree {
+            Some(worktree) => worktree.mount_source(),
+            None => self.project_root.clone(),
+        }
+    }
+
+    /// Record activity, restarting the idle timeout
+    pub fn touch(&mut self) {
+        self.last_activity = Utc::now();
+    }
+
+    /// Find a checkpoint by label, or the latest one if no label is given
+    pub fn checkpoint(&self, label: Option<&str>) -> Option<&Checkpoint> {
+        match label {
+            Some(label) => self.checkpoints.iter().find(|checkpoint| checkpoint.label == label),
+            None => self.checkpoints.last(),
+        }
+    }
+
+    /// Check whether the environment has outlived its TTL or idle timeout at `now`
+    pub fn expiry(&self, now: DateTime<Utc>) -> Option<Expiry> {
+        let elapsed = |since: DateTime<Utc>| (now - since).num_seconds().max(0) as u64;
+
+        if self.ttl_seconds.is_some_and(|ttl| elapsed(self.created_at) >= ttl) {
+            return Some(Expiry::Ttl);
+        }
+        if self.idle_timeout_seconds.is_some_and(|idle| elapsed(self.last_activity) >= idle) {
+            return Some(Expiry::Idle);
+        }
+        None
+    }
 }
 
 #[cfg(test)]
@@ -217,4 +327,69 @@ mod tests {
         handle.add_env_vars(more_vars);
         assert_eq!(handle.env_vars.len(), 3);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_expiry() {
+        let mut handle = EnvironmentHandle::new(
+            "test-env",
+            "container-123",
+            PathBuf::from("/home/user/project"),
+            "alpine:latest",
+        );
+        let now = handle.created_at;
+        let later = |secs| now + chrono::Duration::seconds(secs);
+
+        // Without limits an environment never expires
+        assert_eq!(handle.expiry(later(1_000_000)), None);
+
+        handle.idle_timeout_seconds = Some(60);
+        assert_eq!(handle.expiry(later(59)), None);
+        assert_eq!(handle.expiry(later(60)), Some(Expiry::Idle));
+
+        // Activity restarts the idle timeout
+        handle.last_activity = later(30);
+        assert_eq!(handle.expiry(later(60)), None);
+
+        // The TTL applies however active the environment is
+        handle.ttl_seconds = Some(50);
+        assert_eq!(handle.expiry(later(60)), Some(Expiry::Ttl));
+    }
+
+    #[test]
+    fn test_deserialize_without_limits() {
+        // Handles saved before TTLs existed still load
+        let handle = EnvironmentHandle::new("test-env", "", PathBuf::from("/tmp"), "alpine:latest");
+        let mut json = serde_json::to_value(&handle).unwrap();
+        for field in ["ttl_seconds", "idle_timeout_seconds", "last_activity"] {
+            json.as_object_mut().unwrap().remove(field);
+        }
+
+        let restored: EnvironmentHandle = serde_json::from_value(json).unwrap();
+        assert_eq!(restored.ttl_seconds, None);
+        assert_eq!(restored.expiry(Utc::now()), None);
+    }
+
+    #[test]
+    fn test_checkpoint_lookup() {
+        let mut handle = EnvironmentHandle::new("test-env", "", PathBuf::from("/tmp"), "alpine:latest");
+        assert!(handle.checkpoint(None).is_none());
+
+        for label in ["first", "second"] {
+            handle.checkpoints.push(Checkpoint {
+                label: label.to_string(),
+                commit: None,
+                image: format!("localhost/cofer-checkpoint/test-env:{}", label),
+                created_at: Utc::now(),
+            });
+        }
+        assert_eq!(handle.checkpoint(None).unwrap().label, "second");
+        assert_eq!(handle.checkpoint(Some("first")).unwrap().label, "first");
+        assert!(handle.checkpoint(Some("third")).is_none());
+
+        // Handles saved before checkpoints existed still load
+        let mut json = serde_json::to_value(&handle).unwrap();
+        json.as_object_mut().unwrap().remove("checkpoints");
+        let restored: EnvironmentHandle = serde_json::from_value(json).unwrap();
+        assert!(restored.checkpoints.is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Number of commands remembered per environment
+pub const MAX_HISTORY_PER_ENVIRONMENT: usize = 20;
+
+/// A command that ran in an environment
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandRecord {
+    /// The command as given to run_command, a string or an argv array
+    pub command: Value,
+    pub workdir: String,
+    pub exit_code: i64,
+    pub timed_out: bool,
+    pub duration_ms: u64,
+    pub executed_at: DateTime<Utc>,
+}
+
+/// Recent commands of every environment, oldest first
+#[derive(Debug, Clone, Default)]
+pub struct CommandHistory {
+    commands: Arc<Mutex<HashMap<String, VecDeque<CommandRecord>>>>,
+}
+
+impl CommandHistory {
+    /// Create an empty history
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a command, forgetting the oldest one once the history is full
+    pub fn record(&self, env_id: &str, record: CommandRecord) {
+        let mut commands = self.lock();
+        let history = commands.entry(env_id.to_string()).or_default();
+        if history.len() == MAX_HISTORY_PER_ENVIRONMENT {
+            history.pop_front();
+        }
+        history.push_back(record);
+    }
+
+    /// Recent commands of an environment, oldest first
+    pub fn recent(&self, env_id: &str) -> Vec<CommandRecord> {
+        let commands = self.lock();
+        commands.get(env_id)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Forget the history of an environment
+    pub fn remove(&self, env_id: &str) {
+        self.lock().remove(env_id);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, VecDeque<CommandRecord>>> {
+        // The history stays consistent even if a holder panicked
+        self.commands.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn record(command: &str) -> CommandRecord {
+        CommandRecord {
+            command: json!(command),
+            workdir: "/workdir".to_string(),
+            exit_code: 0,
+            timed_out: false,
+            duration_ms: 5,
+            executed_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_history_is_per_environment() {
+        let history = CommandHistory::new();
+        history.record("env1", record("make"));
+        history.record("env2", record("ls"));
+
+        let recent = history.recent("env1");
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].command, "make");
+        assert!(history.recent("env3").is_empty());
+
+        history.remove("env1");
+        assert!(history.recent("env1").is_empty());
+        assert_eq!(history.recent("env2").len(), 1);
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let history = CommandHistory::new();
+        for i in 0..MAX_HISTORY_PER_ENVIRONMENT + 5 {
+            history.record("env1", record(&format!("echo {}", i)));
+        }
+
+        let recent = history.recent("env1");
+        assert_eq!(recent.len(), MAX_HISTORY_PER_ENVIRONMENT);
+        assert_eq!(recent[0].command, "echo 5");
+        assert_eq!(recent.last().unwrap().command, format!("echo {}", MAX_HISTORY_PER_ENVIRONMENT + 4));
+    }
+}
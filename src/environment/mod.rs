@@ -1,5 +1,9 @@
 pub mod handle;
+pub mod history;
 pub mod registry;
+pub mod store;
 
-pub use handle::{EnvironmentHandle, EnvironmentStatus};
-pub use registry::EnvironmentRegistry;
\ No newline at end of file
+pub use handle::{Checkpoint, EnvironmentHandle, EnvironmentStatus, Expiry};
+pub use history::{CommandHistory, CommandRecord};
+pub use registry::EnvironmentRegistry;
+pub use store::RegistryStore;
@@ -1,4 +1,5 @@
-use super::handle::EnvironmentHandle;
+use super::handle::{EnvironmentHandle, EnvironmentStatus};
+use super::store::RegistryStore;
 use anyhow::{bail, Result};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -10,6 +11,8 @@ use tracing::{debug, info, warn};
 pub struct EnvironmentRegistry {
     /// Map of environment ID to handle
     environments: Arc<RwLock<HashMap<String, EnvironmentHandle>>>,
+    /// Where every change is saved, if anywhere
+    store: Option<RegistryStore>,
 }
 
 impl EnvironmentRegistry {
@@ -17,6 +20,36 @@ impl EnvironmentRegistry {
     pub fn new() -> Self {
         Self {
             environments: Arc::new(RwLock::new(HashMap::new())),
+            store: None,
+        }
+    }
+
+    /// Create an empty registry that saves every change to `store`
+    ///
+    /// Saved environments are not loaded; restore them with
+    /// [`EnvironmentRegistry::register`].
+    pub fn with_store(store: RegistryStore) -> Self {
+        Self {
+            environments: Arc::new(RwLock::new(HashMap::new())),
+            store: Some(store),
+        }
+    }
+
+    /// Store the registry saves to, if any
+    pub fn store(&self) -> Option<&RegistryStore> {
+        self.store.as_ref()
+    }
+
+    /// Save the environments to the store, if there is one
+    ///
+    /// Called with the write lock held so saves happen in the order of the
+    /// changes. A failed save is logged; the in-memory registry stays
+    /// authoritative.
+    async fn persist(&self, envs: &HashMap<String, EnvironmentHandle>) {
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save(envs.values().cloned().collect()).await {
+                warn!("Failed to save environments to {}: {:#}", store.path().display(), e);
+            }
         }
     }
 
@@ -36,6 +69,7 @@ impl EnvironmentRegistry {
         // Register the environment
         debug!("Registering environment: {}", env_id);
         envs.insert(env_id.clone(), handle);
+        self.persist(&envs).await;
 
         // Release the lock before logging (drop guard)
         drop(envs);
@@ -44,6 +78,42 @@ impl EnvironmentRegistry {
         Ok(())
     }
 
+    /// Register a new environment as a child of `parent_id`
+    ///
+    /// Fails if the parent is not registered, checked under the same lock as
+    /// the insert so the parent cannot go away in between.
+    pub async fn register_child(&self, parent_id: &str, mut handle: EnvironmentHandle) -> Result<()> {
+        let env_id = handle.env_id.clone();
+        let mut envs = self.environments.write().await;
+
+        if !envs.contains_key(parent_id) {
+            bail!("Parent environment '{}' not found", parent_id);
+        }
+        if envs.contains_key(&env_id) {
+            bail!("Environment '{}' already exists", env_id);
+        }
+
+        debug!("Registering environment {} as a child of {}", env_id, parent_id);
+        handle.parent = Some(parent_id.to_string());
+        envs.insert(env_id.clone(), handle);
+        self.persist(&envs).await;
+        drop(envs);
+
+        info!("Environment '{}' registered as a child of '{}'", env_id, parent_id);
+        Ok(())
+    }
+
+    /// IDs of the environments forked from `parent_id`, sorted
+    pub async fn children(&self, parent_id: &str) -> Vec<String> {
+        let envs = self.environments.read().await;
+        let mut children: Vec<String> = envs.values()
+            .filter(|handle| handle.parent.as_deref() == Some(parent_id))
+            .map(|handle| handle.env_id.clone())
+            .collect();
+        children.sort();
+        children
+    }
+
     /// Get an environment by ID
     pub async fn get(&self, env_id: &str) -> Result<EnvironmentHandle> {
         let envs = self.environments.read().await;
@@ -64,15 +134,74 @@ impl EnvironmentRegistry {
 
         debug!("Updating environment: {}", env_id);
         envs.insert(env_id, handle);
+        self.persist(&envs).await;
         Ok(())
     }
 
+    /// Record activity in an environment, restarting its idle timeout
+    pub async fn touch(&self, env_id: &str) -> Result<()> {
+        let mut envs = self.environments.write().await;
+
+        match envs.get_mut(env_id) {
+            Some(handle) => handle.touch(),
+            None => bail!("Environment '{}' not found", env_id),
+        }
+
+        self.persist(&envs).await;
+        Ok(())
+    }
+
+    /// Set an environment's status only if it still is `expected`
+    ///
+    /// Returns whether the status was changed.
+    pub async fn set_status_if(&self, env_id: &str, expected: &EnvironmentStatus, status: EnvironmentStatus) -> Result<bool> {
+        let mut envs = self.environments.write().await;
+
+        let handle = match envs.get_mut(env_id) {
+            Some(handle) => handle,
+            None => bail!("Environment '{}' not found", env_id),
+        };
+
+        if &handle.status != expected {
+            return Ok(false);
+        }
+
+        debug!("Environment {} moving from {:?} to {:?}", env_id, handle.status, status);
+        handle.set_status(status);
+        self.persist(&envs).await;
+        Ok(true)
+    }
+
+    /// Move an environment into a transitional status, returning it as it was
+    ///
+    /// Fails while the environment is already being created or stopped, so
+    /// concurrent lifecycle requests cannot interleave.
+    pub async fn begin_transition(&self, env_id: &str, status: EnvironmentStatus) -> Result<EnvironmentHandle> {
+        let mut envs = self.environments.write().await;
+
+        let handle = match envs.get_mut(env_id) {
+            Some(handle) => handle,
+            None => bail!("Environment '{}' not found", env_id),
+        };
+
+        if matches!(handle.status, EnvironmentStatus::Creating | EnvironmentStatus::Stopping) {
+            bail!("Environment '{}' is busy (status: {:?})", env_id, handle.status);
+        }
+
+        debug!("Environment {} moving from {:?} to {:?}", env_id, handle.status, status);
+        let previous = handle.clone();
+        handle.set_status(status);
+        self.persist(&envs).await;
+        Ok(previous)
+    }
+
     /// Remove an environment
     pub async fn remove(&self, env_id: &str) -> Result<EnvironmentHandle> {
         let mut envs = self.environments.write().await;
 
         match envs.remove(env_id) {
             Some(handle) => {
+                self.persist(&envs).await;
                 info!("Environment '{}' removed from registry", env_id);
                 Ok(handle)
             }
@@ -100,6 +229,7 @@ impl EnvironmentRegistry {
         if !handles.is_empty() {
             warn!("Clearing {} environments from registry", handles.len());
             envs.clear();
+            self.persist(&envs).await;
         }
 
         handles
@@ -121,7 +251,6 @@ impl Default for EnvironmentRegistry {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::environment::EnvironmentStatus;
     use std::path::PathBuf;
 
     fn create_test_handle(env_id: &str) -> EnvironmentHandle {
@@ -172,6 +301,24 @@ mod tests {
         assert!(result.unwrap_err().to_string().contains("already exists"));
     }
 
+    #[tokio::test]
+    async fn test_register_child() {
+        let registry = EnvironmentRegistry::new();
+
+        // The parent has to exist
+        assert!(registry.register_child("env1", create_test_handle("env2")).await.is_err());
+
+        registry.register(create_test_handle("env1")).await.unwrap();
+        registry.register_child("env1", create_test_handle("env3")).await.unwrap();
+        registry.register_child("env1", create_test_handle("env2")).await.unwrap();
+        assert!(registry.register_child("env1", create_test_handle("env2")).await.is_err());
+
+        assert_eq!(registry.get("env2").await.unwrap().parent.as_deref(), Some("env1"));
+        assert_eq!(registry.get("env1").await.unwrap().parent, None);
+        assert_eq!(registry.children("env1").await, vec!["env2".to_string(), "env3".to_string()]);
+        assert!(registry.children("env2").await.is_empty());
+    }
+
     #[tokio::test]
     async fn test_get_nonexistent() {
         // Requirement 3.5: Error on nonexistent environment
@@ -199,6 +346,62 @@ mod tests {
         assert_eq!(retrieved.status, EnvironmentStatus::Running);
     }
 
+    #[tokio::test]
+    async fn test_begin_transition() {
+        let registry = EnvironmentRegistry::new();
+        let mut handle = create_test_handle("env1");
+        handle.set_status(EnvironmentStatus::Running);
+        registry.register(handle).await.unwrap();
+
+        let previous = registry.begin_transition("env1", EnvironmentStatus::Stopping).await.unwrap();
+        assert_eq!(previous.status, EnvironmentStatus::Running);
+        assert_eq!(registry.get("env1").await.unwrap().status, EnvironmentStatus::Stopping);
+
+        // A second transition is refused until the first one finishes
+        let result = registry.begin_transition("env1", EnvironmentStatus::Creating).await;
+        assert!(result.unwrap_err().to_string().contains("busy"));
+
+        let result = registry.begin_transition("missing", EnvironmentStatus::Stopping).await;
+        assert!(result.unwrap_err().to_string().contains("not found"));
+    }
+
+    #[tokio::test]
+    async fn test_set_status_if() {
+        let registry = EnvironmentRegistry::new();
+        let mut handle = create_test_handle("env1");
+        handle.set_status(EnvironmentStatus::Running);
+        registry.register(handle).await.unwrap();
+
+        // Only changes a status that was not updated in the meantime
+        assert!(!registry.set_status_if("env1", &EnvironmentStatus::Creating, EnvironmentStatus::Stopped).await.unwrap());
+        assert!(registry.set_status_if("env1", &EnvironmentStatus::Running, EnvironmentStatus::Stopped).await.unwrap());
+        assert_eq!(registry.get("env1").await.unwrap().status, EnvironmentStatus::Stopped);
+
+        assert!(registry.set_status_if("missing", &EnvironmentStatus::Running, EnvironmentStatus::Stopped).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_saved() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = RegistryStore::new(temp_dir.path().join("environments.json"));
+        let registry = EnvironmentRegistry::with_store(store.clone());
+
+        let mut handle = create_test_handle("env1");
+        handle.set_status(EnvironmentStatus::Running);
+        registry.register(handle).await.unwrap();
+        registry.register(create_test_handle("env2")).await.unwrap();
+        registry.begin_transition("env1", EnvironmentStatus::Stopping).await.unwrap();
+        registry.remove("env2").await.unwrap();
+
+        let saved = store.load().await.unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].env_id, "env1");
+        assert_eq!(saved[0].status, EnvironmentStatus::Stopping);
+
+        registry.clear().await;
+        assert!(store.load().await.unwrap().is_empty());
+    }
+
     #[tokio::test]
     async fn test_remove_environment() {
         let registry = EnvironmentRegistry::new();
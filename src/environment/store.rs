@@ -0,0 +1,199 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::debug;
+
+use super::handle::EnvironmentHandle;
+
+/// Version of the state file format
+const STATE_VERSION: u32 = 1;
+
+/// On-disk form of the registry
+#[derive(Debug, Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    environments: Vec<EnvironmentHandle>,
+}
+
+/// JSON file the environment registry is saved to after every change
+#[derive(Debug, Clone)]
+pub struct RegistryStore {
+    path: PathBuf,
+    /// Lock file held open while the store owns `path`
+    lock: Option<Arc<File>>,
+}
+
+impl RegistryStore {
+    /// Create a store backed by `path`; nothing is read or written yet
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), lock: None }
+    }
+
+    /// Take an exclusive lock on the state file for as long as the store lives
+    ///
+    /// Each server saves its whole registry, so two sharing a file would
+    /// overwrite each other's environments. Fails if another process holds
+    /// the lock; it is released when that process exits, even by crashing.
+    pub fn locked(mut self) -> Result<Self> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+
+        let lock_path = self.path.with_extension("json.lock");
+        let file = lock_file(&lock_path)
+            .with_context(|| format!("{} is in use by another cofer server", self.path.display()))?;
+        self.lock = Some(Arc::new(file));
+        Ok(self)
+    }
+
+    /// Store in the per-user data directory
+    pub fn default_location() -> Result<Self> {
+        Ok(Self::new(data_dir()?.join("cofer").join("environments.json")))
+    }
+
+    /// Path of the state file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load the saved environments; a missing file means there are none
+    pub async fn load(&self) -> Result<Vec<EnvironmentHandle>> {
+        let contents = match tokio::fs::read(&self.path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.path.display())),
+        };
+
+        let state: StateFile = serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse {}", self.path.display()))?;
+        if state.version != STATE_VERSION {
+            bail!("Unsupported state file version {} in {}", state.version, self.path.display());
+        }
+
+        debug!("Loaded {} environments from {}", state.environments.len(), self.path.display());
+        Ok(state.environments)
+    }
+
+    /// Replace the saved environments
+    ///
+    /// The file is written next to its destination and renamed over it, so
+    /// a crash never leaves a truncated state file behind.
+    pub async fn save(&self, environments: Vec<EnvironmentHandle>) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+
+        let state = StateFile { version: STATE_VERSION, environments };
+        let contents = serde_json::to_vec_pretty(&state)?;
+
+        let temp_path = self.path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, contents).await
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        tokio::fs::rename(&temp_path, &self.path).await
+            .with_context(|| format!("Failed to replace {}", self.path.display()))?;
+
+        debug!("Saved {} environments to {}", state.environments.len(), self.path.display());
+        Ok(())
+    }
+}
+
+/// Open `path` and lock it exclusively without waiting
+#[cfg(unix)]
+fn lock_file(path: &Path) -> Result<File> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    // SAFETY: flock only reads the descriptor, which stays open for the call
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to lock {}", path.display()));
+    }
+    Ok(file)
+}
+
+/// Open `path` without sharing it, which fails while another process has it open
+#[cfg(windows)]
+fn lock_file(path: &Path) -> Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new().create(true).truncate(false).write(true).share_mode(0).open(path)
+        .with_context(|| format!("Failed to lock {}", path.display()))
+}
+
+/// Per-user data directory, following each platform's convention
+pub(crate) fn data_dir() -> Result<PathBuf> {
+    let env_path = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+
+    if cfg!(windows) {
+        return env_path("LOCALAPPDATA").context("LOCALAPPDATA is not set");
+    }
+
+    let home = env_path("HOME").context("HOME is not set");
+    if cfg!(target_os = "macos") {
+        return Ok(home?.join("Library").join("Application Support"));
+    }
+
+    match env_path("XDG_DATA_HOME") {
+        Some(data_home) => Ok(data_home),
+        None => Ok(home?.join(".local").join("share")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::EnvironmentStatus;
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = RegistryStore::new(temp_dir.path().join("nested").join("environments.json"));
+
+        // Nothing saved yet
+        assert!(store.load().await.unwrap().is_empty());
+
+        let mut handle = EnvironmentHandle::new("env1", "container-1", PathBuf::from("/src"), "alpine:latest");
+        handle.set_status(EnvironmentStatus::Running);
+        store.save(vec![handle]).await.unwrap();
+
+        let loaded = store.load().await.unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].env_id, "env1");
+        assert_eq!(loaded[0].status, EnvironmentStatus::Running);
+        assert!(!store.path().with_extension("json.tmp").exists());
+    }
+
+    #[test]
+    fn test_locked_store_is_exclusive() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("state").join("environments.json");
+
+        let store = RegistryStore::new(&path).locked().unwrap();
+        let error = RegistryStore::new(&path).locked().unwrap_err();
+        assert!(error.to_string().contains("in use"), "{:#}", error);
+
+        // Clones share the lock, which goes with the last of them
+        let clone = store.clone();
+        drop(store);
+        assert!(RegistryStore::new(&path).locked().is_err());
+        drop(clone);
+        assert!(RegistryStore::new(&path).locked().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_load_rejects_bad_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = RegistryStore::new(temp_dir.path().join("environments.json"));
+
+        std::fs::write(store.path(), b"not json").unwrap();
+        assert!(store.load().await.is_err());
+
+        std::fs::write(store.path(), br#"{"version": 99, "environments": []}"#).unwrap();
+        assert!(store.load().await.unwrap_err().to_string().contains("version"));
+    }
+}
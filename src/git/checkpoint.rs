@@ -0,0 +1,152 @@
+use anyhow::{bail, Context, Result};
+use git2::{build::CheckoutBuilder, Oid, Reference, Repository, ResetType};
+use serde::Serialize;
+use std::path::Path;
+use tracing::debug;
+
+use super::commit::{commit_changes, worktree_lock, CommitOptions, CommitSummary};
+
+/// Namespace of the refs keeping checkpoint commits reachable
+pub const CHECKPOINT_REF_PREFIX: &str = "refs/cofer/checkpoints/";
+
+/// Worktree half of an environment checkpoint
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WorktreeCheckpoint {
+    /// Commit the worktree was at once pending changes were committed
+    pub commit: String,
+    /// Ref pinning the commit so a later restore cannot lose it to gc
+    #[serde(rename = "ref")]
+    pub checkpoint_ref: String,
+    /// Pending changes committed before taking the checkpoint
+    pub pending: CommitSummary,
+}
+
+/// Ref a checkpoint of `env_id` named `label` is kept under
+pub fn checkpoint_ref_name(env_id: &str, label: &str) -> Result<String> {
+    let name = format!("{}{}/{}", CHECKPOINT_REF_PREFIX, env_id, label);
+    if label.contains('/') || !Reference::is_valid_name(&name) {
+        bail!("'{}' is not a valid checkpoint label for environment {}", label, env_id);
+    }
+    Ok(name)
+}
+
+/// Commit pending changes in the worktree at `root` and pin HEAD as a checkpoint
+///
+/// An existing checkpoint ref of the same name is moved.
+pub fn checkpoint_worktree(root: &Path, env_id: &str, label: &str, options: &CommitOptions) -> Result<WorktreeCheckpoint> {
+    let checkpoint_ref = checkpoint_ref_name(env_id, label)?;
+    let pending = commit_changes(root, options)?;
+
+    let repo = Repository::open(root)
+        .with_context(|| format!("Failed to open repository at {}", root.display()))?;
+    let commit = repo.head()
+        .and_then(|head| head.peel_to_commit())
+        .context("Failed to resolve HEAD to a commit")?
+        .id();
+    repo.reference(&checkpoint_ref, commit, true, &format!("cofer: checkpoint {}", label))
+        .with_context(|| format!("Failed to create {}", checkpoint_ref))?;
+
+    debug!("Checkpoint {} of {} is at {}", label, root.display(), commit);
+    Ok(WorktreeCheckpoint {
+        commit: commit.to_string(),
+        checkpoint_ref,
+        pending,
+    })
+}
+
+/// Reset the worktree at `root` and its branch to `commit`
+///
+/// Pending changes are committed first so that, like everything else the
+/// reset throws away, they can still be found in the branch's reflog.
+/// Ignored and excluded files are left alone. Returns the commit the branch
+/// was at before, `None` if it already was at `commit`.
+pub fn restore_worktree(root: &Path, commit: &str, options: &CommitOptions) -> Result<Option<String>> {
+    let target = Oid::from_str(commit).with_context(|| format!("'{}' is not a commit id", commit))?;
+    commit_changes(root, options)?;
+
+    let lock = worktree_lock(root);
+    let _guard = lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let repo = Repository::open(root)
+        .with_context(|| format!("Failed to open repository at {}", root.display()))?;
+    let previous = repo.head()
+        .and_then(|head| head.peel_to_commit())
+        .context("Failed to resolve HEAD to a commit")?
+        .id();
+    let object = repo.find_object(target, None)
+        .and_then(|object| object.peel(git2::ObjectType::Commit))
+        .with_context(|| format!("Commit {} not found", commit))?;
+
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force();
+    repo.reset(&object, ResetType::Hard, Some(&mut checkout))
+        .with_context(|| format!("Failed to reset {} to {}", root.display(), commit))?;
+
+    debug!("Reset {} from {} to {}", root.display(), previous, target);
+    Ok((previous != target).then(|| previous.to_string()))
+}
+
+/// Delete the checkpoint refs of `env_id` from the repository at `repository`
+///
+/// Returns how many refs were deleted.
+pub fn remove_checkpoint_refs(repository: &Path, env_id: &str) -> Result<usize> {
+    let repo = Repository::open(repository)
+        .with_context(|| format!("Failed to open repository at {}", repository.display()))?;
+    let glob = format!("{}{}/*", CHECKPOINT_REF_PREFIX, env_id);
+    let mut removed = 0;
+    for reference in repo.references_glob(&glob).context("Failed to list checkpoint refs")? {
+        let mut reference = reference.context("Failed to read checkpoint ref")?;
+        reference.delete()
+            .with_context(|| format!("Failed to delete {}", reference.name().unwrap_or_default()))?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::worktree::tests::init_repo;
+
+    #[test]
+    fn test_checkpoint_ref_name() {
+        assert_eq!(checkpoint_ref_name("env1", "before-upgrade").unwrap(), "refs/cofer/checkpoints/env1/before-upgrade");
+        assert!(checkpoint_ref_name("env1", "a/b").is_err());
+        assert!(checkpoint_ref_name("env1", "bad..label").is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path());
+        let options = CommitOptions::default();
+
+        std::fs::write(dir.path().join("a.txt"), "a\n").unwrap();
+        let checkpoint = checkpoint_worktree(dir.path(), "env1", "one", &options).unwrap();
+        assert!(checkpoint.pending.commit.is_some());
+        assert_eq!(checkpoint.pending.commit.as_deref(), Some(checkpoint.commit.as_str()));
+        let pinned = repo.find_reference(&checkpoint.checkpoint_ref).unwrap().target().unwrap();
+        assert_eq!(pinned.to_string(), checkpoint.commit);
+
+        // Wreck the worktree, partly committed and partly not
+        std::fs::remove_dir_all(dir.path().join("sub")).unwrap();
+        commit_changes(dir.path(), &options).unwrap();
+        std::fs::write(dir.path().join("README.md"), "broken\n").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "b\n").unwrap();
+
+        let discarded = restore_worktree(dir.path(), &checkpoint.commit, &options).unwrap();
+        assert!(discarded.is_some());
+        assert_eq!(repo.head().unwrap().target().unwrap().to_string(), checkpoint.commit);
+        assert_eq!(std::fs::read_to_string(dir.path().join("README.md")).unwrap(), "hello\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("sub").join("file.txt")).unwrap(), "nested\n");
+        assert!(dir.path().join("a.txt").exists());
+        assert!(!dir.path().join("b.txt").exists());
+
+        assert_eq!(restore_worktree(dir.path(), &checkpoint.commit, &options).unwrap(), None);
+
+        checkpoint_worktree(dir.path(), "env2", "one", &options).unwrap();
+        assert_eq!(remove_checkpoint_refs(dir.path(), "env1").unwrap(), 1);
+        assert!(repo.find_reference(&checkpoint.checkpoint_ref).is_err());
+        assert!(repo.find_reference("refs/cofer/checkpoints/env2/one").is_ok());
+    }
+}
@@ -0,0 +1,477 @@
+use anyhow::{Context, Result};
+use git2::{ErrorCode, Index, Repository, Signature, StatusOptions};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use tracing::debug;
+
+/// Paths never committed or watched unless the caller replaces the list
+pub const DEFAULT_EXCLUDES: &[&str] = &[".git/", "node_modules/", "target/"];
+
+/// Bytes inspected when deciding whether content is binary, as git does
+const BINARY_SNIFF_LEN: usize = 8000;
+
+/// Files listed by name in a commit message before the rest are counted
+const MESSAGE_MAX_FILES: usize = 20;
+
+/// How changes in a worktree are turned into a commit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitOptions {
+    /// Glob patterns of paths left out; a trailing `/` matches a directory
+    /// anywhere, a pattern without `/` matches any path component
+    pub exclude: Vec<String>,
+    /// Leave binary files out of the commit
+    pub nonbinary_only: bool,
+}
+
+impl Default for CommitOptions {
+    fn default() -> Self {
+        Self {
+            exclude: DEFAULT_EXCLUDES.iter().map(|pattern| pattern.to_string()).collect(),
+            nonbinary_only: true,
+        }
+    }
+}
+
+/// How a file changed since the last commit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// A file included in a commit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileChange {
+    pub path: String,
+    pub change: ChangeKind,
+}
+
+/// Result of committing a worktree's changes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CommitSummary {
+    /// Id of the new commit, `None` if there was nothing to commit
+    pub commit: Option<String>,
+    pub changes: Vec<FileChange>,
+    /// Changed files left out because they are binary or not regular files
+    pub skipped: Vec<String>,
+}
+
+/// Commit every change in the worktree at `root` to its checked out branch
+///
+/// Excluded and ignored paths are left alone, as are binary files when
+/// `nonbinary_only` is set. Concurrent calls for the same worktree are
+/// serialized so they do not fight over the index lock.
+pub fn commit_changes(root: &Path, options: &CommitOptions) -> Result<CommitSummary> {
+    let lock = worktree_lock(root);
+    let _guard = lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let repo = Repository::open(root)
+        .with_context(|| format!("Failed to open repository at {}", root.display()))?;
+    let mut index = repo.index().context("Failed to open index")?;
+    let mut summary = stage_changes(&repo, &mut index, options)?;
+
+    index.write().context("Failed to write index")?;
+    let tree_id = index.write_tree().context("Failed to write tree")?;
+
+    let parent = match repo.head() {
+        Ok(head) => Some(head.peel_to_commit()?),
+        Err(e) if e.code() == ErrorCode::UnbornBranch => None,
+        Err(e) => return Err(e).context("Failed to resolve HEAD"),
+    };
+    if parent.as_ref().is_some_and(|parent| parent.tree_id() == tree_id) {
+        debug!("Nothing to commit in {}", root.display());
+        summary.changes.clear();
+        return Ok(summary);
+    }
+
+    let tree = repo.find_tree(tree_id)?;
+    let signature = signature(&repo)?;
+    let message = commit_message(&summary.changes);
+    let parents: Vec<_> = parent.iter().collect();
+    let commit = repo.commit(Some("HEAD"), &signature, &signature, &message, &tree, &parents)
+        .context("Failed to commit")?;
+
+    debug!("Committed {} changes in {} as {}", summary.changes.len(), root.display(), commit);
+    summary.commit = Some(commit.to_string());
+    Ok(summary)
+}
+
+/// Record every change in the worktree at `root` in a commit on top of HEAD
+///
+/// Like `git stash create`, but including untracked files: neither the
+/// branch nor the index on disk is touched. Returns HEAD itself if there is
+/// nothing to record.
+pub fn snapshot_changes(root: &Path, options: &CommitOptions) -> Result<String> {
+    let lock = worktree_lock(root);
+    let _guard = lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let repo = Repository::open(root)
+        .with_context(|| format!("Failed to open repository at {}", root.display()))?;
+    let head = repo.head()
+        .and_then(|head| head.peel_to_commit())
+        .context("Failed to resolve HEAD to a commit")?;
+
+    // Changes are staged in memory only; the index is never written back
+    let mut index = repo.index().context("Failed to open index")?;
+    let summary = stage_changes(&repo, &mut index, options)?;
+    let tree_id = index.write_tree().context("Failed to write tree")?;
+    if head.tree_id() == tree_id {
+        debug!("Nothing to snapshot in {}", root.display());
+        return Ok(head.id().to_string());
+    }
+
+    let tree = repo.find_tree(tree_id)?;
+    let signature = signature(&repo)?;
+    let message = commit_message(&summary.changes);
+    let commit = repo.commit(None, &signature, &signature, &message, &tree, &[&head])
+        .context("Failed to commit snapshot")?;
+
+    debug!("Snapshot of {} changes in {} is {}", summary.changes.len(), root.display(), commit);
+    Ok(commit.to_string())
+}
+
+/// Stage every change in the repository's worktree into `index`
+///
+/// The index is reset to HEAD's tree first, so entries staged by hand are
+/// held to the same rules. Excluded, ignored and conflicted paths are left
+/// out, as are binary files when `nonbinary_only` is set.
+fn stage_changes(repo: &Repository, index: &mut Index, options: &CommitOptions) -> Result<CommitSummary> {
+    let workdir = repo.workdir().context("Repository has no working directory")?.to_path_buf();
+
+    match repo.head() {
+        Ok(head) => {
+            let tree = head.peel_to_tree().context("Failed to resolve HEAD to a tree")?;
+            index.read_tree(&tree).context("Failed to reset index to HEAD")?;
+        }
+        Err(e) if e.code() == ErrorCode::UnbornBranch => index.clear().context("Failed to clear index")?,
+        Err(e) => return Err(e).context("Failed to resolve HEAD"),
+    }
+
+    let mut status_options = StatusOptions::new();
+    status_options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .include_ignored(false);
+    let statuses = repo.statuses(Some(&mut status_options)).context("Failed to read status")?;
+
+    let mut summary = CommitSummary::default();
+    for entry in statuses.iter() {
+        let Some(path) = entry.path().map(str::to_string) else {
+            continue;
+        };
+        let status = entry.status();
+        if status.is_conflicted() || is_excluded(&path, &options.exclude) {
+            continue;
+        }
+
+        // Whatever the index said, the change is the worktree against HEAD
+        let full_path = workdir.join(&path);
+        let change = if full_path.symlink_metadata().is_err() {
+            ChangeKind::Deleted
+        } else if index.get_path(Path::new(&path), 0).is_none() {
+            ChangeKind::Added
+        } else {
+            ChangeKind::Modified
+        };
+
+        if change == ChangeKind::Deleted {
+            if index.get_path(Path::new(&path), 0).is_none() {
+                // Staged by hand and deleted since, so never in HEAD
+                continue;
+            }
+            match index.remove_path(Path::new(&path)) {
+                Ok(()) => {}
+                Err(e) if e.code() == ErrorCode::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("Failed to stage removal of {}", path)),
+            }
+        } else if path.ends_with('/') || (options.nonbinary_only && is_binary(&full_path)) {
+            summary.skipped.push(path);
+            continue;
+        } else {
+            index.add_path(Path::new(&path)).with_context(|| format!("Failed to stage {}", path))?;
+        }
+        summary.changes.push(FileChange { path, change });
+    }
+
+    Ok(summary)
+}
+
+/// Signature for commits cofer makes, the repository's configured one if set
+pub(super) fn signature(repo: &Repository) -> Result<Signature<'static>> {
+    repo.signature()
+        .or_else(|_| Signature::now("cofer", "cofer@localhost"))
+        .context("Failed to build commit signature")
+}
+
+/// Lock serializing commits to one worktree
+pub(super) fn worktree_lock(root: &Path) -> Arc<Mutex<()>> {
+    static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> = OnceLock::new();
+
+    let mut locks = LOCKS.get_or_init(Default::default).lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    locks.entry(root.to_path_buf()).or_default().clone()
+}
+
+/// Summarize the changes as a commit message
+///
+/// A single change gets a one-line message such as `Edit src/app.ts`;
+/// several get a count per kind and the list of files in the body.
+pub fn commit_message(changes: &[FileChange]) -> String {
+    if let [change] = changes {
+        let verb = match change.change {
+            ChangeKind::Added => "Add",
+            ChangeKind::Modified => "Edit",
+            ChangeKind::Deleted => "Delete",
+        };
+        return format!("{} {}", verb, change.path);
+    }
+
+    let count = |kind| changes.iter().filter(|change| change.change == kind).count();
+    let counts: Vec<String> = [
+        (ChangeKind::Added, "added"),
+        (ChangeKind::Modified, "modified"),
+        (ChangeKind::Deleted, "deleted"),
+    ]
+    .into_iter()
+    .filter_map(|(kind, label)| match count(kind) {
+        0 => None,
+        n => Some(format!("{} {}", n, label)),
+    })
+    .collect();
+
+    let mut message = format!("Update {} files ({})\n\n", changes.len(), counts.join(", "));
+    for change in changes.iter().take(MESSAGE_MAX_FILES) {
+        let marker = match change.change {
+            ChangeKind::Added => 'A',
+            ChangeKind::Modified => 'M',
+            ChangeKind::Deleted => 'D',
+        };
+        message.push_str(&format!("{} {}\n", marker, change.path));
+    }
+    if changes.len() > MESSAGE_MAX_FILES {
+        message.push_str(&format!("... and {} more\n", changes.len() - MESSAGE_MAX_FILES));
+    }
+    message
+}
+
+/// Check whether content looks binary: it has a NUL byte near the start
+pub(super) fn is_binary_content(content: &[u8]) -> bool {
+    content[..content.len().min(BINARY_SNIFF_LEN)].contains(&0)
+}
+
+/// Check whether a regular file's content looks binary
+fn is_binary(path: &Path) -> bool {
+    let is_file = std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_file());
+    if !is_file {
+        return false;
+    }
+
+    let mut buffer = Vec::with_capacity(BINARY_SNIFF_LEN);
+    match std::fs::File::open(path) {
+        Ok(file) => {
+            let _ = file.take(BINARY_SNIFF_LEN as u64).read_to_end(&mut buffer);
+            is_binary_content(&buffer)
+        }
+        Err(_) => false,
+    }
+}
+
+/// Check a `/`-separated relative path against exclude patterns
+pub fn is_excluded(path: &str, patterns: &[String]) -> bool {
+    let path = path.trim_end_matches('/');
+    let components: Vec<&str> = path.split('/').collect();
+
+    patterns.iter().any(|pattern| {
+        if let Some(dir) = pattern.strip_suffix('/') {
+            // Directories match anywhere above the file itself
+            let dir = dir.trim_start_matches('/');
+            components[..components.len() - 1].iter().any(|component| glob_match(dir, component))
+                || (dir.contains('/') && path.starts_with(&format!("{}/", dir)))
+        } else if pattern.contains('/') {
+            glob_match(pattern.trim_start_matches('/'), path)
+        } else {
+            components.iter().any(|component| glob_match(pattern, component))
+        }
+    })
+}
+
+/// Match `text` against a glob where `*` stays within a path component,
+/// `**` crosses components and `?` is any single character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match pattern {
+            [] => text.is_empty(),
+            ['*', '*', rest @ ..] => {
+                let rest = rest.strip_prefix(&['/']).unwrap_or(rest);
+                (0..=text.len()).any(|skip| matches(rest, &text[skip..]))
+            }
+            ['*', rest @ ..] => {
+                let limit = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+                (0..=limit).any(|skip| matches(rest, &text[skip..]))
+            }
+            ['?', rest @ ..] => text.first().is_some_and(|&c| c != '/') && matches(rest, &text[1..]),
+            [c, rest @ ..] => text.first() == Some(c) && matches(rest, &text[1..]),
+        }
+    }
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches(&pattern, &text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::worktree::tests::init_repo;
+    use git2::Status;
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn test_is_excluded() {
+        let defaults = CommitOptions::default().exclude;
+        assert!(is_excluded("node_modules/left-pad/index.js", &defaults));
+        assert!(is_excluded("web/node_modules/a.js", &defaults));
+        assert!(is_excluded("target/debug/cofer", &defaults));
+        assert!(!is_excluded("src/target.rs", &defaults));
+        assert!(!is_excluded("target", &defaults));
+
+        let custom = patterns(&["*.log", "build/out/", "docs/*.md", "**/gen/*.rs"]);
+        assert!(is_excluded("debug.log", &custom));
+        assert!(is_excluded("logs/today.log", &custom));
+        assert!(is_excluded("build/out/app.js", &custom));
+        assert!(!is_excluded("build/app.js", &custom));
+        assert!(is_excluded("docs/README.md", &custom));
+        assert!(!is_excluded("docs/api/README.md", &custom));
+        assert!(is_excluded("src/gen/a.rs", &custom));
+        assert!(is_excluded("gen/a.rs", &custom));
+    }
+
+    #[test]
+    fn test_commit_message() {
+        let change = |path: &str, change| FileChange { path: path.to_string(), change };
+
+        assert_eq!(commit_message(&[change("src/app.ts", ChangeKind::Modified)]), "Edit src/app.ts");
+
+        let message = commit_message(&[
+            change("a.txt", ChangeKind::Added),
+            change("b.txt", ChangeKind::Added),
+            change("c.txt", ChangeKind::Deleted),
+        ]);
+        assert!(message.starts_with("Update 3 files (2 added, 1 deleted)\n\n"));
+        assert!(message.contains("A a.txt\n"));
+        assert!(message.contains("D c.txt\n"));
+
+        let many: Vec<FileChange> = (0..25).map(|i| change(&format!("f{}", i), ChangeKind::Modified)).collect();
+        assert!(commit_message(&many).ends_with("... and 5 more\n"));
+    }
+
+    #[test]
+    fn test_commit_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path());
+        let root = dir.path();
+
+        // A clean worktree has nothing to commit
+        let summary = commit_changes(root, &CommitOptions::default()).unwrap();
+        assert_eq!(summary, CommitSummary::default());
+
+        std::fs::write(root.join("README.md"), "changed\n").unwrap();
+        std::fs::write(root.join("new.txt"), "new\n").unwrap();
+        std::fs::remove_file(root.join("sub").join("file.txt")).unwrap();
+        std::fs::write(root.join("image.bin"), [0u8, 1, 2, 3]).unwrap();
+        std::fs::create_dir(root.join("node_modules")).unwrap();
+        std::fs::write(root.join("node_modules").join("dep.js"), "dep\n").unwrap();
+
+        let summary = commit_changes(root, &CommitOptions::default()).unwrap();
+        let commit = summary.commit.clone().expect("changes are committed");
+        let mut changes: Vec<(&str, ChangeKind)> = summary.changes.iter()
+            .map(|change| (change.path.as_str(), change.change))
+            .collect();
+        changes.sort_by_key(|(path, _)| *path);
+        assert_eq!(changes, vec![
+            ("README.md", ChangeKind::Modified),
+            ("new.txt", ChangeKind::Added),
+            ("sub/file.txt", ChangeKind::Deleted),
+        ]);
+        assert_eq!(summary.skipped, vec!["image.bin".to_string()]);
+
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.id().to_string(), commit);
+        assert!(head.message().unwrap().starts_with("Update 3 files"));
+        let tree = head.tree().unwrap();
+        assert!(tree.get_path(Path::new("new.txt")).is_ok());
+        assert!(tree.get_path(Path::new("image.bin")).is_err());
+        assert!(tree.get_path(Path::new("node_modules/dep.js")).is_err());
+
+        // Binary files go in when asked for
+        let options = CommitOptions { nonbinary_only: false, ..CommitOptions::default() };
+        let summary = commit_changes(root, &options).unwrap();
+        assert!(summary.commit.is_some());
+        assert_eq!(summary.changes, vec![FileChange { path: "image.bin".to_string(), change: ChangeKind::Added }]);
+    }
+
+    #[test]
+    fn test_commit_changes_ignores_prestaged_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path());
+        let root = dir.path();
+
+        // Staged by hand, as `git add -f` would
+        std::fs::write(root.join("image.bin"), [0u8, 1, 2, 3]).unwrap();
+        std::fs::create_dir(root.join("target")).unwrap();
+        std::fs::write(root.join("target").join("out.o"), "object\n").unwrap();
+        std::fs::write(root.join("gone.txt"), "gone\n").unwrap();
+        let mut index = repo.index().unwrap();
+        for path in ["image.bin", "target/out.o", "gone.txt"] {
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        std::fs::remove_file(root.join("gone.txt")).unwrap();
+        std::fs::write(root.join("new.txt"), "new\n").unwrap();
+
+        let summary = commit_changes(root, &CommitOptions::default()).unwrap();
+        assert!(summary.commit.is_some());
+        assert_eq!(summary.changes, vec![FileChange { path: "new.txt".to_string(), change: ChangeKind::Added }]);
+        assert_eq!(summary.skipped, vec!["image.bin".to_string()]);
+
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        assert!(tree.get_path(Path::new("new.txt")).is_ok());
+        assert!(tree.get_path(Path::new("image.bin")).is_err());
+        assert!(tree.get_path(Path::new("target/out.o")).is_err());
+        assert!(tree.get_path(Path::new("gone.txt")).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path());
+        let root = dir.path();
+        let head = repo.head().unwrap().target().unwrap();
+
+        assert_eq!(snapshot_changes(root, &CommitOptions::default()).unwrap(), head.to_string());
+
+        std::fs::write(root.join("README.md"), "changed\n").unwrap();
+        std::fs::write(root.join("new.txt"), "new\n").unwrap();
+        let snapshot = snapshot_changes(root, &CommitOptions::default()).unwrap();
+        assert_ne!(snapshot, head.to_string());
+
+        // The branch and index are untouched
+        assert_eq!(repo.head().unwrap().target().unwrap(), head);
+        let statuses = repo.statuses(None).unwrap();
+        assert!(statuses.iter().all(|entry| !entry.status().intersects(Status::INDEX_NEW | Status::INDEX_MODIFIED)));
+
+        let commit = repo.find_commit(git2::Oid::from_str(&snapshot).unwrap()).unwrap();
+        assert_eq!(commit.parent_id(0).unwrap(), head);
+        let tree = commit.tree().unwrap();
+        assert!(tree.get_path(Path::new("new.txt")).is_ok());
+        let readme = repo.find_blob(tree.get_path(Path::new("README.md")).unwrap().id()).unwrap();
+        assert_eq!(readme.content(), b"changed\n");
+    }
+}
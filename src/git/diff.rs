@@ -0,0 +1,315 @@
+use anyhow::{Context, Result};
+use gix::bstr::BString;
+use gix::diff::blob::intern::InternedInput;
+use gix::diff::blob::sink::Counter;
+use gix::diff::blob::unified_diff::{ContextSize, NewlineSeparator};
+use gix::diff::blob::{sources, Algorithm, UnifiedDiff};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+use tracing::debug;
+
+use super::commit::{is_binary_content, ChangeKind};
+
+/// Patch text returned unless the caller picks another cap
+pub const DEFAULT_DIFF_MAX_BYTES: usize = 256 * 1024;
+
+/// How much detail a diff carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffFormat {
+    /// Changed paths only
+    NameOnly,
+    /// Changed paths with inserted and deleted line counts
+    Stat,
+    /// Line counts and a unified patch per file
+    Patch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffOptions {
+    pub format: DiffFormat,
+    /// Unchanged lines shown around each hunk of a patch
+    pub context_lines: u32,
+    /// Pathspecs limiting the diff, everything when empty
+    pub paths: Vec<String>,
+    /// Cap on the total size of the patches
+    pub max_bytes: usize,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            format: DiffFormat::Patch,
+            context_lines: 3,
+            paths: Vec::new(),
+            max_bytes: DEFAULT_DIFF_MAX_BYTES,
+        }
+    }
+}
+
+/// How one file differs from the base
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileDiff {
+    pub path: String,
+    pub change: ChangeKind,
+    pub binary: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub insertions: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deletions: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patch: Option<String>,
+}
+
+/// Differences between a base commit and a worktree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WorktreeDiff {
+    /// Commit the worktree was compared against
+    pub base: String,
+    pub files: Vec<FileDiff>,
+    pub insertions: u32,
+    pub deletions: u32,
+    /// Whether patches were cut off at the byte cap
+    pub truncated: bool,
+}
+
+/// Diff the worktree at `root`, committed and uncommitted changes alike, against `base`
+///
+/// Changed paths come from a status of the worktree against the base
+/// commit's tree, including untracked files but not ignored ones. Each file
+/// is then compared as it is on disk with its content in the base.
+pub fn diff_worktree(root: &Path, base: &str, options: &DiffOptions) -> Result<WorktreeDiff> {
+    let repo = gix::open(root).with_context(|| format!("Failed to open repository at {}", root.display()))?;
+    let workdir = repo.workdir().context("Repository has no working directory")?.to_path_buf();
+
+    let base_commit = repo.rev_parse_single(base)
+        .with_context(|| format!("Base ref '{}' not found", base))?
+        .object()?
+        .peel_to_commit()
+        .with_context(|| format!("Base ref '{}' does not name a commit", base))?;
+    let base_tree = base_commit.tree().context("Failed to read the base tree")?;
+
+    let patterns: Vec<BString> = options.paths.iter().map(|path| path.as_str().into()).collect();
+    let mut paths = BTreeSet::new();
+    let status = repo.status(gix::progress::Discard)?
+        .untracked_files(gix::status::UntrackedFiles::Files)
+        .index_worktree_submodules(None)
+        .head_tree(base_tree.id)
+        .into_iter(patterns)
+        .context("Failed to read status")?;
+    for item in status {
+        let item = item.context("Failed to read status")?;
+        paths.insert(item.location().to_string());
+    }
+
+    let mut diff = WorktreeDiff {
+        base: base_commit.id.to_string(),
+        files: Vec::new(),
+        insertions: 0,
+        deletions: 0,
+        truncated: false,
+    };
+    let mut budget = options.max_bytes;
+    for path in paths {
+        let Some(before) = base_content(&base_tree, &path)? else {
+            continue;
+        };
+        let Some(after) = worktree_content(&workdir.join(&path))? else {
+            continue;
+        };
+        let change = match (&before, &after) {
+            (None, None) => continue,
+            (Some(before), Some(after)) if before == after => continue,
+            (None, Some(_)) => ChangeKind::Added,
+            (Some(_), None) => ChangeKind::Deleted,
+            (Some(_), Some(_)) => ChangeKind::Modified,
+        };
+
+        let before = before.unwrap_or_default();
+        let after = after.unwrap_or_default();
+        let mut file = FileDiff {
+            path,
+            change,
+            binary: is_binary_content(&before) || is_binary_content(&after),
+            insertions: None,
+            deletions: None,
+            patch: None,
+        };
+        if options.format != DiffFormat::NameOnly {
+            let (insertions, deletions, patch) = if file.binary {
+                (0, 0, format!("Binary files {} differ\n", file_labels(&file.path, change).join(" and ")))
+            } else {
+                line_diff(&before, &after, options.context_lines)?
+            };
+            file.insertions = Some(insertions);
+            file.deletions = Some(deletions);
+            diff.insertions += insertions;
+            diff.deletions += deletions;
+
+            if options.format == DiffFormat::Patch {
+                let patch = format!("{}{}", patch_header(&file.path, change), patch);
+                if diff.truncated {
+                    // Past the cap, files are only listed
+                } else if patch.len() <= budget {
+                    budget -= patch.len();
+                    file.patch = Some(patch);
+                } else {
+                    file.patch = Some(truncate_at_line(&patch, budget).to_string());
+                    diff.truncated = true;
+                }
+            }
+        }
+        diff.files.push(file);
+    }
+
+    debug!("Diffed {} files in {} against {}", diff.files.len(), root.display(), diff.base);
+    Ok(diff)
+}
+
+/// Content of `path` in the base tree: `Some(None)` if it is absent, `None`
+/// if it is not a file, such as a submodule
+fn base_content(tree: &gix::Tree<'_>, path: &str) -> Result<Option<Option<Vec<u8>>>> {
+    let Some(entry) = tree.lookup_entry_by_path(path).context("Failed to look up base tree entry")? else {
+        return Ok(Some(None));
+    };
+    if !entry.mode().is_blob_or_symlink() {
+        return Ok(None);
+    }
+    let object = entry.object().with_context(|| format!("Failed to read {} from the base", path))?;
+    Ok(Some(Some(object.detach().data)))
+}
+
+/// Content of a worktree file: `Some(None)` if it is gone, `None` if it is a directory
+fn worktree_content(path: &Path) -> Result<Option<Option<Vec<u8>>>> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Some(None)),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    if metadata.is_symlink() {
+        let target = std::fs::read_link(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        return Ok(Some(Some(target.to_string_lossy().into_owned().into_bytes())));
+    }
+    if metadata.is_dir() {
+        return Ok(None);
+    }
+    let content = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(Some(Some(content)))
+}
+
+/// Count changed lines and render the hunks of a unified diff
+fn line_diff(before: &[u8], after: &[u8], context_lines: u32) -> Result<(u32, u32, String)> {
+    let input = InternedInput::new(
+        sources::byte_lines_with_terminator(before),
+        sources::byte_lines_with_terminator(after),
+    );
+    let sink = UnifiedDiff::new(
+        &input,
+        Vec::new(),
+        NewlineSeparator::AfterHeaderAndWhenNeeded("\n"),
+        ContextSize::symmetrical(context_lines),
+    );
+    let counter = gix::diff::blob::diff(Algorithm::Histogram, &input, Counter::new(sink));
+    let hunks = counter.wrapped.context("Failed to render diff")?;
+    Ok((counter.insertions, counter.removals, String::from_utf8_lossy(&hunks).into_owned()))
+}
+
+/// The `a/` and `b/` names of a file in a patch, `/dev/null` where it does not exist
+fn file_labels(path: &str, change: ChangeKind) -> [String; 2] {
+    let before = if change == ChangeKind::Added { "/dev/null".to_string() } else { format!("a/{}", path) };
+    let after = if change == ChangeKind::Deleted { "/dev/null".to_string() } else { format!("b/{}", path) };
+    [before, after]
+}
+
+fn patch_header(path: &str, change: ChangeKind) -> String {
+    let [before, after] = file_labels(path, change);
+    let mode = match change {
+        ChangeKind::Added => "new file\n",
+        ChangeKind::Deleted => "deleted file\n",
+        ChangeKind::Modified => "",
+    };
+    format!("diff --git a/{path} b/{path}\n{mode}--- {before}\n+++ {after}\n")
+}
+
+/// Cut `text` to at most `max` bytes, at the end of a line when there is one
+fn truncate_at_line(text: &str, max: usize) -> &str {
+    let mut end = max.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    match text[..end].rfind('\n') {
+        Some(newline) => &text[..=newline],
+        None => &text[..end],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::commit::{commit_changes, CommitOptions};
+    use crate::git::worktree::tests::init_repo;
+
+    /// A repository whose base commit is followed by one committed and some uncommitted changes
+    fn changed_repo() -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path());
+        let base = repo.head().unwrap().target().unwrap().to_string();
+
+        std::fs::write(dir.path().join("committed.txt"), "one\ntwo\n").unwrap();
+        commit_changes(dir.path(), &CommitOptions::default()).unwrap();
+
+        std::fs::write(dir.path().join("README.md"), "hello\nworld\n").unwrap();
+        std::fs::remove_file(dir.path().join("sub").join("file.txt")).unwrap();
+        std::fs::write(dir.path().join("untracked.bin"), [0u8, 1, 2]).unwrap();
+        (dir, base)
+    }
+
+    #[test]
+    fn test_diff_worktree() {
+        let (dir, base) = changed_repo();
+
+        let diff = diff_worktree(dir.path(), &base, &DiffOptions::default()).unwrap();
+        assert_eq!(diff.base, base);
+        assert!(!diff.truncated);
+        let files: Vec<(&str, ChangeKind, bool)> = diff.files.iter()
+            .map(|file| (file.path.as_str(), file.change, file.binary))
+            .collect();
+        assert_eq!(files, vec![
+            ("README.md", ChangeKind::Modified, false),
+            ("committed.txt", ChangeKind::Added, false),
+            ("sub/file.txt", ChangeKind::Deleted, false),
+            ("untracked.bin", ChangeKind::Added, true),
+        ]);
+        assert_eq!((diff.insertions, diff.deletions), (3, 1));
+
+        let readme = diff.files[0].patch.as_deref().unwrap();
+        assert_eq!(readme, "diff --git a/README.md b/README.md\n--- a/README.md\n+++ b/README.md\n@@ -1,1 +1,2 @@\n hello\n+world\n");
+        let deleted = diff.files[2].patch.as_deref().unwrap();
+        assert!(deleted.contains("deleted file\n--- a/sub/file.txt\n+++ /dev/null\n"), "{}", deleted);
+        assert!(diff.files[3].patch.as_deref().unwrap().ends_with("Binary files /dev/null and b/untracked.bin differ\n"));
+    }
+
+    #[test]
+    fn test_diff_options() {
+        let (dir, base) = changed_repo();
+
+        let options = DiffOptions { format: DiffFormat::NameOnly, ..DiffOptions::default() };
+        let diff = diff_worktree(dir.path(), &base, &options).unwrap();
+        assert_eq!(diff.files.len(), 4);
+        assert!(diff.files.iter().all(|file| file.insertions.is_none() && file.patch.is_none()));
+
+        let options = DiffOptions { format: DiffFormat::Stat, paths: vec!["sub".to_string()], ..DiffOptions::default() };
+        let diff = diff_worktree(dir.path(), &base, &options).unwrap();
+        assert_eq!(diff.files.len(), 1);
+        assert_eq!((diff.files[0].insertions, diff.files[0].deletions), (Some(0), Some(1)));
+        assert_eq!(diff.files[0].patch, None);
+
+        let options = DiffOptions { max_bytes: 80, ..DiffOptions::default() };
+        let diff = diff_worktree(dir.path(), &base, &options).unwrap();
+        assert!(diff.truncated);
+        assert!(diff.files[0].patch.as_ref().unwrap().len() <= 80);
+        assert_eq!(diff.files[3].patch, None);
+
+        assert!(diff_worktree(dir.path(), "no-such-ref", &DiffOptions::default()).is_err());
+    }
+}
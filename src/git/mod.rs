@@ -0,0 +1,10 @@
+pub mod checkpoint;
+pub mod commit;
+pub mod diff;
+pub mod notes;
+pub mod propagate;
+pub mod watcher;
+pub mod worktree;
+
+pub use watcher::WorktreeWatchers;
+pub use worktree::EnvironmentWorktree;
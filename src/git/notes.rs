@@ -0,0 +1,195 @@
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use git2::{ErrorCode, Reference, Repository};
+use serde::Serialize;
+use std::path::Path;
+use tracing::debug;
+
+use super::commit::signature;
+
+/// Notes ref payloads are appended to unless the caller picks another
+pub const DEFAULT_NOTES_REF: &str = "refs/notes/cofer";
+
+/// Lines kept from each end of a text payload unless the caller picks another
+pub const DEFAULT_CAP_LINES: usize = 120;
+
+/// First line of a note section holding a binary payload
+const BASE64_HEADER: &str = "content-transfer-encoding: base64";
+
+/// Width base64 payloads are wrapped at, as in MIME
+const BASE64_LINE_WIDTH: usize = 76;
+
+/// Content to append to a note
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotePayload {
+    /// Text such as a command transcript, capped to its first and last lines
+    Text(String),
+    /// Bytes stored base64-encoded under a header line, never capped
+    Binary(Vec<u8>),
+}
+
+impl NotePayload {
+    /// Decode a base64 payload, keeping it as text if it is UTF-8
+    pub fn from_base64(encoded: &str) -> Result<Self> {
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .context("Payload is not valid base64")?;
+        Ok(match String::from_utf8(bytes) {
+            Ok(text) => Self::Text(text),
+            Err(e) => Self::Binary(e.into_bytes()),
+        })
+    }
+}
+
+/// Result of appending to a note
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AppendedNote {
+    /// Id of the note blob
+    pub note: String,
+    /// Commit the note is attached to
+    pub commit: String,
+    #[serde(rename = "ref")]
+    pub notes_ref: String,
+    /// Text lines left out of the middle of the payload
+    pub lines_omitted: usize,
+    /// Size of the whole note after appending
+    pub bytes: usize,
+}
+
+/// Expand a short notes ref such as `cofer` to `refs/notes/cofer` and check it
+pub fn notes_ref_name(name: &str) -> Result<String> {
+    let full = if name.starts_with("refs/") {
+        name.to_string()
+    } else {
+        format!("refs/notes/{}", name)
+    };
+    if !full.starts_with("refs/notes/") || !Reference::is_valid_name(&full) {
+        bail!("'{}' is not a valid notes ref", name);
+    }
+    Ok(full)
+}
+
+/// Append `payload` to the note on HEAD of the repository containing `path`
+///
+/// Like `git notes append`, an existing note on the commit is kept and the
+/// payload added after a blank line.
+pub fn append_note(path: &Path, notes_ref: &str, payload: &NotePayload, cap_lines: usize) -> Result<AppendedNote> {
+    let notes_ref = notes_ref_name(notes_ref)?;
+    let repo = Repository::discover(path)
+        .with_context(|| format!("Failed to open repository at {}", path.display()))?;
+    let commit = repo.head()
+        .and_then(|head| head.peel_to_commit())
+        .context("Failed to resolve HEAD to a commit")?
+        .id();
+
+    let (section, lines_omitted) = match payload {
+        NotePayload::Text(text) => cap_text(text, cap_lines),
+        NotePayload::Binary(bytes) => (encode_binary(bytes), 0),
+    };
+
+    let existing = match repo.find_note(Some(&notes_ref), commit) {
+        Ok(note) => note.message().map(str::to_string),
+        Err(e) if e.code() == ErrorCode::NotFound => None,
+        Err(e) => return Err(e).with_context(|| format!("Failed to read note on {}", commit)),
+    };
+    let message = match existing {
+        Some(existing) if !existing.trim_end().is_empty() => format!("{}\n\n{}", existing.trim_end(), section),
+        _ => section,
+    };
+
+    let signature = signature(&repo)?;
+    let note = repo.note(&signature, &signature, Some(&notes_ref), commit, &message, true)
+        .with_context(|| format!("Failed to write note on {}", commit))?;
+
+    debug!("Appended {} bytes to note {} on {} in {}", message.len(), note, commit, notes_ref);
+    Ok(AppendedNote {
+        note: note.to_string(),
+        commit: commit.to_string(),
+        notes_ref,
+        lines_omitted,
+        bytes: message.len(),
+    })
+}
+
+/// Keep the first and last `cap_lines` lines of `text`, returning how many were dropped
+fn cap_text(text: &str, cap_lines: usize) -> (String, usize) {
+    let text = text.trim_end_matches('\n');
+    let lines: Vec<&str> = text.lines().collect();
+    if lines.len() <= cap_lines * 2 {
+        return (format!("{}\n", text), 0);
+    }
+
+    let omitted = lines.len() - cap_lines * 2;
+    let mut capped = Vec::with_capacity(cap_lines * 2 + 1);
+    capped.extend_from_slice(&lines[..cap_lines]);
+    let marker = format!("... {} lines omitted ...", omitted);
+    capped.push(&marker);
+    capped.extend_from_slice(&lines[lines.len() - cap_lines..]);
+    (format!("{}\n", capped.join("\n")), omitted)
+}
+
+/// Encode binary content as wrapped base64 under a header line
+fn encode_binary(bytes: &[u8]) -> String {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(bytes);
+    let mut section = format!("{}\n", BASE64_HEADER);
+    for chunk in encoded.as_bytes().chunks(BASE64_LINE_WIDTH) {
+        section.push_str(std::str::from_utf8(chunk).unwrap_or_default());
+        section.push('\n');
+    }
+    section
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::worktree::tests::init_repo;
+
+    #[test]
+    fn test_cap_text() {
+        assert_eq!(cap_text("one\ntwo\n", 1), ("one\ntwo\n".to_string(), 0));
+
+        let text: String = (1..=10).map(|i| format!("line {}\n", i)).collect();
+        let (capped, omitted) = cap_text(&text, 2);
+        assert_eq!(omitted, 6);
+        assert_eq!(capped, "line 1\nline 2\n... 6 lines omitted ...\nline 9\nline 10\n");
+    }
+
+    #[test]
+    fn test_notes_ref_name() {
+        assert_eq!(notes_ref_name("cofer").unwrap(), "refs/notes/cofer");
+        assert_eq!(notes_ref_name("refs/notes/build").unwrap(), "refs/notes/build");
+        assert!(notes_ref_name("refs/heads/main").is_err());
+        assert!(notes_ref_name("bad..ref").is_err());
+    }
+
+    #[test]
+    fn test_payload_from_base64() {
+        assert_eq!(NotePayload::from_base64("aGVsbG8=").unwrap(), NotePayload::Text("hello".to_string()));
+        assert_eq!(NotePayload::from_base64("AP8=").unwrap(), NotePayload::Binary(vec![0x00, 0xff]));
+        assert!(NotePayload::from_base64("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_append_note() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path());
+        let head = repo.head().unwrap().target().unwrap();
+
+        let first = append_note(dir.path(), DEFAULT_NOTES_REF, &NotePayload::Text("$ make\nok\n".to_string()), 10)
+            .unwrap();
+        assert_eq!(first.commit, head.to_string());
+        assert_eq!(first.notes_ref, DEFAULT_NOTES_REF);
+        assert_eq!(first.lines_omitted, 0);
+
+        let second = append_note(dir.path(), "cofer", &NotePayload::Binary(vec![0, 1, 2]), 10).unwrap();
+        assert_ne!(second.note, first.note);
+
+        let note = repo.find_note(Some(DEFAULT_NOTES_REF), head).unwrap();
+        assert_eq!(note.message().unwrap(), "$ make\nok\n\ncontent-transfer-encoding: base64\nAAEC\n");
+        assert_eq!(second.bytes, note.message().unwrap().len());
+
+        // Other refs keep their own notes
+        append_note(dir.path(), "other", &NotePayload::Text("x".to_string()), 10).unwrap();
+        assert_eq!(repo.find_note(Some("refs/notes/other"), head).unwrap().message(), Some("x\n"));
+    }
+}
@@ -0,0 +1,390 @@
+use anyhow::{bail, Context, Result};
+use git2::build::CheckoutBuilder;
+use git2::{BranchType, ErrorCode, Index, Oid, Repository};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+use super::commit::signature;
+use super::worktree::EnvironmentWorktree;
+
+/// Namespace environment branches are fetched into, like a remote's
+pub const REMOTE_NAME: &str = "cofer";
+
+/// How an environment's work is brought into a target branch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Only move the target forward; diverged branches are reported
+    FastForwardOnly,
+    /// Fast-forward when possible, otherwise create a merge commit
+    Merge,
+}
+
+/// What happened to the target branch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeOutcome {
+    /// The target already contains the environment's work
+    UpToDate,
+    FastForward,
+    Merged,
+    /// The branches diverged and only a fast-forward was allowed
+    NotFastForward,
+    /// The merge conflicts; nothing was changed
+    Conflicts,
+    /// The target could not be updated without touching uncommitted or
+    /// checked out work; nothing was changed
+    Blocked,
+}
+
+/// How a path conflicts between the target and the environment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    BothModified,
+    BothAdded,
+    DeletedByUs,
+    DeletedByThem,
+}
+
+/// A path the merge could not resolve
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Conflict {
+    pub path: String,
+    pub kind: ConflictKind,
+}
+
+/// Result of bringing an environment's branch into a target branch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MergeReport {
+    pub target: String,
+    pub outcome: MergeOutcome,
+    /// Commit the target points at afterwards
+    pub commit: String,
+    pub conflicts: Vec<Conflict>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Result of propagating an environment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Propagation {
+    /// Remote-tracking ref now pointing at the environment's branch
+    pub remote_ref: String,
+    /// Tip of the environment's branch
+    pub commit: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merge: Option<MergeReport>,
+}
+
+/// Remote-tracking ref an environment is fetched into
+pub fn remote_ref_name(env_id: &str) -> String {
+    format!("refs/remotes/{}/{}", REMOTE_NAME, env_id)
+}
+
+/// Bring an environment's branch into the repository at `project_root`
+///
+/// The worktree shares the repository's objects, so fetching only points
+/// `refs/remotes/cofer/<env_id>` at the branch tip. With a `target` branch
+/// the work is then fast-forwarded or merged into it. Merges are computed
+/// in memory: conflicts, or uncommitted changes the update would overwrite,
+/// are reported without touching the target branch or its checkout.
+pub fn propagate(
+    project_root: &Path,
+    env_id: &str,
+    worktree: &EnvironmentWorktree,
+    target: Option<&str>,
+    strategy: MergeStrategy,
+) -> Result<Propagation> {
+    let repo = Repository::discover(project_root)
+        .with_context(|| format!("Failed to open repository at {}", project_root.display()))?;
+
+    let tip = repo.find_branch(&worktree.branch, BranchType::Local)
+        .with_context(|| format!("Environment branch {} not found", worktree.branch))?
+        .get()
+        .peel_to_commit()?
+        .id();
+
+    let remote_ref = remote_ref_name(env_id);
+    repo.reference(&remote_ref, tip, true, &format!("cofer: fetch environment {}", env_id))
+        .with_context(|| format!("Failed to update {}", remote_ref))?;
+    debug!("Fetched environment {} into {} at {}", env_id, remote_ref, tip);
+
+    let merge = match target {
+        Some(target) => Some(merge_into(&repo, env_id, worktree, tip, target, strategy)?),
+        None => None,
+    };
+
+    Ok(Propagation {
+        remote_ref,
+        commit: tip.to_string(),
+        merge,
+    })
+}
+
+/// Name of the branch checked out in the repository containing `project_root`
+pub fn current_branch(project_root: &Path) -> Result<String> {
+    let repo = Repository::discover(project_root)
+        .with_context(|| format!("Failed to open repository at {}", project_root.display()))?;
+    let head = repo.head().context("Failed to resolve HEAD")?;
+    if !head.is_branch() {
+        bail!("HEAD is detached in {}", project_root.display());
+    }
+    Ok(head.shorthand().context("Branch name is not UTF-8")?.to_string())
+}
+
+fn merge_into(
+    repo: &Repository,
+    env_id: &str,
+    worktree: &EnvironmentWorktree,
+    tip: Oid,
+    target: &str,
+    strategy: MergeStrategy,
+) -> Result<MergeReport> {
+    let target_branch = repo.find_branch(target, BranchType::Local)
+        .with_context(|| format!("Target branch {} not found", target))?;
+    let target_ref = target_branch.get().name().context("Branch name is not UTF-8")?.to_string();
+    if target_ref == format!("refs/heads/{}", worktree.branch) {
+        bail!("Cannot merge environment {} into its own branch", env_id);
+    }
+    let base = target_branch.get().peel_to_commit()?.id();
+
+    let report = |outcome, commit: Oid, conflicts, detail: Option<String>| MergeReport {
+        target: target.to_string(),
+        outcome,
+        commit: commit.to_string(),
+        conflicts,
+        detail,
+    };
+
+    if base == tip || repo.graph_descendant_of(base, tip)? {
+        return Ok(report(MergeOutcome::UpToDate, base, Vec::new(), None));
+    }
+
+    let fast_forward = repo.graph_descendant_of(tip, base)?;
+    if !fast_forward && strategy == MergeStrategy::FastForwardOnly {
+        return Ok(report(
+            MergeOutcome::NotFastForward,
+            base,
+            Vec::new(),
+            Some(format!("{} and {} have diverged", target, worktree.branch)),
+        ));
+    }
+
+    if let Some(path) = checked_out_elsewhere(repo, &target_ref)? {
+        return Ok(report(
+            MergeOutcome::Blocked,
+            base,
+            Vec::new(),
+            Some(format!("{} is checked out in {}", target, path.display())),
+        ));
+    }
+
+    let (new_commit, outcome) = if fast_forward {
+        (tip, MergeOutcome::FastForward)
+    } else {
+        let ours = repo.find_commit(base)?;
+        let theirs = repo.find_commit(tip)?;
+        let mut index = repo.merge_commits(&ours, &theirs, None).context("Failed to merge")?;
+        if index.has_conflicts() {
+            return Ok(report(MergeOutcome::Conflicts, base, conflicts(&index)?, None));
+        }
+
+        let tree = repo.find_tree(index.write_tree_to(repo).context("Failed to write merged tree")?)?;
+        let signature = signature(repo)?;
+        let message = format!("Merge environment {} ({}) into {}", env_id, worktree.branch, target);
+        let merged = repo.commit(None, &signature, &signature, &message, &tree, &[&ours, &theirs])
+            .context("Failed to create merge commit")?;
+        (merged, MergeOutcome::Merged)
+    };
+
+    // Update the checkout first so a refusal leaves the branch where it was
+    let checked_out = !repo.is_bare() && repo.head().ok().and_then(|head| head.name().map(str::to_string))
+        == Some(target_ref.clone());
+    if checked_out {
+        let commit = repo.find_commit(new_commit)?;
+        let mut checkout = CheckoutBuilder::new();
+        checkout.safe();
+        if let Err(e) = repo.checkout_tree(commit.as_object(), Some(&mut checkout)) {
+            if e.code() == ErrorCode::Conflict {
+                return Ok(report(
+                    MergeOutcome::Blocked,
+                    base,
+                    Vec::new(),
+                    Some(format!("Uncommitted changes would be overwritten: {}", e.message())),
+                ));
+            }
+            return Err(e).context("Failed to check out the merge result");
+        }
+    }
+
+    let verb = if outcome == MergeOutcome::FastForward { "fast-forward" } else { "merge" };
+    repo.reference(&target_ref, new_commit, true, &format!("cofer: {} environment {}", verb, env_id))
+        .with_context(|| format!("Failed to update {}", target_ref))?;
+
+    info!("Propagated environment {} into {} ({}) at {}", env_id, target, verb, new_commit);
+    Ok(report(outcome, new_commit, Vec::new(), None))
+}
+
+/// Describe the conflicts left in a merged index
+fn conflicts(index: &Index) -> Result<Vec<Conflict>> {
+    let mut conflicts = Vec::new();
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        let kind = match (&conflict.ancestor, &conflict.our, &conflict.their) {
+            (Some(_), Some(_), Some(_)) => ConflictKind::BothModified,
+            (None, Some(_), Some(_)) => ConflictKind::BothAdded,
+            (_, Some(_), None) => ConflictKind::DeletedByThem,
+            _ => ConflictKind::DeletedByUs,
+        };
+        let entry = conflict.our.as_ref()
+            .or(conflict.their.as_ref())
+            .or(conflict.ancestor.as_ref())
+            .context("Conflict without entries")?;
+        conflicts.push(Conflict {
+            path: String::from_utf8_lossy(&entry.path).into_owned(),
+            kind,
+        });
+    }
+    Ok(conflicts)
+}
+
+/// Find another worktree of the repository with `target_ref` checked out
+fn checked_out_elsewhere(repo: &Repository, target_ref: &str) -> Result<Option<PathBuf>> {
+    let head_of = |other: &Repository| other.head().ok().and_then(|head| head.name().map(str::to_string));
+
+    if repo.is_worktree() {
+        let main = Repository::open(repo.commondir())?;
+        if head_of(&main).as_deref() == Some(target_ref) {
+            return Ok(Some(main.workdir().unwrap_or(main.path()).to_path_buf()));
+        }
+    }
+
+    for name in repo.worktrees()?.iter().flatten() {
+        let Ok(worktree) = repo.find_worktree(name) else {
+            continue;
+        };
+        if worktree.path() == repo.workdir().unwrap_or(repo.path()) {
+            continue;
+        }
+        if let Ok(other) = Repository::open_from_worktree(&worktree) {
+            if head_of(&other).as_deref() == Some(target_ref) {
+                return Ok(Some(worktree.path().to_path_buf()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::commit::{commit_changes, CommitOptions};
+    use crate::git::worktree::create_worktree;
+    use crate::git::worktree::tests::init_repo;
+
+    /// A repository on `master` with an environment worktree `env1`
+    fn setup() -> (tempfile::TempDir, tempfile::TempDir, Repository, EnvironmentWorktree) {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let worktrees_dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(repo_dir.path());
+        let worktree = create_worktree(repo_dir.path(), "env1", None, worktrees_dir.path()).unwrap().unwrap();
+        (repo_dir, worktrees_dir, repo, worktree)
+    }
+
+    fn write_and_commit(root: &Path, file: &str, content: &str) -> String {
+        std::fs::write(root.join(file), content).unwrap();
+        commit_changes(root, &CommitOptions::default()).unwrap().commit.unwrap()
+    }
+
+    fn head_branch(repo: &Repository) -> String {
+        repo.head().unwrap().shorthand().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_fetch_and_fast_forward() {
+        let (repo_dir, _worktrees_dir, repo, worktree) = setup();
+        let target = head_branch(&repo);
+        let tip = write_and_commit(&worktree.path, "feature.txt", "feature\n");
+
+        let fetched = propagate(repo_dir.path(), "env1", &worktree, None, MergeStrategy::Merge).unwrap();
+        assert_eq!(fetched.remote_ref, "refs/remotes/cofer/env1");
+        assert_eq!(fetched.commit, tip);
+        assert_eq!(fetched.merge, None);
+        assert_eq!(repo.refname_to_id("refs/remotes/cofer/env1").unwrap().to_string(), tip);
+
+        let propagated = propagate(repo_dir.path(), "env1", &worktree, Some(&target), MergeStrategy::FastForwardOnly)
+            .unwrap();
+        let merge = propagated.merge.unwrap();
+        assert_eq!(merge.outcome, MergeOutcome::FastForward);
+        assert_eq!(merge.commit, tip);
+        // The checked out target is updated on disk as well
+        assert_eq!(std::fs::read_to_string(repo_dir.path().join("feature.txt")).unwrap(), "feature\n");
+
+        let again = propagate(repo_dir.path(), "env1", &worktree, Some(&target), MergeStrategy::Merge).unwrap();
+        assert_eq!(again.merge.unwrap().outcome, MergeOutcome::UpToDate);
+        assert_eq!(current_branch(repo_dir.path()).unwrap(), target);
+    }
+
+    #[test]
+    fn test_merge_diverged_branches() {
+        let (repo_dir, _worktrees_dir, repo, worktree) = setup();
+        let target = head_branch(&repo);
+        write_and_commit(&worktree.path, "feature.txt", "feature\n");
+        write_and_commit(repo_dir.path(), "local.txt", "local\n");
+
+        let ff_only = propagate(repo_dir.path(), "env1", &worktree, Some(&target), MergeStrategy::FastForwardOnly)
+            .unwrap();
+        assert_eq!(ff_only.merge.unwrap().outcome, MergeOutcome::NotFastForward);
+
+        let merged = propagate(repo_dir.path(), "env1", &worktree, Some(&target), MergeStrategy::Merge)
+            .unwrap()
+            .merge
+            .unwrap();
+        assert_eq!(merged.outcome, MergeOutcome::Merged);
+        let commit = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(commit.id().to_string(), merged.commit);
+        assert_eq!(commit.parent_count(), 2);
+        assert!(repo_dir.path().join("feature.txt").exists());
+        assert!(repo_dir.path().join("local.txt").exists());
+    }
+
+    #[test]
+    fn test_conflicts_leave_target_untouched() {
+        let (repo_dir, _worktrees_dir, repo, worktree) = setup();
+        let target = head_branch(&repo);
+        write_and_commit(&worktree.path, "README.md", "from the environment\n");
+        let local = write_and_commit(repo_dir.path(), "README.md", "from the user\n");
+
+        let merge = propagate(repo_dir.path(), "env1", &worktree, Some(&target), MergeStrategy::Merge)
+            .unwrap()
+            .merge
+            .unwrap();
+        assert_eq!(merge.outcome, MergeOutcome::Conflicts);
+        assert_eq!(merge.commit, local);
+        assert_eq!(merge.conflicts, vec![Conflict { path: "README.md".to_string(), kind: ConflictKind::BothModified }]);
+        assert_eq!(repo.head().unwrap().target().unwrap().to_string(), local);
+        assert_eq!(std::fs::read_to_string(repo_dir.path().join("README.md")).unwrap(), "from the user\n");
+    }
+
+    #[test]
+    fn test_uncommitted_changes_block_the_update() {
+        let (repo_dir, _worktrees_dir, repo, worktree) = setup();
+        let target = head_branch(&repo);
+        let before = repo.head().unwrap().target().unwrap().to_string();
+        write_and_commit(&worktree.path, "README.md", "from the environment\n");
+        std::fs::write(repo_dir.path().join("README.md"), "uncommitted\n").unwrap();
+
+        let merge = propagate(repo_dir.path(), "env1", &worktree, Some(&target), MergeStrategy::Merge)
+            .unwrap()
+            .merge
+            .unwrap();
+        assert_eq!(merge.outcome, MergeOutcome::Blocked);
+        assert_eq!(repo.head().unwrap().target().unwrap().to_string(), before);
+        assert_eq!(std::fs::read_to_string(repo_dir.path().join("README.md")).unwrap(), "uncommitted\n");
+
+        // Neither the environment's own branch nor a missing branch is a target
+        assert!(propagate(repo_dir.path(), "env1", &worktree, Some("cofer/env1"), MergeStrategy::Merge).is_err());
+        assert!(propagate(repo_dir.path(), "env1", &worktree, Some("missing"), MergeStrategy::Merge).is_err());
+    }
+}
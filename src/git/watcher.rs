@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Context, Result};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use super::commit::{commit_changes, is_excluded, CommitOptions, CommitSummary};
+
+/// Quiet period after the last change before a batch is committed, kept
+/// well inside the 120ms budget for committing a single changed file
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// How a watched worktree is committed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchConfig {
+    /// Changes are batched until nothing has changed for this long
+    pub debounce: Duration,
+    pub commit: CommitOptions,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            debounce: DEFAULT_DEBOUNCE,
+            commit: CommitOptions::default(),
+        }
+    }
+}
+
+/// Called after every batch that produced a commit, or failed to
+pub type CommitCallback = Box<dyn Fn(Result<CommitSummary>) + Send + Sync>;
+
+/// Watches a worktree and commits its changes in debounced batches
+///
+/// Watching stops when the watcher is dropped.
+pub struct WorktreeWatcher {
+    _watcher: RecommendedWatcher,
+    task: JoinHandle<()>,
+}
+
+impl WorktreeWatcher {
+    /// Start watching the worktree at `root`; must be called within a tokio runtime
+    pub fn start(root: &Path, config: WatchConfig, on_commit: CommitCallback) -> Result<Self> {
+        let root = root.canonicalize()
+            .with_context(|| format!("Failed to resolve {}", root.display()))?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            match event {
+                Ok(event) if !event.kind.is_access() => {
+                    let _ = tx.send(event.paths);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("File watcher error: {}", e),
+            }
+        })
+        .context("Failed to create file watcher")?;
+        watcher.watch(&root, RecursiveMode::Recursive)
+            .with_context(|| format!("Failed to watch {}", root.display()))?;
+
+        debug!("Watching {} with a {:?} debounce", root.display(), config.debounce);
+        let task = tokio::spawn(commit_batches(root, config, rx, on_commit));
+        Ok(Self { _watcher: watcher, task })
+    }
+}
+
+impl Drop for WorktreeWatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Wait for relevant changes, let them settle, then commit them
+async fn commit_batches(
+    root: PathBuf,
+    config: WatchConfig,
+    mut rx: mpsc::UnboundedReceiver<Vec<PathBuf>>,
+    on_commit: CommitCallback,
+) {
+    let relevant = |paths: &[PathBuf]| {
+        paths.iter().any(|path| match path.strip_prefix(&root) {
+            Ok(relative) => {
+                let relative = relative.to_string_lossy().replace('\\', "/");
+                !relative.is_empty() && !is_excluded(&relative, &config.commit.exclude)
+            }
+            Err(_) => false,
+        })
+    };
+
+    while let Some(paths) = rx.recv().await {
+        if !relevant(&paths) {
+            continue;
+        }
+
+        // Keep absorbing changes until the worktree has been quiet for the debounce
+        loop {
+            match tokio::time::timeout(config.debounce, rx.recv()).await {
+                Ok(Some(_)) => continue,
+                Ok(None) => return,
+                Err(_) => break,
+            }
+        }
+
+        let (batch_root, options) = (root.clone(), config.commit.clone());
+        let result = tokio::task::spawn_blocking(move || commit_changes(&batch_root, &options))
+            .await
+            .map_err(|e| anyhow!("Commit task failed: {}", e))
+            .and_then(|result| result);
+        match result {
+            Ok(summary) if summary.commit.is_none() => debug!("Nothing to commit in {}", root.display()),
+            result => on_commit(result),
+        }
+    }
+}
+
+/// Worktree watchers per environment
+#[derive(Clone, Default)]
+pub struct WorktreeWatchers {
+    watchers: Arc<Mutex<HashMap<String, WorktreeWatcher>>>,
+}
+
+impl WorktreeWatchers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the watcher for an environment, replacing any previous one
+    pub fn insert(&self, env_id: &str, watcher: WorktreeWatcher) {
+        self.lock().insert(env_id.to_string(), watcher);
+    }
+
+    /// Stop watching an environment's worktree, returning whether it was watched
+    pub fn stop(&self, env_id: &str) -> bool {
+        self.lock().remove(env_id).is_some()
+    }
+
+    /// Check whether an environment's worktree is being watched
+    pub fn is_watching(&self, env_id: &str) -> bool {
+        self.lock().contains_key(env_id)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, WorktreeWatcher>> {
+        self.watchers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::worktree::tests::init_repo;
+
+    #[tokio::test]
+    async fn test_watcher_commits_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path());
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let config = WatchConfig::default();
+        let watcher = WorktreeWatcher::start(dir.path(), config, Box::new(move |result| {
+            let _ = tx.send(result);
+        }))
+        .unwrap();
+
+        // Excluded paths alone never trigger a commit
+        std::fs::create_dir(dir.path().join("target")).unwrap();
+        std::fs::write(dir.path().join("target").join("out.o"), "object").unwrap();
+        std::fs::write(dir.path().join("a.txt"), "a\n").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "b\n").unwrap();
+
+        let summary = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+            .await
+            .expect("a commit within the timeout")
+            .unwrap()
+            .unwrap();
+        let commit = summary.commit.expect("a commit id");
+        assert_eq!(repo.head().unwrap().target().unwrap().to_string(), commit);
+
+        let mut paths: Vec<String> = summary.changes.into_iter().map(|change| change.path).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["a.txt".to_string(), "b.txt".to_string()]);
+
+        let watchers = WorktreeWatchers::new();
+        watchers.insert("env1", watcher);
+        assert!(watchers.is_watching("env1"));
+        assert!(watchers.stop("env1"));
+        assert!(!watchers.stop("env1"));
+    }
+}
@@ -0,0 +1,344 @@
+use anyhow::{bail, Context, Result};
+use git2::{Branch, BranchType, ErrorCode, Repository, WorktreeAddOptions, WorktreePruneOptions};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// Prefix of the branch each environment works on
+pub const BRANCH_PREFIX: &str = "cofer/";
+
+/// A git worktree an environment mounts instead of the developer's checkout
+///
+/// The worktree's `.git` is a gitfile pointing at
+/// `<repository>/worktrees/<env_id>` by its absolute host path. Containers
+/// mount the repository at that same path (see [`Self::git_mounts`]), so
+/// git works inside them and commits made there land on the environment's
+/// branch of the developer's repository.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvironmentWorktree {
+    /// Root of the worktree on the host
+    pub path: PathBuf,
+    /// Branch checked out in the worktree, `cofer/<env_id>`
+    pub branch: String,
+    /// Git directory of the repository the worktree belongs to
+    pub repository: PathBuf,
+    /// Commit the branch started from
+    pub base_commit: String,
+    /// Where the project root sits inside the repository, empty at its root
+    #[serde(default)]
+    pub subdir: PathBuf,
+    /// Whether the branch was created along with the worktree rather than
+    /// reused, in which case destroying the environment deletes it too
+    #[serde(default)]
+    pub created_branch: bool,
+}
+
+impl EnvironmentWorktree {
+    /// Host directory to mount into the container
+    pub fn mount_source(&self) -> PathBuf {
+        self.path.join(&self.subdir)
+    }
+
+    /// Host directories a container must mount at their own paths for git to work in it
+    ///
+    /// The gitfile names the repository's git directory by its host path, so
+    /// that is always needed. When the project is a subdirectory of the
+    /// repository, only the subdirectory is mounted as the project and git
+    /// cannot find the gitfile from there; the whole worktree is then also
+    /// mounted at its host path, where git can be run.
+    pub fn git_mounts(&self) -> Vec<PathBuf> {
+        let mut mounts = vec![self.repository.clone()];
+        if !self.subdir.as_os_str().is_empty() {
+            mounts.push(self.path.clone());
+        }
+        mounts
+    }
+
+    /// Name of the worktree inside the repository's `worktrees/` directory
+    pub fn name(&self) -> Option<&str> {
+        self.path.file_name().and_then(|name| name.to_str())
+    }
+}
+
+/// Default directory environment worktrees are created in
+pub fn default_worktrees_dir() -> Result<PathBuf> {
+    Ok(crate::environment::store::data_dir()?.join("cofer").join("worktrees"))
+}
+
+/// Branch an environment works on
+pub fn branch_name(env_id: &str) -> String {
+    format!("{}{}", BRANCH_PREFIX, env_id)
+}
+
+/// Create a worktree for `env_id` of the repository containing `project_root`
+///
+/// The worktree is created at `<worktrees_dir>/<env_id>` on the branch
+/// `cofer/<env_id>`, which starts at `base_ref` (default `HEAD`). An
+/// existing branch of that name is checked out as it is, so an environment
+/// recreated with the same id carries on where it left off.
+///
+/// Returns `None` if `project_root` is not inside a git repository with a
+/// working directory; such projects are mounted directly.
+pub fn create_worktree(
+    project_root: &Path,
+    env_id: &str,
+    base_ref: Option<&str>,
+    worktrees_dir: &Path,
+) -> Result<Option<EnvironmentWorktree>> {
+    let repo = match Repository::discover(project_root) {
+        Ok(repo) => repo,
+        Err(e) if e.code() == ErrorCode::NotFound => {
+            debug!("{} is not in a git repository", project_root.display());
+            return Ok(None);
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to open repository at {}", project_root.display())),
+    };
+    let Some(workdir) = repo.workdir() else {
+        debug!("Repository at {} is bare", repo.path().display());
+        return Ok(None);
+    };
+
+    let subdir = project_root.canonicalize()
+        .with_context(|| format!("Failed to resolve {}", project_root.display()))?
+        .strip_prefix(workdir.canonicalize()?)
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    let branch = branch_name(env_id);
+    if env_id.contains(['/', '\\']) || !Branch::name_is_valid(&branch)? {
+        bail!("Environment id '{}' cannot be used as a git branch or worktree name", env_id);
+    }
+
+    let path = worktrees_dir.join(env_id);
+    if path.exists() {
+        bail!("Worktree directory {} already exists", path.display());
+    }
+    std::fs::create_dir_all(worktrees_dir)
+        .with_context(|| format!("Failed to create {}", worktrees_dir.display()))?;
+
+    let (reference, created_branch) = match repo.find_branch(&branch, BranchType::Local) {
+        Ok(existing) => {
+            if base_ref.is_some() {
+                warn!("Branch {} already exists, ignoring the base ref", branch);
+            }
+            (existing.into_reference(), false)
+        }
+        Err(e) if e.code() == ErrorCode::NotFound => {
+            let base_ref = base_ref.unwrap_or("HEAD");
+            let base = repo.revparse_single(base_ref)
+                .and_then(|object| object.peel_to_commit())
+                .with_context(|| format!("Base ref '{}' does not name a commit", base_ref))?;
+            let created = repo.branch(&branch, &base, false)
+                .with_context(|| format!("Failed to create branch {}", branch))?;
+            (created.into_reference(), true)
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to look up branch {}", branch)),
+    };
+    let base_commit = reference.peel_to_commit()?.id().to_string();
+
+    let mut options = WorktreeAddOptions::new();
+    options.reference(Some(&reference));
+    if let Err(e) = repo.worktree(env_id, &path, Some(&options)) {
+        if created_branch {
+            if let Err(e) = repo.find_branch(&branch, BranchType::Local).and_then(|mut b| b.delete()) {
+                warn!("Failed to delete branch {}: {}", branch, e);
+            }
+        }
+        return Err(e).with_context(|| format!("Failed to create worktree at {}", path.display()));
+    }
+
+    info!("Created worktree {} on branch {} at {}", env_id, branch, &base_commit[..7]);
+    Ok(Some(EnvironmentWorktree {
+        path,
+        branch,
+        repository: repo.commondir().to_path_buf(),
+        base_commit,
+        subdir,
+        created_branch,
+    }))
+}
+
+/// Remove an environment's worktree, including uncommitted changes in it
+///
+/// The branch is kept, so committed work survives, unless `delete_branch`
+/// is set.
+pub fn remove_worktree(worktree: &EnvironmentWorktree, delete_branch: bool) -> Result<()> {
+    let repo = Repository::open(&worktree.repository)
+        .with_context(|| format!("Failed to open repository at {}", worktree.repository.display()))?;
+
+    let name = worktree.name().context("Worktree path has no name")?;
+    match repo.find_worktree(name) {
+        Ok(registered) => {
+            let mut options = WorktreePruneOptions::new();
+            options.valid(true).working_tree(true);
+            registered.prune(Some(&mut options))
+                .with_context(|| format!("Failed to remove worktree {}", worktree.path.display()))?;
+        }
+        Err(e) if e.code() == ErrorCode::NotFound => {
+            debug!("Worktree {} is not registered, removing its directory", name);
+            if worktree.path.exists() {
+                std::fs::remove_dir_all(&worktree.path)
+                    .with_context(|| format!("Failed to remove {}", worktree.path.display()))?;
+            }
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to look up worktree {}", name)),
+    }
+
+    if delete_branch {
+        match repo.find_branch(&worktree.branch, BranchType::Local) {
+            Ok(mut branch) => branch.delete()
+                .with_context(|| format!("Failed to delete branch {}", worktree.branch))?,
+            Err(e) if e.code() == ErrorCode::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to look up branch {}", worktree.branch)),
+        }
+    }
+
+    info!("Removed worktree {}", worktree.path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Create a repository with one commit containing `README.md` and `sub/file.txt`
+    pub(crate) fn init_repo(dir: &Path) -> Repository {
+        let repo = Repository::init(dir).unwrap();
+        std::fs::write(dir.join("README.md"), "hello\n").unwrap();
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub").join("file.txt"), "nested\n").unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"], git2::IndexAddOption::DEFAULT, None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "Initial commit", &tree, &[]).unwrap();
+        drop(tree);
+        repo
+    }
+
+    #[test]
+    fn test_create_and_remove_worktree() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let worktrees_dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(repo_dir.path());
+
+        let worktree = create_worktree(repo_dir.path(), "env1", None, worktrees_dir.path())
+            .unwrap()
+            .expect("a repository gets a worktree");
+
+        assert_eq!(worktree.branch, "cofer/env1");
+        assert_eq!(worktree.path, worktrees_dir.path().join("env1"));
+        assert_eq!(worktree.subdir, PathBuf::new());
+        assert_eq!(worktree.base_commit, repo.head().unwrap().target().unwrap().to_string());
+        assert!(worktree.path.join("README.md").exists());
+
+        // The worktree's .git is a gitfile pointing into the repository
+        let gitfile = std::fs::read_to_string(worktree.path.join(".git")).unwrap();
+        assert!(gitfile.starts_with("gitdir: "));
+        assert!(gitfile.trim_end().trim_end_matches('/').ends_with("worktrees/env1"), "{}", gitfile);
+
+        // The same id cannot be used twice
+        assert!(create_worktree(repo_dir.path(), "env1", None, worktrees_dir.path()).is_err());
+
+        remove_worktree(&worktree, false).unwrap();
+        assert!(!worktree.path.exists());
+        assert!(repo.find_worktree("env1").is_err());
+        assert!(repo.find_branch("cofer/env1", BranchType::Local).is_ok());
+
+        // Recreating the environment checks out the kept branch again
+        let again = create_worktree(repo_dir.path(), "env1", None, worktrees_dir.path()).unwrap().unwrap();
+        remove_worktree(&again, true).unwrap();
+        assert!(repo.find_branch("cofer/env1", BranchType::Local).is_err());
+    }
+
+    /// Copy the files under `from` to `to`, as a bind mount elsewhere would show them
+    fn copy_tree(from: &Path, to: &Path) {
+        std::fs::create_dir_all(to).unwrap();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_dir() {
+                copy_tree(&entry.path(), &to.join(entry.file_name()));
+            } else {
+                std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_git_status_in_mounted_worktree() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let worktrees_dir = tempfile::tempdir().unwrap();
+        init_repo(repo_dir.path());
+        let worktree = create_worktree(repo_dir.path(), "env1", None, worktrees_dir.path()).unwrap().unwrap();
+        assert_eq!(worktree.git_mounts(), vec![worktree.repository.clone()]);
+
+        // The container sees the worktree at its mount path and, through
+        // git_mounts, the repository at its host path
+        let container = tempfile::tempdir().unwrap();
+        let workdir = container.path().join("workdir");
+        copy_tree(&worktree.path, &workdir);
+
+        // Containers run the git CLI, which takes the worktree root from
+        // where it finds the gitfile; libgit2 would read the host path back
+        let git = |args: &[&str]| {
+            std::process::Command::new("git").args(args).current_dir(&workdir).output()
+        };
+        // Skip if git is not installed
+        let Ok(status) = git(&["status", "--porcelain", "--branch"]) else {
+            return;
+        };
+        assert!(status.status.success(), "{}", String::from_utf8_lossy(&status.stderr));
+        assert_eq!(String::from_utf8_lossy(&status.stdout), "## cofer/env1\n");
+
+        std::fs::write(workdir.join("new.txt"), "new\n").unwrap();
+        let status = git(&["status", "--porcelain"]).unwrap();
+        assert_eq!(String::from_utf8_lossy(&status.stdout), "?? new.txt\n");
+        assert!(!worktree.path.join("new.txt").exists());
+
+        remove_worktree(&worktree, true).unwrap();
+    }
+
+    #[test]
+    fn test_create_worktree_for_subdir_and_base_ref() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let worktrees_dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(repo_dir.path());
+        let first = repo.head().unwrap().target().unwrap();
+
+        let worktree = create_worktree(&repo_dir.path().join("sub"), "env2", Some("HEAD"), worktrees_dir.path())
+            .unwrap()
+            .unwrap();
+        assert_eq!(worktree.subdir, PathBuf::from("sub"));
+        assert!(worktree.mount_source().join("file.txt").exists());
+        assert_eq!(worktree.git_mounts(), vec![worktree.repository.clone(), worktree.path.clone()]);
+        assert_eq!(worktree.base_commit, first.to_string());
+
+        let error = create_worktree(repo_dir.path(), "env3", Some("no-such-ref"), worktrees_dir.path()).unwrap_err();
+        assert!(error.to_string().contains("no-such-ref"));
+        assert!(repo.find_branch("cofer/env3", BranchType::Local).is_err());
+
+        remove_worktree(&worktree, true).unwrap();
+    }
+
+    #[test]
+    fn test_create_worktree_outside_repository() {
+        let project = tempfile::tempdir().unwrap();
+        let worktrees_dir = tempfile::tempdir().unwrap();
+
+        let worktree = create_worktree(project.path(), "env1", None, worktrees_dir.path()).unwrap();
+        assert!(worktree.is_none());
+    }
+
+    #[test]
+    fn test_create_worktree_rejects_bad_ids() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let worktrees_dir = tempfile::tempdir().unwrap();
+        init_repo(repo_dir.path());
+
+        for env_id in ["a/b", "bad..name", "trailing."] {
+            assert!(create_worktree(repo_dir.path(), env_id, None, worktrees_dir.path()).is_err(), "{}", env_id);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 pub mod environment;
+pub mod git;
 pub mod mcp;
 pub mod podman;
 pub mod service;
\ No newline at end of file
@@ -1,9 +1,11 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
+use std::time::Duration;
 use tokio::signal;
 use tokio::sync::watch;
 use tracing::{error, info};
 
 mod environment;
+mod git;
 mod mcp;
 mod podman;
 mod service;
@@ -25,10 +27,61 @@ async fn main() -> Result<()> {
     let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
     // Create MCP server
+    let mut options = parse_args(std::env::args().skip(1))?;
+    if options.shutdown_policy.is_none() {
+        if let Some(policy) = std::env::var(mcp::shutdown::SHUTDOWN_POLICY_ENV).ok().filter(|v| !v.is_empty()) {
+            options.shutdown_policy = Some(policy.parse()
+                .with_context(|| format!("Invalid {}", mcp::shutdown::SHUTDOWN_POLICY_ENV))?);
+        }
+    }
     let mut server = mcp::McpServer::new();
+    if let Some(framing) = options.framing {
+        server = server.with_framing(framing);
+    }
+    if let Some(exec_timeout) = options.exec_timeout {
+        server = server.with_exec_timeout(exec_timeout);
+    }
+    if options.max_output_bytes.is_some() || options.max_output_lines.is_some() {
+        let defaults = podman::capture::CaptureLimits::default();
+        server = server.with_output_limits(podman::capture::CaptureLimits {
+            max_bytes: options.max_output_bytes.unwrap_or(defaults.max_bytes),
+            max_lines: options.max_output_lines.unwrap_or(defaults.max_lines),
+        });
+    }
+    if options.shutdown_policy.is_some() || options.shutdown_timeout.is_some() {
+        server = server.with_shutdown_policy(
+            options.shutdown_policy.unwrap_or_default(),
+            options.shutdown_timeout.unwrap_or(mcp::shutdown::DEFAULT_SHUTDOWN_TIMEOUT),
+        );
+    }
+    if let Some(reap_interval) = options.reap_interval {
+        server = server.with_reap_interval(reap_interval);
+    }
+    if let Some(worktrees_dir) = options.worktrees_dir {
+        server = server.with_worktrees_dir(worktrees_dir);
+    }
+    let store = match options.state_file {
+        Some(path) => Ok(environment::RegistryStore::new(path)),
+        None => environment::RegistryStore::default_location(),
+    };
+    // Another server saving to the same file would overwrite this one's environments
+    let store = store
+        .and_then(environment::RegistryStore::locked)
+        .map_err(|e| error!("Environments will not be saved: {:#}", e))
+        .ok();
+    if let Some(store) = store {
+        info!("Saving environments to {}", store.path().display());
+        server = server.with_registry_store(store);
+        match server.restore_environments().await {
+            Ok(0) => {}
+            Ok(restored) => info!("Restored {} environments", restored),
+            Err(e) => error!("Failed to restore environments: {:#}", e),
+        }
+    }
+    let shutdown_deadline = server.shutdown_deadline();
 
     // Spawn server task
-    let server_handle = tokio::spawn(async move {
+    let mut server_handle = tokio::spawn(async move {
         if let Err(e) = server.run(shutdown_rx).await {
             error!("MCP server error: {}", e);
         }
@@ -37,30 +90,147 @@ async fn main() -> Result<()> {
         }
     });
 
-    // Wait for shutdown signal
-    match signal::ctrl_c().await {
-        Ok(()) => {
-            info!("Received shutdown signal (Ctrl+C)");
+    // Run until the client disconnects or a shutdown signal arrives
+    tokio::select! {
+        result = &mut server_handle => {
+            match result {
+                Ok(()) => info!("Server task completed successfully"),
+                Err(e) => error!("Server task failed: {}", e),
+            }
+        }
+        signal = shutdown_signal() => {
+            info!("Received shutdown signal ({})", signal);
             // Send shutdown signal to server
             let _ = shutdown_tx.send(true);
+
+            // Wait for server to finish cleaning up
+            match tokio::time::timeout(shutdown_deadline, server_handle).await {
+                Ok(Ok(_)) => info!("Server task completed successfully"),
+                Ok(Err(e)) => error!("Server task failed: {}", e),
+                Err(_) => {
+                    error!("Server shutdown timed out after {:?}", shutdown_deadline);
+                }
+            }
+        }
+    }
+
+    info!("Cofer MCP Server stopped");
+    Ok(())
+}
+
+/// Wait for Ctrl+C or, on Unix, SIGTERM, returning which one arrived
+async fn shutdown_signal() -> &'static str {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
         }
-        Err(e) => {
-            error!("Failed to listen for shutdown signal: {}", e);
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => "Ctrl+C",
+        _ = terminate => "SIGTERM",
     }
+}
 
-    // Wait for server to finish with timeout
-    match tokio::time::timeout(
-        std::time::Duration::from_secs(5),
-        server_handle
-    ).await {
-        Ok(Ok(_)) => info!("Server task completed successfully"),
-        Ok(Err(e)) => error!("Server task failed: {}", e),
-        Err(_) => {
-            error!("Server shutdown timed out after 5 seconds");
+/// Command line options
+#[derive(Debug, Default)]
+struct Options {
+    /// Forced wire framing, auto-detected when unset
+    framing: Option<mcp::transport::Framing>,
+    /// Default timeout for run_command
+    exec_timeout: Option<Duration>,
+    /// Default output capture limits for run_command
+    max_output_bytes: Option<usize>,
+    max_output_lines: Option<usize>,
+    /// What happens to environment containers on shutdown, falling back to
+    /// `COFER_SHUTDOWN_POLICY`
+    shutdown_policy: Option<mcp::shutdown::ShutdownPolicy>,
+    /// Time each container gets to shut down
+    shutdown_timeout: Option<Duration>,
+    /// Where the environment registry is saved, the user data dir when unset
+    state_file: Option<std::path::PathBuf>,
+    /// Time between checks for expired environments
+    reap_interval: Option<Duration>,
+    /// Where environment worktrees are created, the user data dir when unset
+    worktrees_dir: Option<std::path::PathBuf>,
+}
+
+/// Parse `--framing <auto|content-length|ndjson>`, `--exec-timeout-ms <ms>`,
+/// `--max-output-bytes <n>`, `--max-output-lines <n>`,
+/// `--shutdown-policy <stop|remove|keep>`, `--shutdown-timeout-secs <n>`,
+/// `--state-file <path>`, `--reap-interval-secs <n>` and `--worktrees-dir <path>`
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options> {
+    let mut args = args;
+    let mut options = Options::default();
+
+    while let Some(arg) = args.next() {
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg.clone(), None),
+        };
+        let mut value = || match inline_value.clone().or_else(|| args.next()) {
+            Some(value) => Ok(value),
+            None => Err(anyhow!("{} requires a value", name)),
+        };
+
+        let mut positive = |flag: &str| -> Result<u64> {
+            let n: u64 = value()?.parse().with_context(|| format!("Invalid {} value", flag))?;
+            if n == 0 {
+                bail!("{} must be positive", flag);
+            }
+            Ok(n)
+        };
+
+        match name.as_str() {
+            "--framing" => {
+                options.framing = match value()?.as_str() {
+                    "auto" => None,
+                    other => Some(other.parse()?),
+                };
+            }
+            "--exec-timeout-ms" => {
+                options.exec_timeout = Some(Duration::from_millis(positive("--exec-timeout-ms")?));
+            }
+            "--max-output-bytes" => {
+                options.max_output_bytes = Some(positive("--max-output-bytes")? as usize);
+            }
+            "--max-output-lines" => {
+                options.max_output_lines = Some(positive("--max-output-lines")? as usize);
+            }
+            "--shutdown-policy" => {
+                options.shutdown_policy = Some(value()?.parse()?);
+            }
+            "--shutdown-timeout-secs" => {
+                options.shutdown_timeout = Some(Duration::from_secs(positive("--shutdown-timeout-secs")?));
+            }
+            "--state-file" => {
+                options.state_file = Some(value()?.into());
+            }
+            "--reap-interval-secs" => {
+                options.reap_interval = Some(Duration::from_secs(positive("--reap-interval-secs")?));
+            }
+            "--worktrees-dir" => {
+                options.worktrees_dir = Some(value()?.into());
+            }
+            _ => bail!("Unknown argument: {}", arg),
         }
     }
 
-    info!("Cofer MCP Server stopped");
-    Ok(())
-}
\ No newline at end of file
+    Ok(options)
+}
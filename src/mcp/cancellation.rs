@@ -0,0 +1,90 @@
+use futures::future::AbortHandle;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Key identifying a request, derived from its JSON-RPC id
+///
+/// Ids may be numbers or strings, so `1` and `"1"` map to different keys.
+pub fn request_key(id: &Value) -> String {
+    id.to_string()
+}
+
+/// Abort handles of the requests currently being handled
+#[derive(Clone, Default)]
+pub struct InFlightRequests {
+    requests: Arc<Mutex<HashMap<String, AbortHandle>>>,
+}
+
+impl InFlightRequests {
+    /// Create an empty set of in-flight requests
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track a request so it can be cancelled later
+    pub fn insert(&self, key: impl Into<String>, handle: AbortHandle) {
+        self.lock().insert(key.into(), handle);
+    }
+
+    /// Stop tracking a request once it has completed
+    pub fn remove(&self, key: &str) {
+        self.lock().remove(key);
+    }
+
+    /// Abort the request with the given key
+    ///
+    /// Returns `false` if no such request is in flight.
+    pub fn cancel(&self, key: &str) -> bool {
+        match self.lock().remove(key) {
+            Some(handle) => {
+                handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, AbortHandle>> {
+        self.requests.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{abortable, Aborted};
+    use serde_json::json;
+
+    #[test]
+    fn test_request_key() {
+        assert_eq!(request_key(&json!(1)), "1");
+        assert_eq!(request_key(&json!("1")), "\"1\"");
+    }
+
+    #[tokio::test]
+    async fn test_cancel_aborts_request() {
+        let in_flight = InFlightRequests::new();
+        let (future, handle) = abortable(std::future::pending::<()>());
+        in_flight.insert("1", handle);
+
+        assert!(in_flight.cancel("1"));
+        assert_eq!(future.await, Err(Aborted));
+
+        // Already cancelled
+        assert!(!in_flight.cancel("1"));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_unknown_or_completed_request() {
+        let in_flight = InFlightRequests::new();
+        assert!(!in_flight.cancel("missing"));
+
+        let (future, handle) = abortable(async { 42 });
+        in_flight.insert("2", handle);
+        assert_eq!(future.await, Ok(42));
+        in_flight.remove("2");
+
+        assert!(!in_flight.cancel("2"));
+    }
+}
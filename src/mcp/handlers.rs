@@ -1,14 +1,34 @@
 use async_trait::async_trait;
 use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
-use tracing::{info, error};
+use tracing::{debug, error, info, warn};
 use chrono::Utc;
+use bollard::models::{ContainerInspectResponse, ContainerSummary};
+use futures::future::join_all;
+use serde::Serialize;
 
+use super::cancellation::request_key;
+use super::progress::{LogLevel, Notifier, ProgressReporter};
 use super::server::ServerState;
+use super::tools::ToolRegistry;
 use super::types::{McpError, McpRequest};
-use crate::environment::{EnvironmentHandle, EnvironmentStatus};
+use crate::environment::{Checkpoint, CommandHistory, CommandRecord, EnvironmentHandle, EnvironmentRegistry, EnvironmentStatus};
+use crate::git::checkpoint;
+use crate::git::commit::{commit_changes, snapshot_changes, CommitOptions, CommitSummary};
+use crate::git::diff::{self, DiffFormat, DiffOptions};
+use crate::git::notes::{self, NotePayload};
+use crate::git::propagate::{self, MergeStrategy};
+use crate::git::watcher::{CommitCallback, WatchConfig, WorktreeWatcher, DEFAULT_DEBOUNCE};
+use crate::git::{worktree, EnvironmentWorktree};
+use crate::podman::container::{ExecOptions, ExecResult};
+use crate::podman::exec::RunningExec;
+use crate::podman::image;
+use crate::podman::labels::{ContainerLabels, LABEL_CHECKPOINT, LABEL_ENV_ID};
+use crate::podman::ports::{self, ContainerPorts, PortMapping, PortSpec};
 use crate::podman::PodmanClient;
 
 /// Trait for handling MCP methods
@@ -18,35 +38,159 @@ pub trait Handler: Send + Sync {
     async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError>;
 }
 
+/// MCP protocol versions supported by this server, newest first
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
 /// Handler for the initialize method
 pub struct InitializeHandler;
 
 #[async_trait]
 impl Handler for InitializeHandler {
-    async fn handle(&self, _request: &McpRequest, _state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+    async fn handle(&self, request: &McpRequest, _state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
         info!("Handling initialize request");
+
+        // Echo the client's version if we support it, otherwise offer our latest
+        let requested = request.params.as_ref()
+            .and_then(|p| p.get("protocolVersion"))
+            .and_then(|v| v.as_str());
+        let protocol_version = match requested {
+            Some(version) if SUPPORTED_PROTOCOL_VERSIONS.contains(&version) => version,
+            _ => SUPPORTED_PROTOCOL_VERSIONS[0],
+        };
+
         Ok(json!({
-            "protocolVersion": "0.1.0",
+            "protocolVersion": protocol_version,
             "serverInfo": {
                 "name": "cofer",
                 "version": env!("CARGO_PKG_VERSION"),
             },
             "capabilities": {
-                "tools": [
-                    {
-                        "name": "create_environment",
-                        "description": "Create a new container environment"
-                    },
-                    {
-                        "name": "run_command",
-                        "description": "Execute a command in an environment"
-                    }
-                ]
+                "tools": {
+                    "listChanged": false
+                },
+                "logging": {}
             }
         }))
     }
 }
 
+/// Handler for the notifications/initialized notification
+pub struct InitializedHandler;
+
+#[async_trait]
+impl Handler for InitializedHandler {
+    async fn handle(&self, _request: &McpRequest, _state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        debug!("Client finished initialization");
+        Ok(Value::Null)
+    }
+}
+
+/// Handler for the ping method
+pub struct PingHandler;
+
+#[async_trait]
+impl Handler for PingHandler {
+    async fn handle(&self, _request: &McpRequest, _state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        Ok(json!({}))
+    }
+}
+
+/// Handler for logging/setLevel
+pub struct SetLogLevelHandler;
+
+#[async_trait]
+impl Handler for SetLogLevelHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let level = request.params.as_ref()
+            .and_then(|params| params.get("level"))
+            .ok_or_else(|| McpError::invalid_params("Missing level"))?;
+        let level: LogLevel = serde_json::from_value(level.clone())
+            .map_err(|_| McpError::invalid_params(format!("Invalid log level: {}", level)))?;
+
+        debug!("Client log level set to {:?}", level);
+        if let Some(notifier) = &state.read().await.notifier {
+            notifier.set_log_level(level);
+        }
+        Ok(json!({}))
+    }
+}
+
+/// Handler for tools/list
+pub struct ToolsListHandler {
+    pub tools: Arc<ToolRegistry>,
+}
+
+#[async_trait]
+impl Handler for ToolsListHandler {
+    async fn handle(&self, _request: &McpRequest, _state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        Ok(json!({
+            "tools": self.tools.definitions()
+        }))
+    }
+}
+
+/// Handler for tools/call, dispatching into the registered tool handlers
+pub struct ToolsCallHandler {
+    pub tools: Arc<ToolRegistry>,
+}
+
+#[async_trait]
+impl Handler for ToolsCallHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.as_ref()
+            .ok_or_else(|| McpError::invalid_params("Missing parameters"))?;
+
+        let name = params.get("name")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing tool name"))?;
+
+        let tool = self.tools.get(name)
+            .ok_or_else(|| McpError::invalid_params(format!("Unknown tool: {}", name)))?;
+
+        info!("Calling tool: {}", name);
+
+        let mut arguments = params.get("arguments").cloned().unwrap_or_else(|| json!({}));
+
+        // The progress token arrives with the tools/call params
+        if let (Some(meta), Some(arguments)) = (params.get("_meta"), arguments.as_object_mut()) {
+            arguments.entry("_meta").or_insert_with(|| meta.clone());
+        }
+
+        // Present the arguments to the tool handler as a regular request
+        let tool_request = McpRequest {
+            jsonrpc: request.jsonrpc.clone(),
+            id: request.id.clone(),
+            method: name.to_string(),
+            params: Some(arguments),
+        };
+
+        // Tool failures are reported in the result so the model can see them
+        match tool.handler.handle(&tool_request, state).await {
+            Ok(result) => Ok(tool_result(result)),
+            Err(error) => {
+                debug!("Tool {} failed: {}", name, error);
+                Ok(json!({
+                    "content": [{ "type": "text", "text": error.message }],
+                    "isError": true
+                }))
+            }
+        }
+    }
+}
+
+/// Wrap a handler result as an MCP tool result
+fn tool_result(result: Value) -> Value {
+    let text = serde_json::to_string_pretty(&result).unwrap_or_else(|_| result.to_string());
+    let mut response = json!({
+        "content": [{ "type": "text", "text": text }],
+        "isError": false
+    });
+    if result.is_object() {
+        response["structuredContent"] = result;
+    }
+    response
+}
+
 /// Handler for create_environment method
 pub struct CreateEnvironmentHandler;
 
@@ -88,21 +232,18 @@ impl Handler for CreateEnvironmentHandler {
                     .filter_map(|(k, v)| v.as_str().map(|s| (k.clone(), s.to_string())))
                     .collect()
             })
-            .unwrap_or_else(std::collections::HashMap::new);
+            .unwrap_or_else(HashMap::new);
 
         let mount_path = params.get("mount_path")
             .and_then(|v| v.as_str())
             .unwrap_or("/workdir")
             .to_string();
 
-        let ports = params.get("ports")
-            .and_then(|v| v.as_array())
-            .map(|arr| {
-                arr.iter()
-                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default();
+        let ports = port_specs_param(params, "ports")?;
+
+        let ttl_seconds = positive_integer_param(params, "ttl_seconds")?;
+        let idle_timeout_seconds = positive_integer_param(params, "idle_timeout_seconds")?;
+        let base_ref = optional_string_param(params, "base_ref")?.map(str::to_string);
 
         // Clone the registry to avoid holding the lock across await
         let registry = {
@@ -115,8 +256,9 @@ impl Handler for CreateEnvironmentHandler {
             return Err(McpError::invalid_params(format!("Environment '{}' already exists", env_id)));
         }
 
-        // Connect to Podman
-        let podman = match PodmanClient::new().await {
+        // Connect to Podman, reusing the shared connection
+        let shared_podman = state.read().await.podman.clone();
+        let podman = match shared_podman.get().await {
             Ok(client) => client,
             Err(e) => {
                 error!("Failed to connect to Podman: {}", e);
@@ -124,35 +266,53 @@ impl Handler for CreateEnvironmentHandler {
             }
         };
 
-        // Ensure image exists
-        if let Err(e) = podman.ensure_image(&image).await {
-            error!("Failed to ensure image {}: {}", image, e);
-            return Err(McpError::internal_error(format!("Failed to ensure image: {}", e)));
+        let progress = progress_reporter(request, state).await;
+
+        // Give the environment its own worktree so edits stay off the developer's checkout
+        let (worktrees_dir, instance_id) = {
+            let state_guard = state.read().await;
+            (state_guard.worktrees_dir.clone(), state_guard.instance_id.clone())
+        };
+        let worktree = match worktrees_dir {
+            Some(worktrees_dir) => {
+                let root = PathBuf::from(&project_root);
+                let id = env_id.clone();
+                let base = base_ref.clone();
+                tokio::task::spawn_blocking(move || {
+                    worktree::create_worktree(&root, &id, base.as_deref(), &worktrees_dir)
+                })
+                .await
+                .map_err(|e| McpError::internal_error(format!("Worktree task failed: {}", e)))?
+                .map_err(|e| McpError::invalid_params(format!("Failed to create worktree: {:#}", e)))?
+            }
+            None => None,
+        };
+        if worktree.is_none() && base_ref.is_some() {
+            return Err(McpError::invalid_params("base_ref requires project_root to be in a git repository"));
         }
+        let mount_source = match &worktree {
+            Some(worktree) => worktree.mount_source(),
+            None => PathBuf::from(&project_root),
+        };
 
-        // Create container
-        let container_id = match podman.create_container(
-            &env_id,
-            &image,
-            &project_root,
-            &mount_path,
-            env_vars.clone(),
-        ).await {
-            Ok(id) => id,
+        let labels = ContainerLabels {
+            env_id: env_id.clone(),
+            project_root: project_root.clone(),
+            mount_path: mount_path.clone(),
+            instance_id,
+            worktree: worktree.clone(),
+        };
+
+        let provisioned = provision_container(&podman, &image, &mount_source, &labels, &env_vars, &ports, &progress).await;
+        let ProvisionedContainer { container_id, host_mappings } = match provisioned {
+            Ok(provisioned) => provisioned,
             Err(e) => {
-                error!("Failed to create container: {}", e);
-                return Err(McpError::internal_error(format!("Failed to create container: {}", e)));
+                shared_podman.mark_unverified().await;
+                discard_worktree(worktree).await;
+                return Err(e);
             }
         };
 
-        // Start container
-        if let Err(e) = podman.start_container(&container_id).await {
-            error!("Failed to start container: {}", e);
-            // Clean up the created container
-            let _ = podman.remove_container(&container_id, true).await;
-            return Err(McpError::internal_error(format!("Failed to start container: {}", e)));
-        }
-
         // Create environment handle
         let mut handle = EnvironmentHandle::new(
             env_id.clone(),
@@ -163,18 +323,30 @@ impl Handler for CreateEnvironmentHandler {
 
         // Set mount path
         handle.mount_path = mount_path.clone();
+        handle.worktree = worktree.clone();
 
         // Add environment variables
         if !env_vars.is_empty() {
             handle.add_env_vars(env_vars.clone());
         }
 
+        handle.ports = ports.clone();
+        handle.host_mappings = host_mappings.clone();
+        handle.ttl_seconds = ttl_seconds;
+        handle.idle_timeout_seconds = idle_timeout_seconds;
+
         // Set status to running
         handle.set_status(EnvironmentStatus::Running);
 
-        // Register in the registry
-        registry.register(handle.clone()).await
-            .map_err(|e| McpError::internal_error(e.to_string()))?;
+        // Register in the registry; a concurrent request may have won the env_id
+        if let Err(e) = registry.register(handle.clone()).await {
+            error!("Failed to register environment {}: {}", env_id, e);
+            let _ = podman.remove_container(&container_id, true).await;
+            discard_worktree(worktree).await;
+            return Err(McpError::invalid_params(e.to_string()));
+        }
+
+        progress.report(100.0, Some(100.0), format!("Environment {} is running", env_id));
 
         // Build response object
         let mut response = json!({
@@ -191,15 +363,146 @@ impl Handler for CreateEnvironmentHandler {
             response["env_vars"] = json!(env_vars);
         }
 
-        // Add ports if present
+        // Add ports if present, with where they ended up on the host
         if !ports.is_empty() {
             response["ports"] = json!(ports);
+            response["host_mappings"] = json!(host_mappings);
+        }
+
+        if let Some(worktree) = &worktree {
+            response["worktree"] = json!(worktree.path);
+            response["branch"] = json!(worktree.branch);
+            response["base_commit"] = json!(worktree.base_commit);
+        }
+
+        if let Some(ttl_seconds) = ttl_seconds {
+            response["ttl_seconds"] = json!(ttl_seconds);
+        }
+        if let Some(idle_timeout_seconds) = idle_timeout_seconds {
+            response["idle_timeout_seconds"] = json!(idle_timeout_seconds);
         }
 
         Ok(response)
     }
 }
 
+/// An environment's freshly started container
+struct ProvisionedContainer {
+    container_id: String,
+    /// Where the requested ports ended up on the host
+    host_mappings: Vec<PortMapping>,
+}
+
+/// Pull the image if needed, then create and start an environment's container
+///
+/// The container is named after the environment, carries `labels` and has
+/// `mount_source` bind-mounted at the labelled mount path. Pull progress is
+/// mapped onto the first 80% of `progress`. A container that fails to start
+/// is removed again.
+///
+/// All `ports` are published at once. A port whose host port is taken is
+/// only exposed inside the container network instead, and if the container
+/// still fails to start over a taken port it is recreated with every port
+/// internal only.
+async fn provision_container(
+    podman: &PodmanClient,
+    image: &str,
+    mount_source: &Path,
+    labels: &ContainerLabels,
+    env_vars: &HashMap<String, String>,
+    ports: &[PortSpec],
+    progress: &ProgressReporter,
+) -> Result<ProvisionedContainer, McpError> {
+    progress.report(0.0, Some(100.0), format!("Checking image {}", image));
+    let ensured = podman.ensure_image_with_progress(image, |pull| {
+        if let Some(percent) = pull.percent() {
+            progress.report(
+                (percent * 0.8).floor(),
+                Some(100.0),
+                format!("Pulling {}: {} ({} / {} bytes)", image, pull.status, pull.current, pull.total),
+            );
+        }
+    }).await;
+    if let Err(e) = ensured {
+        error!("Failed to ensure image {}: {}", image, e);
+        return Err(McpError::internal_error(format!("Failed to ensure image: {}", e)));
+    }
+
+    let mut container_ports = ContainerPorts::publish_available(ports);
+    for spec in &container_ports.internal {
+        warn!("Host port of {} is taken, keeping it internal to environment {}", spec, labels.env_id);
+    }
+
+    loop {
+        progress.report(80.0, Some(100.0), "Creating container");
+        let container_id = match podman.create_environment_container(
+            image,
+            &mount_source.to_string_lossy(),
+            labels,
+            env_vars.clone(),
+            &container_ports,
+        ).await {
+            Ok(id) => id,
+            Err(e) => {
+                error!("Failed to create container: {}", e);
+                return Err(McpError::internal_error(format!("Failed to create container: {}", e)));
+            }
+        };
+
+        progress.report(90.0, Some(100.0), "Starting container");
+        if let Err(e) = podman.start_container(&container_id).await {
+            // Clean up the created container
+            let _ = podman.remove_container(&container_id, true).await;
+            if !container_ports.published.is_empty() && ports::is_port_conflict(&e) {
+                warn!("A host port for environment {} is taken, falling back to internal ports: {:#}", labels.env_id, e);
+                container_ports = ContainerPorts::internal_only(ports);
+                continue;
+            }
+            error!("Failed to start container: {}", e);
+            return Err(McpError::internal_error(format!("Failed to start container: {}", e)));
+        }
+
+        let host_mappings = published_ports(podman, &container_id, ports).await;
+        return Ok(ProvisionedContainer { container_id, host_mappings });
+    }
+}
+
+/// Read back where a container's requested ports are bound on the host
+///
+/// Failing to inspect the container is only logged, leaving the mappings
+/// empty.
+async fn published_ports(podman: &PodmanClient, container_id: &str, ports: &[PortSpec]) -> Vec<PortMapping> {
+    if ports.is_empty() {
+        return Vec::new();
+    }
+    match podman.inspect_container(container_id).await {
+        Ok(Some(inspect)) => ports::host_mappings(&inspect, ports),
+        Ok(None) => Vec::new(),
+        Err(e) => {
+            warn!("Failed to read port mappings of container {}: {}", container_id, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Remove a worktree made for an environment that could not be created
+///
+/// The branch goes too if it was created along with the worktree.
+async fn discard_worktree(worktree: Option<EnvironmentWorktree>) {
+    let Some(worktree) = worktree else {
+        return;
+    };
+
+    let removed = tokio::task::spawn_blocking(move || {
+        worktree::remove_worktree(&worktree, worktree.created_branch)
+    }).await;
+    match removed {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("Failed to remove worktree: {:#}", e),
+        Err(e) => warn!("Worktree task failed: {}", e),
+    }
+}
+
 /// Handler for run_command method
 pub struct RunCommandHandler;
 
@@ -215,16 +518,31 @@ impl Handler for RunCommandHandler {
             .ok_or_else(|| McpError::invalid_params("Missing env_id"))?;
 
         let command = params.get("command")
-            .and_then(|v| v.as_str())
             .ok_or_else(|| McpError::invalid_params("Missing command"))?;
+        let argv = command_argv(command)?;
 
-        info!("Running command in environment {}: {}", env_id, command);
+        let env = string_map_param(params, "env")?;
+        let workdir = optional_string_param(params, "workdir")?;
+        let user = optional_string_param(params, "user")?;
 
-        // Get environment from registry
-        let registry = {
+        let timeout = positive_integer_param(params, "timeout_ms")?.map(Duration::from_millis);
+        let max_bytes = positive_integer_param(params, "max_output_bytes")?;
+        let max_lines = positive_integer_param(params, "max_output_lines")?;
+
+        info!("Running command in environment {}: {:?}", env_id, argv);
+
+        // Get environment from registry and the server defaults
+        let (registry, default_timeout, mut limits) = {
             let state_guard = state.read().await;
-            state_guard.registry.clone()
+            (state_guard.registry.clone(), state_guard.exec_timeout, state_guard.output_limits)
         };
+        let timeout = timeout.unwrap_or(default_timeout);
+        if let Some(max_bytes) = max_bytes {
+            limits.max_bytes = max_bytes as usize;
+        }
+        if let Some(max_lines) = max_lines {
+            limits.max_lines = max_lines as usize;
+        }
 
         let handle = registry.get(env_id).await
             .map_err(|e| McpError::invalid_params(format!("Environment not found: {}", e)))?;
@@ -237,8 +555,9 @@ impl Handler for RunCommandHandler {
             )));
         }
 
-        // Connect to Podman
-        let podman = match PodmanClient::new().await {
+        // Connect to Podman, reusing the shared connection
+        let shared_podman = state.read().await.podman.clone();
+        let podman = match shared_podman.get().await {
             Ok(client) => client,
             Err(e) => {
                 error!("Failed to connect to Podman: {}", e);
@@ -246,153 +565,2876 @@ impl Handler for RunCommandHandler {
             }
         };
 
+        let workdir = workdir
+            .map(|workdir| resolve_workdir(&handle.mount_path, workdir))
+            .transpose()?;
+
+        // Track the exec so notifications/cancelled can kill it
+        let exec = RunningExec::new(&handle.container_id);
+        let options = ExecOptions {
+            env,
+            workdir: workdir.clone(),
+            user: user.map(str::to_string),
+            limits,
+            tracked: Some(exec.clone()),
+        };
+        let request_key = request.id.as_ref().map(request_key);
+        let execs = state.read().await.execs.clone();
+        if let Some(key) = &request_key {
+            execs.insert(key.clone(), exec.clone());
+        }
+
+        let progress = progress_reporter(request, state).await;
+
         // Execute command in container
-        let exec_result = match podman.exec_command(
-            &handle.container_id,
-            vec!["sh".to_string(), "-c".to_string(), command.to_string()],
-            None,
-        ).await {
-            Ok(result) => result,
+        let outcome = exec_with_timeout(&podman, &exec, argv, options, &progress, timeout).await;
+
+        if let Some(key) = &request_key {
+            execs.remove(key);
+        }
+
+        // The idle timeout counts from when the command finished
+        if let Err(e) = registry.touch(env_id).await {
+            debug!("Not recording activity for environment {}: {}", env_id, e);
+        }
+
+        let outcome = match outcome {
+            Ok(outcome) => outcome,
             Err(e) => {
                 error!("Failed to execute command: {}", e);
+                shared_podman.mark_unverified().await;
                 return Err(McpError::internal_error(format!("Failed to execute command: {}", e)));
             }
         };
 
-        // Return execution result
+        let workdir = workdir.unwrap_or(handle.mount_path);
+        let executed_at = Utc::now();
+        state.read().await.history.record(env_id, CommandRecord {
+            command: command.clone(),
+            workdir: workdir.clone(),
+            exit_code: outcome.result.exit_code.unwrap_or(-1),
+            timed_out: outcome.timed_out,
+            duration_ms: outcome.duration.as_millis() as u64,
+            executed_at,
+        });
+
+        // Return execution result; a timeout is reported, not an error.
+        // Truncated output comes as head and tail, which never overlap.
+        let stdout = &outcome.result.stdout;
+        let stderr = &outcome.result.stderr;
         Ok(json!({
             "env_id": env_id,
             "command": command,
-            "exit_code": exec_result.exit_code.unwrap_or(-1),
-            "stdout": exec_result.stdout,
-            "stderr": exec_result.stderr,
-            "executed_at": Utc::now().to_rfc3339()
+            "workdir": workdir,
+            "exit_code": outcome.result.exit_code.unwrap_or(-1),
+            "stdout": stdout.head,
+            "stderr": stderr.head,
+            "stdout_tail": stdout.truncated.then_some(&stdout.tail),
+            "stderr_tail": stderr.truncated.then_some(&stderr.tail),
+            "stdout_truncated": stdout.truncated,
+            "stderr_truncated": stderr.truncated,
+            "stdout_bytes": stdout.total_bytes,
+            "stderr_bytes": stderr.total_bytes,
+            "timed_out": outcome.timed_out,
+            "killed": outcome.killed,
+            "kill_error": outcome.kill_error,
+            "duration_ms": outcome.duration.as_millis() as u64,
+            "executed_at": executed_at.to_rfc3339()
         }))
     }
 }
 
-/// Handler for unimplemented methods
-pub struct UnimplementedHandler {
-    pub method: String,
+/// Build the argv for a command given as a shell string or an argv array
+///
+/// Shell strings run through `sh -c`; argv arrays run directly, so they work
+/// in images without a shell and need no quoting.
+fn command_argv(command: &Value) -> Result<Vec<String>, McpError> {
+    match command {
+        Value::String(script) if !script.trim().is_empty() => {
+            Ok(vec!["sh".to_string(), "-c".to_string(), script.clone()])
+        }
+        Value::Array(args) if !args.is_empty() => {
+            let argv = args.iter()
+                .map(|arg| arg.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| McpError::invalid_params("command array must contain only strings"))?;
+            if argv[0].is_empty() {
+                return Err(McpError::invalid_params("command array must start with a program"));
+            }
+            Ok(argv)
+        }
+        _ => Err(McpError::invalid_params("command must be a non-empty string or array of strings")),
+    }
 }
 
-#[async_trait]
-impl Handler for UnimplementedHandler {
-    async fn handle(&self, _request: &McpRequest, _state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
-        Err(McpError::method_not_found(format!("Method '{}' is not implemented", self.method)))
+/// Resolve a working directory against the environment's mount path
+///
+/// Relative paths may not climb above the mount path; absolute paths are
+/// used as given.
+fn resolve_workdir(mount_path: &str, workdir: &str) -> Result<String, McpError> {
+    if workdir.starts_with('/') {
+        return Ok(workdir.to_string());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::environment::EnvironmentRegistry;
-    use serde_json::json;
+    let mut parts: Vec<&str> = Vec::new();
+    for part in workdir.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()
+                    .ok_or_else(|| McpError::invalid_params(format!("workdir '{}' escapes {}", workdir, mount_path)))?;
+            }
+            part => parts.push(part),
+        }
+    }
 
-    async fn create_test_state() -> Arc<RwLock<ServerState>> {
-        Arc::new(RwLock::new(ServerState {
-            registry: EnvironmentRegistry::new(),
-        }))
+    if parts.is_empty() {
+        return Ok(mount_path.to_string());
     }
+    Ok(format!("{}/{}", mount_path.trim_end_matches('/'), parts.join("/")))
+}
 
-    #[tokio::test]
-    async fn test_initialize_handler() {
-        let handler = InitializeHandler;
-        let state = create_test_state().await;
-        let request = McpRequest {
-            jsonrpc: "2.0".to_string(),
-            id: Some(json!(1)),
-            method: "initialize".to_string(),
-            params: None,
-        };
+/// Read an optional non-empty string parameter
+fn optional_string_param<'a>(params: &'a Value, name: &str) -> Result<Option<&'a str>, McpError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value.as_str()
+            .filter(|s| !s.is_empty())
+            .map(Some)
+            .ok_or_else(|| McpError::invalid_params(format!("{} must be a non-empty string", name))),
+    }
+}
 
-        let result = handler.handle(&request, &state).await;
-        assert!(result.is_ok());
+/// Read an optional object of string values, such as environment variables
+fn string_map_param(params: &Value, name: &str) -> Result<HashMap<String, String>, McpError> {
+    let object = match params.get(name) {
+        None | Some(Value::Null) => return Ok(HashMap::new()),
+        Some(value) => value.as_object()
+            .ok_or_else(|| McpError::invalid_params(format!("{} must be an object", name)))?,
+    };
+
+    object.iter()
+        .map(|(key, value)| {
+            if key.is_empty() || key.contains('=') {
+                return Err(McpError::invalid_params(format!("Invalid {} key '{}'", name, key)));
+            }
+            value.as_str()
+                .map(|value| (key.clone(), value.to_string()))
+                .ok_or_else(|| McpError::invalid_params(format!("{}.{} must be a string", name, key)))
+        })
+        .collect()
+}
 
-        let value = result.unwrap();
-        assert!(value.get("protocolVersion").is_some());
-        assert!(value.get("serverInfo").is_some());
+/// Read an optional positive integer parameter
+fn positive_integer_param(params: &Value, name: &str) -> Result<Option<u64>, McpError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value.as_u64()
+            .filter(|n| *n > 0)
+            .map(Some)
+            .ok_or_else(|| McpError::invalid_params(format!("{} must be a positive integer", name))),
     }
+}
 
-    #[tokio::test]
-    async fn test_create_environment_validation() {
-        let handler = CreateEnvironmentHandler;
-        let state = create_test_state().await;
+/// Read an optional boolean parameter
+fn bool_param(params: &Value, name: &str, default: bool) -> Result<bool, McpError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(default),
+        Some(value) => value.as_bool()
+            .ok_or_else(|| McpError::invalid_params(format!("{} must be a boolean", name))),
+    }
+}
 
-        // Test missing parameters
-        let request = McpRequest {
-            jsonrpc: "2.0".to_string(),
-            id: Some(json!(1)),
-            method: "create_environment".to_string(),
-            params: None,
-        };
+/// Read an optional array of non-empty strings
+fn string_list_param(params: &Value, name: &str) -> Result<Vec<String>, McpError> {
+    let items = match params.get(name) {
+        None | Some(Value::Null) => return Ok(Vec::new()),
+        Some(value) => value.as_array()
+            .ok_or_else(|| McpError::invalid_params(format!("{} must be an array of strings", name)))?,
+    };
+
+    items.iter()
+        .map(|item| item.as_str()
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| McpError::invalid_params(format!("{} must be an array of non-empty strings", name))))
+        .collect()
+}
 
-        let result = handler.handle(&request, &state).await;
-        assert!(result.is_err());
+/// Read an optional array of ports given as numbers or `podman run -p` strings
+fn port_specs_param(params: &Value, name: &str) -> Result<Vec<PortSpec>, McpError> {
+    let items = match params.get(name) {
+        None | Some(Value::Null) => return Ok(Vec::new()),
+        Some(value) => value.as_array()
+            .ok_or_else(|| McpError::invalid_params(format!("{} must be an array of ports", name)))?,
+    };
+
+    items.iter()
+        .map(|item| {
+            let spec = match item {
+                Value::Number(port) => port.to_string(),
+                Value::String(spec) => spec.clone(),
+                _ => return Err(McpError::invalid_params(format!("{} must be an array of numbers or strings", name))),
+            };
+            spec.parse().map_err(|e| McpError::invalid_params(format!("{:#}", e)))
+        })
+        .collect()
+}
 
-        // Test missing env_id
-        let request = McpRequest {
-            jsonrpc: "2.0".to_string(),
-            id: Some(json!(2)),
-            method: "create_environment".to_string(),
-            params: Some(json!({
-                "project_root": "/tmp/test",
-                "image": "alpine:latest"
-            })),
-        };
+/// How long to wait for a killed exec to finish delivering its output
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Result of an exec run under a timeout
+struct ExecOutcome {
+    result: ExecResult,
+    timed_out: bool,
+    /// Whether the exec ended after being killed on timeout
+    killed: bool,
+    /// Why killing the exec failed, if it did
+    kill_error: Option<String>,
+    duration: Duration,
+}
 
-        let result = handler.handle(&request, &state).await;
-        assert!(result.is_err());
+/// Run an exec, killing its process tree if it outlives `timeout`
+async fn exec_with_timeout(
+    podman: &PodmanClient,
+    exec: &RunningExec,
+    cmd: Vec<String>,
+    options: ExecOptions,
+    progress: &ProgressReporter,
+    timeout: Duration,
+) -> anyhow::Result<ExecOutcome> {
+    let started = Instant::now();
+    let running = exec_with_progress(podman, &exec.container_id, cmd, options, progress);
+    tokio::pin!(running);
+
+    let (result, timed_out, killed, kill_error) = tokio::select! {
+        result = &mut running => (result?, false, false, None),
+        _ = tokio::time::sleep(timeout) => {
+            warn!("Command timed out after {}ms, killing exec {}", timeout.as_millis(), exec.marker);
+            let mut kill_error = match podman.kill_exec(exec).await {
+                Ok(()) => None,
+                Err(e) => {
+                    error!("Failed to kill timed out exec {}: {:#}", exec.marker, e);
+                    Some(format!("{:#}", e))
+                }
+            };
+
+            // Keep whatever output was produced before the kill
+            let (result, ended) = match tokio::time::timeout(KILL_GRACE_PERIOD, &mut running).await {
+                Ok(Ok(result)) => (result, true),
+                Ok(Err(e)) => {
+                    warn!("Timed out exec ended with an error: {}", e);
+                    (ExecResult::default(), true)
+                }
+                Err(_) => {
+                    warn!("Exec {} still running after kill", exec.marker);
+                    kill_error.get_or_insert_with(|| "Exec still running after kill".to_string());
+                    (ExecResult::default(), false)
+                }
+            };
+            (result, true, ended, kill_error)
+        }
+    };
+
+    Ok(ExecOutcome {
+        result,
+        timed_out,
+        killed,
+        kill_error,
+        duration: started.elapsed(),
+    })
+}
+
+/// Interval between progress notifications for a running command
+const EXEC_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Build a progress reporter for the request's progress token, if any
+async fn progress_reporter(request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> ProgressReporter {
+    let notifier = state.read().await.notifier.clone();
+    ProgressReporter::from_request(request, notifier)
+}
+
+/// Run an exec, periodically reporting elapsed time and recent output
+async fn exec_with_progress(
+    podman: &PodmanClient,
+    container_id: &str,
+    cmd: Vec<String>,
+    options: ExecOptions,
+    progress: &ProgressReporter,
+) -> anyhow::Result<ExecResult> {
+    if !progress.is_enabled() {
+        return podman.exec_command_streaming(container_id, cmd, options, |_, _| {}).await;
     }
 
-    #[tokio::test]
-    async fn test_run_command_validation() {
-        let handler = RunCommandHandler;
-        let state = create_test_state().await;
+    let started = Instant::now();
+    let output = std::sync::Mutex::new(OutputSummary::default());
+    let exec = podman.exec_command_streaming(container_id, cmd, options, |_, chunk| {
+        output.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).record(chunk);
+    });
+    tokio::pin!(exec);
+
+    let mut ticker = tokio::time::interval(EXEC_PROGRESS_INTERVAL);
+    ticker.tick().await;
+
+    loop {
+        tokio::select! {
+            result = &mut exec => return result,
+            _ = ticker.tick() => {
+                let elapsed = started.elapsed().as_secs();
+                let message = output.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).message(elapsed);
+                progress.report(elapsed as f64, None, message);
+            }
+        }
+    }
+}
 
-        // Test missing parameters
-        let request = McpRequest {
-            jsonrpc: "2.0".to_string(),
-            id: Some(json!(1)),
-            method: "run_command".to_string(),
-            params: None,
-        };
+/// Running summary of a command's output for progress messages
+#[derive(Debug, Default)]
+struct OutputSummary {
+    bytes: usize,
+    last_line: String,
+}
 
-        let result = handler.handle(&request, &state).await;
-        assert!(result.is_err());
+impl OutputSummary {
+    /// Longest output line quoted in a progress message
+    const MAX_LINE_CHARS: usize = 200;
 
-        // Test missing command
-        let request = McpRequest {
-            jsonrpc: "2.0".to_string(),
-            id: Some(json!(2)),
-            method: "run_command".to_string(),
-            params: Some(json!({
-                "env_id": "test-env"
-            })),
-        };
+    fn record(&mut self, chunk: &[u8]) {
+        self.bytes += chunk.len();
+        let text = String::from_utf8_lossy(chunk);
+        if let Some(line) = text.lines().rev().map(str::trim).find(|line| !line.is_empty()) {
+            self.last_line = line.chars().take(Self::MAX_LINE_CHARS).collect();
+        }
+    }
 
-        let result = handler.handle(&request, &state).await;
-        assert!(result.is_err());
+    fn message(&self, elapsed_secs: u64) -> String {
+        if self.last_line.is_empty() {
+            format!("Running for {}s, no output yet", elapsed_secs)
+        } else {
+            format!("Running for {}s, {} bytes of output: {}", elapsed_secs, self.bytes, self.last_line)
+        }
+    }
+}
+
+/// Handler for the up method
+///
+/// Restarts a stopped environment's container, or recreates it from the
+/// parameters stored on the handle if `down` removed it. A container that
+/// cannot start because one of its host ports was taken meanwhile is
+/// recreated too, keeping the taken ports internal.
+pub struct UpHandler;
+
+#[async_trait]
+impl Handler for UpHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.as_ref()
+            .ok_or_else(|| McpError::invalid_params("Missing parameters"))?;
+
+        let env_id = params.get("env_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing env_id"))?;
+
+        let (registry, shared_podman) = {
+            let state_guard = state.read().await;
+            (state_guard.registry.clone(), state_guard.podman.clone())
+        };
+
+        let handle = registry.get(env_id).await
+            .map_err(|e| McpError::invalid_params(format!("Environment not found: {}", e)))?;
+        if handle.is_running() {
+            debug!("Environment {} is already running", env_id);
+            return Ok(lifecycle_response(&handle, json!({ "recreated": false })));
+        }
+
+        info!("Bringing up environment: {}", env_id);
+        let previous = registry.begin_transition(env_id, EnvironmentStatus::Creating).await
+            .map_err(|e| McpError::invalid_request(e.to_string()))?;
+
+        let podman = match shared_podman.get().await {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Failed to connect to Podman: {}", e);
+                let _ = registry.update(previous).await;
+                return Err(McpError::internal_error(format!("Failed to connect to Podman: {}", e)));
+            }
+        };
+
+        let exists = if previous.container_id.is_empty() {
+            false
+        } else {
+            match podman.container_exists(&previous.container_id).await {
+                Ok(exists) => exists,
+                Err(e) => {
+                    error!("Failed to inspect container {}: {}", previous.container_id, e);
+                    shared_podman.mark_unverified().await;
+                    return Err(record_failure(&registry, previous, format!("Failed to inspect container: {}", e)).await);
+                }
+            }
+        };
+
+        let mut handle = previous.clone();
+        let mut recreate = !exists;
+        if exists {
+            match podman.start_container(&handle.container_id).await {
+                Ok(()) => {
+                    // Assigned host ports can change across restarts
+                    handle.host_mappings = published_ports(&podman, &handle.container_id, &handle.ports).await;
+                }
+                Err(e) if !handle.ports.is_empty() && ports::is_port_conflict(&e) => {
+                    warn!("A host port for environment {} is taken, recreating its container: {:#}", env_id, e);
+                    if let Err(e) = podman.remove_container(&handle.container_id, true).await {
+                        error!("Failed to remove container {}: {}", handle.container_id, e);
+                        shared_podman.mark_unverified().await;
+                        return Err(record_failure(&registry, previous, format!("Failed to remove container: {}", e)).await);
+                    }
+                    recreate = true;
+                }
+                Err(e) => {
+                    error!("Failed to start container: {}", e);
+                    shared_podman.mark_unverified().await;
+                    return Err(record_failure(&registry, previous, format!("Failed to start container: {}", e)).await);
+                }
+            }
+        } else {
+            info!("Container for environment {} is gone, recreating it", env_id);
+        }
+
+        if recreate {
+            let progress = progress_reporter(request, state).await;
+            let labels = ContainerLabels {
+                env_id: env_id.to_string(),
+                project_root: handle.project_root.to_string_lossy().into_owned(),
+                mount_path: handle.mount_path.clone(),
+                instance_id: state.read().await.instance_id.clone(),
+                worktree: handle.worktree.clone(),
+            };
+            let provisioned = provision_container(
+                &podman,
+                &handle.image,
+                &handle.mount_source(),
+                &labels,
+                &handle.env_vars,
+                &handle.ports,
+                &progress,
+            ).await;
+            match provisioned {
+                Ok(provisioned) => {
+                    handle.container_id = provisioned.container_id;
+                    handle.host_mappings = provisioned.host_mappings;
+                }
+                Err(e) => {
+                    shared_podman.mark_unverified().await;
+                    let mut failed = previous;
+                    failed.container_id = String::new();
+                    return Err(record_failure(&registry, failed, e.message).await);
+                }
+            }
+        }
+
+        handle.set_status(EnvironmentStatus::Running);
+        handle.touch();
+        registry.update(handle.clone()).await
+            .map_err(|e| McpError::internal_error(e.to_string()))?;
+
+        info!("Environment {} is running", env_id);
+        Ok(lifecycle_response(&handle, json!({ "recreated": recreate })))
+    }
+}
+
+/// Handler for the down method
+///
+/// Stops an environment's container and, with `remove`, deletes it. The
+/// environment stays registered so `up` can bring it back.
+pub struct DownHandler;
+
+#[async_trait]
+impl Handler for DownHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.as_ref()
+            .ok_or_else(|| McpError::invalid_params("Missing parameters"))?;
+
+        let env_id = params.get("env_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing env_id"))?;
+
+        let remove = match params.get("remove") {
+            None | Some(Value::Null) => false,
+            Some(value) => value.as_bool()
+                .ok_or_else(|| McpError::invalid_params("remove must be a boolean"))?,
+        };
+
+        let (registry, shared_podman) = {
+            let state_guard = state.read().await;
+            (state_guard.registry.clone(), state_guard.podman.clone())
+        };
+
+        registry.get(env_id).await
+            .map_err(|e| McpError::invalid_params(format!("Environment not found: {}", e)))?;
+
+        info!("Bringing down environment: {} (remove: {})", env_id, remove);
+        let previous = registry.begin_transition(env_id, EnvironmentStatus::Stopping).await
+            .map_err(|e| McpError::invalid_request(e.to_string()))?;
+
+        let mut handle = previous.clone();
+        if !handle.container_id.is_empty() {
+            let podman = match shared_podman.get().await {
+                Ok(client) => client,
+                Err(e) => {
+                    error!("Failed to connect to Podman: {}", e);
+                    let _ = registry.update(previous).await;
+                    return Err(McpError::internal_error(format!("Failed to connect to Podman: {}", e)));
+                }
+            };
+
+            if let Err(e) = podman.stop_container(&handle.container_id, None).await {
+                error!("Failed to stop container: {}", e);
+                shared_podman.mark_unverified().await;
+                return Err(record_failure(&registry, previous, format!("Failed to stop container: {}", e)).await);
+            }
+
+            if remove {
+                if let Err(e) = podman.remove_container(&handle.container_id, true).await {
+                    error!("Failed to remove container: {}", e);
+                    shared_podman.mark_unverified().await;
+                    return Err(record_failure(&registry, previous, format!("Failed to remove container: {}", e)).await);
+                }
+                handle.container_id = String::new();
+            }
+        }
+
+        handle.host_mappings.clear();
+        handle.set_status(EnvironmentStatus::Stopped);
+        registry.update(handle.clone()).await
+            .map_err(|e| McpError::internal_error(e.to_string()))?;
+
+        info!("Environment {} is stopped", env_id);
+        Ok(lifecycle_response(&handle, json!({ "removed": handle.container_id.is_empty() })))
+    }
+}
+
+/// Describe an environment after a lifecycle change, merging in `extra` fields
+fn lifecycle_response(handle: &EnvironmentHandle, extra: Value) -> Value {
+    let mut response = json!({
+        "env_id": handle.env_id,
+        "container_id": handle.container_id,
+        "project_root": handle.project_root,
+        "mount_path": handle.mount_path,
+        "image": handle.image,
+        "status": handle.status
+    });
+    if let (Some(response), Value::Object(extra)) = (response.as_object_mut(), extra) {
+        response.extend(extra);
+    }
+    response
+}
+
+/// Put a failed lifecycle change on record and turn it into an error
+async fn record_failure(registry: &EnvironmentRegistry, mut handle: EnvironmentHandle, message: String) -> McpError {
+    handle.set_status(EnvironmentStatus::Error(message.clone()));
+    if let Err(e) = registry.update(handle).await {
+        warn!("Failed to record error for environment: {}", e);
+    }
+    McpError::internal_error(message)
+}
+
+/// Handler for the list_environments method
+///
+/// Statuses are reconciled with Podman, so an environment whose container
+/// stopped behind our back is reported as stopped.
+pub struct ListEnvironmentsHandler;
+
+#[async_trait]
+impl Handler for ListEnvironmentsHandler {
+    async fn handle(&self, _request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let (registry, shared_podman) = {
+            let state_guard = state.read().await;
+            (state_guard.registry.clone(), state_guard.podman.clone())
+        };
+
+        let mut handles = registry.list_all().await;
+        handles.sort_by(|a, b| a.env_id.cmp(&b.env_id));
+
+        // Listing still works without Podman, just without container state
+        let podman = match shared_podman.get().await {
+            Ok(client) => Some(client),
+            Err(e) => {
+                warn!("Listing environments without Podman: {}", e);
+                None
+            }
+        };
+
+        let observed = join_all(handles.into_iter().map(|handle| {
+            observe_environment(podman.as_ref(), &registry, handle)
+        })).await;
+
+        let environments: Vec<Value> = observed.iter()
+            .map(|observed| {
+                let handle = &observed.handle;
+                json!({
+                    "env_id": handle.env_id,
+                    "status": handle.status,
+                    "image": handle.image,
+                    "project_root": handle.project_root,
+                    "container_id": handle.container_id,
+                    "container_state": observed.container_state(),
+                    "created_at": handle.created_at.to_rfc3339(),
+                    "uptime_secs": observed.uptime_secs(),
+                    "parent": handle.parent
+                })
+            })
+            .collect();
+
+        Ok(json!({
+            "count": environments.len(),
+            "environments": environments,
+            "podman_available": podman.is_some()
+        }))
+    }
+}
+
+/// Handler for the inspect_environment method
+pub struct InspectEnvironmentHandler;
+
+#[async_trait]
+impl Handler for InspectEnvironmentHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.as_ref()
+            .ok_or_else(|| McpError::invalid_params("Missing parameters"))?;
+
+        let env_id = params.get("env_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing env_id"))?;
+
+        let (registry, shared_podman, history, watchers) = {
+            let state_guard = state.read().await;
+            (
+                state_guard.registry.clone(),
+                state_guard.podman.clone(),
+                state_guard.history.clone(),
+                state_guard.watchers.clone(),
+            )
+        };
+
+        let handle = registry.get(env_id).await
+            .map_err(|e| McpError::invalid_params(format!("Environment not found: {}", e)))?;
+
+        let podman = match shared_podman.get().await {
+            Ok(client) => Some(client),
+            Err(e) => {
+                warn!("Inspecting environment {} without Podman: {}", env_id, e);
+                None
+            }
+        };
+
+        let observed = observe_environment(podman.as_ref(), &registry, handle).await;
+
+        // Sampling stats takes about a second, so only do it for live containers
+        let resources = match (&podman, observed.is_running()) {
+            (Some(podman), true) => match podman.container_stats(&observed.handle.container_id).await {
+                Ok(usage) => Some(usage),
+                Err(e) => {
+                    warn!("Failed to get resource usage for {}: {}", env_id, e);
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let inspect = observed.inspect.as_ref();
+        let mounts: Vec<Value> = inspect
+            .and_then(|inspect| inspect.mounts.as_ref())
+            .map(|mounts| mounts.iter()
+                .map(|mount| json!({
+                    "source": mount.source,
+                    "destination": mount.destination,
+                    "rw": mount.rw
+                }))
+                .collect())
+            .unwrap_or_default();
+        let ports = inspect
+            .and_then(|inspect| inspect.network_settings.as_ref())
+            .and_then(|network| network.ports.clone())
+            .unwrap_or_default();
+
+        let mut response = serde_json::to_value(&observed.handle)
+            .map_err(|e| McpError::internal_error(format!("Failed to serialize environment: {}", e)))?;
+        response["container_state"] = json!(observed.container_state());
+        response["uptime_secs"] = json!(observed.uptime_secs());
+        response["mounts"] = json!(mounts);
+        response["ports"] = json!(ports);
+        response["resources"] = json!(resources);
+        response["recent_commands"] = json!(history.recent(env_id));
+        response["watching"] = json!(watchers.is_watching(env_id));
+        response["children"] = json!(registry.children(env_id).await);
+
+        Ok(response)
+    }
+}
+
+/// An environment as it currently looks to Podman
+struct ObservedEnvironment {
+    handle: EnvironmentHandle,
+    /// Container details, `None` if the container is gone or Podman was unreachable
+    inspect: Option<ContainerInspectResponse>,
+    /// Whether Podman was asked at all
+    checked: bool,
+}
+
+impl ObservedEnvironment {
+    /// Podman's status for the container, "missing" if it is gone
+    fn container_state(&self) -> Option<String> {
+        if !self.checked {
+            return None;
+        }
+        Some(match self.inspect.as_ref().and_then(|inspect| inspect.state.as_ref()) {
+            Some(state) => state.status.map(|status| status.to_string()).unwrap_or_default(),
+            None => "missing".to_string(),
+        })
+    }
+
+    fn is_running(&self) -> bool {
+        self.inspect.as_ref()
+            .and_then(|inspect| inspect.state.as_ref())
+            .and_then(|state| state.running)
+            .unwrap_or(false)
+    }
+
+    /// Seconds since the container was last started, while it is running
+    fn uptime_secs(&self) -> Option<i64> {
+        if !self.is_running() {
+            return None;
+        }
+        let started_at = self.inspect.as_ref()?.state.as_ref()?.started_at.as_ref()?;
+        let started_at = chrono::DateTime::parse_from_rfc3339(started_at).ok()?;
+        Some(Utc::now().signed_duration_since(started_at).num_seconds().max(0))
+    }
+}
+
+/// Look up an environment's container and bring its recorded status in line with it
+///
+/// Only settled environments are reconciled; one being created or stopped
+/// is left to the request doing that.
+async fn observe_environment(
+    podman: Option<&PodmanClient>,
+    registry: &EnvironmentRegistry,
+    mut handle: EnvironmentHandle,
+) -> ObservedEnvironment {
+    let Some(podman) = podman else {
+        return ObservedEnvironment { handle, inspect: None, checked: false };
+    };
+
+    let inspect = if handle.container_id.is_empty() {
+        None
+    } else {
+        match podman.inspect_container(&handle.container_id).await {
+            Ok(inspect) => inspect,
+            Err(e) => {
+                warn!("Failed to inspect container for {}: {}", handle.env_id, e);
+                return ObservedEnvironment { handle, inspect: None, checked: false };
+            }
+        }
+    };
+
+    let observed = ObservedEnvironment { handle: handle.clone(), inspect, checked: true };
+    let actual = match (&handle.status, observed.is_running()) {
+        (EnvironmentStatus::Running, false) => Some(EnvironmentStatus::Stopped),
+        (EnvironmentStatus::Stopped, true) => Some(EnvironmentStatus::Running),
+        _ => None,
+    };
+    if let Some(actual) = actual {
+        info!("Environment {} is {:?} in Podman, updating from {:?}", handle.env_id, actual, handle.status);
+        if let Ok(true) = registry.set_status_if(&handle.env_id, &handle.status, actual.clone()).await {
+            handle.set_status(actual);
+        }
+    }
+
+    ObservedEnvironment { handle, ..observed }
+}
+
+/// Handler for the destroy_environment method
+///
+/// Tears the environment down completely. Every step is attempted and
+/// reported, so a partial failure shows what is left behind.
+pub struct DestroyEnvironmentHandler;
+
+#[async_trait]
+impl Handler for DestroyEnvironmentHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.as_ref()
+            .ok_or_else(|| McpError::invalid_params("Missing parameters"))?;
+
+        let env_id = params.get("env_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing env_id"))?;
+
+        let (registry, shared_podman, history, watchers) = {
+            let state_guard = state.read().await;
+            (
+                state_guard.registry.clone(),
+                state_guard.podman.clone(),
+                state_guard.history.clone(),
+                state_guard.watchers.clone(),
+            )
+        };
+
+        let existing = registry.get(env_id).await
+            .map_err(|e| McpError::invalid_params(format!("Environment not found: {}", e)))?;
+        let created_branch = existing.worktree.as_ref().is_some_and(|worktree| worktree.created_branch);
+        let delete_branch = bool_param(params, "delete_branch", created_branch)?;
+
+        info!("Destroying environment: {}", env_id);
+        let handle = registry.begin_transition(env_id, EnvironmentStatus::Stopping).await
+            .map_err(|e| McpError::invalid_request(e.to_string()))?;
+        watchers.stop(env_id);
+
+        let podman = shared_podman.get().await;
+        let steps = destroy_environment(podman.as_ref().ok(), &registry, &history, handle, Some(delete_branch)).await;
+        if podman.is_err() || steps.iter().any(|step| step.status == StepStatus::Failed) {
+            shared_podman.mark_unverified().await;
+        }
+
+        let destroyed = steps.iter().all(|step| step.status != StepStatus::Failed);
+        Ok(json!({
+            "env_id": env_id,
+            "destroyed": destroyed,
+            "steps": steps
+        }))
+    }
+}
+
+/// Outcome of one step of tearing down an environment
+#[derive(Debug, Clone, Serialize)]
+pub struct CleanupStep {
+    pub step: &'static str,
+    pub status: StepStatus,
+    pub detail: String,
+}
+
+/// Whether a cleanup step did its work
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepStatus {
+    Done,
+    Skipped,
+    Failed,
+}
+
+impl CleanupStep {
+    fn new(step: &'static str, status: StepStatus, detail: impl Into<String>) -> Self {
+        Self { step, status, detail: detail.into() }
+    }
+}
+
+/// Remove an environment's container with its volumes and its worktree, then forget the environment
+///
+/// The worktree's branch is deleted if `delete_branch` is set, by default
+/// only if cofer created it. The registry entry is kept if the
+/// container or worktree could not be removed, marked as failed, so
+/// destroying it can be retried.
+pub(crate) async fn destroy_environment(
+    podman: Option<&PodmanClient>,
+    registry: &EnvironmentRegistry,
+    history: &CommandHistory,
+    mut handle: EnvironmentHandle,
+    delete_branch: Option<bool>,
+) -> Vec<CleanupStep> {
+    let env_id = handle.env_id.clone();
+    let mut steps = Vec::new();
+
+    let container = if handle.container_id.is_empty() {
+        CleanupStep::new("container", StepStatus::Skipped, "No container")
+    } else {
+        match podman {
+            None => CleanupStep::new("container", StepStatus::Failed, "Podman is not available"),
+            Some(podman) => match podman.container_exists(&handle.container_id).await {
+                Ok(false) => CleanupStep::new("container", StepStatus::Skipped, "Container no longer exists"),
+                Ok(true) => match podman.remove_container(&handle.container_id, true).await {
+                    Ok(()) => CleanupStep::new(
+                        "container",
+                        StepStatus::Done,
+                        format!("Removed container {} and its volumes", handle.container_id),
+                    ),
+                    Err(e) => CleanupStep::new("container", StepStatus::Failed, format!("{:#}", e)),
+                },
+                Err(e) => CleanupStep::new("container", StepStatus::Failed, format!("{:#}", e)),
+            },
+        }
+    };
+    let container_removed = container.status != StepStatus::Failed;
+    if !container_removed {
+        error!("Failed to remove container of environment {}: {}", env_id, container.detail);
+    }
+    steps.push(container);
+
+    let worktree = match handle.worktree.clone() {
+        None => CleanupStep::new("worktree", StepStatus::Skipped, "No worktree"),
+        Some(_) if !container_removed => {
+            CleanupStep::new("worktree", StepStatus::Skipped, "Kept while the container still mounts it")
+        }
+        Some(worktree) => {
+            let path = worktree.path.clone();
+            let branch = worktree.branch.clone();
+            // Branches cofer created go with the environment, others were there before it
+            let delete_branch = delete_branch.unwrap_or(worktree.created_branch);
+            match tokio::task::spawn_blocking(move || worktree::remove_worktree(&worktree, delete_branch)).await {
+                Ok(Ok(())) => CleanupStep::new(
+                    "worktree",
+                    StepStatus::Done,
+                    if delete_branch {
+                        format!("Removed worktree {} and branch {}", path.display(), branch)
+                    } else {
+                        format!("Removed worktree {}, kept branch {}", path.display(), branch)
+                    },
+                ),
+                Ok(Err(e)) => CleanupStep::new("worktree", StepStatus::Failed, format!("{:#}", e)),
+                Err(e) => CleanupStep::new("worktree", StepStatus::Failed, format!("Worktree task failed: {}", e)),
+            }
+        }
+    };
+    let worktree_removed = worktree.status != StepStatus::Failed;
+    if container_removed && !worktree_removed {
+        error!("Failed to remove worktree of environment {}: {}", env_id, worktree.detail);
+    }
+    steps.push(worktree);
+
+    let checkpoints = if handle.checkpoints.is_empty() {
+        CleanupStep::new("checkpoints", StepStatus::Skipped, "No checkpoints")
+    } else if !container_removed {
+        CleanupStep::new("checkpoints", StepStatus::Skipped, "Kept while the container may still use them")
+    } else {
+        remove_checkpoints(podman, &handle).await
+    };
+    if checkpoints.status == StepStatus::Failed {
+        warn!("Failed to remove checkpoints of environment {}: {}", env_id, checkpoints.detail);
+    }
+    steps.push(checkpoints);
+
+    let registry_step = if container_removed && worktree_removed {
+        history.remove(&env_id);
+        match registry.remove(&env_id).await {
+            Ok(_) => CleanupStep::new("registry", StepStatus::Done, "Removed from registry"),
+            Err(e) => CleanupStep::new("registry", StepStatus::Failed, e.to_string()),
+        }
+    } else {
+        let failed = if container_removed { "worktree" } else { "container" };
+        handle.set_status(EnvironmentStatus::Error(format!("Destroy failed to remove the {}", failed)));
+        if let Err(e) = registry.update(handle).await {
+            warn!("Failed to record error for environment {}: {}", env_id, e);
+        }
+        CleanupStep::new("registry", StepStatus::Skipped, "Kept so destroying can be retried")
+    };
+    steps.push(registry_step);
+
+    steps
+}
+
+/// Remove an environment's checkpoint images and the refs pinning their commits
+async fn remove_checkpoints(podman: Option<&PodmanClient>, handle: &EnvironmentHandle) -> CleanupStep {
+    let mut failures = Vec::new();
+    if let Some(worktree) = handle.worktree.clone() {
+        let env_id = handle.env_id.clone();
+        match tokio::task::spawn_blocking(move || checkpoint::remove_checkpoint_refs(&worktree.repository, &env_id)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => failures.push(format!("{:#}", e)),
+            Err(e) => failures.push(format!("Checkpoint task failed: {}", e)),
+        }
+    }
+
+    let Some(podman) = podman else {
+        return CleanupStep::new("checkpoints", StepStatus::Failed, "Podman is not available");
+    };
+    for checkpoint in &handle.checkpoints {
+        match podman.image_exists(&checkpoint.image).await {
+            Ok(false) => {}
+            Ok(true) => {
+                if let Err(e) = podman.remove_image(&checkpoint.image, false).await {
+                    failures.push(format!("{}: {:#}", checkpoint.image, e));
+                }
+            }
+            Err(e) => failures.push(format!("{}: {:#}", checkpoint.image, e)),
+        }
+    }
+
+    if failures.is_empty() {
+        CleanupStep::new(
+            "checkpoints",
+            StepStatus::Done,
+            format!("Removed {} checkpoints", handle.checkpoints.len()),
+        )
+    } else {
+        CleanupStep::new("checkpoints", StepStatus::Failed, failures.join("; "))
+    }
+}
+
+/// What to do with containers cofer created but no longer tracks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanAction {
+    /// Register the container as an environment again
+    Adopt,
+    /// Force-remove the container and its volumes
+    Remove,
+}
+
+/// Handler for the adopt_orphans and gc methods
+///
+/// Finds containers labelled as cofer's that the registry does not know
+/// about and adopts or removes them. The two methods differ only in their
+/// default action. Containers of other server instances, which may be live
+/// environments of a server still running, are only touched when asked for
+/// by env_id or with force. A call without action, env_id or force is a
+/// dry run.
+pub struct AdoptOrphansHandler {
+    pub default_action: OrphanAction,
+}
+
+#[async_trait]
+impl Handler for AdoptOrphansHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.clone().unwrap_or_else(|| json!({}));
+
+        let requested_action = optional_string_param(&params, "action")?;
+        let action = match requested_action {
+            None => self.default_action,
+            Some("adopt") => OrphanAction::Adopt,
+            Some("remove") => OrphanAction::Remove,
+            Some(other) => {
+                return Err(McpError::invalid_params(format!(
+                    "Invalid action '{}': expected adopt or remove",
+                    other
+                )));
+            }
+        };
+        let only_env_id = optional_string_param(&params, "env_id")?;
+        let force = bool_param(&params, "force", false)?;
+        let bare = requested_action.is_none() && only_env_id.is_none() && !force;
+        let dry_run = bool_param(&params, "dry_run", bare)?;
+
+        let (registry, shared_podman, instance_id) = {
+            let state_guard = state.read().await;
+            (state_guard.registry.clone(), state_guard.podman.clone(), state_guard.instance_id.clone())
+        };
+
+        let podman = match shared_podman.get().await {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Failed to connect to Podman: {}", e);
+                return Err(McpError::internal_error(format!("Failed to connect to Podman: {}", e)));
+            }
+        };
+
+        let containers = match podman.list_managed_containers().await {
+            Ok(containers) => containers,
+            Err(e) => {
+                error!("Failed to list cofer containers: {}", e);
+                shared_podman.mark_unverified().await;
+                return Err(McpError::internal_error(format!("Failed to list containers: {:#}", e)));
+            }
+        };
+
+        let mut orphans = find_orphans(&containers, &registry.list_all().await, &instance_id);
+        if let Some(env_id) = only_env_id {
+            orphans.retain(|orphan| orphan.labels.as_ref().is_some_and(|labels| labels.env_id == env_id));
+        }
+        info!("Found {} orphaned cofer containers", orphans.len());
+
+        let mut reports = Vec::with_capacity(orphans.len());
+        for orphan in orphans {
+            let (status, detail) = if orphan.foreign && only_env_id.is_none() && !force {
+                (StepStatus::Skipped, "Belongs to another cofer instance, pass its env_id or force".to_string())
+            } else {
+                settle_orphan(&podman, &registry, &orphan, action, dry_run).await
+            };
+            reports.push(json!({
+                "container_id": orphan.container_id,
+                "env_id": orphan.labels.as_ref().map(|labels| labels.env_id.clone()),
+                "project_root": orphan.labels.as_ref().map(|labels| labels.project_root.clone()),
+                "instance_id": orphan.labels.as_ref().map(|labels| labels.instance_id.clone()),
+                "container_state": orphan.state,
+                "status": status,
+                "detail": detail
+            }));
+        }
+
+        Ok(json!({
+            "action": match action {
+                OrphanAction::Adopt => "adopt",
+                OrphanAction::Remove => "remove",
+            },
+            "dry_run": dry_run,
+            "instance_id": instance_id,
+            "count": reports.len(),
+            "orphans": reports
+        }))
+    }
+}
+
+/// A cofer container the registry does not track
+#[derive(Debug, Clone)]
+struct Orphan {
+    container_id: String,
+    image: String,
+    state: Option<String>,
+    created: Option<i64>,
+    /// `None` if the labels are incomplete, so it cannot be adopted
+    labels: Option<ContainerLabels>,
+    /// Its env_id is registered to a different container
+    conflict: bool,
+    /// It was not created by this server instance, or cannot be told
+    foreign: bool,
+}
+
+/// Pick the labelled containers that no registered environment owns
+fn find_orphans(containers: &[ContainerSummary], registered: &[EnvironmentHandle], instance_id: &str) -> Vec<Orphan> {
+    containers.iter()
+        .filter_map(|container| {
+            let container_id = container.id.clone()?;
+            if registered.iter().any(|handle| handle.container_id == container_id) {
+                return None;
+            }
+
+            let labels = container.labels.as_ref().and_then(ContainerLabels::from_map);
+            let conflict = labels.as_ref()
+                .is_some_and(|labels| registered.iter().any(|handle| handle.env_id == labels.env_id));
+            let foreign = labels.as_ref().map_or(true, |labels| labels.instance_id != instance_id);
+
+            Some(Orphan {
+                container_id,
+                image: container.image.clone().unwrap_or_default(),
+                state: container.state.map(|state| state.to_string()),
+                created: container.created,
+                labels,
+                conflict,
+                foreign,
+            })
+        })
+        .collect()
+}
+
+/// Build the environment an orphan is registered as when adopted
+fn adopted_handle(orphan: &Orphan, labels: &ContainerLabels) -> EnvironmentHandle {
+    let mut handle = EnvironmentHandle::new(
+        labels.env_id.clone(),
+        orphan.container_id.clone(),
+        PathBuf::from(&labels.project_root),
+        orphan.image.clone(),
+    );
+    handle.mount_path = labels.mount_path.clone();
+    handle.worktree = labels.worktree.clone();
+    if let Some(created_at) = orphan.created.and_then(|secs| chrono::DateTime::from_timestamp(secs, 0)) {
+        handle.created_at = created_at;
+    }
+    handle.set_status(if orphan.state.as_deref() == Some("running") {
+        EnvironmentStatus::Running
+    } else {
+        EnvironmentStatus::Stopped
+    });
+    handle
+}
+
+/// Adopt or remove one orphan, returning the outcome and a description
+async fn settle_orphan(
+    podman: &PodmanClient,
+    registry: &EnvironmentRegistry,
+    orphan: &Orphan,
+    action: OrphanAction,
+    dry_run: bool,
+) -> (StepStatus, String) {
+    match action {
+        OrphanAction::Adopt => {
+            let Some(labels) = &orphan.labels else {
+                return (StepStatus::Skipped, "Labels are incomplete, remove it instead".to_string());
+            };
+            if orphan.conflict {
+                return (
+                    StepStatus::Skipped,
+                    format!("Environment '{}' is registered to another container", labels.env_id),
+                );
+            }
+            if dry_run {
+                return (StepStatus::Skipped, format!("Would adopt as environment '{}'", labels.env_id));
+            }
+
+            match registry.register(adopted_handle(orphan, labels)).await {
+                Ok(()) => {
+                    info!("Adopted container {} as environment {}", orphan.container_id, labels.env_id);
+                    (StepStatus::Done, format!("Adopted as environment '{}'", labels.env_id))
+                }
+                Err(e) => (StepStatus::Failed, e.to_string()),
+            }
+        }
+        OrphanAction::Remove => {
+            if dry_run {
+                return (StepStatus::Skipped, "Would remove the container and its volumes".to_string());
+            }
+
+            if let Err(e) = podman.remove_container(&orphan.container_id, true).await {
+                error!("Failed to remove orphaned container {}: {}", orphan.container_id, e);
+                return (StepStatus::Failed, format!("{:#}", e));
+            }
+
+            // A conflicting orphan may share its worktree with the registered environment
+            let worktree = orphan.labels.as_ref()
+                .and_then(|labels| labels.worktree.clone())
+                .filter(|_| !orphan.conflict);
+            let Some(worktree) = worktree else {
+                return (StepStatus::Done, "Removed the container and its volumes".to_string());
+            };
+            let branch = worktree.branch.clone();
+            match tokio::task::spawn_blocking(move || worktree::remove_worktree(&worktree, false)).await {
+                Ok(Ok(())) => (
+                    StepStatus::Done,
+                    format!("Removed the container, its volumes and its worktree, kept branch {}", branch),
+                ),
+                Ok(Err(e)) => (StepStatus::Failed, format!("Removed the container but not its worktree: {:#}", e)),
+                Err(e) => (StepStatus::Failed, format!("Worktree task failed: {}", e)),
+            }
+        }
+    }
+}
+
+/// Handler for the watch-commit tool
+///
+/// Commits what is pending in the environment's worktree right away, then
+/// watches the worktree and commits later changes in debounced batches,
+/// logging each commit to the client. With `stop` the watch ends after the
+/// final commit.
+pub struct WatchCommitHandler;
+
+#[async_trait]
+impl Handler for WatchCommitHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.as_ref()
+            .ok_or_else(|| McpError::invalid_params("Missing parameters"))?;
+
+        let env_id = params.get("env_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing env_id"))?;
+        let debounce = positive_integer_param(params, "debounce_ms")?
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_DEBOUNCE);
+        let stop = bool_param(params, "stop", false)?;
+
+        let mut options = CommitOptions::default();
+        options.exclude.extend(string_list_param(params, "exclude")?);
+        options.nonbinary_only = bool_param(params, "nonbinary_only", options.nonbinary_only)?;
+
+        let (registry, watchers, notifier) = {
+            let state_guard = state.read().await;
+            (state_guard.registry.clone(), state_guard.watchers.clone(), state_guard.notifier.clone())
+        };
+
+        let handle = registry.get(env_id).await
+            .map_err(|e| McpError::invalid_params(format!("Environment not found: {}", e)))?;
+        let worktree = handle.worktree
+            .ok_or_else(|| McpError::invalid_request(format!("Environment {} has no git worktree", env_id)))?;
+
+        // Watch before the first commit so nothing written in between is missed
+        if stop {
+            watchers.stop(env_id);
+        } else {
+            let config = WatchConfig { debounce, commit: options.clone() };
+            let watcher = WorktreeWatcher::start(&worktree.path, config, auto_commit_callback(env_id, notifier))
+                .map_err(|e| McpError::internal_error(format!("Failed to watch worktree: {:#}", e)))?;
+            watchers.insert(env_id, watcher);
+            info!("Watching worktree of environment {} on branch {}", env_id, worktree.branch);
+        }
+
+        let path = worktree.path.clone();
+        let summary = tokio::task::spawn_blocking(move || commit_changes(&path, &options))
+            .await
+            .map_err(|e| McpError::internal_error(format!("Commit task failed: {}", e)))?
+            .map_err(|e| McpError::internal_error(format!("Failed to commit changes: {:#}", e)))?;
+
+        Ok(json!({
+            "env_id": env_id,
+            "watching": !stop,
+            "branch": worktree.branch,
+            "commit": summary.commit,
+            "changes": summary.changes.len(),
+            "files": summary.changes,
+            "skipped": summary.skipped
+        }))
+    }
+}
+
+/// Report each automatic commit in an environment to the client
+fn auto_commit_callback(env_id: &str, notifier: Option<Notifier>) -> CommitCallback {
+    let env_id = env_id.to_string();
+    Box::new(move |result| match result {
+        Ok(summary) => {
+            info!("Committed {} changes in environment {}", summary.changes.len(), env_id);
+            if let Some(notifier) = &notifier {
+                notifier.log(LogLevel::Info, json!({
+                    "event": "auto_commit",
+                    "message": format!("Committed {} changes in environment {}", summary.changes.len(), env_id),
+                    "env_id": env_id,
+                    "commit": summary.commit,
+                    "changes": summary.changes.len(),
+                    "files": summary.changes,
+                    "skipped": summary.skipped
+                }));
+            }
+        }
+        Err(e) => {
+            warn!("Failed to commit changes in environment {}: {:#}", env_id, e);
+            if let Some(notifier) = &notifier {
+                notifier.log(LogLevel::Warning, json!({
+                    "event": "auto_commit_failed",
+                    "message": format!("Failed to commit changes in environment {}: {:#}", env_id, e),
+                    "env_id": env_id
+                }));
+            }
+        }
+    })
+}
+
+/// Handler for the note-append tool
+///
+/// Appends a payload, typically the transcript of the commands behind a
+/// commit, to the git note on the environment's HEAD commit.
+pub struct NoteAppendHandler;
+
+#[async_trait]
+impl Handler for NoteAppendHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.as_ref()
+            .ok_or_else(|| McpError::invalid_params("Missing parameters"))?;
+
+        let env_id = params.get("env_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing env_id"))?;
+        let payload = params.get("payload")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing payload"))?;
+        let notes_ref = notes::notes_ref_name(optional_string_param(params, "ref")?.unwrap_or(notes::DEFAULT_NOTES_REF))
+            .map_err(|e| McpError::invalid_params(e.to_string()))?;
+        let cap_lines = positive_integer_param(params, "cap_lines")?
+            .map(|n| n as usize)
+            .unwrap_or(notes::DEFAULT_CAP_LINES);
+        let payload = match optional_string_param(params, "encoding")?.unwrap_or("base64") {
+            "base64" => NotePayload::from_base64(payload)
+                .map_err(|e| McpError::invalid_params(e.to_string()))?,
+            "text" => NotePayload::Text(payload.to_string()),
+            other => {
+                return Err(McpError::invalid_params(format!(
+                    "Unknown encoding '{}', expected 'base64' or 'text'",
+                    other
+                )));
+            }
+        };
+
+        let registry = state.read().await.registry.clone();
+        let handle = registry.get(env_id).await
+            .map_err(|e| McpError::invalid_params(format!("Environment not found: {}", e)))?;
+        let worktree = handle.worktree
+            .ok_or_else(|| McpError::invalid_request(format!("Environment {} has no git worktree", env_id)))?;
+
+        let appended = tokio::task::spawn_blocking(move || {
+            notes::append_note(&worktree.path, &notes_ref, &payload, cap_lines)
+        })
+        .await
+        .map_err(|e| McpError::internal_error(format!("Note task failed: {}", e)))?
+        .map_err(|e| McpError::internal_error(format!("Failed to append note: {:#}", e)))?;
+
+        info!("Appended to note {} on {} in environment {}", appended.note, appended.commit, env_id);
+        let mut response = serde_json::to_value(&appended)
+            .map_err(|e| McpError::internal_error(format!("Failed to serialize note: {}", e)))?;
+        response["env_id"] = json!(env_id);
+        Ok(response)
+    }
+}
+
+/// Handler for the propagate and merge_environment tools
+///
+/// Commits what is pending in the environment's worktree, fetches its
+/// branch into the user's repository as `cofer/<env_id>` and, given a
+/// target branch, fast-forwards or merges it. merge_environment targets
+/// the branch checked out in the project when none is given.
+pub struct PropagateHandler {
+    pub merge_current_branch: bool,
+}
+
+#[async_trait]
+impl Handler for PropagateHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.as_ref()
+            .ok_or_else(|| McpError::invalid_params("Missing parameters"))?;
+
+        let env_id = params.get("env_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing env_id"))?;
+        let target = optional_string_param(params, "target_branch")?.map(str::to_string);
+        let strategy = match optional_string_param(params, "strategy")?.unwrap_or("merge") {
+            "merge" => MergeStrategy::Merge,
+            "ff-only" => MergeStrategy::FastForwardOnly,
+            other => {
+                return Err(McpError::invalid_params(format!(
+                    "Unknown strategy '{}', expected 'merge' or 'ff-only'",
+                    other
+                )));
+            }
+        };
+        let commit_pending = bool_param(params, "commit_pending", true)?;
+
+        let registry = state.read().await.registry.clone();
+        let handle = registry.get(env_id).await
+            .map_err(|e| McpError::invalid_params(format!("Environment not found: {}", e)))?;
+        let worktree = handle.worktree.clone()
+            .ok_or_else(|| McpError::invalid_request(format!("Environment {} has no git worktree", env_id)))?;
+
+        let project_root = handle.project_root.clone();
+        let target = match target {
+            Some(target) => Some(target),
+            None if self.merge_current_branch => {
+                let root = project_root.clone();
+                let branch = tokio::task::spawn_blocking(move || propagate::current_branch(&root))
+                    .await
+                    .map_err(|e| McpError::internal_error(format!("Git task failed: {}", e)))?
+                    .map_err(|e| McpError::invalid_request(format!("No branch to merge into: {:#}", e)))?;
+                Some(branch)
+            }
+            None => None,
+        };
+
+        let id = env_id.to_string();
+        let (pending, propagation) = tokio::task::spawn_blocking(move || {
+            let pending = if commit_pending {
+                commit_changes(&worktree.path, &CommitOptions::default())?
+            } else {
+                CommitSummary::default()
+            };
+            let propagation = propagate::propagate(&project_root, &id, &worktree, target.as_deref(), strategy)?;
+            anyhow::Ok((pending, propagation))
+        })
+        .await
+        .map_err(|e| McpError::internal_error(format!("Git task failed: {}", e)))?
+        .map_err(|e| McpError::invalid_request(format!("Failed to propagate environment: {:#}", e)))?;
+
+        info!("Propagated environment {} as {}", env_id, propagation.remote_ref);
+        Ok(json!({
+            "env_id": env_id,
+            "branch": handle.worktree.map(|worktree| worktree.branch),
+            "pending_commit": pending.commit,
+            "remote_ref": propagation.remote_ref,
+            "commit": propagation.commit,
+            "merge": propagation.merge
+        }))
+    }
+}
+
+/// Handler for the diff_environment tool
+///
+/// Diffs the environment's worktree, committed and uncommitted changes
+/// alike, against the commit its branch started from or another base.
+pub struct DiffEnvironmentHandler;
+
+#[async_trait]
+impl Handler for DiffEnvironmentHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.as_ref()
+            .ok_or_else(|| McpError::invalid_params("Missing parameters"))?;
+
+        let env_id = params.get("env_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing env_id"))?;
+        let base = optional_string_param(params, "base")?.map(str::to_string);
+
+        let defaults = DiffOptions::default();
+        let format = match optional_string_param(params, "format")?.unwrap_or("patch") {
+            "name-only" => DiffFormat::NameOnly,
+            "stat" => DiffFormat::Stat,
+            "patch" => DiffFormat::Patch,
+            other => {
+                return Err(McpError::invalid_params(format!(
+                    "Unknown format '{}', expected 'name-only', 'stat' or 'patch'",
+                    other
+                )));
+            }
+        };
+        let context_lines = match params.get("context_lines") {
+            None | Some(Value::Null) => defaults.context_lines,
+            Some(value) => value.as_u64()
+                .and_then(|n| u32::try_from(n).ok())
+                .ok_or_else(|| McpError::invalid_params("context_lines must be a non-negative integer"))?,
+        };
+        let options = DiffOptions {
+            format,
+            context_lines,
+            paths: string_list_param(params, "paths")?,
+            max_bytes: positive_integer_param(params, "max_bytes")?
+                .map(|n| n as usize)
+                .unwrap_or(defaults.max_bytes),
+        };
+
+        let registry = state.read().await.registry.clone();
+        let handle = registry.get(env_id).await
+            .map_err(|e| McpError::invalid_params(format!("Environment not found: {}", e)))?;
+        let worktree = handle.worktree
+            .ok_or_else(|| McpError::invalid_request(format!("Environment {} has no git worktree", env_id)))?;
+
+        let branch = worktree.branch.clone();
+        let diff = tokio::task::spawn_blocking(move || {
+            let base = base.unwrap_or_else(|| worktree.base_commit.clone());
+            diff::diff_worktree(&worktree.path, &base, &options)
+        })
+        .await
+        .map_err(|e| McpError::internal_error(format!("Diff task failed: {}", e)))?
+        .map_err(|e| McpError::invalid_request(format!("Failed to diff environment: {:#}", e)))?;
+
+        let mut response = serde_json::to_value(&diff)
+            .map_err(|e| McpError::internal_error(format!("Failed to serialize diff: {}", e)))?;
+        response["env_id"] = json!(env_id);
+        response["branch"] = json!(branch);
+        Ok(response)
+    }
+}
+
+/// Handler for the checkpoint_environment tool
+///
+/// Records the worktree commit, after committing pending changes, and a
+/// `podman commit` image of the container filesystem, so
+/// `restore_environment` can roll both back to this point.
+pub struct CheckpointEnvironmentHandler;
+
+#[async_trait]
+impl Handler for CheckpointEnvironmentHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.as_ref()
+            .ok_or_else(|| McpError::invalid_params("Missing parameters"))?;
+
+        let env_id = params.get("env_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing env_id"))?;
+        let label = optional_string_param(params, "label")?;
+
+        let (registry, shared_podman) = {
+            let state_guard = state.read().await;
+            (state_guard.registry.clone(), state_guard.podman.clone())
+        };
+
+        let handle = registry.get(env_id).await
+            .map_err(|e| McpError::invalid_params(format!("Environment not found: {}", e)))?;
+        if handle.container_id.is_empty() {
+            return Err(McpError::invalid_request(format!("Environment {} has no container, bring it up first", env_id)));
+        }
+
+        let label = match label {
+            Some(label) => label.to_string(),
+            None => next_checkpoint_label(&handle),
+        };
+        if !image::is_valid_tag(&label) || checkpoint::checkpoint_ref_name(env_id, &label).is_err() {
+            return Err(McpError::invalid_params(format!(
+                "Invalid label '{}': use up to 128 letters, digits, '_', '.' and '-', starting with a letter, digit or '_'",
+                label
+            )));
+        }
+        if handle.checkpoint(Some(&label)).is_some() {
+            return Err(McpError::invalid_params(format!("Checkpoint '{}' already exists", label)));
+        }
+
+        let podman = shared_podman.get().await
+            .map_err(|e| McpError::internal_error(format!("Failed to connect to Podman: {}", e)))?;
+
+        info!("Checkpointing environment {} as {}", env_id, label);
+        let worktree = match handle.worktree.clone() {
+            Some(worktree) => {
+                let (id, name) = (env_id.to_string(), label.clone());
+                let checkpointed = tokio::task::spawn_blocking(move || {
+                    checkpoint::checkpoint_worktree(&worktree.path, &id, &name, &CommitOptions::default())
+                })
+                .await
+                .map_err(|e| McpError::internal_error(format!("Checkpoint task failed: {}", e)))?
+                .map_err(|e| McpError::internal_error(format!("Failed to checkpoint worktree: {:#}", e)))?;
+                Some(checkpointed)
+            }
+            None => None,
+        };
+
+        let image = image::checkpoint_image(env_id, &label);
+        let image_labels = HashMap::from([
+            (LABEL_ENV_ID.to_string(), env_id.to_string()),
+            (LABEL_CHECKPOINT.to_string(), label.clone()),
+        ]);
+        if let Err(e) = podman.commit_container(&handle.container_id, &image, image_labels).await {
+            error!("Failed to commit container of environment {}: {:#}", env_id, e);
+            shared_podman.mark_unverified().await;
+            return Err(McpError::internal_error(format!("Failed to commit container: {:#}", e)));
+        }
+
+        let checkpoint = Checkpoint {
+            label: label.clone(),
+            commit: worktree.as_ref().map(|worktree| worktree.commit.clone()),
+            image,
+            created_at: Utc::now(),
+        };
+
+        // Re-read the handle so changes made while committing are kept
+        let mut handle = registry.get(env_id).await
+            .map_err(|e| McpError::invalid_request(format!("Environment {} went away while checkpointing: {}", env_id, e)))?;
+        if handle.checkpoint(Some(&label)).is_some() {
+            return Err(McpError::invalid_request(format!("Checkpoint '{}' was taken concurrently", label)));
+        }
+        handle.checkpoints.push(checkpoint.clone());
+        registry.update(handle.clone()).await
+            .map_err(|e| McpError::internal_error(e.to_string()))?;
+
+        info!("Environment {} checkpointed as {}", env_id, label);
+        Ok(json!({
+            "env_id": env_id,
+            "label": checkpoint.label,
+            "commit": checkpoint.commit,
+            "image": checkpoint.image,
+            "created_at": checkpoint.created_at.to_rfc3339(),
+            "ref": worktree.as_ref().map(|worktree| &worktree.checkpoint_ref),
+            "pending_commit": worktree.as_ref().and_then(|worktree| worktree.pending.commit.as_ref()),
+            "checkpoints": handle.checkpoints.len()
+        }))
+    }
+}
+
+/// First free label of the form `cp-<n>`
+fn next_checkpoint_label(handle: &EnvironmentHandle) -> String {
+    (handle.checkpoints.len() + 1..)
+        .map(|n| format!("cp-{}", n))
+        .find(|label| handle.checkpoint(Some(label)).is_none())
+        .unwrap_or_default()
+}
+
+/// Handler for the restore_environment tool
+///
+/// Resets the worktree to a checkpoint's commit and replaces the container
+/// with one created from the checkpoint's image. Work thrown away by the
+/// reset is committed first, so it stays in the branch's reflog.
+pub struct RestoreEnvironmentHandler;
+
+#[async_trait]
+impl Handler for RestoreEnvironmentHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.as_ref()
+            .ok_or_else(|| McpError::invalid_params("Missing parameters"))?;
+
+        let env_id = params.get("env_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing env_id"))?;
+        let label = optional_string_param(params, "label")?;
+
+        let (registry, shared_podman) = {
+            let state_guard = state.read().await;
+            (state_guard.registry.clone(), state_guard.podman.clone())
+        };
+
+        let handle = registry.get(env_id).await
+            .map_err(|e| McpError::invalid_params(format!("Environment not found: {}", e)))?;
+        let checkpoint = match (handle.checkpoint(label), label) {
+            (Some(checkpoint), _) => checkpoint.clone(),
+            (None, Some(label)) => return Err(McpError::invalid_params(format!("Checkpoint '{}' not found", label))),
+            (None, None) => return Err(McpError::invalid_request(format!("Environment {} has no checkpoints", env_id))),
+        };
+
+        info!("Restoring environment {} to checkpoint {}", env_id, checkpoint.label);
+        let previous = registry.begin_transition(env_id, EnvironmentStatus::Creating).await
+            .map_err(|e| McpError::invalid_request(e.to_string()))?;
+
+        let podman = match shared_podman.get().await {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Failed to connect to Podman: {}", e);
+                let _ = registry.update(previous).await;
+                return Err(McpError::internal_error(format!("Failed to connect to Podman: {}", e)));
+            }
+        };
+
+        // Nothing is touched unless the image to restore from is still there
+        match podman.image_exists(&checkpoint.image).await {
+            Ok(true) => {}
+            Ok(false) => {
+                let _ = registry.update(previous).await;
+                return Err(McpError::invalid_request(format!("Checkpoint image {} no longer exists", checkpoint.image)));
+            }
+            Err(e) => {
+                shared_podman.mark_unverified().await;
+                let _ = registry.update(previous).await;
+                return Err(McpError::internal_error(format!("Failed to inspect image: {}", e)));
+            }
+        }
+
+        let discarded = match (previous.worktree.clone(), checkpoint.commit.clone()) {
+            (Some(worktree), Some(commit)) => {
+                let restored = tokio::task::spawn_blocking(move || {
+                    checkpoint::restore_worktree(&worktree.path, &commit, &CommitOptions::default())
+                })
+                .await
+                .map_err(|e| anyhow::anyhow!("Restore task failed: {}", e))
+                .and_then(|restored| restored);
+                match restored {
+                    Ok(discarded) => discarded,
+                    Err(e) => {
+                        return Err(record_failure(&registry, previous, format!("Failed to restore worktree: {:#}", e)).await);
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        // The worktree now matches the checkpoint, so even if the rest fails
+        // the container must be recreated from the checkpoint's image
+        let mut handle = previous;
+        handle.image = checkpoint.image.clone();
+        if !handle.container_id.is_empty() {
+            let removed = match podman.container_exists(&handle.container_id).await {
+                Ok(true) => podman.remove_container(&handle.container_id, true).await,
+                Ok(false) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = removed {
+                error!("Failed to remove container {}: {}", handle.container_id, e);
+                shared_podman.mark_unverified().await;
+                return Err(record_failure(&registry, handle, format!("Failed to remove container: {}", e)).await);
+            }
+        }
+
+        let progress = progress_reporter(request, state).await;
+        let labels = ContainerLabels {
+            env_id: env_id.to_string(),
+            project_root: handle.project_root.to_string_lossy().into_owned(),
+            mount_path: handle.mount_path.clone(),
+            instance_id: state.read().await.instance_id.clone(),
+            worktree: handle.worktree.clone(),
+        };
+        let provisioned = provision_container(
+            &podman,
+            &checkpoint.image,
+            &handle.mount_source(),
+            &labels,
+            &handle.env_vars,
+            &handle.ports,
+            &progress,
+        ).await;
+        match provisioned {
+            Ok(provisioned) => {
+                handle.container_id = provisioned.container_id;
+                handle.host_mappings = provisioned.host_mappings;
+            }
+            Err(e) => {
+                shared_podman.mark_unverified().await;
+                handle.container_id = String::new();
+                return Err(record_failure(&registry, handle, e.message).await);
+            }
+        }
+
+        handle.set_status(EnvironmentStatus::Running);
+        handle.touch();
+        registry.update(handle.clone()).await
+            .map_err(|e| McpError::internal_error(e.to_string()))?;
+
+        info!("Environment {} restored to checkpoint {}", env_id, checkpoint.label);
+        Ok(lifecycle_response(&handle, json!({
+            "checkpoint": checkpoint.label,
+            "commit": checkpoint.commit,
+            "discarded": discarded
+        })))
+    }
+}
+
+/// Checkpoint label a fork's starting point is recorded under
+const FORK_CHECKPOINT: &str = "fork";
+
+/// Handler for the fork_environment tool
+///
+/// Creates a new environment from the current state of an existing one: a
+/// worktree branched from a snapshot of the source's worktree, uncommitted
+/// changes included, and a container created from a committed image of the
+/// source's container. The fork is registered as a child of the source and
+/// can be restored to its starting point through its `fork` checkpoint.
+pub struct ForkEnvironmentHandler;
+
+#[async_trait]
+impl Handler for ForkEnvironmentHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.as_ref()
+            .ok_or_else(|| McpError::invalid_params("Missing parameters"))?;
+
+        let env_id = params.get("env_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing env_id"))?;
+        let new_env_id = params.get("new_env_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| McpError::invalid_params("Missing new_env_id"))?;
+
+        let (registry, shared_podman) = {
+            let state_guard = state.read().await;
+            (state_guard.registry.clone(), state_guard.podman.clone())
+        };
+
+        let source = registry.get(env_id).await
+            .map_err(|e| McpError::invalid_params(format!("Environment not found: {}", e)))?;
+        if registry.get(new_env_id).await.is_ok() {
+            return Err(McpError::invalid_params(format!("Environment '{}' already exists", new_env_id)));
+        }
+        if source.container_id.is_empty() {
+            return Err(McpError::invalid_request(format!("Environment {} has no container, bring it up first", env_id)));
+        }
+
+        let podman = shared_podman.get().await
+            .map_err(|e| McpError::internal_error(format!("Failed to connect to Podman: {}", e)))?;
+        let progress = progress_reporter(request, state).await;
+        let (worktrees_dir, instance_id) = {
+            let state_guard = state.read().await;
+            (state_guard.worktrees_dir.clone(), state_guard.instance_id.clone())
+        };
+
+        info!("Forking environment {} into {}", env_id, new_env_id);
+
+        // Branch off a snapshot so uncommitted work comes along without being committed in the source
+        let worktree = match (source.worktree.clone(), worktrees_dir) {
+            (Some(source_worktree), Some(worktrees_dir)) => {
+                let (project_root, id) = (source.project_root.clone(), new_env_id.to_string());
+                tokio::task::spawn_blocking(move || fork_worktree(&source_worktree, &project_root, &id, &worktrees_dir))
+                    .await
+                    .map_err(|e| McpError::internal_error(format!("Worktree task failed: {}", e)))?
+                    .map_err(|e| McpError::invalid_request(format!("Failed to fork worktree: {:#}", e)))?
+            }
+            _ => None,
+        };
+
+        progress.report(0.0, Some(100.0), format!("Committing container of {}", env_id));
+        let image = image::checkpoint_image(new_env_id, FORK_CHECKPOINT);
+        let image_labels = HashMap::from([
+            (LABEL_ENV_ID.to_string(), new_env_id.to_string()),
+            (LABEL_CHECKPOINT.to_string(), FORK_CHECKPOINT.to_string()),
+        ]);
+        if let Err(e) = podman.commit_container(&source.container_id, &image, image_labels).await {
+            error!("Failed to commit container of environment {}: {:#}", env_id, e);
+            shared_podman.mark_unverified().await;
+            discard_worktree(worktree).await;
+            return Err(McpError::internal_error(format!("Failed to commit container: {:#}", e)));
+        }
+
+        let mount_source = match &worktree {
+            Some(worktree) => worktree.mount_source(),
+            None => source.project_root.clone(),
+        };
+        let labels = ContainerLabels {
+            env_id: new_env_id.to_string(),
+            project_root: source.project_root.to_string_lossy().into_owned(),
+            mount_path: source.mount_path.clone(),
+            instance_id,
+            worktree: worktree.clone(),
+        };
+        // The source holds its fixed host ports, so the fork gets assigned ones
+        let ports: Vec<PortSpec> = source.ports.iter().map(PortSpec::auto_assigned).collect();
+        let provisioned = provision_container(&podman, &image, &mount_source, &labels, &source.env_vars, &ports, &progress).await;
+        let ProvisionedContainer { container_id, host_mappings } = match provisioned {
+            Ok(provisioned) => provisioned,
+            Err(e) => {
+                shared_podman.mark_unverified().await;
+                let _ = podman.remove_image(&image, false).await;
+                discard_worktree(worktree).await;
+                return Err(e);
+            }
+        };
+
+        let mut handle = EnvironmentHandle::new(new_env_id, container_id.clone(), source.project_root.clone(), image.clone());
+        handle.mount_path = source.mount_path.clone();
+        handle.worktree = worktree.clone();
+        handle.add_env_vars(source.env_vars.clone());
+        handle.ports = ports;
+        handle.host_mappings = host_mappings;
+        handle.ttl_seconds = source.ttl_seconds;
+        handle.idle_timeout_seconds = source.idle_timeout_seconds;
+        handle.checkpoints.push(Checkpoint {
+            label: FORK_CHECKPOINT.to_string(),
+            commit: worktree.as_ref().map(|worktree| worktree.base_commit.clone()),
+            image,
+            created_at: handle.created_at,
+        });
+        handle.set_status(EnvironmentStatus::Running);
+
+        // The source may have been destroyed meanwhile, or the new env_id taken
+        if let Err(e) = registry.register_child(env_id, handle.clone()).await {
+            error!("Failed to register environment {}: {}", new_env_id, e);
+            let _ = podman.remove_container(&container_id, true).await;
+            let _ = podman.remove_image(&handle.image, false).await;
+            discard_worktree(worktree).await;
+            return Err(McpError::invalid_request(e.to_string()));
+        }
+
+        // Pin the starting point so restoring the fork checkpoint survives a restore elsewhere
+        if let Some(worktree) = worktree.clone() {
+            let id = new_env_id.to_string();
+            let pinned = tokio::task::spawn_blocking(move || {
+                checkpoint::checkpoint_worktree(&worktree.path, &id, FORK_CHECKPOINT, &CommitOptions::default())
+            }).await;
+            if !matches!(pinned, Ok(Ok(_))) {
+                warn!("Failed to pin the fork checkpoint of environment {}", new_env_id);
+            }
+        }
+
+        progress.report(100.0, Some(100.0), format!("Environment {} is running", new_env_id));
+        info!("Forked environment {} into {}", env_id, new_env_id);
+
+        let mut response = lifecycle_response(&handle, json!({
+            "parent": env_id,
+            "created_at": handle.created_at.to_rfc3339()
+        }));
+        if !handle.env_vars.is_empty() {
+            response["env_vars"] = json!(handle.env_vars);
+        }
+        if !handle.ports.is_empty() {
+            response["ports"] = json!(handle.ports);
+            response["host_mappings"] = json!(handle.host_mappings);
+        }
+        if let Some(worktree) = &worktree {
+            response["worktree"] = json!(worktree.path);
+            response["branch"] = json!(worktree.branch);
+            response["base_commit"] = json!(worktree.base_commit);
+        }
+        Ok(response)
+    }
+}
+
+/// Create the worktree of a fork of the environment owning `source`
+///
+/// The fork's branch starts at a snapshot of `source` with its uncommitted
+/// changes, and must not exist yet.
+fn fork_worktree(
+    source: &EnvironmentWorktree,
+    project_root: &Path,
+    env_id: &str,
+    worktrees_dir: &Path,
+) -> anyhow::Result<Option<EnvironmentWorktree>> {
+    let base = snapshot_changes(&source.path, &CommitOptions::default())?;
+    let worktree = worktree::create_worktree(project_root, env_id, Some(&base), worktrees_dir)?;
+    if let Some(existing) = worktree.as_ref().filter(|worktree| !worktree.created_branch) {
+        worktree::remove_worktree(existing, false)?;
+        anyhow::bail!("Branch {} already exists", existing.branch);
+    }
+    Ok(worktree)
+}
+
+/// Handler for the notifications/cancelled notification
+///
+/// Aborts the in-flight request so it never gets a response, and kills the
+/// exec it started, if any.
+pub struct CancelledHandler;
+
+#[async_trait]
+impl Handler for CancelledHandler {
+    async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+        let params = request.params.as_ref()
+            .ok_or_else(|| McpError::invalid_params("Missing parameters"))?;
+
+        let request_id = params.get("requestId")
+            .ok_or_else(|| McpError::invalid_params("Missing requestId"))?;
+
+        if let Some(reason) = params.get("reason").and_then(|v| v.as_str()) {
+            info!("Cancelling request {}: {}", request_id, reason);
+        } else {
+            info!("Cancelling request {}", request_id);
+        }
+
+        let (in_flight, execs, podman) = {
+            let state_guard = state.read().await;
+            (state_guard.in_flight.clone(), state_guard.execs.clone(), state_guard.podman.clone())
+        };
+
+        let key = request_key(request_id);
+        let cancelled = in_flight.cancel(&key);
+        if !cancelled {
+            debug!("Request {} is not in flight", request_id);
+        }
+
+        // The aborted handler no longer waits on the exec, so stop it explicitly
+        let Some(exec) = execs.remove(&key) else {
+            return Ok(json!({ "cancelled": cancelled }));
+        };
+        let killed = match podman.get().await {
+            Ok(podman) => podman.kill_exec(&exec).await,
+            Err(e) => Err(e.context("Failed to connect to Podman")),
+        };
+        let kill_error = killed.err().map(|e| {
+            error!("Failed to kill exec for request {}: {:#}", request_id, e);
+            format!("{:#}", e)
+        });
+
+        Ok(json!({
+            "cancelled": cancelled,
+            "killed": kill_error.is_none(),
+            "kill_error": kill_error
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcp::tools;
+    use bollard::models::{ContainerStateStatusEnum, ContainerSummaryStateEnum};
+    use serde_json::json;
+
+    async fn create_test_state() -> Arc<RwLock<ServerState>> {
+        Arc::new(RwLock::new(ServerState::default()))
+    }
+
+    #[tokio::test]
+    async fn test_initialize_handler() {
+        let handler = InitializeHandler;
+        let state = create_test_state().await;
+        let request = McpRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(1)),
+            method: "initialize".to_string(),
+            params: None,
+        };
+
+        let result = handler.handle(&request, &state).await;
+        assert!(result.is_ok());
+
+        let value = result.unwrap();
+        assert!(value.get("protocolVersion").is_some());
+        assert!(value.get("serverInfo").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_initialize_protocol_negotiation() {
+        let handler = InitializeHandler;
+        let state = create_test_state().await;
+
+        // Supported version is echoed back
+        let request = McpRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(1)),
+            method: "initialize".to_string(),
+            params: Some(json!({ "protocolVersion": "2024-11-05" })),
+        };
+        let value = handler.handle(&request, &state).await.unwrap();
+        assert_eq!(value["protocolVersion"], "2024-11-05");
+        assert!(value["capabilities"]["tools"].is_object());
+
+        // Unknown version falls back to the latest supported one
+        let request = McpRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(2)),
+            method: "initialize".to_string(),
+            params: Some(json!({ "protocolVersion": "0.1.0" })),
+        };
+        let value = handler.handle(&request, &state).await.unwrap();
+        assert_eq!(value["protocolVersion"], SUPPORTED_PROTOCOL_VERSIONS[0]);
+    }
+
+    fn create_test_tools() -> Arc<ToolRegistry> {
+        let mut tools = ToolRegistry::new();
+        tools.register(tools::create_environment_tool(), Arc::new(CreateEnvironmentHandler));
+        tools.register(tools::run_command_tool(), Arc::new(RunCommandHandler));
+        Arc::new(tools)
+    }
+
+    #[tokio::test]
+    async fn test_tools_list_handler() {
+        let handler = ToolsListHandler { tools: create_test_tools() };
+        let state = create_test_state().await;
+        let request = McpRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(1)),
+            method: "tools/list".to_string(),
+            params: None,
+        };
+
+        let value = handler.handle(&request, &state).await.unwrap();
+        let tools = value["tools"].as_array().unwrap();
+        assert_eq!(tools.len(), 2);
+        for tool in tools {
+            assert!(tool["name"].is_string());
+            assert!(tool["description"].is_string());
+            assert_eq!(tool["inputSchema"]["type"], "object");
+        }
+    }
+
+    /// Tool handler returning the params it was called with
+    struct EchoHandler;
+
+    #[async_trait]
+    impl Handler for EchoHandler {
+        async fn handle(&self, request: &McpRequest, _state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+            Ok(request.params.clone().unwrap_or(Value::Null))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tools_call_forwards_progress_token() {
+        let mut registry = ToolRegistry::new();
+        registry.register(tools::run_command_tool(), Arc::new(EchoHandler));
+        let handler = ToolsCallHandler { tools: Arc::new(registry) };
+        let state = create_test_state().await;
+        let request = McpRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(1)),
+            method: "tools/call".to_string(),
+            params: Some(json!({
+                "name": "run_command",
+                "arguments": { "env_id": "e", "command": "true" },
+                "_meta": { "progressToken": "tok" }
+            })),
+        };
+
+        let value = handler.handle(&request, &state).await.unwrap();
+        let arguments = &value["structuredContent"];
+        assert_eq!(arguments["command"], "true");
+        assert_eq!(arguments["_meta"]["progressToken"], "tok");
+    }
+
+    #[test]
+    fn test_command_argv() {
+        assert_eq!(command_argv(&json!("echo hi")).unwrap(), vec!["sh", "-c", "echo hi"]);
+        assert_eq!(command_argv(&json!(["ls", "-la", "a b"])).unwrap(), vec!["ls", "-la", "a b"]);
+
+        for invalid in [json!(""), json!("  "), json!([]), json!(["ls", 1]), json!([""]), json!(42)] {
+            assert_eq!(command_argv(&invalid).unwrap_err().code, -32602, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_resolve_workdir() {
+        assert_eq!(resolve_workdir("/workdir", "src").unwrap(), "/workdir/src");
+        assert_eq!(resolve_workdir("/workdir/", "./a//b/").unwrap(), "/workdir/a/b");
+        assert_eq!(resolve_workdir("/workdir", "a/../b").unwrap(), "/workdir/b");
+        assert_eq!(resolve_workdir("/workdir", ".").unwrap(), "/workdir");
+        assert_eq!(resolve_workdir("/workdir", "/tmp").unwrap(), "/tmp");
+        assert!(resolve_workdir("/workdir", "../etc").is_err());
+        assert!(resolve_workdir("/workdir", "a/../../etc").is_err());
+    }
+
+    #[test]
+    fn test_string_map_param() {
+        let params = json!({ "env": { "CI": "1", "NODE_ENV": "test" } });
+        let env = string_map_param(&params, "env").unwrap();
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["CI"], "1");
+
+        assert!(string_map_param(&json!({}), "env").unwrap().is_empty());
+        assert!(string_map_param(&json!({ "env": ["CI=1"] }), "env").is_err());
+        assert!(string_map_param(&json!({ "env": { "CI": 1 } }), "env").is_err());
+        assert!(string_map_param(&json!({ "env": { "A=B": "c" } }), "env").is_err());
+    }
+
+    #[test]
+    fn test_output_summary() {
+        let mut summary = OutputSummary::default();
+        assert_eq!(summary.message(0), "Running for 0s, no output yet");
+
+        summary.record(b"step 1\nstep 2\n\n");
+        summary.record(b"");
+        assert_eq!(summary.message(3), "Running for 3s, 15 bytes of output: step 2");
+
+        summary.record("x".repeat(500).as_bytes());
+        assert_eq!(summary.last_line.len(), OutputSummary::MAX_LINE_CHARS);
+    }
+
+    #[tokio::test]
+    async fn test_tools_call_unknown_tool() {
+        let handler = ToolsCallHandler { tools: create_test_tools() };
+        let state = create_test_state().await;
+        let request = McpRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(1)),
+            method: "tools/call".to_string(),
+            params: Some(json!({ "name": "no_such_tool", "arguments": {} })),
+        };
+
+        let error = handler.handle(&request, &state).await.unwrap_err();
+        assert_eq!(error.code, -32602);
+    }
+
+    #[tokio::test]
+    async fn test_tools_call_reports_tool_errors_as_content() {
+        let handler = ToolsCallHandler { tools: create_test_tools() };
+        let state = create_test_state().await;
+        let request = McpRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(1)),
+            method: "tools/call".to_string(),
+            params: Some(json!({
+                "name": "run_command",
+                "arguments": { "env_id": "missing-env", "command": "echo hi" }
+            })),
+        };
+
+        let value = handler.handle(&request, &state).await.unwrap();
+        assert_eq!(value["isError"], true);
+        assert_eq!(value["content"][0]["type"], "text");
+        assert!(value["content"][0]["text"].as_str().unwrap().contains("not found"));
+    }
+
+    #[test]
+    fn test_tool_result_wrapping() {
+        let value = tool_result(json!({ "exit_code": 0 }));
+        assert_eq!(value["isError"], false);
+        assert_eq!(value["structuredContent"]["exit_code"], 0);
+        assert!(value["content"][0]["text"].as_str().unwrap().contains("exit_code"));
     }
 
     #[tokio::test]
-    async fn test_unimplemented_handler() {
-        let handler = UnimplementedHandler {
-            method: "unknown_method".to_string(),
+    async fn test_create_environment_validation() {
+        let handler = CreateEnvironmentHandler;
+        let state = create_test_state().await;
+
+        // Test missing parameters
+        let request = McpRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(1)),
+            method: "create_environment".to_string(),
+            params: None,
+        };
+
+        let result = handler.handle(&request, &state).await;
+        assert!(result.is_err());
+
+        // Test missing env_id
+        let request = McpRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(2)),
+            method: "create_environment".to_string(),
+            params: Some(json!({
+                "project_root": "/tmp/test",
+                "image": "alpine:latest"
+            })),
         };
+
+        let result = handler.handle(&request, &state).await;
+        assert!(result.is_err());
+
+        // Test invalid lifetimes
+        for name in ["ttl_seconds", "idle_timeout_seconds"] {
+            let req = self::request("create_environment", json!({
+                "env_id": "test-env",
+                "project_root": "/tmp",
+                "image": "alpine:latest",
+                name: 0
+            }));
+
+            let error = handler.handle(&req, &state).await.unwrap_err();
+            assert_eq!(error.code, -32602);
+            assert!(error.message.contains(name));
+        }
+
+        // Test invalid ports
+        for ports in [json!("3000"), json!([true]), json!(["3000:abc"]), json!([70000])] {
+            let req = self::request("create_environment", json!({
+                "env_id": "test-env",
+                "project_root": "/tmp",
+                "image": "alpine:latest",
+                "ports": ports
+            }));
+
+            let error = handler.handle(&req, &state).await.unwrap_err();
+            assert_eq!(error.code, -32602);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_command_validation() {
+        let handler = RunCommandHandler;
         let state = create_test_state().await;
+
+        // Test missing parameters
         let request = McpRequest {
             jsonrpc: "2.0".to_string(),
             id: Some(json!(1)),
-            method: "unknown_method".to_string(),
+            method: "run_command".to_string(),
             params: None,
         };
 
         let result = handler.handle(&request, &state).await;
         assert!(result.is_err());
 
-        if let Err(error) = result {
-            assert_eq!(error.code, -32601); // Method not found
+        // Test missing command
+        let request = McpRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(2)),
+            method: "run_command".to_string(),
+            params: Some(json!({
+                "env_id": "test-env"
+            })),
+        };
+
+        let result = handler.handle(&request, &state).await;
+        assert!(result.is_err());
+    }
+
+    fn request(method: &str, params: Value) -> McpRequest {
+        McpRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(1)),
+            method: method.to_string(),
+            params: Some(params),
+        }
+    }
+
+    /// Register `env_id` with a worktree of a fresh repository
+    ///
+    /// Returns the directories holding the repository and the worktree, which
+    /// must outlive the test.
+    async fn git_environment(env_id: &str) -> (tempfile::TempDir, tempfile::TempDir, Arc<RwLock<ServerState>>, EnvironmentWorktree) {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let worktrees_dir = tempfile::tempdir().unwrap();
+        crate::git::worktree::tests::init_repo(repo_dir.path());
+        let worktree = worktree::create_worktree(repo_dir.path(), env_id, None, worktrees_dir.path())
+            .unwrap()
+            .unwrap();
+
+        let state = create_test_state().await;
+        let mut handle = EnvironmentHandle::new(env_id, "", repo_dir.path().to_path_buf(), "alpine:latest");
+        handle.worktree = Some(worktree.clone());
+        state.read().await.registry.register(handle).await.unwrap();
+        (repo_dir, worktrees_dir, state, worktree)
+    }
+
+    #[tokio::test]
+    async fn test_up_down_validation() {
+        let state = create_test_state().await;
+
+        let error = UpHandler.handle(&request("up", json!({ "env_id": "missing" })), &state).await.unwrap_err();
+        assert_eq!(error.code, -32602);
+        assert!(error.message.contains("not found"));
+
+        let error = DownHandler.handle(&request("down", json!({ "env_id": "missing" })), &state).await.unwrap_err();
+        assert_eq!(error.code, -32602);
+
+        let req = request("down", json!({ "env_id": "missing", "remove": "yes" }));
+        let error = DownHandler.handle(&req, &state).await.unwrap_err();
+        assert!(error.message.contains("remove"));
+    }
+
+    #[tokio::test]
+    async fn test_down_without_container() {
+        let state = create_test_state().await;
+        let registry = state.read().await.registry.clone();
+        let mut handle = EnvironmentHandle::new("env1", "", PathBuf::from("/tmp"), "alpine:latest");
+        handle.set_status(EnvironmentStatus::Running);
+        registry.register(handle).await.unwrap();
+
+        // Nothing to stop, so Podman is never contacted
+        let value = DownHandler.handle(&request("down", json!({ "env_id": "env1" })), &state).await.unwrap();
+        assert_eq!(value["status"], "stopped");
+        assert_eq!(value["removed"], true);
+        assert_eq!(registry.get("env1").await.unwrap().status, EnvironmentStatus::Stopped);
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_refused_while_busy() {
+        let state = create_test_state().await;
+        let registry = state.read().await.registry.clone();
+        let mut handle = EnvironmentHandle::new("env1", "container-1", PathBuf::from("/tmp"), "alpine:latest");
+        handle.set_status(EnvironmentStatus::Stopping);
+        registry.register(handle).await.unwrap();
+
+        let error = UpHandler.handle(&request("up", json!({ "env_id": "env1" })), &state).await.unwrap_err();
+        assert_eq!(error.code, -32600);
+        assert!(error.message.contains("busy"));
+
+        let error = DownHandler.handle(&request("down", json!({ "env_id": "env1" })), &state).await.unwrap_err();
+        assert!(error.message.contains("busy"));
+    }
+
+    #[test]
+    fn test_lifecycle_response() {
+        let mut handle = EnvironmentHandle::new("env1", "c1", PathBuf::from("/src"), "alpine:latest");
+        handle.set_status(EnvironmentStatus::Running);
+
+        let value = lifecycle_response(&handle, json!({ "recreated": true }));
+        assert_eq!(value["env_id"], "env1");
+        assert_eq!(value["container_id"], "c1");
+        assert_eq!(value["status"], "running");
+        assert_eq!(value["recreated"], true);
+    }
+
+    fn inspect_with_state(status: ContainerStateStatusEnum, started_at: &str) -> ContainerInspectResponse {
+        ContainerInspectResponse {
+            state: Some(bollard::models::ContainerState {
+                status: Some(status),
+                running: Some(status == ContainerStateStatusEnum::RUNNING),
+                started_at: Some(started_at.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_observe_environment_without_podman() {
+        let registry = EnvironmentRegistry::new();
+        let mut handle = EnvironmentHandle::new("env1", "", PathBuf::from("/tmp"), "alpine:latest");
+        handle.set_status(EnvironmentStatus::Running);
+        registry.register(handle.clone()).await.unwrap();
+
+        // Without Podman nothing is known about the container
+        let observed = observe_environment(None, &registry, handle).await;
+        assert_eq!(observed.container_state(), None);
+        assert_eq!(observed.uptime_secs(), None);
+        assert_eq!(observed.handle.status, EnvironmentStatus::Running);
+    }
+
+    #[test]
+    fn test_observed_environment_state() {
+        let handle = EnvironmentHandle::new("env1", "c1", PathBuf::from("/tmp"), "alpine:latest");
+        let started_at = (Utc::now() - chrono::Duration::seconds(90)).to_rfc3339();
+
+        let running = ObservedEnvironment {
+            handle: handle.clone(),
+            inspect: Some(inspect_with_state(ContainerStateStatusEnum::RUNNING, &started_at)),
+            checked: true,
+        };
+        assert_eq!(running.container_state().as_deref(), Some("running"));
+        assert!((90..95).contains(&running.uptime_secs().unwrap()));
+
+        let exited = ObservedEnvironment {
+            handle: handle.clone(),
+            inspect: Some(inspect_with_state(ContainerStateStatusEnum::EXITED, &started_at)),
+            checked: true,
+        };
+        assert_eq!(exited.container_state().as_deref(), Some("exited"));
+        assert_eq!(exited.uptime_secs(), None);
+
+        let missing = ObservedEnvironment { handle, inspect: None, checked: true };
+        assert_eq!(missing.container_state().as_deref(), Some("missing"));
+    }
+
+    #[tokio::test]
+    async fn test_list_and_inspect_environments() {
+        let state = create_test_state().await;
+        let registry = state.read().await.registry.clone();
+        for env_id in ["b-env", "a-env"] {
+            registry.register(EnvironmentHandle::new(env_id, "", PathBuf::from("/tmp"), "alpine:latest")).await.unwrap();
+        }
+        state.read().await.history.record("a-env", CommandRecord {
+            command: json!("make test"),
+            workdir: "/workdir".to_string(),
+            exit_code: 2,
+            timed_out: false,
+            duration_ms: 10,
+            executed_at: Utc::now(),
+        });
+
+        let value = ListEnvironmentsHandler.handle(&request("list_environments", json!({})), &state).await.unwrap();
+        assert_eq!(value["count"], 2);
+        assert_eq!(value["environments"][0]["env_id"], "a-env");
+        assert_eq!(value["environments"][1]["env_id"], "b-env");
+        assert_eq!(value["environments"][0]["image"], "alpine:latest");
+
+        let req = request("inspect_environment", json!({ "env_id": "a-env" }));
+        let value = InspectEnvironmentHandler.handle(&req, &state).await.unwrap();
+        assert_eq!(value["env_id"], "a-env");
+        assert_eq!(value["mount_path"], "/workdir");
+        assert_eq!(value["recent_commands"][0]["command"], "make test");
+        assert_eq!(value["recent_commands"][0]["exit_code"], 2);
+
+        let req = request("inspect_environment", json!({ "env_id": "missing" }));
+        let error = InspectEnvironmentHandler.handle(&req, &state).await.unwrap_err();
+        assert_eq!(error.code, -32602);
+    }
+
+    #[tokio::test]
+    async fn test_destroy_environment_steps() {
+        let registry = EnvironmentRegistry::new();
+        let history = CommandHistory::new();
+
+        // Without a container there is nothing Podman needs to do
+        let handle = EnvironmentHandle::new("env1", "", PathBuf::from("/tmp"), "alpine:latest");
+        registry.register(handle.clone()).await.unwrap();
+        let steps = destroy_environment(None, &registry, &history, handle, None).await;
+        let statuses: Vec<(&str, StepStatus)> = steps.iter().map(|step| (step.step, step.status)).collect();
+        assert_eq!(statuses, vec![
+            ("container", StepStatus::Skipped),
+            ("worktree", StepStatus::Skipped),
+            ("checkpoints", StepStatus::Skipped),
+            ("registry", StepStatus::Done),
+        ]);
+        assert!(registry.get("env1").await.is_err());
+
+        // A container that cannot be removed keeps the environment around
+        let handle = EnvironmentHandle::new("env2", "container-2", PathBuf::from("/tmp"), "alpine:latest");
+        registry.register(handle.clone()).await.unwrap();
+        let steps = destroy_environment(None, &registry, &history, handle, None).await;
+        assert_eq!(steps[0].status, StepStatus::Failed);
+        assert_eq!(steps[3].status, StepStatus::Skipped);
+        assert!(registry.get("env2").await.unwrap().is_error());
+    }
+
+    #[tokio::test]
+    async fn test_destroy_environment_removes_worktree() {
+        let (repo_dir, worktrees_dir, state, worktree) = git_environment("env1").await;
+        let repo = git2::Repository::open(repo_dir.path()).unwrap();
+        let registry = state.read().await.registry.clone();
+
+        let mut handle = registry.get("env1").await.unwrap();
+        assert_eq!(handle.mount_source(), worktree.path);
+        let pinned = checkpoint::checkpoint_worktree(&worktree.path, "env1", "cp-1", &CommitOptions::default()).unwrap();
+        handle.checkpoints.push(Checkpoint {
+            label: "cp-1".to_string(),
+            commit: Some(pinned.commit),
+            image: image::checkpoint_image("env1", "cp-1"),
+            created_at: Utc::now(),
+        });
+        registry.update(handle.clone()).await.unwrap();
+
+        let steps = destroy_environment(None, &registry, &CommandHistory::new(), handle, None).await;
+        assert_eq!(steps[1].status, StepStatus::Done, "{}", steps[1].detail);
+        assert_eq!(steps[3].status, StepStatus::Done);
+        assert!(!worktree.path.exists());
+        assert!(repo.find_branch("cofer/env1", git2::BranchType::Local).is_err());
+
+        // Checkpoint refs go even when their images cannot be removed
+        assert_eq!(steps[2].status, StepStatus::Failed);
+        assert!(repo.find_reference(&pinned.checkpoint_ref).is_err());
+
+        // A branch that existed before the environment is kept
+        let worktree = worktree::create_worktree(repo_dir.path(), "env2", None, worktrees_dir.path())
+            .unwrap()
+            .unwrap();
+        let mut handle = EnvironmentHandle::new("env2", "", repo_dir.path().to_path_buf(), "alpine:latest");
+        handle.worktree = Some(worktree::EnvironmentWorktree { created_branch: false, ..worktree });
+        registry.register(handle.clone()).await.unwrap();
+        let steps = destroy_environment(None, &registry, &CommandHistory::new(), handle, None).await;
+        assert!(steps[1].detail.contains("kept branch"), "{}", steps[1].detail);
+        assert!(repo.find_branch("cofer/env2", git2::BranchType::Local).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_destroy_environment_handler() {
+        let state = create_test_state().await;
+        let registry = state.read().await.registry.clone();
+        let mut handle = EnvironmentHandle::new("env1", "", PathBuf::from("/tmp"), "alpine:latest");
+        handle.set_status(EnvironmentStatus::Stopped);
+        registry.register(handle).await.unwrap();
+
+        let req = request("destroy_environment", json!({ "env_id": "env1" }));
+        let value = DestroyEnvironmentHandler.handle(&req, &state).await.unwrap();
+        assert_eq!(value["destroyed"], true);
+        assert_eq!(value["steps"][3]["step"], "registry");
+        assert_eq!(value["steps"][3]["status"], "done");
+
+        let error = DestroyEnvironmentHandler.handle(&req, &state).await.unwrap_err();
+        assert_eq!(error.code, -32602);
+    }
+
+    #[tokio::test]
+    async fn test_watch_commit() {
+        let (_repo_dir, _worktrees_dir, state, worktree) = git_environment("env1").await;
+        let (registry, watchers) = {
+            let state = state.read().await;
+            (state.registry.clone(), state.watchers.clone())
+        };
+        registry.register(EnvironmentHandle::new("plain", "", PathBuf::from("/tmp"), "alpine:latest")).await.unwrap();
+
+        std::fs::write(worktree.path.join("README.md"), "edited\n").unwrap();
+        let req = request("watch-commit", json!({ "env_id": "env1", "exclude": ["*.log"] }));
+        let value = WatchCommitHandler.handle(&req, &state).await.unwrap();
+        assert_eq!(value["watching"], true);
+        assert_eq!(value["branch"], "cofer/env1");
+        assert_eq!(value["changes"], 1);
+        assert_eq!(value["files"], json!([{ "path": "README.md", "change": "modified" }]));
+        assert!(value["commit"].is_string());
+        assert!(watchers.is_watching("env1"));
+
+        let req = request("watch-commit", json!({ "env_id": "env1", "stop": true }));
+        let value = WatchCommitHandler.handle(&req, &state).await.unwrap();
+        assert_eq!(value["watching"], false);
+        assert_eq!(value["commit"], Value::Null);
+        assert!(!watchers.is_watching("env1"));
+
+        // Environments without a worktree have nothing to commit to
+        let req = request("watch-commit", json!({ "env_id": "plain" }));
+        assert_eq!(WatchCommitHandler.handle(&req, &state).await.unwrap_err().code, -32600);
+
+        let req = request("watch-commit", json!({ "env_id": "env1", "exclude": "target/" }));
+        assert_eq!(WatchCommitHandler.handle(&req, &state).await.unwrap_err().code, -32602);
+
+        worktree::remove_worktree(&worktree, true).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_note_append() {
+        let (repo_dir, _worktrees_dir, state, worktree) = git_environment("env1").await;
+        let repo = git2::Repository::open(repo_dir.path()).unwrap();
+
+        // "$ make\nok\n"
+        let req = request("note-append", json!({ "env_id": "env1", "payload": "JCBtYWtlCm9rCg==" }));
+        let value = NoteAppendHandler.handle(&req, &state).await.unwrap();
+        assert_eq!(value["env_id"], "env1");
+        assert_eq!(value["ref"], "refs/notes/cofer");
+        assert_eq!(value["commit"], worktree.base_commit);
+
+        let req = request("note-append", json!({
+            "env_id": "env1",
+            "payload": "a\nb\nc\nd\n",
+            "encoding": "text",
+            "cap_lines": 1
+        }));
+        let value = NoteAppendHandler.handle(&req, &state).await.unwrap();
+        assert_eq!(value["lines_omitted"], 2);
+
+        let commit = git2::Oid::from_str(&worktree.base_commit).unwrap();
+        let note = repo.find_note(Some("refs/notes/cofer"), commit).unwrap();
+        assert_eq!(note.message().unwrap(), "$ make\nok\n\na\n... 2 lines omitted ...\nd\n");
+
+        for params in [
+            json!({ "env_id": "env1", "payload": "%%%" }),
+            json!({ "env_id": "env1", "payload": "eA==", "ref": "refs/heads/main" }),
+            json!({ "env_id": "env1", "payload": "eA==", "encoding": "hex" }),
+            json!({ "env_id": "missing", "payload": "eA==" }),
+        ] {
+            let req = request("note-append", params.clone());
+            assert_eq!(NoteAppendHandler.handle(&req, &state).await.unwrap_err().code, -32602, "{}", params);
+        }
+
+        worktree::remove_worktree(&worktree, true).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_propagate() {
+        let (repo_dir, _worktrees_dir, state, worktree) = git_environment("env1").await;
+        let repo = git2::Repository::open(repo_dir.path()).unwrap();
+
+        // Pending changes are committed before fetching
+        std::fs::write(worktree.path.join("feature.txt"), "feature\n").unwrap();
+        let req = request("propagate", json!({ "env_id": "env1" }));
+        let value = PropagateHandler { merge_current_branch: false }.handle(&req, &state).await.unwrap();
+        assert_eq!(value["remote_ref"], "refs/remotes/cofer/env1");
+        assert_eq!(value["commit"], value["pending_commit"]);
+        assert_eq!(value["merge"], Value::Null);
+        assert!(!repo_dir.path().join("feature.txt").exists());
+
+        let req = request("merge_environment", json!({ "env_id": "env1", "strategy": "ff-only" }));
+        let value = PropagateHandler { merge_current_branch: true }.handle(&req, &state).await.unwrap();
+        assert_eq!(value["merge"]["outcome"], "fast_forward");
+        assert_eq!(value["pending_commit"], Value::Null);
+        assert!(repo_dir.path().join("feature.txt").exists());
+        assert_eq!(repo.head().unwrap().target().unwrap().to_string(), value["commit"].as_str().unwrap());
+
+        let req = request("propagate", json!({ "env_id": "env1", "strategy": "rebase" }));
+        let error = PropagateHandler { merge_current_branch: false }.handle(&req, &state).await.unwrap_err();
+        assert_eq!(error.code, -32602);
+
+        worktree::remove_worktree(&worktree, true).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_diff_environment() {
+        let (_repo_dir, _worktrees_dir, state, worktree) = git_environment("env1").await;
+
+        std::fs::write(worktree.path.join("README.md"), "hello\nagain\n").unwrap();
+        std::fs::write(worktree.path.join("new.txt"), "new\n").unwrap();
+
+        let req = request("diff_environment", json!({ "env_id": "env1", "format": "stat" }));
+        let value = DiffEnvironmentHandler.handle(&req, &state).await.unwrap();
+        assert_eq!(value["base"], worktree.base_commit);
+        assert_eq!(value["branch"], "cofer/env1");
+        assert_eq!(value["insertions"], 2);
+        assert_eq!(value["files"][0]["path"], "README.md");
+        assert_eq!(value["files"][1]["change"], "added");
+        assert!(value["files"][0].get("patch").is_none());
+
+        let req = request("diff_environment", json!({
+            "env_id": "env1",
+            "paths": ["README.md"],
+            "context_lines": 0
+        }));
+        let value = DiffEnvironmentHandler.handle(&req, &state).await.unwrap();
+        assert_eq!(value["files"].as_array().unwrap().len(), 1);
+        let patch = value["files"][0]["patch"].as_str().unwrap();
+        assert!(patch.ends_with("+again\n"), "{}", patch);
+        assert!(!patch.contains(" hello"), "{}", patch);
+
+        for params in [
+            json!({ "env_id": "env1", "format": "html" }),
+            json!({ "env_id": "env1", "context_lines": -1 }),
+            json!({ "env_id": "env1", "max_bytes": 0 }),
+        ] {
+            let req = request("diff_environment", params.clone());
+            assert_eq!(DiffEnvironmentHandler.handle(&req, &state).await.unwrap_err().code, -32602, "{}", params);
+        }
+        let req = request("diff_environment", json!({ "env_id": "env1", "base": "no-such-ref" }));
+        assert_eq!(DiffEnvironmentHandler.handle(&req, &state).await.unwrap_err().code, -32600);
+
+        worktree::remove_worktree(&worktree, true).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_environment_validation() {
+        let state = create_test_state().await;
+        let registry = state.read().await.registry.clone();
+        registry.register(EnvironmentHandle::new("env1", "", PathBuf::from("/tmp"), "alpine:latest")).await.unwrap();
+        let mut handle = EnvironmentHandle::new("env2", "container-2", PathBuf::from("/tmp"), "alpine:latest");
+        handle.checkpoints.push(Checkpoint {
+            label: "cp-1".to_string(),
+            commit: None,
+            image: image::checkpoint_image("env2", "cp-1"),
+            created_at: Utc::now(),
+        });
+        registry.register(handle.clone()).await.unwrap();
+        assert_eq!(next_checkpoint_label(&handle), "cp-2");
+
+        // Without a container there is no filesystem to snapshot
+        let req = request("checkpoint_environment", json!({ "env_id": "env1" }));
+        assert_eq!(CheckpointEnvironmentHandler.handle(&req, &state).await.unwrap_err().code, -32600);
+
+        for label in ["cp-1", "has space", "-x", "a..b", "123"] {
+            let req = request("checkpoint_environment", json!({ "env_id": "env2", "label": label }));
+            assert_eq!(CheckpointEnvironmentHandler.handle(&req, &state).await.unwrap_err().code, -32602, "{}", label);
+        }
+
+        let req = request("restore_environment", json!({ "env_id": "env1" }));
+        assert_eq!(RestoreEnvironmentHandler.handle(&req, &state).await.unwrap_err().code, -32600);
+        let req = request("restore_environment", json!({ "env_id": "env2", "label": "cp-9" }));
+        assert_eq!(RestoreEnvironmentHandler.handle(&req, &state).await.unwrap_err().code, -32602);
+        let req = request("restore_environment", json!({ "env_id": "missing" }));
+        assert_eq!(RestoreEnvironmentHandler.handle(&req, &state).await.unwrap_err().code, -32602);
+    }
+
+    #[tokio::test]
+    async fn test_fork_environment_validation() {
+        let state = create_test_state().await;
+        let registry = state.read().await.registry.clone();
+        registry.register(EnvironmentHandle::new("env1", "", PathBuf::from("/tmp"), "alpine:latest")).await.unwrap();
+        registry.register(EnvironmentHandle::new("env2", "container-2", PathBuf::from("/tmp"), "alpine:latest")).await.unwrap();
+
+        for params in [
+            json!({ "env_id": "env2" }),
+            json!({ "env_id": "missing", "new_env_id": "env3" }),
+            json!({ "env_id": "env2", "new_env_id": "env1" }),
+            json!({ "env_id": "env2", "new_env_id": "env2" }),
+        ] {
+            let req = request("fork_environment", params.clone());
+            assert_eq!(ForkEnvironmentHandler.handle(&req, &state).await.unwrap_err().code, -32602, "{}", params);
+        }
+
+        // Without a container there is no filesystem to fork
+        let req = request("fork_environment", json!({ "env_id": "env1", "new_env_id": "env3" }));
+        assert_eq!(ForkEnvironmentHandler.handle(&req, &state).await.unwrap_err().code, -32600);
+    }
+
+    #[test]
+    fn test_fork_worktree() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let worktrees_dir = tempfile::tempdir().unwrap();
+        let repo = crate::git::worktree::tests::init_repo(repo_dir.path());
+        let source = worktree::create_worktree(repo_dir.path(), "env1", None, worktrees_dir.path())
+            .unwrap()
+            .unwrap();
+        std::fs::write(source.path.join("README.md"), "work in progress\n").unwrap();
+        std::fs::write(source.path.join("new.txt"), "new\n").unwrap();
+
+        let fork = fork_worktree(&source, repo_dir.path(), "env2", worktrees_dir.path()).unwrap().unwrap();
+        assert_eq!(fork.branch, "cofer/env2");
+        assert_ne!(fork.base_commit, source.base_commit);
+        assert_eq!(std::fs::read_to_string(fork.path.join("README.md")).unwrap(), "work in progress\n");
+        assert!(fork.path.join("new.txt").exists());
+
+        // The source keeps its changes uncommitted
+        let source_head = repo.find_branch("cofer/env1", git2::BranchType::Local).unwrap().get().target().unwrap();
+        assert_eq!(source_head.to_string(), source.base_commit);
+        assert_eq!(std::fs::read_to_string(source.path.join("README.md")).unwrap(), "work in progress\n");
+
+        // A fork never carries on from a branch left behind by an earlier environment
+        worktree::remove_worktree(&fork, false).unwrap();
+        let error = fork_worktree(&source, repo_dir.path(), "env2", worktrees_dir.path()).unwrap_err();
+        assert!(error.to_string().contains("already exists"), "{}", error);
+        assert!(!fork.path.exists());
+
+        worktree::remove_worktree(&source, true).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_reports_failed_kill() {
+        let state = create_test_state().await;
+        let execs = state.read().await.execs.clone();
+
+        let req = request("notifications/cancelled", json!({ "requestId": 7 }));
+        let value = CancelledHandler.handle(&req, &state).await.unwrap();
+        assert_eq!(value, json!({ "cancelled": false }));
+
+        // There is no such container, so the kill can only fail and must say so
+        execs.insert(request_key(&json!(7)), RunningExec::new("no-such-container"));
+        let value = CancelledHandler.handle(&req, &state).await.unwrap();
+        assert_eq!(value["killed"], false);
+        assert!(value["kill_error"].as_str().is_some_and(|e| !e.is_empty()));
+        assert!(execs.remove(&request_key(&json!(7))).is_none());
+    }
+
+    fn labelled_container(id: &str, env_id: &str, state: ContainerSummaryStateEnum) -> ContainerSummary {
+        let labels = ContainerLabels {
+            env_id: env_id.to_string(),
+            project_root: "/tmp/project".to_string(),
+            mount_path: "/src".to_string(),
+            instance_id: "instance-1".to_string(),
+            worktree: None,
+        };
+        ContainerSummary {
+            id: Some(id.to_string()),
+            image: Some("alpine:latest".to_string()),
+            created: Some(1_700_000_000),
+            labels: Some(labels.to_map()),
+            state: Some(state),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_find_orphans() {
+        let tracked = EnvironmentHandle::new("env1", "container-1", PathBuf::from("/tmp"), "alpine:latest");
+        let mut unlabelled = labelled_container("container-4", "env4", ContainerSummaryStateEnum::EXITED);
+        unlabelled.labels.as_mut().unwrap().remove(crate::podman::labels::LABEL_ENV_ID);
+        let containers = vec![
+            labelled_container("container-1", "env1", ContainerSummaryStateEnum::RUNNING),
+            labelled_container("container-2", "env1", ContainerSummaryStateEnum::RUNNING),
+            labelled_container("container-3", "env3", ContainerSummaryStateEnum::RUNNING),
+            unlabelled,
+        ];
+
+        let orphans = find_orphans(&containers, std::slice::from_ref(&tracked), "instance-1");
+        let found: Vec<(&str, bool, bool, bool)> = orphans.iter()
+            .map(|orphan| (orphan.container_id.as_str(), orphan.labels.is_some(), orphan.conflict, orphan.foreign))
+            .collect();
+        assert_eq!(found, vec![
+            ("container-2", true, true, false),
+            ("container-3", true, false, false),
+            ("container-4", false, false, true),
+        ]);
+
+        // Containers labelled by another instance are foreign to this one
+        let foreign: Vec<bool> = find_orphans(&containers, &[tracked], "instance-2").iter()
+            .map(|orphan| orphan.foreign)
+            .collect();
+        assert_eq!(foreign, vec![true, true, true]);
+
+        let orphan = &orphans[1];
+        let handle = adopted_handle(orphan, orphan.labels.as_ref().unwrap());
+        assert_eq!(handle.env_id, "env3");
+        assert_eq!(handle.container_id, "container-3");
+        assert_eq!(handle.project_root, PathBuf::from("/tmp/project"));
+        assert_eq!(handle.mount_path, "/src");
+        assert_eq!(handle.created_at.timestamp(), 1_700_000_000);
+        assert!(handle.is_running());
+
+        let orphan = find_orphans(&containers[3..], &[], "instance-1").remove(0);
+        assert!(orphan.labels.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_adopt_orphans_invalid_params() {
+        let state = create_test_state().await;
+        let handler = AdoptOrphansHandler { default_action: OrphanAction::Adopt };
+
+        for params in [
+            json!({ "action": "delete" }),
+            json!({ "dry_run": "yes" }),
+            json!({ "env_id": 1 }),
+            json!({ "force": "yes" }),
+        ] {
+            let req = request("adopt_orphans", params);
+            let error = handler.handle(&req, &state).await.unwrap_err();
+            assert_eq!(error.code, -32602);
         }
     }
 
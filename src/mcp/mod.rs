@@ -1,5 +1,11 @@
+pub mod cancellation;
 pub mod server;
 pub mod handlers;
+pub mod progress;
+pub mod reaper;
+pub mod shutdown;
+pub mod tools;
+pub mod transport;
 pub mod types;
 
 pub use server::McpServer;
\ No newline at end of file
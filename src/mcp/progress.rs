@@ -0,0 +1,224 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use super::types::McpRequest;
+
+/// Severity of a log message sent to the client, as in syslog
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Notice,
+    Warning,
+    Error,
+    Critical,
+    Alert,
+    Emergency,
+}
+
+/// Sends server-initiated notifications to the client
+#[derive(Clone)]
+pub struct Notifier {
+    outgoing_tx: mpsc::UnboundedSender<String>,
+    /// Least severe log message the client wants, set by `logging/setLevel`
+    log_level: Arc<Mutex<LogLevel>>,
+}
+
+impl Notifier {
+    /// Create a notifier writing to the server's outgoing message queue
+    pub fn new(outgoing_tx: mpsc::UnboundedSender<String>) -> Self {
+        Self {
+            outgoing_tx,
+            log_level: Arc::new(Mutex::new(LogLevel::Info)),
+        }
+    }
+
+    /// Only send log messages at least as severe as `level` from now on
+    pub fn set_log_level(&self, level: LogLevel) {
+        *self.log_level.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = level;
+    }
+
+    /// Send a `notifications/message` log entry unless it is below the client's level
+    pub fn log(&self, level: LogLevel, data: Value) {
+        let min_level = *self.log_level.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if level < min_level {
+            return;
+        }
+
+        self.notify("notifications/message", json!({
+            "level": level,
+            "logger": "cofer",
+            "data": data
+        }));
+    }
+
+    /// Queue a notification; it is dropped if the client is gone
+    pub fn notify(&self, method: &str, params: Value) {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params
+        });
+        if self.outgoing_tx.send(notification.to_string()).is_err() {
+            debug!("Dropping {} notification, output closed", method);
+        }
+    }
+}
+
+/// Reports progress of a request through `notifications/progress`
+///
+/// Reporting is a no-op unless the request carried a `progressToken` in
+/// its `_meta` and the server has a client to notify.
+pub struct ProgressReporter {
+    target: Option<(Notifier, Value)>,
+    last_progress: Mutex<Option<f64>>,
+}
+
+impl ProgressReporter {
+    /// Create a reporter for the progress token of `request`, if any
+    pub fn from_request(request: &McpRequest, notifier: Option<Notifier>) -> Self {
+        let token = request.params.as_ref()
+            .and_then(|params| params.get("_meta"))
+            .and_then(|meta| meta.get("progressToken"))
+            .filter(|token| token.is_string() || token.is_number())
+            .cloned();
+
+        Self {
+            target: notifier.zip(token),
+            last_progress: Mutex::new(None),
+        }
+    }
+
+    /// Whether progress notifications are actually sent
+    pub fn is_enabled(&self) -> bool {
+        self.target.is_some()
+    }
+
+    /// Report progress, skipping values that do not increase
+    ///
+    /// The MCP spec requires progress to increase with every notification,
+    /// so callers can report freely and stale values are dropped here.
+    pub fn report(&self, progress: f64, total: Option<f64>, message: impl Into<String>) {
+        let Some((notifier, token)) = &self.target else {
+            return;
+        };
+
+        {
+            let mut last = self.last_progress.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if last.is_some_and(|last| progress <= last) {
+                return;
+            }
+            *last = Some(progress);
+        }
+
+        let mut params = json!({
+            "progressToken": token,
+            "progress": progress,
+            "message": message.into()
+        });
+        if let Some(total) = total {
+            params["total"] = json!(total);
+        }
+        notifier.notify("notifications/progress", params);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with_meta(meta: Value) -> McpRequest {
+        McpRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(json!(1)),
+            method: "run_command".to_string(),
+            params: Some(json!({ "_meta": meta })),
+        }
+    }
+
+    fn drain(rx: &mut mpsc::UnboundedReceiver<String>) -> Vec<Value> {
+        let mut messages = Vec::new();
+        while let Ok(message) = rx.try_recv() {
+            messages.push(serde_json::from_str(&message).unwrap());
+        }
+        messages
+    }
+
+    #[test]
+    fn test_report_sends_progress_notification() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let request = request_with_meta(json!({ "progressToken": "tok-1" }));
+        let reporter = ProgressReporter::from_request(&request, Some(Notifier::new(tx)));
+        assert!(reporter.is_enabled());
+
+        reporter.report(10.0, Some(100.0), "Pulling image");
+        reporter.report(20.0, None, "Elapsed 20s");
+
+        let messages = drain(&mut rx);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["method"], "notifications/progress");
+        assert!(messages[0].get("id").is_none());
+        assert_eq!(messages[0]["params"]["progressToken"], "tok-1");
+        assert_eq!(messages[0]["params"]["progress"], 10.0);
+        assert_eq!(messages[0]["params"]["total"], 100.0);
+        assert_eq!(messages[0]["params"]["message"], "Pulling image");
+        assert!(messages[1]["params"].get("total").is_none());
+    }
+
+    #[test]
+    fn test_report_requires_increasing_progress() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let request = request_with_meta(json!({ "progressToken": 7 }));
+        let reporter = ProgressReporter::from_request(&request, Some(Notifier::new(tx)));
+
+        reporter.report(5.0, None, "a");
+        reporter.report(5.0, None, "b");
+        reporter.report(3.0, None, "c");
+        reporter.report(6.0, None, "d");
+
+        let progress: Vec<f64> = drain(&mut rx)
+            .iter()
+            .map(|m| m["params"]["progress"].as_f64().unwrap())
+            .collect();
+        assert_eq!(progress, vec![5.0, 6.0]);
+    }
+
+    #[test]
+    fn test_reporter_without_token_is_silent() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let request = request_with_meta(json!({}));
+        let reporter = ProgressReporter::from_request(&request, Some(Notifier::new(tx)));
+        assert!(!reporter.is_enabled());
+
+        reporter.report(1.0, None, "ignored");
+        assert!(drain(&mut rx).is_empty());
+
+        // A token without a client to notify is silent as well
+        let request = request_with_meta(json!({ "progressToken": "tok" }));
+        assert!(!ProgressReporter::from_request(&request, None).is_enabled());
+    }
+
+    #[test]
+    fn test_log_respects_level() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let notifier = Notifier::new(tx);
+
+        notifier.log(LogLevel::Debug, json!("hidden"));
+        notifier.log(LogLevel::Info, json!("shown"));
+        notifier.set_log_level(LogLevel::Error);
+        notifier.log(LogLevel::Warning, json!("hidden"));
+        notifier.log(LogLevel::Critical, json!("shown"));
+
+        let messages = drain(&mut rx);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["method"], "notifications/message");
+        assert_eq!(messages[0]["params"]["level"], "info");
+        assert_eq!(messages[0]["params"]["logger"], "cofer");
+        assert_eq!(messages[1]["params"]["level"], "critical");
+        assert_eq!(messages[1]["params"]["data"], "shown");
+    }
+}
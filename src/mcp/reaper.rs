@@ -0,0 +1,270 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+use super::handlers::{destroy_environment, CleanupStep, StepStatus};
+use super::progress::LogLevel;
+use super::server::ServerState;
+use crate::environment::{EnvironmentHandle, EnvironmentStatus, Expiry};
+use crate::git::commit::{commit_changes, CommitOptions};
+use crate::podman::exec::ExecTracker;
+
+/// Default time between checks for expired environments
+pub const DEFAULT_REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time an expired environment's container gets to stop before removal
+const REAP_STOP_TIMEOUT_SECS: i64 = 10;
+
+/// An environment the reaper tore down
+#[derive(Debug, Clone, Serialize)]
+pub struct ReapedEnvironment {
+    pub env_id: String,
+    pub reason: Expiry,
+    pub destroyed: bool,
+    pub steps: Vec<CleanupStep>,
+}
+
+/// Pick the environments that have outlived their TTL or idle timeout
+///
+/// Environments in the middle of a lifecycle change are left alone, as are
+/// idle-expired environments with a command still running.
+pub fn expired_environments(
+    envs: Vec<EnvironmentHandle>,
+    execs: &ExecTracker,
+    now: DateTime<Utc>,
+) -> Vec<(EnvironmentHandle, Expiry)> {
+    envs.into_iter()
+        .filter(|handle| !matches!(handle.status, EnvironmentStatus::Creating | EnvironmentStatus::Stopping))
+        .filter_map(|handle| {
+            let expiry = handle.expiry(now)?;
+            if expiry == Expiry::Idle && execs.is_running_in(&handle.container_id) {
+                return None;
+            }
+            Some((handle, expiry))
+        })
+        .collect()
+}
+
+/// Stop and remove every expired environment, logging each one to the client
+///
+/// Uncommitted changes in an environment's worktree are committed to its
+/// branch first, and the branch is kept, so no work is lost. An environment
+/// whose changes cannot be committed is left in place.
+pub async fn reap_expired(state: &Arc<RwLock<ServerState>>) -> Vec<ReapedEnvironment> {
+    let (registry, shared_podman, history, execs, watchers, notifier) = {
+        let state_guard = state.read().await;
+        (
+            state_guard.registry.clone(),
+            state_guard.podman.clone(),
+            state_guard.history.clone(),
+            state_guard.execs.clone(),
+            state_guard.watchers.clone(),
+            state_guard.notifier.clone(),
+        )
+    };
+
+    let expired = expired_environments(registry.list_all().await, &execs, Utc::now());
+    if expired.is_empty() {
+        return Vec::new();
+    }
+
+    let podman = shared_podman.get().await
+        .map_err(|e| warn!("Cannot reach Podman to reap environments: {}", e))
+        .ok();
+
+    let mut reaped = Vec::new();
+    for (handle, reason) in expired {
+        let env_id = handle.env_id.clone();
+
+        // Leave containers alone until Podman is back, rather than failing every round
+        if podman.is_none() && !handle.container_id.is_empty() {
+            debug!("Postponing reaping of environment {}", env_id);
+            continue;
+        }
+
+        let handle = match registry.begin_transition(&env_id, EnvironmentStatus::Stopping).await {
+            Ok(previous) => previous,
+            Err(e) => {
+                debug!("Not reaping environment {}: {}", env_id, e);
+                continue;
+            }
+        };
+
+        // A command may have run since the environment was picked
+        if handle.expiry(Utc::now()).is_none() {
+            if let Err(e) = registry.update(handle).await {
+                warn!("Failed to restore environment {}: {}", env_id, e);
+            }
+            continue;
+        }
+
+        info!("Reaping environment {}: {}", env_id, reason);
+        watchers.stop(&env_id);
+        if let Some(podman) = &podman {
+            if handle.is_running() && !handle.container_id.is_empty() {
+                if let Err(e) = podman.stop_container(&handle.container_id, Some(REAP_STOP_TIMEOUT_SECS)).await {
+                    warn!("Failed to stop container of environment {}: {}", env_id, e);
+                }
+            }
+        }
+
+        if let Some(worktree) = handle.worktree.clone() {
+            let committed = tokio::task::spawn_blocking(move || {
+                commit_changes(&worktree.path, &CommitOptions::default())
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("Commit task failed: {}", e))
+            .and_then(|committed| committed);
+            if let Err(e) = committed {
+                warn!("Not reaping environment {}, its changes could not be committed: {:#}", env_id, e);
+                let mut handle = handle;
+                handle.set_status(EnvironmentStatus::Error(format!("Failed to commit changes before reaping: {:#}", e)));
+                if let Err(e) = registry.update(handle).await {
+                    warn!("Failed to record error for environment {}: {}", env_id, e);
+                }
+                continue;
+            }
+        }
+
+        let steps = destroy_environment(podman.as_ref(), &registry, &history, handle, Some(false)).await;
+        let destroyed = steps.iter().all(|step| step.status != StepStatus::Failed);
+        if !destroyed {
+            shared_podman.mark_unverified().await;
+        }
+
+        let entry = ReapedEnvironment { env_id, reason, destroyed, steps };
+        if let Some(notifier) = &notifier {
+            let level = if destroyed { LogLevel::Info } else { LogLevel::Warning };
+            notifier.log(level, json!({
+                "event": "environment_expired",
+                "message": format!("Environment {} removed: {}", entry.env_id, reason),
+                "env_id": entry.env_id,
+                "reason": reason,
+                "destroyed": destroyed,
+                "steps": entry.steps
+            }));
+        }
+        reaped.push(entry);
+    }
+
+    reaped
+}
+
+/// Reap expired environments every `interval`, forever
+pub async fn run_reaper(state: Arc<RwLock<ServerState>>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    // The first tick completes immediately
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let reaped = reap_expired(&state).await;
+        if !reaped.is_empty() {
+            info!("Reaped {} expired environments", reaped.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcp::progress::Notifier;
+    use crate::podman::exec::RunningExec;
+    use std::path::PathBuf;
+    use tokio::sync::mpsc;
+
+    fn environment(env_id: &str, container_id: &str, status: EnvironmentStatus) -> EnvironmentHandle {
+        let mut handle = EnvironmentHandle::new(env_id, container_id, PathBuf::from("/tmp"), "alpine:latest");
+        handle.set_status(status);
+        handle.idle_timeout_seconds = Some(60);
+        handle
+    }
+
+    #[test]
+    fn test_expired_environments() {
+        let execs = ExecTracker::new();
+        execs.insert("1", RunningExec::new("container-busy"));
+
+        let mut ttl = environment("ttl", "container-busy", EnvironmentStatus::Running);
+        ttl.ttl_seconds = Some(30);
+        let envs = vec![
+            environment("idle", "container-idle", EnvironmentStatus::Stopped),
+            environment("busy", "container-busy", EnvironmentStatus::Running),
+            environment("creating", "", EnvironmentStatus::Creating),
+            ttl,
+        ];
+
+        let now = Utc::now() + chrono::Duration::seconds(120);
+        let expired: Vec<(String, Expiry)> = expired_environments(envs, &execs, now)
+            .into_iter()
+            .map(|(handle, expiry)| (handle.env_id, expiry))
+            .collect();
+
+        assert_eq!(expired, vec![
+            ("idle".to_string(), Expiry::Idle),
+            ("ttl".to_string(), Expiry::Ttl),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_reap_expired_without_containers() {
+        let state = Arc::new(RwLock::new(ServerState::default()));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let registry = {
+            let mut state = state.write().await;
+            state.notifier = Some(Notifier::new(tx));
+            state.registry.clone()
+        };
+
+        let mut expired = environment("expired", "", EnvironmentStatus::Stopped);
+        expired.last_activity = Utc::now() - chrono::Duration::seconds(120);
+        registry.register(expired).await.unwrap();
+        registry.register(environment("fresh", "", EnvironmentStatus::Stopped)).await.unwrap();
+
+        let reaped = reap_expired(&state).await;
+        assert_eq!(reaped.len(), 1);
+        assert_eq!(reaped[0].env_id, "expired");
+        assert!(reaped[0].destroyed);
+        assert_eq!(registry.list().await, vec!["fresh".to_string()]);
+
+        let message: serde_json::Value = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        assert_eq!(message["method"], "notifications/message");
+        assert_eq!(message["params"]["level"], "info");
+        assert_eq!(message["params"]["data"]["env_id"], "expired");
+        assert_eq!(message["params"]["data"]["reason"], "idle");
+    }
+
+    #[tokio::test]
+    async fn test_reap_expired_keeps_worktree_work() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let worktrees_dir = tempfile::tempdir().unwrap();
+        let repo = crate::git::worktree::tests::init_repo(repo_dir.path());
+        let worktree = crate::git::worktree::create_worktree(repo_dir.path(), "expired", None, worktrees_dir.path())
+            .unwrap()
+            .unwrap();
+        assert!(worktree.created_branch);
+        std::fs::write(worktree.path.join("work.txt"), "unsaved\n").unwrap();
+
+        let state = Arc::new(RwLock::new(ServerState::default()));
+        let registry = state.read().await.registry.clone();
+        let mut expired = environment("expired", "", EnvironmentStatus::Stopped);
+        expired.last_activity = Utc::now() - chrono::Duration::seconds(120);
+        expired.worktree = Some(worktree.clone());
+        registry.register(expired).await.unwrap();
+
+        let reaped = reap_expired(&state).await;
+        assert_eq!(reaped.len(), 1);
+        assert!(reaped[0].destroyed);
+        assert!(!worktree.path.exists());
+
+        // The branch survives with the uncommitted changes on it
+        let branch = repo.find_branch("cofer/expired", git2::BranchType::Local).unwrap();
+        let tree = branch.get().peel_to_tree().unwrap();
+        assert!(tree.get_path(std::path::Path::new("work.txt")).is_ok());
+    }
+}
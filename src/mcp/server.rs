@@ -1,20 +1,50 @@
 use anyhow::Result;
+use bollard::models::ContainerInspectResponse;
+use futures::future::{abortable, join_all};
+use serde_json::Value;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::sync::{RwLock, watch};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use tokio::sync::{mpsc, RwLock, watch};
+use tokio::task::{JoinHandle, JoinSet};
 use tracing::{debug, error, info, warn};
 
+use super::cancellation::{request_key, InFlightRequests};
 use super::handlers;
+use super::progress::Notifier;
+use super::reaper::{self, DEFAULT_REAP_INTERVAL};
+use super::shutdown::{self, ShutdownPolicy, ShutdownSummary, DEFAULT_SHUTDOWN_TIMEOUT};
+use super::tools::{self, ToolRegistry};
+use super::transport::{self, Framing};
 use super::types::{McpError, McpRequest, McpResponse};
-use crate::environment::EnvironmentRegistry;
+use crate::environment::{CommandHistory, EnvironmentHandle, EnvironmentRegistry, EnvironmentStatus, RegistryStore};
+use crate::git::{worktree, WorktreeWatchers};
+use crate::podman::capture::CaptureLimits;
+use crate::podman::exec::ExecTracker;
+use crate::podman::SharedPodmanClient;
+
+/// Default run_command timeout, as specified in the design doc
+pub const DEFAULT_EXEC_TIMEOUT: Duration = Duration::from_millis(120_000);
 
 /// MCP server that handles JSON-RPC requests over stdio
+///
+/// Cloning is cheap; clones share handlers and state.
+#[derive(Clone)]
 pub struct McpServer {
     /// Registry of method handlers
-    handlers: HashMap<String, Box<dyn handlers::Handler>>,
+    handlers: Arc<HashMap<String, Arc<dyn handlers::Handler>>>,
     /// Shared state for the server
     state: Arc<RwLock<ServerState>>,
+    /// Response framing, detected from the first message when unset
+    framing: Option<Framing>,
+    /// What happens to environment containers on shutdown
+    shutdown_policy: ShutdownPolicy,
+    /// Time each container gets to shut down
+    shutdown_timeout: Duration,
+    /// Time between checks for environments past their TTL or idle timeout
+    reap_interval: Duration,
 }
 
 /// Server state that can be shared across handlers
@@ -22,12 +52,44 @@ pub struct McpServer {
 pub struct ServerState {
     /// Environment registry for managing container environments
     pub registry: EnvironmentRegistry,
+    /// Requests being handled, so they can be cancelled
+    pub in_flight: InFlightRequests,
+    /// Execs started by in-flight requests
+    pub execs: ExecTracker,
+    /// Notifications to the connected client, set while serving
+    pub notifier: Option<Notifier>,
+    /// Timeout for run_command when the request does not set one
+    pub exec_timeout: Duration,
+    /// Output capture limits for run_command when the request does not set them
+    pub output_limits: CaptureLimits,
+    /// Podman client shared by all requests
+    pub podman: SharedPodmanClient,
+    /// Recent run_command invocations per environment
+    pub history: CommandHistory,
+    /// Identifies this server process in the labels of the containers it creates
+    pub instance_id: String,
+    /// Where environment worktrees are created; projects are mounted directly when unset
+    pub worktrees_dir: Option<PathBuf>,
+    /// Worktrees being watched and committed by watch-commit
+    pub watchers: WorktreeWatchers,
 }
 
 impl Default for ServerState {
     fn default() -> Self {
         Self {
             registry: EnvironmentRegistry::new(),
+            in_flight: InFlightRequests::new(),
+            execs: ExecTracker::new(),
+            notifier: None,
+            exec_timeout: DEFAULT_EXEC_TIMEOUT,
+            output_limits: CaptureLimits::default(),
+            podman: SharedPodmanClient::new(),
+            history: CommandHistory::new(),
+            instance_id: uuid::Uuid::new_v4().simple().to_string(),
+            worktrees_dir: worktree::default_worktrees_dir()
+                .map_err(|e| warn!("Environments will mount projects directly: {}", e))
+                .ok(),
+            watchers: WorktreeWatchers::new(),
         }
     }
 }
@@ -35,52 +97,218 @@ impl Default for ServerState {
 impl McpServer {
     /// Create a new MCP server
     pub fn new() -> Self {
-        let mut handlers: HashMap<String, Box<dyn handlers::Handler>> = HashMap::new();
+        let mut handlers: HashMap<String, Arc<dyn handlers::Handler>> = HashMap::new();
+        let mut tools = ToolRegistry::new();
 
-        // Register core handlers
-        handlers.insert("initialize".to_string(), Box::new(handlers::InitializeHandler));
-        handlers.insert("create_environment".to_string(), Box::new(handlers::CreateEnvironmentHandler));
-        handlers.insert("run_command".to_string(), Box::new(handlers::RunCommandHandler));
+        // Register tools; they also stay callable as plain JSON-RPC methods
+        let create_environment: Arc<dyn handlers::Handler> = Arc::new(handlers::CreateEnvironmentHandler);
+        handlers.insert("create_environment".to_string(), create_environment.clone());
+        tools.register(tools::create_environment_tool(), create_environment);
 
-        // Register unimplemented handlers
-        handlers.insert("watch-commit".to_string(), Box::new(handlers::UnimplementedHandler {
-            method: "watch-commit".to_string(),
-        }));
-        handlers.insert("note-append".to_string(), Box::new(handlers::UnimplementedHandler {
-            method: "note-append".to_string(),
-        }));
-        handlers.insert("up".to_string(), Box::new(handlers::UnimplementedHandler {
-            method: "up".to_string(),
+        let run_command: Arc<dyn handlers::Handler> = Arc::new(handlers::RunCommandHandler);
+        handlers.insert("run_command".to_string(), run_command.clone());
+        tools.register(tools::run_command_tool(), run_command);
+
+        let up: Arc<dyn handlers::Handler> = Arc::new(handlers::UpHandler);
+        handlers.insert("up".to_string(), up.clone());
+        tools.register(tools::up_tool(), up);
+
+        let down: Arc<dyn handlers::Handler> = Arc::new(handlers::DownHandler);
+        handlers.insert("down".to_string(), down.clone());
+        tools.register(tools::down_tool(), down);
+
+        let list_environments: Arc<dyn handlers::Handler> = Arc::new(handlers::ListEnvironmentsHandler);
+        handlers.insert("list_environments".to_string(), list_environments.clone());
+        tools.register(tools::list_environments_tool(), list_environments);
+
+        let inspect_environment: Arc<dyn handlers::Handler> = Arc::new(handlers::InspectEnvironmentHandler);
+        handlers.insert("inspect_environment".to_string(), inspect_environment.clone());
+        tools.register(tools::inspect_environment_tool(), inspect_environment);
+
+        let destroy_environment: Arc<dyn handlers::Handler> = Arc::new(handlers::DestroyEnvironmentHandler);
+        handlers.insert("destroy_environment".to_string(), destroy_environment.clone());
+        tools.register(tools::destroy_environment_tool(), destroy_environment);
+
+        let adopt_orphans: Arc<dyn handlers::Handler> = Arc::new(handlers::AdoptOrphansHandler {
+            default_action: handlers::OrphanAction::Adopt,
+        });
+        handlers.insert("adopt_orphans".to_string(), adopt_orphans.clone());
+        tools.register(tools::adopt_orphans_tool(), adopt_orphans);
+
+        let gc: Arc<dyn handlers::Handler> = Arc::new(handlers::AdoptOrphansHandler {
+            default_action: handlers::OrphanAction::Remove,
+        });
+        handlers.insert("gc".to_string(), gc.clone());
+        tools.register(tools::gc_tool(), gc);
+
+        let watch_commit: Arc<dyn handlers::Handler> = Arc::new(handlers::WatchCommitHandler);
+        handlers.insert("watch-commit".to_string(), watch_commit.clone());
+        tools.register(tools::watch_commit_tool(), watch_commit);
+
+        let note_append: Arc<dyn handlers::Handler> = Arc::new(handlers::NoteAppendHandler);
+        handlers.insert("note-append".to_string(), note_append.clone());
+        tools.register(tools::note_append_tool(), note_append);
+
+        let propagate: Arc<dyn handlers::Handler> = Arc::new(handlers::PropagateHandler {
+            merge_current_branch: false,
+        });
+        handlers.insert("propagate".to_string(), propagate.clone());
+        tools.register(tools::propagate_tool(), propagate);
+
+        let merge_environment: Arc<dyn handlers::Handler> = Arc::new(handlers::PropagateHandler {
+            merge_current_branch: true,
+        });
+        handlers.insert("merge_environment".to_string(), merge_environment.clone());
+        tools.register(tools::merge_environment_tool(), merge_environment);
+
+        let diff_environment: Arc<dyn handlers::Handler> = Arc::new(handlers::DiffEnvironmentHandler);
+        handlers.insert("diff_environment".to_string(), diff_environment.clone());
+        tools.register(tools::diff_environment_tool(), diff_environment);
+
+        let checkpoint_environment: Arc<dyn handlers::Handler> = Arc::new(handlers::CheckpointEnvironmentHandler);
+        handlers.insert("checkpoint_environment".to_string(), checkpoint_environment.clone());
+        tools.register(tools::checkpoint_environment_tool(), checkpoint_environment);
+
+        let restore_environment: Arc<dyn handlers::Handler> = Arc::new(handlers::RestoreEnvironmentHandler);
+        handlers.insert("restore_environment".to_string(), restore_environment.clone());
+        tools.register(tools::restore_environment_tool(), restore_environment);
+
+        let fork_environment: Arc<dyn handlers::Handler> = Arc::new(handlers::ForkEnvironmentHandler);
+        handlers.insert("fork_environment".to_string(), fork_environment.clone());
+        tools.register(tools::fork_environment_tool(), fork_environment);
+
+        let tools = Arc::new(tools);
+
+        // Register MCP protocol handlers
+        handlers.insert("initialize".to_string(), Arc::new(handlers::InitializeHandler));
+        handlers.insert("notifications/initialized".to_string(), Arc::new(handlers::InitializedHandler));
+        handlers.insert("notifications/cancelled".to_string(), Arc::new(handlers::CancelledHandler));
+        handlers.insert("ping".to_string(), Arc::new(handlers::PingHandler));
+        handlers.insert("logging/setLevel".to_string(), Arc::new(handlers::SetLogLevelHandler));
+        handlers.insert("tools/list".to_string(), Arc::new(handlers::ToolsListHandler {
+            tools: tools.clone(),
         }));
-        handlers.insert("down".to_string(), Box::new(handlers::UnimplementedHandler {
-            method: "down".to_string(),
+        handlers.insert("tools/call".to_string(), Arc::new(handlers::ToolsCallHandler {
+            tools: tools.clone(),
         }));
 
         Self {
-            handlers,
+            handlers: Arc::new(handlers),
             state: Arc::new(RwLock::new(ServerState::default())),
+            framing: None,
+            shutdown_policy: ShutdownPolicy::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            reap_interval: DEFAULT_REAP_INTERVAL,
+        }
+    }
+
+    /// Force a framing for responses instead of detecting it from the client
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.framing = Some(framing);
+        self
+    }
+
+    /// Save the environment registry to `store` after every change
+    ///
+    /// Call [`McpServer::restore_environments`] to load what was saved.
+    pub fn with_registry_store(self, store: RegistryStore) -> Self {
+        if let Ok(mut state) = self.state.try_write() {
+            state.registry = EnvironmentRegistry::with_store(store);
+        }
+        self
+    }
+
+    /// Choose what happens to environment containers on shutdown
+    pub fn with_shutdown_policy(mut self, policy: ShutdownPolicy, timeout: Duration) -> Self {
+        self.shutdown_policy = policy;
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Create environment worktrees under `dir`
+    pub fn with_worktrees_dir(self, dir: impl Into<PathBuf>) -> Self {
+        if let Ok(mut state) = self.state.try_write() {
+            state.worktrees_dir = Some(dir.into());
+        }
+        self
+    }
+
+    /// Set how often expired environments are looked for while serving
+    pub fn with_reap_interval(mut self, interval: Duration) -> Self {
+        self.reap_interval = interval;
+        self
+    }
+
+    /// Set the default run_command timeout
+    pub fn with_exec_timeout(self, timeout: Duration) -> Self {
+        // Nothing else holds the state while the server is being built
+        if let Ok(mut state) = self.state.try_write() {
+            state.exec_timeout = timeout;
         }
+        self
     }
 
-    /// Run the server, listening on stdio with LSP-style transport
-    pub async fn run(&mut self, mut shutdown_rx: watch::Receiver<bool>) -> Result<()> {
-        info!("MCP server starting on stdio with Content-Length headers");
+    /// Set the default run_command output capture limits
+    pub fn with_output_limits(self, limits: CaptureLimits) -> Self {
+        if let Ok(mut state) = self.state.try_write() {
+            state.output_limits = limits;
+        }
+        self
+    }
+
+    /// Run the server, listening on stdio
+    ///
+    /// Replies use the framing of the first message received unless a
+    /// framing was forced with [`McpServer::with_framing`].
+    pub async fn run(&mut self, shutdown_rx: watch::Receiver<bool>) -> Result<()> {
+        match self.framing {
+            Some(framing) => info!("MCP server starting on stdio with {} framing", framing),
+            None => info!("MCP server starting on stdio with auto-detected framing"),
+        }
+
+        self.serve(tokio::io::stdin(), tokio::io::stdout(), shutdown_rx).await
+    }
+
+    /// Serve requests from `reader`, writing responses to `writer`
+    ///
+    /// Every request is handled on its own task so a long-running command
+    /// does not block other requests. Responses are funnelled through a single
+    /// writer task, so they may be written in a different order than the
+    /// requests arrived; clients correlate them by id.
+    pub async fn serve<R, W>(
+        &self,
+        reader: R,
+        writer: W,
+        mut shutdown_rx: watch::Receiver<bool>,
+    ) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut reader = BufReader::new(reader);
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel::<String>();
+        self.state.write().await.notifier = Some(Notifier::new(outgoing_tx.clone()));
+
+        // Expired environments are only reaped while a client is connected
+        let reaper = tokio::spawn(reaper::run_reaper(self.state.clone(), self.reap_interval));
 
-        let stdin = tokio::io::stdin();
-        let stdout = tokio::io::stdout();
-        let mut reader = BufReader::new(stdin);
-        let mut stdout = stdout;
+        // The writer starts once the framing is known
+        let mut pending_writer = Some((writer, outgoing_rx));
+        let mut writer_task: Option<JoinHandle<Result<()>>> = None;
+        let mut requests = JoinSet::new();
+        let mut shutdown_requested = false;
 
         loop {
             // Check for shutdown signal
             if *shutdown_rx.borrow() {
                 info!("Shutdown signal received");
+                shutdown_requested = true;
                 break;
             }
 
-            // Try to read the next message with a timeout
+            // Try to read the next message
             let message = tokio::select! {
-                result = self.read_message(&mut reader) => {
+                result = transport::read_message(&mut reader) => {
                     match result {
                         Ok(Some(msg)) => msg,
                         Ok(None) => {
@@ -97,170 +325,362 @@ impl McpServer {
                 },
                 _ = shutdown_rx.changed() => {
                     info!("Shutdown signal received during read");
+                    shutdown_requested = true;
                     break;
                 }
             };
 
-            debug!("Received message: {}", message);
+            if let Some((writer, outgoing_rx)) = pending_writer.take() {
+                let framing = self.framing.unwrap_or_else(|| {
+                    info!("Detected {} framing", message.framing);
+                    message.framing
+                });
+                writer_task = Some(tokio::spawn(write_responses(writer, framing, outgoing_rx)));
+            }
+
+            debug!("Received message: {}", message.body);
+
+            // Handle the request on its own task
+            let server = self.clone();
+            let outgoing_tx = outgoing_tx.clone();
+            requests.spawn(async move {
+                if let Some(response) = server.handle_message(&message.body).await {
+                    let _ = outgoing_tx.send(response.to_string());
+                }
+            });
+
+            // Reap finished request tasks
+            while let Some(result) = requests.try_join_next() {
+                if let Err(e) = result {
+                    error!("Request task failed: {}", e);
+                }
+            }
+        }
 
-            // Parse and handle the request
-            let response = self.handle_request(&message).await;
+        if shutdown_requested && !requests.is_empty() {
+            warn!("Abandoning {} in-flight requests", requests.len());
+            requests.abort_all();
+        }
 
-            // Send response with Content-Length header
-            let response_str = serde_json::to_string(&response)?;
-            let header = format!("Content-Length: {}\r\n\r\n", response_str.len());
+        // Let in-flight requests deliver their responses
+        while let Some(result) = requests.join_next().await {
+            if let Err(e) = result {
+                if !e.is_cancelled() {
+                    error!("Request task failed: {}", e);
+                }
+            }
+        }
 
-            debug!("Sending response with header: {} bytes", response_str.len());
+        reaper.abort();
 
-            stdout.write_all(header.as_bytes()).await?;
-            stdout.write_all(response_str.as_bytes()).await?;
-            stdout.flush().await?;
+        // The writer finishes once every sender is gone
+        self.state.write().await.notifier = None;
+        drop(outgoing_tx);
+        if let Some(writer_task) = writer_task {
+            writer_task.await??;
         }
 
         info!("MCP server shutting down");
         Ok(())
     }
 
-    /// Read a message with Content-Length header
-    async fn read_message<R>(&self, reader: &mut BufReader<R>) -> Result<Option<String>>
-    where
-        R: AsyncReadExt + Unpin,
-    {
-        let mut header_line = String::new();
-
-        // Read until we find Content-Length header
-        loop {
-            header_line.clear();
-
-            // Read a line
-            let bytes_read = reader.read_line(&mut header_line).await?;
-            if bytes_read == 0 {
-                // EOF
-                return Ok(None);
+    /// Handle a raw message: a single request, a notification or a batch
+    ///
+    /// Returns `None` when nothing should be written back, i.e. for
+    /// notifications and batches made up only of notifications.
+    pub async fn handle_message(&self, input: &str) -> Option<Value> {
+        let value = match serde_json::from_str::<Value>(input) {
+            Ok(value) => value,
+            Err(e) => {
+                let response = McpResponse::failure(None, McpError::parse_error(format!("Parse error: {}", e)));
+                return serde_json::to_value(response).ok();
             }
+        };
 
-            // Check for Content-Length header
-            if header_line.starts_with("Content-Length: ") {
-                break;
+        match value {
+            Value::Array(entries) if entries.is_empty() => {
+                let response = McpResponse::failure(None, McpError::invalid_request("Invalid request: empty batch"));
+                serde_json::to_value(response).ok()
             }
-
-            // Skip other headers if any
-            if header_line.trim().is_empty() {
-                // Empty line without Content-Length is unexpected
-                continue;
+            Value::Array(entries) => {
+                // Batch entries are independent, so handle them concurrently
+                let responses: Vec<Value> = join_all(entries.into_iter().map(|entry| self.handle_entry(entry)))
+                    .await
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                (!responses.is_empty()).then_some(Value::Array(responses))
             }
+            value => self.handle_entry(value).await,
         }
+    }
 
-        // Parse content length
-        let len_str = header_line
-            .strip_prefix("Content-Length: ")
-            .ok_or_else(|| anyhow::anyhow!("Invalid Content-Length header"))?
-            .trim();
+    /// Handle a single JSON-RPC request, always producing a response
+    ///
+    /// A cancelled request yields a request cancelled error here, while on
+    /// the wire it gets no response at all.
+    pub async fn handle_request(&self, input: &str) -> McpResponse {
+        let value = match serde_json::from_str::<Value>(input) {
+            Ok(value) => value,
+            Err(e) => return McpResponse::failure(None, McpError::parse_error(format!("Parse error: {}", e))),
+        };
 
-        let content_length: usize = len_str
-            .parse()
-            .map_err(|e| anyhow::anyhow!("Invalid Content-Length value: {}", e))?;
+        let id = value.get("id").cloned();
+        self.handle_value(value)
+            .await
+            .unwrap_or_else(|| McpResponse::failure(id, McpError::request_cancelled()))
+    }
 
-        // Read until empty line (end of headers)
-        let mut line = String::new();
-        loop {
-            line.clear();
-            reader.read_line(&mut line).await?;
-            if line.trim().is_empty() {
-                break;
+    /// Handle one message or batch entry, dropping the reply for notifications
+    async fn handle_entry(&self, value: Value) -> Option<Value> {
+        // A notification is a request without an id member
+        let is_notification = value.get("method").is_some() && value.get("id").is_none();
+
+        // Cancelled requests must not receive a late response
+        let response = self.handle_value(value).await?;
+
+        if is_notification {
+            if let Some(error) = &response.error {
+                debug!("Notification failed: {}", error);
             }
+            return None;
         }
 
-        // Read exact content length
-        let mut content = vec![0u8; content_length];
-        reader.read_exact(&mut content).await?;
-
-        Ok(Some(String::from_utf8(content)?))
+        serde_json::to_value(response).ok()
     }
 
-    /// Handle a single JSON-RPC request
-    pub async fn handle_request(&self, input: &str) -> McpResponse {
-        // Parse the JSON
-        let request = match serde_json::from_str::<McpRequest>(input) {
+    /// Validate and dispatch an already parsed request
+    ///
+    /// Returns `None` if the request was cancelled while being handled.
+    async fn handle_value(&self, value: Value) -> Option<McpResponse> {
+        let id = value.get("id").cloned();
+        let request = match serde_json::from_value::<McpRequest>(value) {
             Ok(req) => req,
             Err(e) => {
-                return McpResponse {
-                    jsonrpc: "2.0".to_string(),
-                    id: None,
-                    result: None,
-                    error: Some(McpError::invalid_request(format!("Invalid JSON: {}", e))),
-                };
+                return Some(McpResponse::failure(id, McpError::invalid_request(format!("Invalid request: {}", e))));
             }
         };
 
         // Validate JSON-RPC version
         if request.jsonrpc != "2.0" {
-            return McpResponse {
-                jsonrpc: "2.0".to_string(),
-                id: request.id,
-                result: None,
-                error: Some(McpError::invalid_request("Invalid JSON-RPC version")),
-            };
+            return Some(McpResponse::failure(request.id, McpError::invalid_request("Invalid JSON-RPC version")));
         }
 
         // Check if method exists
         let handler = match self.handlers.get(&request.method) {
             Some(h) => h,
             None => {
-                return McpResponse {
-                    jsonrpc: "2.0".to_string(),
-                    id: request.id,
-                    result: None,
-                    error: Some(McpError::method_not_found(&request.method)),
-                };
+                return Some(McpResponse::failure(request.id, McpError::method_not_found(&request.method)));
             }
         };
 
-        // Execute the handler
-        match handler.handle(&request, &self.state).await {
-            Ok(result) => McpResponse {
-                jsonrpc: "2.0".to_string(),
-                id: request.id,
-                result: Some(result),
-                error: None,
-            },
-            Err(error) => McpResponse {
-                jsonrpc: "2.0".to_string(),
-                id: request.id,
-                result: None,
-                error: Some(error),
-            },
+        // The initialize request must not be cancelled by the client
+        let cancel_key = request.id.as_ref()
+            .filter(|_| request.method != "initialize")
+            .map(request_key);
+        let in_flight = self.state.read().await.in_flight.clone();
+
+        // Execute the handler, abortably if it can be cancelled
+        let (handling, abort_handle) = abortable(handler.handle(&request, &self.state));
+        if let Some(key) = &cancel_key {
+            in_flight.insert(key.clone(), abort_handle);
+        }
+        let outcome = handling.await;
+        if let Some(key) = &cancel_key {
+            in_flight.remove(key);
+        }
+
+        match outcome {
+            Ok(Ok(result)) => Some(McpResponse::success(request.id, result)),
+            Ok(Err(error)) => Some(McpResponse::failure(request.id, error)),
+            Err(_) => {
+                info!("Request {} ({}) was cancelled", cancel_key.unwrap_or_default(), request.method);
+                None
+            }
         }
     }
 
+    /// Load the environments saved by a previous run and re-attach to their containers
+    ///
+    /// Each environment's status is brought in line with its container:
+    /// live containers are adopted, vanished ones are marked as errors.
+    /// Returns how many environments were restored.
+    pub async fn restore_environments(&self) -> Result<usize> {
+        let (registry, shared_podman) = {
+            let state = self.state.read().await;
+            (state.registry.clone(), state.podman.clone())
+        };
+        let Some(store) = registry.store().cloned() else {
+            return Ok(0);
+        };
+
+        let saved = store.load().await?;
+        if saved.is_empty() {
+            return Ok(0);
+        }
+
+        info!("Re-attaching {} environments from {}", saved.len(), store.path().display());
+        let podman = match shared_podman.get().await {
+            Ok(client) => Some(client),
+            Err(e) => {
+                warn!("Restoring environments without Podman: {}", e);
+                None
+            }
+        };
+
+        let mut restored = 0;
+        for mut handle in saved {
+            let inspect = match (&podman, handle.container_id.is_empty()) {
+                (Some(podman), false) => match podman.inspect_container(&handle.container_id).await {
+                    Ok(inspect) => Some(inspect),
+                    Err(e) => {
+                        warn!("Failed to inspect container of {}: {}", handle.env_id, e);
+                        None
+                    }
+                },
+                _ => None,
+            };
+
+            if let Some(status) = reattached_status(&handle, inspect.as_ref()) {
+                info!("Environment {} restored as {:?} (was {:?})", handle.env_id, status, handle.status);
+                handle.set_status(status);
+            }
+
+            let env_id = handle.env_id.clone();
+            match registry.register(handle).await {
+                Ok(()) => restored += 1,
+                Err(e) => warn!("Failed to restore environment {}: {}", env_id, e),
+            }
+        }
+
+        Ok(restored)
+    }
+
     /// Shutdown the server gracefully
-    pub async fn shutdown(&mut self) -> Result<()> {
-        info!("Shutting down MCP server");
+    ///
+    /// Kills execs still running, then applies the shutdown policy to every
+    /// registered environment.
+    pub async fn shutdown(&mut self) -> Result<ShutdownSummary> {
+        info!("Shutting down MCP server (policy: {})", self.shutdown_policy);
 
-        // Clean up environments
-        // Clone the registry to avoid holding the lock across await
-        let registry = {
+        // Clone what is needed to avoid holding the lock across await
+        let (registry, execs, shared_podman) = {
             let state = self.state.read().await;
-            state.registry.clone()
+            (state.registry.clone(), state.execs.clone(), state.podman.clone())
         };
 
-        let environments = registry.clear().await;
+        let environments = registry.list_all().await;
+        let execs = execs.drain();
+        if environments.is_empty() && execs.is_empty() {
+            return Ok(ShutdownSummary::default());
+        }
 
-        if !environments.is_empty() {
-            warn!("Cleaning up {} active environments", environments.len());
-            // TODO: Actually stop/remove containers via Podman
-            for env in environments {
-                debug!("Cleanup required for environment: {}", env.env_id);
+        warn!("Cleaning up {} environments and {} running execs", environments.len(), execs.len());
+        let podman = match shared_podman.get().await {
+            Ok(client) => Some(client),
+            Err(e) => {
+                error!("Cannot clean up containers without Podman: {}", e);
+                None
+            }
+        };
+
+        let summary = shutdown::cleanup_environments(
+            podman.as_ref(),
+            environments,
+            execs,
+            self.shutdown_policy,
+            self.shutdown_timeout,
+        ).await;
+
+        // Kept and stopped environments stay registered for the next start, as
+        // do removed ones with a worktree so their work is not lost track of
+        for env_id in &summary.removed {
+            match registry.get(env_id).await {
+                Ok(mut handle) if handle.worktree.is_some() => {
+                    handle.container_id = String::new();
+                    handle.set_status(EnvironmentStatus::Stopped);
+                    let _ = registry.update(handle).await;
+                }
+                _ => {
+                    let _ = registry.remove(env_id).await;
+                }
+            }
+        }
+        for env_id in &summary.stopped {
+            if let Ok(mut handle) = registry.get(env_id).await {
+                handle.set_status(EnvironmentStatus::Stopped);
+                let _ = registry.update(handle).await;
             }
         }
 
-        Ok(())
+        if summary.failed.is_empty() {
+            info!("Shutdown cleanup: {}", summary);
+        } else {
+            warn!("Shutdown cleanup: {}", summary);
+        }
+        Ok(summary)
+    }
+
+    /// Upper bound on how long [`McpServer::shutdown`] takes
+    pub fn shutdown_deadline(&self) -> Duration {
+        // Exec kills and container cleanup each get the timeout plus some slack
+        self.shutdown_timeout * 2 + Duration::from_secs(10)
+    }
+}
+
+/// Status of a saved environment after a restart, if it has to change
+///
+/// `inspect` is `None` when the container was not looked up, and
+/// `Some(None)` when Podman reported it missing.
+fn reattached_status(
+    handle: &EnvironmentHandle,
+    inspect: Option<&Option<ContainerInspectResponse>>,
+) -> Option<EnvironmentStatus> {
+    if handle.container_id.is_empty() {
+        // Brought down with remove; up recreates it
+        return (handle.status != EnvironmentStatus::Stopped).then_some(EnvironmentStatus::Stopped);
+    }
+
+    let status = match inspect {
+        Some(None) => EnvironmentStatus::Error("Container no longer exists".to_string()),
+        Some(Some(inspect)) => {
+            let running = inspect.state.as_ref().and_then(|state| state.running).unwrap_or(false);
+            if running {
+                EnvironmentStatus::Running
+            } else {
+                EnvironmentStatus::Stopped
+            }
+        }
+        // Without Podman, only a change that was cut short is known to be stale
+        None if matches!(handle.status, EnvironmentStatus::Creating | EnvironmentStatus::Stopping) => {
+            EnvironmentStatus::Error("Interrupted by a server restart".to_string())
+        }
+        None => return None,
+    };
+
+    (status != handle.status).then_some(status)
+}
+
+/// Write queued responses in order until every sender is dropped
+async fn write_responses<W>(
+    mut writer: W,
+    framing: Framing,
+    mut outgoing_rx: mpsc::UnboundedReceiver<String>,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(body) = outgoing_rx.recv().await {
+        debug!("Sending response: {} bytes", body.len());
+        transport::write_message(&mut writer, framing, &body).await?;
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mcp::progress::ProgressReporter;
     use serde_json::json;
 
     #[tokio::test]
@@ -270,6 +690,119 @@ mod tests {
         assert!(server.handlers.contains_key("initialize"));
         assert!(server.handlers.contains_key("create_environment"));
         assert!(server.handlers.contains_key("run_command"));
+        assert!(server.handlers.contains_key("up"));
+        assert!(server.handlers.contains_key("down"));
+        assert!(server.handlers.contains_key("list_environments"));
+        assert!(server.handlers.contains_key("inspect_environment"));
+        assert!(server.handlers.contains_key("destroy_environment"));
+        assert!(server.handlers.contains_key("adopt_orphans"));
+        assert!(server.handlers.contains_key("gc"));
+        assert!(server.handlers.contains_key("watch-commit"));
+        assert!(server.handlers.contains_key("note-append"));
+        assert!(server.handlers.contains_key("propagate"));
+        assert!(server.handlers.contains_key("merge_environment"));
+        assert!(server.handlers.contains_key("diff_environment"));
+        assert!(server.handlers.contains_key("checkpoint_environment"));
+        assert!(server.handlers.contains_key("restore_environment"));
+        assert!(server.handlers.contains_key("fork_environment"));
+        assert!(server.handlers.contains_key("tools/list"));
+        assert!(server.handlers.contains_key("tools/call"));
+    }
+
+    #[tokio::test]
+    async fn test_with_exec_timeout() {
+        let server = McpServer::new();
+        assert_eq!(server.state.read().await.exec_timeout, DEFAULT_EXEC_TIMEOUT);
+
+        let server = server.with_exec_timeout(Duration::from_secs(5));
+        assert_eq!(server.state.read().await.exec_timeout, Duration::from_secs(5));
+
+        let limits = CaptureLimits { max_bytes: 1024, max_lines: 10 };
+        let server = server.with_output_limits(limits);
+        assert_eq!(server.state.read().await.output_limits, limits);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_keeps_environments_without_containers() {
+        let mut server = McpServer::new().with_shutdown_policy(ShutdownPolicy::Remove, Duration::from_secs(1));
+        assert_eq!(server.shutdown().await.unwrap(), ShutdownSummary::default());
+
+        // An environment without a container needs nothing from Podman
+        let registry = server.state.read().await.registry.clone();
+        registry.register(EnvironmentHandle::new("env1", "", std::path::PathBuf::from("/tmp"), "alpine:latest"))
+            .await
+            .unwrap();
+
+        let summary = server.shutdown().await.unwrap();
+        assert_eq!(summary.kept, vec!["env1"]);
+        assert!(summary.failed.is_empty());
+        assert_eq!(registry.count().await, 1);
+        assert_eq!(server.shutdown_deadline(), Duration::from_secs(12));
+    }
+
+    #[tokio::test]
+    async fn test_restore_environments() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = RegistryStore::new(temp_dir.path().join("environments.json"));
+
+        let mut removed = EnvironmentHandle::new("removed", "", std::path::PathBuf::from("/tmp"), "alpine:latest");
+        removed.set_status(EnvironmentStatus::Running);
+        store.save(vec![removed]).await.unwrap();
+
+        let server = McpServer::new().with_registry_store(store.clone());
+        assert_eq!(server.restore_environments().await.unwrap(), 1);
+
+        let registry = server.state.read().await.registry.clone();
+        assert_eq!(registry.get("removed").await.unwrap().status, EnvironmentStatus::Stopped);
+        assert_eq!(store.load().await.unwrap()[0].status, EnvironmentStatus::Stopped);
+
+        // Without a store there is nothing to restore
+        assert_eq!(McpServer::new().restore_environments().await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reattached_status() {
+        let mut handle = EnvironmentHandle::new("env1", "c1", std::path::PathBuf::from("/tmp"), "alpine:latest");
+        handle.set_status(EnvironmentStatus::Running);
+        let inspect = |running: bool| ContainerInspectResponse {
+            state: Some(bollard::models::ContainerState { running: Some(running), ..Default::default() }),
+            ..Default::default()
+        };
+
+        assert_eq!(reattached_status(&handle, Some(&Some(inspect(true)))), None);
+        assert_eq!(reattached_status(&handle, Some(&Some(inspect(false)))), Some(EnvironmentStatus::Stopped));
+        assert!(matches!(reattached_status(&handle, Some(&None)), Some(EnvironmentStatus::Error(_))));
+        assert_eq!(reattached_status(&handle, None), None);
+
+        handle.set_status(EnvironmentStatus::Stopping);
+        assert!(matches!(reattached_status(&handle, None), Some(EnvironmentStatus::Error(_))));
+    }
+
+    #[tokio::test]
+    async fn test_tools_call_dispatch() {
+        let server = McpServer::new();
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": "tools/call",
+            "params": {
+                "name": "create_environment",
+                "arguments": {
+                    "env_id": "test-env",
+                    "project_root": "/nonexistent/path/to/nowhere",
+                    "image": "alpine:latest"
+                }
+            }
+        });
+
+        let response = server.handle_request(&request.to_string()).await;
+        assert_eq!(response.id, Some(json!(7)));
+        assert!(response.error.is_none());
+
+        // Handler errors come back as tool results with isError set
+        let result = response.result.unwrap();
+        assert_eq!(result["isError"], true);
+        assert!(result["content"][0]["text"].as_str().unwrap().contains("does not exist"));
     }
 
     #[tokio::test]
@@ -279,7 +812,71 @@ mod tests {
 
         assert_eq!(response.jsonrpc, "2.0");
         assert!(response.error.is_some());
-        assert_eq!(response.error.unwrap().code, -32600);
+        assert_eq!(response.error.unwrap().code, -32700);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request_structure() {
+        let server = McpServer::new();
+        let response = server.handle_message(r#"{"jsonrpc": "2.0", "id": 4}"#).await.unwrap();
+
+        assert_eq!(response["id"], 4);
+        assert_eq!(response["error"]["code"], -32600);
+    }
+
+    #[tokio::test]
+    async fn test_notification_gets_no_response() {
+        let server = McpServer::new();
+
+        let notification = json!({"jsonrpc": "2.0", "method": "notifications/initialized"});
+        assert!(server.handle_message(&notification.to_string()).await.is_none());
+
+        // Even failing notifications stay silent
+        let notification = json!({"jsonrpc": "2.0", "method": "unknown_method"});
+        assert!(server.handle_message(&notification.to_string()).await.is_none());
+
+        // A null id is still a request
+        let request = json!({"jsonrpc": "2.0", "id": null, "method": "ping"});
+        assert!(server.handle_message(&request.to_string()).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_batch_request() {
+        let server = McpServer::new();
+        let batch = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "ping"},
+            {"jsonrpc": "2.0", "method": "notifications/initialized"},
+            {"jsonrpc": "2.0", "id": 2, "method": "unknown_method"},
+            {"foo": "bar"}
+        ]);
+
+        let response = server.handle_message(&batch.to_string()).await.unwrap();
+        let responses = response.as_array().unwrap();
+
+        // One response per entry except the notification
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[0]["result"], json!({}));
+        assert_eq!(responses[1]["error"]["code"], -32601);
+        assert_eq!(responses[2]["error"]["code"], -32600);
+    }
+
+    #[tokio::test]
+    async fn test_batch_edge_cases() {
+        let server = McpServer::new();
+
+        // Empty batch is an invalid request
+        let response = server.handle_message("[]").await.unwrap();
+        assert_eq!(response["error"]["code"], -32600);
+
+        // Batch of notifications produces nothing
+        let batch = json!([{"jsonrpc": "2.0", "method": "notifications/initialized"}]);
+        assert!(server.handle_message(&batch.to_string()).await.is_none());
+
+        // Malformed batch is a parse error
+        let response = server.handle_message(r#"[{"jsonrpc": "2.0", "method""#).await.unwrap();
+        assert_eq!(response["error"]["code"], -32700);
+        assert!(response["id"].is_null());
     }
 
     #[tokio::test]
@@ -312,43 +909,214 @@ mod tests {
         assert_eq!(response.error.unwrap().code, -32601);
     }
 
+    /// Handler that takes a while, standing in for a long run_command
+    struct SlowHandler;
+
+    #[async_trait::async_trait]
+    impl handlers::Handler for SlowHandler {
+        async fn handle(&self, _request: &McpRequest, _state: &Arc<RwLock<ServerState>>) -> Result<serde_json::Value, McpError> {
+            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+            Ok(json!("slow done"))
+        }
+    }
+
+    fn server_with_slow_handler() -> McpServer {
+        let mut server = McpServer::new();
+        Arc::get_mut(&mut server.handlers)
+            .unwrap()
+            .insert("slow".to_string(), Arc::new(SlowHandler));
+        server
+    }
+
+    type ClientReader = BufReader<tokio::io::ReadHalf<tokio::io::DuplexStream>>;
+    type ClientWriter = tokio::io::WriteHalf<tokio::io::DuplexStream>;
+
+    /// Serve over an in-memory pipe, returning the client ends
+    fn spawn_serve(server: McpServer) -> (ClientReader, ClientWriter, watch::Sender<bool>, JoinHandle<Result<()>>) {
+        let (client, server_io) = tokio::io::duplex(64 * 1024);
+        let (server_read, server_write) = tokio::io::split(server_io);
+        let (client_read, client_write) = tokio::io::split(client);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        let serve = tokio::spawn(async move { server.serve(server_read, server_write, shutdown_rx).await });
+
+        (BufReader::new(client_read), client_write, shutdown_tx, serve)
+    }
+
+    async fn read_response(reader: &mut ClientReader) -> serde_json::Value {
+        use tokio::io::AsyncBufReadExt;
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    /// Reports two progress steps before answering
+    struct ProgressHandler;
+
+    #[async_trait::async_trait]
+    impl handlers::Handler for ProgressHandler {
+        async fn handle(&self, request: &McpRequest, state: &Arc<RwLock<ServerState>>) -> Result<serde_json::Value, McpError> {
+            let notifier = state.read().await.notifier.clone();
+            let progress = ProgressReporter::from_request(request, notifier);
+            progress.report(1.0, Some(2.0), "halfway");
+            progress.report(2.0, Some(2.0), "done");
+            Ok(json!("finished"))
+        }
+    }
+
     #[tokio::test]
-    async fn test_unimplemented_method() {
-        let server = McpServer::new();
+    async fn test_serve_streams_progress_notifications() {
+        use tokio::io::AsyncWriteExt;
+
+        let mut server = McpServer::new();
+        Arc::get_mut(&mut server.handlers)
+            .unwrap()
+            .insert("progress".to_string(), Arc::new(ProgressHandler));
+        let (mut reader, mut writer, _shutdown_tx, serve) = spawn_serve(server);
+
         let request = json!({
             "jsonrpc": "2.0",
             "id": 1,
-            "method": "watch-commit",
-            "params": {}
+            "method": "progress",
+            "params": {"_meta": {"progressToken": "tok-1"}}
         });
+        writer.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
 
-        let response = server.handle_request(&request.to_string()).await;
-        assert!(response.error.is_some());
+        // Notifications precede the response and carry the token
+        for (expected, message) in [(1.0, "halfway"), (2.0, "done")] {
+            let notification = read_response(&mut reader).await;
+            assert_eq!(notification["method"], "notifications/progress");
+            assert_eq!(notification["params"]["progressToken"], "tok-1");
+            assert_eq!(notification["params"]["progress"], expected);
+            assert_eq!(notification["params"]["message"], message);
+        }
+        let response = read_response(&mut reader).await;
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"], "finished");
 
-        let error = response.error.unwrap();
-        assert_eq!(error.code, -32601); // Unimplemented methods return MethodNotFound
-        assert!(error.message.to_lowercase().contains("not implemented"));
+        writer.shutdown().await.unwrap();
+        serve.await.unwrap().unwrap();
     }
 
     #[tokio::test]
-    async fn test_read_message_with_content_length() {
-        let server = McpServer::new();
+    async fn test_cancelled_request_gets_no_response() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        let (mut reader, mut writer, _shutdown_tx, serve) = spawn_serve(server_with_slow_handler());
 
-        // Create a test message with Content-Length header
-        let json_content = r#"{"jsonrpc":"2.0","id":1,"method":"test"}"#;
-        let message = format!("Content-Length: {}\r\n\r\n{}", json_content.len(), json_content);
+        let slow = json!({"jsonrpc": "2.0", "id": 1, "method": "slow"});
+        writer.write_all(format!("{}\n", slow).as_bytes()).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
 
-        let mut reader = BufReader::new(message.as_bytes());
-        let result = server.read_message(&mut reader).await.unwrap();
+        let cancel = json!({
+            "jsonrpc": "2.0",
+            "method": "notifications/cancelled",
+            "params": {"requestId": 1, "reason": "user gave up"}
+        });
+        let ping = json!({"jsonrpc": "2.0", "id": 2, "method": "ping"});
+        writer.write_all(format!("{}\n{}\n", cancel, ping).as_bytes()).await.unwrap();
 
-        assert_eq!(result, Some(json_content.to_string()));
+        let response = read_response(&mut reader).await;
+        assert_eq!(response["id"], 2);
+
+        // Once the server has drained, the output ends without a reply to id 1
+        writer.shutdown().await.unwrap();
+        serve.await.unwrap().unwrap();
+        let mut rest = String::new();
+        reader.read_line(&mut rest).await.unwrap();
+        assert!(rest.is_empty(), "unexpected output: {}", rest);
     }
 
     #[tokio::test]
-    async fn test_read_message_eof() {
-        let server = McpServer::new();
-        let mut reader = BufReader::new("".as_bytes());
-        let result = server.read_message(&mut reader).await.unwrap();
-        assert_eq!(result, None);
+    async fn test_handle_request_reports_cancellation() {
+        let server = server_with_slow_handler();
+
+        let slow = json!({"jsonrpc": "2.0", "id": "job-1", "method": "slow"});
+        let pending = tokio::spawn({
+            let server = server.clone();
+            async move { server.handle_request(&slow.to_string()).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let cancel = json!({
+            "jsonrpc": "2.0",
+            "id": 9,
+            "method": "notifications/cancelled",
+            "params": {"requestId": "job-1"}
+        });
+        let response = server.handle_request(&cancel.to_string()).await;
+        assert_eq!(response.result, Some(json!({"cancelled": true})));
+
+        let response = pending.await.unwrap();
+        assert_eq!(response.id, Some(json!("job-1")));
+        assert_eq!(response.error.unwrap().code, -32800);
+
+        // Unknown requests are ignored
+        let response = server.handle_request(&cancel.to_string()).await;
+        assert_eq!(response.result, Some(json!({"cancelled": false})));
+    }
+
+    #[tokio::test]
+    async fn test_serve_does_not_block_on_slow_request() {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut reader, mut writer, _shutdown_tx, serve) = spawn_serve(server_with_slow_handler());
+
+        let slow = json!({"jsonrpc": "2.0", "id": 1, "method": "slow"});
+        let ping = json!({"jsonrpc": "2.0", "id": 2, "method": "ping"});
+        writer.write_all(format!("{}\n{}\n", slow, ping).as_bytes()).await.unwrap();
+
+        // The ping overtakes the slow request
+        let first = read_response(&mut reader).await;
+        assert_eq!(first["id"], 2);
+        let second = read_response(&mut reader).await;
+        assert_eq!(second["id"], 1);
+        assert_eq!(second["result"], "slow done");
+
+        // Closing stdin lets the server finish cleanly
+        writer.shutdown().await.unwrap();
+        serve.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serve_correlates_concurrent_responses() {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut reader, mut writer, _shutdown_tx, _serve) = spawn_serve(server_with_slow_handler());
+
+        let mut input = String::new();
+        for id in 0..20 {
+            let method = if id % 2 == 0 { "slow" } else { "ping" };
+            input.push_str(&json!({"jsonrpc": "2.0", "id": id, "method": method}).to_string());
+            input.push('\n');
+        }
+        writer.write_all(input.as_bytes()).await.unwrap();
+
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..20 {
+            let response = read_response(&mut reader).await;
+            let id = response["id"].as_i64().unwrap();
+            if id % 2 == 0 {
+                assert_eq!(response["result"], "slow done");
+            } else {
+                assert_eq!(response["result"], json!({}));
+            }
+            assert!(seen.insert(id));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_shutdown_abandons_in_flight_requests() {
+        use tokio::io::AsyncWriteExt;
+
+        let (_reader, mut writer, shutdown_tx, serve) = spawn_serve(server_with_slow_handler());
+
+        let slow = json!({"jsonrpc": "2.0", "id": 1, "method": "slow"});
+        writer.write_all(format!("{}\n", slow).as_bytes()).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        shutdown_tx.send(true).unwrap();
+        let result = tokio::time::timeout(std::time::Duration::from_millis(200), serve).await;
+        assert!(result.is_ok(), "serve should stop without waiting for the slow request");
     }
 }
\ No newline at end of file
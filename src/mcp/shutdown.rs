@@ -0,0 +1,220 @@
+use anyhow::{bail, Result};
+use futures::future::join_all;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+use crate::environment::EnvironmentHandle;
+use crate::podman::exec::RunningExec;
+use crate::podman::PodmanClient;
+
+/// Default time each container gets to stop at shutdown
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Environment variable choosing the shutdown policy when no flag is given
+pub const SHUTDOWN_POLICY_ENV: &str = "COFER_SHUTDOWN_POLICY";
+
+/// Extra time allowed for a Podman call beyond the container's own timeout
+const PODMAN_CALL_GRACE: Duration = Duration::from_secs(5);
+
+/// What happens to environment containers when the server shuts down
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShutdownPolicy {
+    /// Stop containers, leaving them to be started again
+    Stop,
+    /// Remove containers and their volumes
+    #[default]
+    Remove,
+    /// Leave containers running, to be re-attached on the next start
+    Keep,
+}
+
+impl FromStr for ShutdownPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "stop" => Ok(Self::Stop),
+            "remove" => Ok(Self::Remove),
+            "keep" => Ok(Self::Keep),
+            other => bail!("Unknown shutdown policy '{}' (expected stop, remove or keep)", other),
+        }
+    }
+}
+
+impl fmt::Display for ShutdownPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stop => write!(f, "stop"),
+            Self::Remove => write!(f, "remove"),
+            Self::Keep => write!(f, "keep"),
+        }
+    }
+}
+
+/// What shutdown did to the environments that were still registered, by env_id
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    pub execs_killed: usize,
+    pub stopped: Vec<String>,
+    pub removed: Vec<String>,
+    pub kept: Vec<String>,
+    /// Environments whose container could not be cleaned up
+    pub failed: Vec<String>,
+}
+
+impl fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} execs killed, {} containers stopped, {} removed, {} kept, {} failed",
+            self.execs_killed,
+            self.stopped.len(),
+            self.removed.len(),
+            self.kept.len(),
+            self.failed.len()
+        )?;
+        if !self.failed.is_empty() {
+            write!(f, " ({})", self.failed.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Kill running execs, then apply the shutdown policy to every environment
+///
+/// All containers are handled in parallel, each within `timeout`.
+/// Without a Podman client only the keep policy can succeed.
+pub async fn cleanup_environments(
+    podman: Option<&PodmanClient>,
+    environments: Vec<EnvironmentHandle>,
+    execs: Vec<RunningExec>,
+    policy: ShutdownPolicy,
+    timeout: Duration,
+) -> ShutdownSummary {
+    let mut summary = ShutdownSummary::default();
+
+    if let Some(podman) = podman {
+        let kills = join_all(execs.iter().map(|exec| async move {
+            match tokio::time::timeout(timeout, podman.kill_exec(exec)).await {
+                Ok(Ok(())) => true,
+                Ok(Err(e)) => {
+                    error!("Failed to kill exec {}: {:#}", exec.marker, e);
+                    false
+                }
+                Err(_) => {
+                    error!("Timed out killing exec {}", exec.marker);
+                    false
+                }
+            }
+        })).await;
+        summary.execs_killed = kills.into_iter().filter(|killed| *killed).count();
+    }
+
+    let outcomes = join_all(environments.iter().map(|env| async move {
+        let outcome = cleanup_environment(podman, env, policy, timeout).await;
+        (env.env_id.clone(), outcome)
+    })).await;
+
+    for (env_id, outcome) in outcomes {
+        match outcome {
+            Ok(ShutdownPolicy::Stop) => summary.stopped.push(env_id),
+            Ok(ShutdownPolicy::Remove) => summary.removed.push(env_id),
+            Ok(ShutdownPolicy::Keep) => summary.kept.push(env_id),
+            Err(e) => {
+                error!("Failed to clean up environment {}: {}", env_id, e);
+                summary.failed.push(env_id);
+            }
+        }
+    }
+
+    summary
+}
+
+/// Apply the shutdown policy to one environment, returning what was done
+async fn cleanup_environment(
+    podman: Option<&PodmanClient>,
+    env: &EnvironmentHandle,
+    policy: ShutdownPolicy,
+    timeout: Duration,
+) -> Result<ShutdownPolicy> {
+    // An environment brought down with remove has no container left
+    if policy == ShutdownPolicy::Keep || env.container_id.is_empty() {
+        debug!("Keeping environment {}", env.env_id);
+        return Ok(ShutdownPolicy::Keep);
+    }
+
+    let Some(podman) = podman else {
+        bail!("Podman is not available");
+    };
+
+    let cleanup = async {
+        match policy {
+            ShutdownPolicy::Stop => podman.stop_container(&env.container_id, Some(timeout.as_secs() as i64)).await,
+            _ => podman.remove_container(&env.container_id, true).await,
+        }
+    };
+
+    match tokio::time::timeout(timeout + PODMAN_CALL_GRACE, cleanup).await {
+        Ok(Ok(())) => {
+            info!("Shutdown: applied {} to environment {}", policy, env.env_id);
+            Ok(policy)
+        }
+        Ok(Err(e)) => Err(e),
+        Err(_) => bail!("Timed out after {:?}", timeout + PODMAN_CALL_GRACE),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_shutdown_policy_parsing() {
+        assert_eq!("stop".parse::<ShutdownPolicy>().unwrap(), ShutdownPolicy::Stop);
+        assert_eq!("Remove".parse::<ShutdownPolicy>().unwrap(), ShutdownPolicy::Remove);
+        assert_eq!("keep".parse::<ShutdownPolicy>().unwrap(), ShutdownPolicy::Keep);
+        assert!("delete".parse::<ShutdownPolicy>().is_err());
+
+        for policy in [ShutdownPolicy::Stop, ShutdownPolicy::Remove, ShutdownPolicy::Keep] {
+            assert_eq!(policy.to_string().parse::<ShutdownPolicy>().unwrap(), policy);
+        }
+
+        // Leaving containers behind is opt-in
+        assert_eq!(ShutdownPolicy::default(), ShutdownPolicy::Remove);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_without_podman() {
+        let environments = vec![
+            EnvironmentHandle::new("env-b", "container-b", PathBuf::from("/tmp"), "alpine:latest"),
+            EnvironmentHandle::new("env-a", "container-a", PathBuf::from("/tmp"), "alpine:latest"),
+            EnvironmentHandle::new("env-removed", "", PathBuf::from("/tmp"), "alpine:latest"),
+        ];
+
+        let summary = cleanup_environments(None, environments.clone(), Vec::new(), ShutdownPolicy::Remove, DEFAULT_SHUTDOWN_TIMEOUT).await;
+        assert_eq!(summary.failed, vec!["env-b", "env-a"]);
+        assert_eq!(summary.kept, vec!["env-removed"]);
+        assert!(summary.removed.is_empty());
+
+        let summary = cleanup_environments(None, environments, Vec::new(), ShutdownPolicy::Keep, DEFAULT_SHUTDOWN_TIMEOUT).await;
+        assert_eq!(summary.kept.len(), 3);
+        assert!(summary.failed.is_empty());
+    }
+
+    #[test]
+    fn test_summary_display() {
+        let summary = ShutdownSummary {
+            execs_killed: 1,
+            removed: vec!["env-a".to_string(), "env-b".to_string()],
+            failed: vec!["env-x".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            summary.to_string(),
+            "1 execs killed, 0 containers stopped, 2 removed, 0 kept, 1 failed (env-x)"
+        );
+    }
+}
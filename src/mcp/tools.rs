@@ -0,0 +1,602 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use super::handlers::Handler;
+
+/// Tool metadata advertised to clients through `tools/list`
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    /// JSON Schema describing the tool arguments
+    #[serde(rename = "inputSchema")]
+    pub input_schema: Value,
+}
+
+/// A tool definition paired with the handler that executes it
+#[derive(Clone)]
+pub struct Tool {
+    pub definition: ToolDefinition,
+    pub handler: Arc<dyn Handler>,
+}
+
+/// Ordered collection of the tools exposed over MCP
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: Vec<Tool>,
+}
+
+impl ToolRegistry {
+    /// Create an empty tool registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a tool, replacing any previous tool with the same name
+    pub fn register(&mut self, definition: ToolDefinition, handler: Arc<dyn Handler>) {
+        self.tools.retain(|tool| tool.definition.name != definition.name);
+        self.tools.push(Tool { definition, handler });
+    }
+
+    /// Look up a tool by name
+    pub fn get(&self, name: &str) -> Option<&Tool> {
+        self.tools.iter().find(|tool| tool.definition.name == name)
+    }
+
+    /// Get all tool definitions in registration order
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools.iter().map(|tool| tool.definition.clone()).collect()
+    }
+}
+
+/// Definition of the create_environment tool
+pub fn create_environment_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "create_environment".to_string(),
+        description: "Create a new container environment. A project in a git repository gets its own worktree \
+                      on branch cofer/<env_id>, which is mounted instead of the project root"
+            .to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "env_id": {
+                    "type": "string",
+                    "description": "Unique identifier for the environment"
+                },
+                "project_root": {
+                    "type": "string",
+                    "description": "Absolute path of the project on the host"
+                },
+                "image": {
+                    "type": "string",
+                    "description": "Container image to run, e.g. docker.io/library/alpine:latest"
+                },
+                "env_vars": {
+                    "type": "object",
+                    "additionalProperties": { "type": "string" },
+                    "description": "Environment variables set in the container"
+                },
+                "mount_path": {
+                    "type": "string",
+                    "description": "Mount path inside the container (default: /workdir)"
+                },
+                "ports": {
+                    "type": "array",
+                    "items": { "type": ["string", "integer"] },
+                    "description": "Ports to publish as [[host_ip:]host_port:]container_port[/tcp|udp]; the host port is assigned if omitted and a taken host port leaves the container port internal-only"
+                },
+                "base_ref": {
+                    "type": "string",
+                    "description": "Commit, branch or tag the cofer/<env_id> branch starts from (default: HEAD)"
+                },
+                "ttl_seconds": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Destroy the environment this many seconds after creation"
+                },
+                "idle_timeout_seconds": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Destroy the environment after this many seconds without commands"
+                }
+            },
+            "required": ["env_id", "project_root", "image"]
+        }),
+    }
+}
+
+/// Definition of the run_command tool
+pub fn run_command_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "run_command".to_string(),
+        description: "Execute a command in a running environment".to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "env_id": {
+                    "type": "string",
+                    "description": "Environment to run the command in"
+                },
+                "command": {
+                    "oneOf": [
+                        { "type": "string", "minLength": 1 },
+                        { "type": "array", "items": { "type": "string" }, "minItems": 1 }
+                    ],
+                    "description": "Shell string executed with sh -c, or an argv array executed directly"
+                },
+                "env": {
+                    "type": "object",
+                    "additionalProperties": { "type": "string" },
+                    "description": "Environment variables added for this command"
+                },
+                "workdir": {
+                    "type": "string",
+                    "description": "Working directory, relative to the mount path unless absolute"
+                },
+                "user": {
+                    "type": "string",
+                    "description": "User to run as, e.g. 1000 or node"
+                },
+                "timeout_ms": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Kill the command after this many milliseconds (default: 120000)"
+                },
+                "max_output_bytes": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Bytes of stdout and stderr each kept, returned as head and tail once exceeded (default: 65536)"
+                },
+                "max_output_lines": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Lines of stdout and stderr each kept, returned as head and tail once exceeded (default: 2000)"
+                }
+            },
+            "required": ["env_id", "command"]
+        }),
+    }
+}
+
+/// Definition of the up tool
+pub fn up_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "up".to_string(),
+        description: "Start a stopped environment, recreating its container if it was removed".to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "env_id": {
+                    "type": "string",
+                    "description": "Environment to start"
+                }
+            },
+            "required": ["env_id"]
+        }),
+    }
+}
+
+/// Definition of the down tool
+pub fn down_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "down".to_string(),
+        description: "Stop an environment, keeping it registered so up can start it again".to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "env_id": {
+                    "type": "string",
+                    "description": "Environment to stop"
+                },
+                "remove": {
+                    "type": "boolean",
+                    "description": "Also remove the container; up recreates it (default: false)"
+                }
+            },
+            "required": ["env_id"]
+        }),
+    }
+}
+
+/// Definition of the list_environments tool
+pub fn list_environments_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "list_environments".to_string(),
+        description: "List all environments with their status and container state".to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {}
+        }),
+    }
+}
+
+/// Definition of the inspect_environment tool
+pub fn inspect_environment_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "inspect_environment".to_string(),
+        description: "Show an environment in detail: mounts, ports, resource usage and recent commands"
+            .to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "env_id": {
+                    "type": "string",
+                    "description": "Environment to inspect"
+                }
+            },
+            "required": ["env_id"]
+        }),
+    }
+}
+
+/// Definition of the destroy_environment tool
+pub fn destroy_environment_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "destroy_environment".to_string(),
+        description: "Delete an environment: its container, volumes, worktree, branch and registry entry, reporting each step"
+            .to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "env_id": {
+                    "type": "string",
+                    "description": "Environment to destroy"
+                },
+                "delete_branch": {
+                    "type": "boolean",
+                    "description": "Delete the cofer/<env_id> branch (default: only if cofer created it)"
+                }
+            },
+            "required": ["env_id"]
+        }),
+    }
+}
+
+/// Input schema shared by the adopt_orphans and gc tools
+fn orphans_schema(default_action: &str) -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "action": {
+                "type": "string",
+                "enum": ["adopt", "remove"],
+                "description": format!(
+                    "Register orphaned containers as environments again, or remove them (default: {})",
+                    default_action
+                )
+            },
+            "env_id": {
+                "type": "string",
+                "description": "Only consider containers labelled with this environment, even if another server instance created it"
+            },
+            "force": {
+                "type": "boolean",
+                "description": "Also act on containers created by other server instances, which may still be running (default: false)"
+            },
+            "dry_run": {
+                "type": "boolean",
+                "description": "Only report the orphans and what would be done (default: true if no action, env_id or force is given)"
+            }
+        }
+    })
+}
+
+/// Definition of the adopt_orphans tool
+pub fn adopt_orphans_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "adopt_orphans".to_string(),
+        description: "Find cofer containers unknown to the registry and register them as environments again"
+            .to_string(),
+        input_schema: orphans_schema("adopt"),
+    }
+}
+
+/// Definition of the gc tool
+pub fn gc_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "gc".to_string(),
+        description: "Find cofer containers unknown to the registry and remove them with their volumes"
+            .to_string(),
+        input_schema: orphans_schema("remove"),
+    }
+}
+
+/// Definition of the watch-commit tool
+pub fn watch_commit_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "watch-commit".to_string(),
+        description: "Commit changes in an environment's worktree now, then keep committing them \
+            to its branch in debounced batches as files change"
+            .to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "env_id": {
+                    "type": "string",
+                    "description": "Environment whose worktree to watch"
+                },
+                "debounce_ms": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Quiet period after the last change before committing (default: 50)"
+                },
+                "exclude": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Extra glob patterns to leave out, on top of .git/, node_modules/ and target/"
+                },
+                "nonbinary_only": {
+                    "type": "boolean",
+                    "description": "Leave binary files out of commits (default: true)"
+                },
+                "stop": {
+                    "type": "boolean",
+                    "description": "Stop watching instead, committing what is pending (default: false)"
+                }
+            },
+            "required": ["env_id"]
+        }),
+    }
+}
+
+/// Definition of the note-append tool
+pub fn note_append_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "note-append".to_string(),
+        description: "Append a payload, such as a command transcript, to the git note on an environment's HEAD commit"
+            .to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "env_id": {
+                    "type": "string",
+                    "description": "Environment whose HEAD commit gets the note"
+                },
+                "payload": {
+                    "type": "string",
+                    "description": "Content to append, base64-encoded unless encoding is text"
+                },
+                "encoding": {
+                    "type": "string",
+                    "enum": ["base64", "text"],
+                    "description": "How payload is encoded (default: base64); binary content is stored as base64"
+                },
+                "ref": {
+                    "type": "string",
+                    "description": "Notes ref to append to (default: refs/notes/cofer)"
+                },
+                "cap_lines": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Keep only this many lines from each end of a text payload (default: 120)"
+                }
+            },
+            "required": ["env_id", "payload"]
+        }),
+    }
+}
+
+/// Input schema shared by the propagate and merge_environment tools
+fn propagate_schema(target_description: &str) -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "env_id": {
+                "type": "string",
+                "description": "Environment whose branch to bring into the project's repository"
+            },
+            "target_branch": {
+                "type": "string",
+                "description": target_description
+            },
+            "strategy": {
+                "type": "string",
+                "enum": ["merge", "ff-only"],
+                "description": "Merge diverged branches, or only fast-forward the target (default: merge)"
+            },
+            "commit_pending": {
+                "type": "boolean",
+                "description": "Commit uncommitted changes in the environment's worktree first (default: true)"
+            }
+        },
+        "required": ["env_id"]
+    })
+}
+
+/// Definition of the propagate tool
+pub fn propagate_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "propagate".to_string(),
+        description: "Fetch an environment's branch into the project's repository as cofer/<env_id>, \
+            optionally merging it into a branch; conflicts are reported without changing anything"
+            .to_string(),
+        input_schema: propagate_schema("Branch to fast-forward or merge the environment's work into (default: none, only fetch)"),
+    }
+}
+
+/// Definition of the merge_environment tool
+pub fn merge_environment_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "merge_environment".to_string(),
+        description: "Merge an environment's branch into a branch of the project's repository; \
+            conflicts are reported without changing anything"
+            .to_string(),
+        input_schema: propagate_schema("Branch to merge the environment's work into (default: the branch checked out in the project)"),
+    }
+}
+
+/// Definition of the diff_environment tool
+pub fn diff_environment_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "diff_environment".to_string(),
+        description: "Show how an environment's worktree, committed and uncommitted changes alike, \
+            differs from the commit its branch started from"
+            .to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "env_id": {
+                    "type": "string",
+                    "description": "Environment to diff"
+                },
+                "base": {
+                    "type": "string",
+                    "description": "Commit or ref to diff against (default: the commit the environment started from)"
+                },
+                "format": {
+                    "type": "string",
+                    "enum": ["name-only", "stat", "patch"],
+                    "description": "Changed paths only, with line counts, or with unified patches (default: patch)"
+                },
+                "context_lines": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "description": "Unchanged lines around each hunk of a patch (default: 3)"
+                },
+                "paths": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Only diff these paths or pathspecs"
+                },
+                "max_bytes": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Cap on the total size of the patches (default: 262144)"
+                }
+            },
+            "required": ["env_id"]
+        }),
+    }
+}
+
+/// Definition of the checkpoint_environment tool
+pub fn checkpoint_environment_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "checkpoint_environment".to_string(),
+        description: "Checkpoint an environment: commit its worktree and snapshot the container \
+            filesystem as an image, so restore_environment can roll back to this point"
+            .to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "env_id": {
+                    "type": "string",
+                    "description": "Environment to checkpoint"
+                },
+                "label": {
+                    "type": "string",
+                    "description": "Name of the checkpoint, used as the image tag (default: cp-<n>)"
+                }
+            },
+            "required": ["env_id"]
+        }),
+    }
+}
+
+/// Definition of the restore_environment tool
+pub fn restore_environment_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "restore_environment".to_string(),
+        description: "Roll an environment back to a checkpoint: reset its worktree to the checkpoint's \
+            commit and recreate its container from the checkpoint's image"
+            .to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "env_id": {
+                    "type": "string",
+                    "description": "Environment to restore"
+                },
+                "label": {
+                    "type": "string",
+                    "description": "Checkpoint to restore (default: the latest)"
+                }
+            },
+            "required": ["env_id"]
+        }),
+    }
+}
+
+/// Definition of the fork_environment tool
+pub fn fork_environment_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "fork_environment".to_string(),
+        description: "Fork an environment into a new one: a new worktree branch from the source's HEAD \
+            including uncommitted changes, and a new container from an image of the source's container"
+            .to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "env_id": {
+                    "type": "string",
+                    "description": "Environment to fork"
+                },
+                "new_env_id": {
+                    "type": "string",
+                    "description": "Unique identifier for the new environment"
+                }
+            },
+            "required": ["env_id", "new_env_id"]
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcp::server::ServerState;
+    use crate::mcp::types::{McpError, McpRequest};
+    use tokio::sync::RwLock;
+
+    /// Handler standing in for a tool's real one
+    struct DummyHandler;
+
+    #[async_trait::async_trait]
+    impl Handler for DummyHandler {
+        async fn handle(&self, _request: &McpRequest, _state: &Arc<RwLock<ServerState>>) -> Result<Value, McpError> {
+            Ok(Value::Null)
+        }
+    }
+
+    fn dummy_handler() -> Arc<dyn Handler> {
+        Arc::new(DummyHandler)
+    }
+
+    #[test]
+    fn test_register_and_get() {
+        let mut registry = ToolRegistry::new();
+        assert!(registry.definitions().is_empty());
+
+        registry.register(create_environment_tool(), dummy_handler());
+        registry.register(run_command_tool(), dummy_handler());
+
+        assert_eq!(registry.definitions().len(), 2);
+        assert!(registry.get("create_environment").is_some());
+        assert!(registry.get("run_command").is_some());
+        assert!(registry.get("unknown").is_none());
+
+        // Registration order is preserved
+        let names: Vec<String> = registry.definitions().into_iter().map(|d| d.name).collect();
+        assert_eq!(names, vec!["create_environment", "run_command"]);
+    }
+
+    #[test]
+    fn test_register_replaces_existing() {
+        let mut registry = ToolRegistry::new();
+        registry.register(run_command_tool(), dummy_handler());
+        registry.register(run_command_tool(), dummy_handler());
+
+        assert_eq!(registry.definitions().len(), 1);
+    }
+
+    #[test]
+    fn test_definition_serialization() {
+        let json = serde_json::to_value(create_environment_tool()).unwrap();
+
+        assert_eq!(json["name"], "create_environment");
+        assert_eq!(json["inputSchema"]["type"], "object");
+        assert_eq!(json["inputSchema"]["required"], json!(["env_id", "project_root", "image"]));
+        assert!(json.get("input_schema").is_none());
+    }
+}
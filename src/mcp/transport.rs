@@ -0,0 +1,306 @@
+use anyhow::{bail, Result};
+use std::fmt;
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{debug, warn};
+
+/// Upper bound for a single message, whichever its framing
+const MAX_CONTENT_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Wire framing of JSON-RPC messages on stdio
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// LSP-style `Content-Length` headers followed by the JSON body
+    ContentLength,
+    /// One JSON message per line, as specified by the MCP stdio transport
+    NewlineDelimited,
+}
+
+impl FromStr for Framing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "content-length" | "header" | "lsp" => Ok(Self::ContentLength),
+            "ndjson" | "newline" | "jsonl" => Ok(Self::NewlineDelimited),
+            other => bail!("Unknown framing '{}' (expected content-length or ndjson)", other),
+        }
+    }
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ContentLength => write!(f, "content-length"),
+            Self::NewlineDelimited => write!(f, "ndjson"),
+        }
+    }
+}
+
+/// A raw message read from the transport
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// Framing the message arrived in
+    pub framing: Framing,
+    /// Message body, expected to be JSON
+    pub body: String,
+}
+
+/// Read the next message, detecting its framing from the first bytes
+///
+/// Lines starting with a JSON value are newline-delimited messages, header
+/// lines start a Content-Length framed message. Any other non-empty line is
+/// returned as a newline-delimited body so the caller can answer it with a
+/// parse error instead of silently dropping it.
+pub async fn read_message<R>(reader: &mut BufReader<R>) -> Result<Option<Message>>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let mut content_length: Option<usize> = None;
+    let mut in_headers = false;
+
+    loop {
+        let line = match read_line(reader, MAX_CONTENT_LENGTH).await? {
+            Some(line) => line,
+            None => {
+                if in_headers {
+                    bail!("EOF inside message headers");
+                }
+                return Ok(None);
+            }
+        };
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() {
+            if let Some(length) = content_length {
+                return read_body(reader, length).await.map(Some);
+            }
+            if in_headers {
+                warn!("Header block without Content-Length, skipping");
+                in_headers = false;
+            }
+            continue;
+        }
+
+        if let Some((name, value)) = parse_header(trimmed) {
+            in_headers = true;
+            if name.eq_ignore_ascii_case("Content-Length") {
+                let length: usize = value
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid Content-Length value: {}", e))?;
+                if length > MAX_CONTENT_LENGTH {
+                    bail!("Content-Length {} exceeds limit of {} bytes", length, MAX_CONTENT_LENGTH);
+                }
+                content_length = Some(length);
+            } else {
+                debug!("Ignoring header: {}", name);
+            }
+            continue;
+        }
+
+        if content_length.is_some() {
+            bail!("Malformed header block: expected empty line before body");
+        }
+        if in_headers {
+            warn!("Discarding incomplete header block");
+        }
+
+        return Ok(Some(Message {
+            framing: Framing::NewlineDelimited,
+            body: trimmed.to_string(),
+        }));
+    }
+}
+
+/// Write a message body using the given framing
+pub async fn write_message<W>(writer: &mut W, framing: Framing, body: &str) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    match framing {
+        Framing::ContentLength => {
+            let header = format!("Content-Length: {}\r\n\r\n", body.len());
+            writer.write_all(header.as_bytes()).await?;
+            writer.write_all(body.as_bytes()).await?;
+        }
+        Framing::NewlineDelimited => {
+            writer.write_all(body.as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read a single line of at most `limit` bytes, tolerating invalid UTF-8
+async fn read_line<R>(reader: &mut BufReader<R>, limit: usize) -> Result<Option<String>>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let mut buf = Vec::new();
+    let bytes_read = reader.take(limit as u64 + 1).read_until(b'\n', &mut buf).await?;
+    if bytes_read == 0 {
+        return Ok(None);
+    }
+    if bytes_read > limit {
+        bail!("Line exceeds limit of {} bytes", limit);
+    }
+    Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+}
+
+/// Read exactly `length` bytes of message body
+async fn read_body<R>(reader: &mut BufReader<R>, length: usize) -> Result<Message>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let mut content = vec![0u8; length];
+    reader.read_exact(&mut content).await?;
+
+    Ok(Message {
+        framing: Framing::ContentLength,
+        body: String::from_utf8(content)?,
+    })
+}
+
+/// Split a `Name: value` header line; JSON bodies never match
+fn parse_header(line: &str) -> Option<(&str, &str)> {
+    if line.starts_with('{') || line.starts_with('[') {
+        return None;
+    }
+    let (name, value) = line.split_once(':')?;
+    let is_token = !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    is_token.then(|| (name, value.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_all(input: &str) -> Vec<Result<Option<Message>>> {
+        let mut reader = BufReader::new(input.as_bytes());
+        let mut results = Vec::new();
+        loop {
+            let result = read_message(&mut reader).await;
+            let done = matches!(result, Ok(None));
+            results.push(result);
+            if done {
+                break;
+            }
+        }
+        results
+    }
+
+    #[tokio::test]
+    async fn test_read_message_with_content_length() {
+        let json_content = r#"{"jsonrpc":"2.0","id":1,"method":"test"}"#;
+        let message = format!("Content-Length: {}\r\n\r\n{}", json_content.len(), json_content);
+
+        let mut reader = BufReader::new(message.as_bytes());
+        let result = read_message(&mut reader).await.unwrap().unwrap();
+
+        assert_eq!(result.framing, Framing::ContentLength);
+        assert_eq!(result.body, json_content);
+    }
+
+    #[tokio::test]
+    async fn test_read_message_newline_delimited() {
+        let input = "{\"id\":1}\n\n{\"id\":2}\r\n[{\"id\":3}]\n";
+        let mut reader = BufReader::new(input.as_bytes());
+
+        for expected in [r#"{"id":1}"#, r#"{"id":2}"#, r#"[{"id":3}]"#] {
+            let message = read_message(&mut reader).await.unwrap().unwrap();
+            assert_eq!(message.framing, Framing::NewlineDelimited);
+            assert_eq!(message.body, expected);
+        }
+        assert!(read_message(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_read_message_extra_headers() {
+        let body = r#"{"id":1}"#;
+        let input = format!(
+            "Content-Type: application/vscode-jsonrpc\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let mut reader = BufReader::new(input.as_bytes());
+
+        let message = read_message(&mut reader).await.unwrap().unwrap();
+        assert_eq!(message.framing, Framing::ContentLength);
+        assert_eq!(message.body, body);
+    }
+
+    #[tokio::test]
+    async fn test_read_message_mixed_framing() {
+        let body = r#"{"id":2}"#;
+        let input = format!("{{\"id\":1}}\nContent-Length: {}\r\n\r\n{}\n{{\"id\":3}}\n", body.len(), body);
+
+        let results = read_all(&input).await;
+        let messages: Vec<Message> =
+            results.into_iter().filter_map(|r| r.unwrap()).collect();
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].framing, Framing::NewlineDelimited);
+        assert_eq!(messages[1].framing, Framing::ContentLength);
+        assert_eq!(messages[1].body, body);
+        assert_eq!(messages[2].body, r#"{"id":3}"#);
+    }
+
+    #[tokio::test]
+    async fn test_read_message_garbled_input_recovery() {
+        // A bad Content-Length is reported, then reading resumes
+        let input = "Content-Length: abc\r\n\r\nnot json at all\n{\"id\":1}\n";
+        let mut reader = BufReader::new(input.as_bytes());
+
+        assert!(read_message(&mut reader).await.is_err());
+
+        let garbage = read_message(&mut reader).await.unwrap().unwrap();
+        assert_eq!(garbage.framing, Framing::NewlineDelimited);
+        assert_eq!(garbage.body, "not json at all");
+
+        let valid = read_message(&mut reader).await.unwrap().unwrap();
+        assert_eq!(valid.body, r#"{"id":1}"#);
+    }
+
+    #[tokio::test]
+    async fn test_read_message_eof() {
+        let mut reader = BufReader::new("".as_bytes());
+        let result = read_message(&mut reader).await.unwrap();
+        assert_eq!(result, None);
+
+        // EOF in the middle of a header block is an error
+        let mut reader = BufReader::new("Content-Length: 10\r\n".as_bytes());
+        assert!(read_message(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_line_limit() {
+        let mut reader = BufReader::new("12345\n".as_bytes());
+        assert_eq!(read_line(&mut reader, 6).await.unwrap().as_deref(), Some("12345\n"));
+
+        // A line that never ends is cut off at the limit
+        let mut reader = BufReader::new("1234567890".as_bytes());
+        assert!(read_line(&mut reader, 6).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write_message() {
+        let body = r#"{"id":1}"#;
+
+        let mut out = Vec::new();
+        write_message(&mut out, Framing::ContentLength, body).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("Content-Length: 8\r\n\r\n{}", body));
+
+        let mut out = Vec::new();
+        write_message(&mut out, Framing::NewlineDelimited, body).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}\n", body));
+    }
+
+    #[test]
+    fn test_framing_from_str() {
+        assert_eq!("content-length".parse::<Framing>().unwrap(), Framing::ContentLength);
+        assert_eq!("NDJSON".parse::<Framing>().unwrap(), Framing::NewlineDelimited);
+        assert!("xml".parse::<Framing>().is_err());
+    }
+}
@@ -31,7 +31,38 @@ pub struct McpError {
     pub data: Option<Value>,
 }
 
+impl McpResponse {
+    /// Successful response carrying a result
+    pub fn success(id: Option<Value>, result: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    /// Error response
+    pub fn failure(id: Option<Value>, error: McpError) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
 impl McpError {
+    /// Parse error (-32700)
+    pub fn parse_error(message: impl Into<String>) -> Self {
+        Self {
+            code: -32700,
+            message: message.into(),
+            data: None,
+        }
+    }
+
     /// Invalid Request error (-32600)
     pub fn invalid_request(message: impl Into<String>) -> Self {
         Self {
@@ -67,6 +98,15 @@ impl McpError {
             data: None,
         }
     }
+
+    /// Request cancelled (-32800)
+    pub fn request_cancelled() -> Self {
+        Self {
+            code: -32800,
+            message: "Request cancelled".to_string(),
+            data: None,
+        }
+    }
 }
 
 impl fmt::Display for McpError {
@@ -135,8 +175,22 @@ mod tests {
         assert!(!json_str.contains("\"result\""));
     }
 
+    #[test]
+    fn test_response_constructors() {
+        let response = McpResponse::success(Some(json!(3)), json!({"ok": true}));
+        assert_eq!(response.id, Some(json!(3)));
+        assert!(response.error.is_none());
+
+        let response = McpResponse::failure(None, McpError::parse_error("bad"));
+        assert!(response.result.is_none());
+        assert_eq!(response.error.unwrap().code, -32700);
+    }
+
     #[test]
     fn test_error_constructors() {
+        let err = McpError::parse_error("bad json");
+        assert_eq!(err.code, -32700);
+
         let err = McpError::invalid_request("test");
         assert_eq!(err.code, -32600);
 
@@ -149,5 +203,8 @@ mod tests {
 
         let err = McpError::internal_error("internal");
         assert_eq!(err.code, -32603);
+
+        let err = McpError::request_cancelled();
+        assert_eq!(err.code, -32800);
     }
 }
\ No newline at end of file
@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+
+/// Default cap on the bytes kept from one output stream
+pub const DEFAULT_MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+/// Default cap on the lines kept from one output stream
+pub const DEFAULT_MAX_OUTPUT_LINES: usize = 2000;
+
+/// Limits applied when capturing command output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureLimits {
+    /// Maximum bytes kept, split between head and tail
+    pub max_bytes: usize,
+    /// Maximum lines kept, split between head and tail
+    pub max_lines: usize,
+}
+
+impl Default for CaptureLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+            max_lines: DEFAULT_MAX_OUTPUT_LINES,
+        }
+    }
+}
+
+impl CaptureLimits {
+    /// Split a limit into head and tail budgets; the tail gets the larger share
+    fn split(limit: usize) -> (usize, usize) {
+        let head = limit / 4;
+        (head, limit - head)
+    }
+}
+
+/// Head and tail of a captured stream
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CaptureSummary {
+    /// Start of the output, within the head budget; all of it unless truncated
+    pub head: String,
+    /// End of the output, within the tail budget
+    pub tail: String,
+    /// Total bytes the stream produced
+    pub total_bytes: u64,
+    /// Whether anything was dropped between head and tail
+    pub truncated: bool,
+}
+
+impl CaptureSummary {
+    /// Render the captured text
+    ///
+    /// When output was dropped, the text is the head and tail joined by a
+    /// marker stating how many bytes were omitted.
+    pub fn text(&self) -> String {
+        if !self.truncated {
+            return self.head.clone();
+        }
+        let omitted = self.total_bytes.saturating_sub((self.head.len() + self.tail.len()) as u64);
+        let separator = if self.head.is_empty() || self.head.ends_with('\n') { "" } else { "\n" };
+        format!("{}{}[... {} bytes omitted ...]\n{}", self.head, separator, omitted, self.tail)
+    }
+}
+
+/// Bounded capture of an output stream keeping its head and tail
+///
+/// Memory use is bounded by the byte limit no matter how much output the
+/// stream produces.
+#[derive(Debug, Clone)]
+pub struct OutputCapture {
+    limits: CaptureLimits,
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    head_limit: usize,
+    tail_limit: usize,
+    total_bytes: u64,
+}
+
+impl OutputCapture {
+    /// Create an empty capture with the given limits
+    pub fn new(limits: CaptureLimits) -> Self {
+        let (head_limit, tail_limit) = CaptureLimits::split(limits.max_bytes);
+        Self {
+            limits,
+            head: Vec::with_capacity(head_limit.min(8192)),
+            tail: VecDeque::with_capacity(tail_limit.min(8192)),
+            head_limit,
+            tail_limit,
+            total_bytes: 0,
+        }
+    }
+
+    /// Append a chunk of output
+    pub fn write(&mut self, chunk: &[u8]) {
+        self.total_bytes += chunk.len() as u64;
+
+        let head_room = self.head_limit.saturating_sub(self.head.len());
+        self.head.extend_from_slice(&chunk[..head_room.min(chunk.len())]);
+
+        // The tail always holds the last bytes of the stream, even if they
+        // are also part of the head
+        let keep = chunk.len().min(self.tail_limit);
+        self.tail.extend(&chunk[chunk.len() - keep..]);
+        let excess = self.tail.len().saturating_sub(self.tail_limit);
+        self.tail.drain(..excess);
+    }
+
+    /// Split the captured output into its head and tail
+    pub fn finish(self) -> CaptureSummary {
+        let (head_lines, tail_lines) = CaptureLimits::split(self.limits.max_lines);
+        let tail_bytes: Vec<u8> = self.tail.into_iter().collect();
+        let kept = (self.head.len() + tail_bytes.len()) as u64;
+
+        let tail = last_lines(&decode_tail(&tail_bytes), tail_lines);
+
+        let (head, tail, truncated) = if kept >= self.total_bytes {
+            // Head and tail overlap, so together they hold the whole output
+            let overlap = (kept - self.total_bytes) as usize;
+            let mut full = self.head;
+            full.extend_from_slice(&tail_bytes[overlap..]);
+            let full = String::from_utf8_lossy(&full).into_owned();
+
+            if full.split_inclusive('\n').count() <= self.limits.max_lines {
+                (full, tail, false)
+            } else {
+                (first_lines(&full, head_lines), last_lines(&full, tail_lines), true)
+            }
+        } else {
+            (first_lines(&decode_head(&self.head), head_lines), tail, true)
+        };
+
+        CaptureSummary { head, tail, total_bytes: self.total_bytes, truncated }
+    }
+}
+
+/// Decode the head, dropping a character cut off at the end
+fn decode_head(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(e) if e.error_len().is_none() => String::from_utf8_lossy(&bytes[..e.valid_up_to()]).into_owned(),
+        Err(_) => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Decode the tail, dropping a character cut off at the start
+fn decode_tail(bytes: &[u8]) -> String {
+    let start = bytes.iter().take(3).take_while(|b| (**b & 0xC0) == 0x80).count();
+    String::from_utf8_lossy(&bytes[start..]).into_owned()
+}
+
+/// First `count` lines of `text`, keeping line endings
+fn first_lines(text: &str, count: usize) -> String {
+    text.split_inclusive('\n').take(count).collect()
+}
+
+/// Last `count` lines of `text`, keeping line endings
+fn last_lines(text: &str, count: usize) -> String {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    lines[lines.len().saturating_sub(count)..].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(limits: CaptureLimits, chunks: &[&[u8]]) -> (String, CaptureSummary) {
+        let mut capture = OutputCapture::new(limits);
+        for chunk in chunks {
+            capture.write(chunk);
+        }
+        let summary = capture.finish();
+        (summary.text(), summary)
+    }
+
+    #[test]
+    fn test_small_output_is_kept_whole() {
+        let (text, summary) = capture(CaptureLimits::default(), &[b"hello ", b"world\n"]);
+
+        assert_eq!(text, "hello world\n");
+        assert_eq!(summary.head, "hello world\n");
+        assert_eq!(summary.tail, "hello world\n");
+        assert_eq!(summary.total_bytes, 12);
+        assert!(!summary.truncated);
+    }
+
+    #[test]
+    fn test_byte_limit_keeps_head_and_tail() {
+        let limits = CaptureLimits { max_bytes: 40, max_lines: 1000 };
+        let output: String = (0..100).map(|i| format!("{:02}\n", i)).collect();
+
+        // Feed in uneven chunks
+        let chunks: Vec<&[u8]> = output.as_bytes().chunks(7).collect();
+        let (text, summary) = capture(limits, &chunks);
+
+        assert!(summary.truncated);
+        assert_eq!(summary.total_bytes, 300);
+        assert!(text.starts_with("00\n01\n02\n"));
+        assert!(text.ends_with("97\n98\n99\n"));
+        assert!(text.contains("bytes omitted"));
+        assert!(summary.head.starts_with("00\n01\n02\n"));
+        assert!(!summary.head.contains("99"));
+        assert_eq!(summary.tail.len(), 30);
+        assert!(summary.tail.ends_with("99\n"));
+    }
+
+    #[test]
+    fn test_line_limit_keeps_head_and_tail() {
+        let limits = CaptureLimits { max_bytes: 1024, max_lines: 8 };
+        let output: String = (0..20).map(|i| format!("line {}\n", i)).collect();
+        let (text, summary) = capture(limits, &[output.as_bytes()]);
+
+        assert!(summary.truncated);
+        let kept: Vec<&str> = text.lines().filter(|line| line.starts_with("line")).collect();
+        assert_eq!(kept, vec!["line 0", "line 1", "line 14", "line 15", "line 16", "line 17", "line 18", "line 19"]);
+        assert_eq!(summary.tail.lines().count(), 6);
+    }
+
+    #[test]
+    fn test_line_limit_tail_is_whole_lines() {
+        // The last lines are longer than the tail's byte budget of 75
+        let limits = CaptureLimits { max_bytes: 100, max_lines: 4 };
+        let long_line = format!("{}\n", "x".repeat(29));
+        let output = format!("1\n2\n{}", long_line.repeat(3));
+        let (text, summary) = capture(limits, &[output.as_bytes()]);
+
+        assert!(summary.truncated);
+        assert_eq!(summary.head, "1\n");
+        assert_eq!(summary.tail, long_line.repeat(3));
+        assert!(text.ends_with(&summary.tail));
+    }
+
+    #[test]
+    fn test_utf8_boundaries_are_respected() {
+        let limits = CaptureLimits { max_bytes: 16, max_lines: 100 };
+        let output = "é".repeat(50);
+        let (text, summary) = capture(limits, &[output.as_bytes()]);
+
+        assert!(summary.truncated);
+        assert!(!text.contains('\u{FFFD}'));
+        assert!(summary.tail.chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn test_memory_is_bounded() {
+        let limits = CaptureLimits { max_bytes: 1024, max_lines: 100 };
+        let mut capture = OutputCapture::new(limits);
+        for _ in 0..1000 {
+            capture.write(&[b'x'; 4096]);
+        }
+
+        assert!(capture.head.len() + capture.tail.len() <= 1024);
+        assert_eq!(capture.total_bytes, 4_096_000);
+    }
+}
@@ -89,6 +89,11 @@ impl PodmanClient {
         }
     }
 
+    /// Check that the Podman service still answers
+    pub async fn ping(&self) -> Result<()> {
+        Self::verify_connection(&self.docker).await
+    }
+
     /// Get version information
     pub async fn version(&self) -> Result<bollard::models::SystemVersion> {
         self.docker
@@ -1,16 +1,20 @@
 use anyhow::{Context, Result};
-use bollard::container::{
-    Config, CreateContainerOptions, ListContainersOptions, LogsOptions,
-    RemoveContainerOptions, StartContainerOptions, StopContainerOptions,
-};
 use bollard::exec::{CreateExecOptions, StartExecResults};
-use bollard::models::ContainerSummary;
+use bollard::models::{ContainerCreateBody, ContainerInspectResponse, ContainerSummary};
+use bollard::query_parameters::{
+    CreateContainerOptionsBuilder, InspectContainerOptions, ListContainersOptionsBuilder, LogsOptionsBuilder,
+    RemoveContainerOptionsBuilder, StartContainerOptions, StopContainerOptionsBuilder,
+};
 use bollard::service::{HostConfig, Mount, MountTypeEnum};
 use futures::StreamExt;
 use std::collections::HashMap;
 use tracing::{debug, error, info};
 
+use super::capture::{CaptureLimits, CaptureSummary, OutputCapture};
 use super::client::PodmanClient;
+use super::exec;
+use super::labels::ContainerLabels;
+use super::ports::ContainerPorts;
 
 /// Container lifecycle management for Podman
 impl PodmanClient {
@@ -23,42 +27,67 @@ impl PodmanClient {
         mount_path: &str,
         env_vars: HashMap<String, String>,
     ) -> Result<String> {
-        info!("Creating container: {} from image: {}", name, image);
+        self.create_container_with_labels(name, image, project_root, mount_path, env_vars, HashMap::new())
+            .await
+    }
 
-        // Prepare environment variables
-        let env: Vec<String> = env_vars
-            .into_iter()
-            .map(|(k, v)| format!("{}={}", k, v))
-            .collect();
-
-        // Create bind mount
-        let mount = Mount {
-            target: Some(mount_path.to_string()),
-            source: Some(project_root.to_string()),
-            typ: Some(MountTypeEnum::BIND),
-            read_only: Some(false),
-            ..Default::default()
-        };
+    /// Create a new container carrying the given labels
+    pub async fn create_container_with_labels(
+        &self,
+        name: &str,
+        image: &str,
+        project_root: &str,
+        mount_path: &str,
+        env_vars: HashMap<String, String>,
+        labels: HashMap<String, String>,
+    ) -> Result<String> {
+        let config = container_config(image, project_root, mount_path, env_vars, labels);
+        self.create_from_config(name, config).await
+    }
 
-        // Container configuration
-        let config = Config {
-            image: Some(image.to_string()),
-            env: Some(env),
-            working_dir: Some(mount_path.to_string()),
-            attach_stdout: Some(true),
-            attach_stderr: Some(true),
-            host_config: Some(HostConfig {
-                mounts: Some(vec![mount]),
-                auto_remove: Some(false),
-                ..Default::default()
-            }),
-            ..Default::default()
-        };
+    /// Create an environment's container, named after the environment
+    ///
+    /// `mount_source` is bind-mounted at the labelled mount path, and the
+    /// labelled worktree's git directories at their host paths so git works
+    /// inside. Published ports are bound on the host, internal ones only
+    /// exposed.
+    pub async fn create_environment_container(
+        &self,
+        image: &str,
+        mount_source: &str,
+        labels: &ContainerLabels,
+        env_vars: HashMap<String, String>,
+        ports: &ContainerPorts,
+    ) -> Result<String> {
+        let mut config = container_config(image, mount_source, &labels.mount_path, env_vars, labels.to_map());
+        if let Some(worktree) = &labels.worktree {
+            let git_mounts = worktree.git_mounts().into_iter().map(|path| {
+                let path = path.to_string_lossy().into_owned();
+                Mount {
+                    target: Some(path.clone()),
+                    source: Some(path),
+                    typ: Some(MountTypeEnum::BIND),
+                    read_only: Some(false),
+                    ..Default::default()
+                }
+            });
+            if let Some(mounts) = config.host_config.as_mut().and_then(|host| host.mounts.as_mut()) {
+                mounts.extend(git_mounts);
+            }
+        }
+        if !ports.is_empty() {
+            config.exposed_ports = Some(ports.exposed_ports());
+        }
+        if let Some(host_config) = config.host_config.as_mut().filter(|_| !ports.published.is_empty()) {
+            host_config.port_bindings = Some(ports.port_bindings());
+        }
+        self.create_from_config(&labels.env_id, config).await
+    }
 
-        let options = CreateContainerOptions {
-            name,
-            platform: None,
-        };
+    async fn create_from_config(&self, name: &str, config: ContainerCreateBody) -> Result<String> {
+        info!("Creating container: {} from image: {}", name, config.image.as_deref().unwrap_or_default());
+
+        let options = CreateContainerOptionsBuilder::new().name(name).build();
 
         let response = self
             .docker
@@ -77,7 +106,7 @@ impl PodmanClient {
         info!("Starting container: {}", container_id);
 
         self.docker
-            .start_container(container_id, None::<StartContainerOptions<String>>)
+            .start_container(container_id, None::<StartContainerOptions>)
             .await
             .context("Failed to start container")?;
 
@@ -89,9 +118,8 @@ impl PodmanClient {
     pub async fn stop_container(&self, container_id: &str, timeout: Option<i64>) -> Result<()> {
         info!("Stopping container: {}", container_id);
 
-        let options = StopContainerOptions {
-            t: timeout.unwrap_or(10),
-        };
+        let timeout = i32::try_from(timeout.unwrap_or(10)).unwrap_or(i32::MAX);
+        let options = StopContainerOptionsBuilder::new().t(timeout).build();
 
         self.docker
             .stop_container(container_id, Some(options))
@@ -106,11 +134,10 @@ impl PodmanClient {
     pub async fn remove_container(&self, container_id: &str, force: bool) -> Result<()> {
         info!("Removing container: {} (force: {})", container_id, force);
 
-        let options = RemoveContainerOptions {
-            force,
-            v: true, // Remove volumes
-            ..Default::default()
-        };
+        let options = RemoveContainerOptionsBuilder::new()
+            .force(force)
+            .v(true) // Remove volumes
+            .build();
 
         self.docker
             .remove_container(container_id, Some(options))
@@ -125,10 +152,7 @@ impl PodmanClient {
     pub async fn list_containers(&self, all: bool) -> Result<Vec<ContainerSummary>> {
         debug!("Listing containers (all: {})", all);
 
-        let options = ListContainersOptions::<String> {
-            all,
-            ..Default::default()
-        };
+        let options = ListContainersOptionsBuilder::new().all(all).build();
 
         let containers = self
             .docker
@@ -140,18 +164,59 @@ impl PodmanClient {
         Ok(containers)
     }
 
+    /// Inspect a container, returning `None` if it does not exist
+    pub async fn inspect_container(&self, container_id: &str) -> Result<Option<ContainerInspectResponse>> {
+        debug!("Inspecting container: {}", container_id);
+
+        match self
+            .docker
+            .inspect_container(container_id, None::<InspectContainerOptions>)
+            .await
+        {
+            Ok(inspect) => Ok(Some(inspect)),
+            Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => Ok(None),
+            Err(e) => Err(e).context("Failed to inspect container"),
+        }
+    }
+
+    /// Check whether a container exists, running or not
+    pub async fn container_exists(&self, container_id: &str) -> Result<bool> {
+        Ok(self.inspect_container(container_id).await?.is_some())
+    }
+
     /// Execute a command in a container
     pub async fn exec_command(
         &self,
         container_id: &str,
         cmd: Vec<String>,
         env_vars: Option<HashMap<String, String>>,
+    ) -> Result<ExecResult> {
+        let options = ExecOptions {
+            env: env_vars.unwrap_or_default(),
+            ..Default::default()
+        };
+        self.exec_command_streaming(container_id, cmd, options, |_, _| {}).await
+    }
+
+    /// Execute a command in a container, passing output chunks to `on_output` as they arrive
+    ///
+    /// Only the head and tail of each stream are kept, within the capture limits.
+    pub async fn exec_command_streaming(
+        &self,
+        container_id: &str,
+        cmd: Vec<String>,
+        options: ExecOptions,
+        mut on_output: impl FnMut(ExecStream, &[u8]) + Send,
     ) -> Result<ExecResult> {
         info!("Executing command in container {}: {:?}", container_id, cmd);
 
         // Prepare environment variables
-        let env = env_vars.map(|vars| {
-            vars.into_iter()
+        let mut env_vars = options.env;
+        if let Some(tracked) = &options.tracked {
+            env_vars.extend(tracked.env());
+        }
+        let env = (!env_vars.is_empty()).then(|| {
+            env_vars.into_iter()
                 .map(|(k, v)| format!("{}={}", k, v))
                 .collect::<Vec<_>>()
         });
@@ -159,10 +224,13 @@ impl PodmanClient {
         let exec_config = CreateExecOptions {
             cmd: Some(cmd),
             env,
+            working_dir: options.workdir,
+            user: options.user,
             attach_stdout: Some(true),
             attach_stderr: Some(true),
             ..Default::default()
         };
+        let limits = options.limits;
 
         // Create exec instance
         let exec_create = self
@@ -172,22 +240,27 @@ impl PodmanClient {
             .context("Failed to create exec instance")?;
 
         let exec_id = exec_create.id;
+        if let Some(tracked) = &options.tracked {
+            tracked.set_exec_id(&exec_id);
+        }
 
         // Start exec and collect output
         let exec_start = self.docker.start_exec(&exec_id, None).await?;
 
-        let mut stdout = Vec::new();
-        let mut stderr = Vec::new();
+        let mut stdout = OutputCapture::new(limits);
+        let mut stderr = OutputCapture::new(limits);
 
         match exec_start {
             StartExecResults::Attached { mut output, .. } => {
                 while let Some(chunk) = output.next().await {
                     match chunk {
                         Ok(bollard::container::LogOutput::StdOut { message }) => {
-                            stdout.extend_from_slice(&message);
+                            on_output(ExecStream::Stdout, &message);
+                            stdout.write(&message);
                         }
                         Ok(bollard::container::LogOutput::StdErr { message }) => {
-                            stderr.extend_from_slice(&message);
+                            on_output(ExecStream::Stderr, &message);
+                            stderr.write(&message);
                         }
                         Ok(_) => {}
                         Err(e) => {
@@ -208,12 +281,62 @@ impl PodmanClient {
 
         Ok(ExecResult {
             exit_code,
-            stdout: String::from_utf8_lossy(&stdout).to_string(),
-            stderr: String::from_utf8_lossy(&stderr).to_string(),
+            stdout: stdout.finish(),
+            stderr: stderr.finish(),
         })
     }
 
-    /// Get container logs
+    /// Kill every process started by a tracked exec
+    ///
+    /// Podman has no API to stop an exec. The exec's process tree is killed
+    /// from the host through the PID Podman reports for it, which needs
+    /// nothing inside the container. When Podman runs elsewhere, such as in a
+    /// VM, the processes are found through the marker variable in their
+    /// environment and killed from inside the container instead, which needs
+    /// `sh` in the image.
+    pub async fn kill_exec(&self, exec: &exec::RunningExec) -> Result<()> {
+        info!("Killing exec {} in container {}", exec.marker, exec.container_id);
+
+        let host_error = match exec.exec_id() {
+            Some(exec_id) => match self.kill_exec_from_host(exec_id, exec).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            },
+            None => anyhow::anyhow!("Exec {} has not been created", exec.marker),
+        };
+        debug!("Cannot kill exec {} from the host: {:#}", exec.marker, host_error);
+
+        let cmd = vec!["sh".to_string(), "-c".to_string(), exec::kill_script(&exec.marker)];
+        let result = self
+            .exec_command(&exec.container_id, cmd, None)
+            .await
+            .with_context(|| format!("Failed to kill exec ({:#})", host_error))?;
+
+        if result.exit_code != Some(0) {
+            anyhow::bail!("Killing exec failed: {} ({:#})", result.stderr.text().trim(), host_error);
+        }
+
+        Ok(())
+    }
+
+    /// Kill an exec's process tree through the host PID Podman reports for it
+    async fn kill_exec_from_host(&self, exec_id: &str, exec: &exec::RunningExec) -> Result<()> {
+        let inspect = self.docker.inspect_exec(exec_id).await.context("Failed to inspect exec")?;
+        if inspect.running == Some(false) {
+            debug!("Exec {} already ended", exec.marker);
+            return Ok(());
+        }
+
+        let pid = inspect.pid
+            .filter(|pid| *pid > 0)
+            .and_then(|pid| u32::try_from(pid).ok())
+            .with_context(|| format!("Podman reported no PID for exec {}", exec_id))?;
+        let container_id = inspect.container_id.unwrap_or_else(|| exec.container_id.clone());
+        exec::kill_host_processes(pid, &container_id, &exec.marker)?;
+        Ok(())
+    }
+
+    /// Get container logs, keeping the head and tail of each stream within the default limits
     pub async fn get_logs(
         &self,
         container_id: &str,
@@ -221,25 +344,24 @@ impl PodmanClient {
     ) -> Result<(String, String)> {
         debug!("Getting logs for container: {}", container_id);
 
-        let options = LogsOptions::<String> {
-            stdout: true,
-            stderr: true,
-            tail: tail.unwrap_or_else(|| "all".to_string()),
-            ..Default::default()
-        };
+        let options = LogsOptionsBuilder::new()
+            .stdout(true)
+            .stderr(true)
+            .tail(tail.as_deref().unwrap_or("all"))
+            .build();
 
         let mut stream = self.docker.logs(container_id, Some(options));
 
-        let mut stdout = Vec::new();
-        let mut stderr = Vec::new();
+        let mut stdout = OutputCapture::new(CaptureLimits::default());
+        let mut stderr = OutputCapture::new(CaptureLimits::default());
 
         while let Some(chunk) = stream.next().await {
             match chunk {
                 Ok(bollard::container::LogOutput::StdOut { message }) => {
-                    stdout.extend_from_slice(&message);
+                    stdout.write(&message);
                 }
                 Ok(bollard::container::LogOutput::StdErr { message }) => {
-                    stderr.extend_from_slice(&message);
+                    stderr.write(&message);
                 }
                 Ok(_) => {}
                 Err(e) => {
@@ -249,19 +371,79 @@ impl PodmanClient {
             }
         }
 
-        Ok((
-            String::from_utf8_lossy(&stdout).to_string(),
-            String::from_utf8_lossy(&stderr).to_string(),
-        ))
+        Ok((stdout.finish().text(), stderr.finish().text()))
     }
 }
 
+/// Options for executing a command in a container
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    /// Environment variables added for this exec
+    pub env: HashMap<String, String>,
+    /// Working directory, the container's default when unset
+    pub workdir: Option<String>,
+    /// User to run as, the container's default when unset
+    pub user: Option<String>,
+    /// Limits for the captured stdout and stderr
+    pub limits: CaptureLimits,
+    /// Record of the exec to tag and note Podman's exec id on, so it can be killed
+    pub tracked: Option<exec::RunningExec>,
+}
+
 /// Result from executing a command in a container
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ExecResult {
     pub exit_code: Option<i64>,
-    pub stdout: String,
-    pub stderr: String,
+    /// Captured stdout, split into head and tail if it exceeded the limits
+    pub stdout: CaptureSummary,
+    /// Captured stderr, split into head and tail if it exceeded the limits
+    pub stderr: CaptureSummary,
+}
+
+/// Output stream of an exec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecStream {
+    Stdout,
+    Stderr,
+}
+
+/// Configuration of a container with `project_root` bind-mounted at `mount_path`
+fn container_config(
+    image: &str,
+    project_root: &str,
+    mount_path: &str,
+    env_vars: HashMap<String, String>,
+    labels: HashMap<String, String>,
+) -> ContainerCreateBody {
+    // Prepare environment variables
+    let env: Vec<String> = env_vars
+        .into_iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+
+    // Create bind mount
+    let mount = Mount {
+        target: Some(mount_path.to_string()),
+        source: Some(project_root.to_string()),
+        typ: Some(MountTypeEnum::BIND),
+        read_only: Some(false),
+        ..Default::default()
+    };
+
+    ContainerCreateBody {
+        image: Some(image.to_string()),
+        env: Some(env),
+        working_dir: Some(mount_path.to_string()),
+        labels: Some(labels),
+        attach_stdout: Some(true),
+        attach_stderr: Some(true),
+        host_config: Some(HostConfig {
+            mounts: Some(vec![mount]),
+            auto_remove: Some(false),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
 }
 
 #[cfg(test)]
@@ -312,7 +494,7 @@ mod tests {
                     .await;
 
                 if let Ok(exec) = exec_result {
-                    assert_eq!(exec.stdout.trim(), "hello");
+                    assert_eq!(exec.stdout.head.trim(), "hello");
                     assert_eq!(exec.exit_code, Some(0));
                 }
 
@@ -344,4 +526,67 @@ mod tests {
             );
         }
     }
-}
\ No newline at end of file
+
+    /// Start `cmd` as a tracked exec in `container_id`, then kill it
+    async fn assert_exec_killed(client: &PodmanClient, container_id: &str, cmd: Vec<String>) {
+        let exec = exec::RunningExec::new(container_id);
+        let running = {
+            let client = PodmanClient::new().await.unwrap();
+            let container_id = container_id.to_string();
+            let options = ExecOptions { tracked: Some(exec.clone()), ..Default::default() };
+            tokio::spawn(async move {
+                client.exec_command_streaming(&container_id, cmd, options, |_, _| {}).await
+            })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert!(exec.exec_id().is_some());
+
+        client.kill_exec(&exec).await.unwrap();
+        let result = tokio::time::timeout(std::time::Duration::from_secs(10), running)
+            .await
+            .expect("exec should end once killed")
+            .unwrap()
+            .unwrap();
+        assert_ne!(result.exit_code, Some(0));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Podman and image
+    async fn test_kill_exec() {
+        if let Ok(client) = PodmanClient::new().await {
+            let _ = client.ensure_image("docker.io/library/alpine:latest").await;
+
+            let container_name = format!("test-kill-exec-{}", uuid::Uuid::new_v4());
+            let container_id = client
+                .create_container(&container_name, "alpine:latest", "/tmp", "/workdir", HashMap::new())
+                .await
+                .unwrap();
+            client.start_container(&container_id).await.unwrap();
+
+            assert_exec_killed(&client, &container_id, vec!["sleep".to_string(), "300".to_string()]).await;
+
+            client.remove_container(&container_id, true).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires Podman and image
+    async fn test_kill_exec_without_shell() {
+        if let Ok(client) = PodmanClient::new().await {
+            // The pause image holds nothing but the pause binary
+            let image = "registry.k8s.io/pause:3.9";
+            client.ensure_image(image).await.unwrap();
+
+            let container_name = format!("test-kill-exec-noshell-{}", uuid::Uuid::new_v4());
+            let container_id = client
+                .create_container(&container_name, image, "/tmp", "/workdir", HashMap::new())
+                .await
+                .unwrap();
+            client.start_container(&container_id).await.unwrap();
+
+            assert_exec_killed(&client, &container_id, vec!["/pause".to_string()]).await;
+
+            client.remove_container(&container_id, true).await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use tracing::debug;
+
+/// Environment variable tagging every process started by a tracked exec
+///
+/// Children inherit it, so processes an exec left behind, such as
+/// daemonized children, can still be found and killed.
+pub const EXEC_MARKER_VAR: &str = "COFER_EXEC_ID";
+
+/// An exec that is currently running inside a container
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunningExec {
+    /// Container the exec runs in
+    pub container_id: String,
+    /// Value of [`EXEC_MARKER_VAR`] for this exec
+    pub marker: String,
+    /// Podman's id for the exec, shared by clones once it has been created
+    exec_id: Arc<OnceLock<String>>,
+}
+
+impl RunningExec {
+    /// Create a running exec record with a fresh marker
+    pub fn new(container_id: impl Into<String>) -> Self {
+        Self {
+            container_id: container_id.into(),
+            marker: uuid::Uuid::new_v4().simple().to_string(),
+            exec_id: Arc::default(),
+        }
+    }
+
+    /// Environment variables to pass to the exec so it can be killed later
+    pub fn env(&self) -> HashMap<String, String> {
+        HashMap::from([(EXEC_MARKER_VAR.to_string(), self.marker.clone())])
+    }
+
+    /// Podman's id for the exec, `None` until it has been created
+    pub fn exec_id(&self) -> Option<&str> {
+        self.exec_id.get().map(String::as_str)
+    }
+
+    /// Record the id Podman gave the exec
+    pub(crate) fn set_exec_id(&self, exec_id: &str) {
+        if self.exec_id.set(exec_id.to_string()).is_err() {
+            debug!("Exec {} already has an id", self.marker);
+        }
+    }
+}
+
+/// Tracks running execs by the key of the request that started them
+#[derive(Clone, Default)]
+pub struct ExecTracker {
+    execs: Arc<Mutex<HashMap<String, RunningExec>>>,
+}
+
+impl ExecTracker {
+    /// Create an empty tracker
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record an exec started on behalf of `key`
+    pub fn insert(&self, key: impl Into<String>, exec: RunningExec) {
+        let key = key.into();
+        debug!("Tracking exec {} for request {}", exec.marker, key);
+        self.lock().insert(key, exec);
+    }
+
+    /// Stop tracking the exec for `key`, returning it if present
+    pub fn remove(&self, key: &str) -> Option<RunningExec> {
+        self.lock().remove(key)
+    }
+
+    /// Check whether any tracked exec runs in `container_id`
+    pub fn is_running_in(&self, container_id: &str) -> bool {
+        self.lock().values().any(|exec| exec.container_id == container_id)
+    }
+
+    /// Stop tracking every exec, returning them
+    pub fn drain(&self) -> Vec<RunningExec> {
+        self.lock().drain().map(|(_, exec)| exec).collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, RunningExec>> {
+        // The map stays consistent even if a holder panicked
+        self.execs.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Kill the host processes of an exec whose main process has host PID `pid`
+///
+/// Only works when Podman runs on this host, and `pid` is only trusted if it
+/// sits in the cgroup of `container_id`. Needs nothing inside the container,
+/// so images without a shell are covered. Returns how many processes were
+/// killed.
+#[cfg(target_os = "linux")]
+pub(crate) fn kill_host_processes(pid: u32, container_id: &str, marker: &str) -> Result<usize> {
+    let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid))
+        .map_err(|e| anyhow::anyhow!("Process {} is not visible on this host: {}", pid, e))?;
+    if container_id.is_empty() || !cgroup.contains(container_id) {
+        anyhow::bail!("Process {} does not belong to container {}", pid, container_id);
+    }
+
+    let pids = exec_processes(pid, marker);
+    for &pid in &pids {
+        // SAFETY: kill has no memory safety requirements
+        if unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) } != 0 {
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::ESRCH) {
+                anyhow::bail!("Failed to kill process {}: {}", pid, error);
+            }
+        }
+    }
+    debug!("Killed {} host processes of exec {}", pids.len(), marker);
+    Ok(pids.len())
+}
+
+/// Killing from the host needs `/proc`, which only Linux hosts have
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn kill_host_processes(_pid: u32, _container_id: &str, _marker: &str) -> Result<usize> {
+    anyhow::bail!("Killing exec processes from the host is only supported on Linux")
+}
+
+/// Host PIDs of `pid`, its descendants and the processes in its cgroup carrying `marker`
+#[cfg(target_os = "linux")]
+fn exec_processes(pid: u32, marker: &str) -> Vec<u32> {
+    let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok();
+    let tagged = format!("{}={}", EXEC_MARKER_VAR, marker);
+
+    let mut parents = HashMap::new();
+    let mut carriers = Vec::new();
+    for entry in std::fs::read_dir("/proc").into_iter().flatten().flatten() {
+        let Some(other) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+            continue;
+        };
+        if let Some(parent) = parent_pid(other) {
+            parents.insert(other, parent);
+        }
+        let same_cgroup = cgroup.is_some()
+            && std::fs::read_to_string(entry.path().join("cgroup")).ok() == cgroup;
+        // Environments of other users' processes are unreadable, which is fine
+        let carries_marker = same_cgroup && std::fs::read(entry.path().join("environ"))
+            .map(|environ| environ.split(|b| *b == 0).any(|var| var == tagged.as_bytes()))
+            .unwrap_or(false);
+        if carries_marker {
+            carriers.push(other);
+        }
+    }
+
+    let mut pids = vec![pid];
+    let mut index = 0;
+    while index < pids.len() {
+        let current = pids[index];
+        pids.extend(parents.iter().filter(|(_, parent)| **parent == current).map(|(child, _)| *child));
+        index += 1;
+    }
+    for carrier in carriers {
+        if !pids.contains(&carrier) {
+            pids.push(carrier);
+        }
+    }
+    pids
+}
+
+/// Parent PID from `/proc/<pid>/stat`, whose command field may contain spaces
+#[cfg(target_os = "linux")]
+fn parent_pid(pid: u32) -> Option<u32> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Shell script killing every process whose environment carries `marker`
+pub(crate) fn kill_script(marker: &str) -> String {
+    format!(
+        "for p in /proc/[0-9]*; do \
+           if tr '\\0' '\\n' < \"$p/environ\" 2>/dev/null | grep -qx '{}={}'; then \
+             kill -KILL \"${{p#/proc/}}\" 2>/dev/null; \
+           fi; \
+         done; true",
+        EXEC_MARKER_VAR, marker
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_running_exec_env() {
+        let exec = RunningExec::new("container-1");
+        let env = exec.env();
+
+        assert_eq!(env.get(EXEC_MARKER_VAR), Some(&exec.marker));
+        assert_ne!(RunningExec::new("container-1").marker, exec.marker);
+    }
+
+    #[test]
+    fn test_tracker_insert_remove() {
+        let tracker = ExecTracker::new();
+        let exec = RunningExec::new("container-1");
+
+        tracker.insert("1", exec.clone());
+        assert_eq!(tracker.remove("1"), Some(exec));
+        assert_eq!(tracker.remove("1"), None);
+
+        tracker.insert("2", RunningExec::new("container-1"));
+        tracker.insert("3", RunningExec::new("container-2"));
+        assert!(tracker.is_running_in("container-2"));
+        assert!(!tracker.is_running_in("container-3"));
+        assert_eq!(tracker.drain().len(), 2);
+        assert!(!tracker.is_running_in("container-2"));
+        assert_eq!(tracker.remove("2"), None);
+    }
+
+    #[test]
+    fn test_kill_script_matches_marker_exactly() {
+        let script = kill_script("abc123");
+        assert!(script.contains("grep -qx 'COFER_EXEC_ID=abc123'"));
+        assert!(script.contains("kill -KILL"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_exec_processes_covers_tree_and_marker() {
+        let exec = RunningExec::new("container-1");
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "sleep 300 & sleep 301"])
+            .envs(exec.env())
+            .spawn()
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(300));
+
+        let pid = child.id();
+        let pids = exec_processes(pid, &exec.marker);
+        assert_eq!(pids[0], pid);
+        assert!(pids.len() >= 3, "expected sh and both sleeps, got {:?}", pids);
+        assert!(!pids.contains(&std::process::id()));
+
+        // The test process is in no container, so its cgroup never matches
+        assert!(kill_host_processes(pid, "not-a-container-id", &exec.marker).is_err());
+
+        for pid in &pids {
+            unsafe { libc::kill(*pid as libc::pid_t, libc::SIGKILL) };
+        }
+        assert!(!child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_exec_id_is_shared_by_clones() {
+        let exec = RunningExec::new("container-1");
+        let clone = exec.clone();
+        assert_eq!(clone.exec_id(), None);
+
+        exec.set_exec_id("abc");
+        exec.set_exec_id("def");
+        assert_eq!(clone.exec_id(), Some("abc"));
+    }
+}
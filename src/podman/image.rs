@@ -1,12 +1,18 @@
 use anyhow::{Context, Result};
-use bollard::image::{CreateImageOptions, ListImagesOptions};
-use bollard::models::ImageSummary;
+use bollard::models::{ContainerConfig, ImageSummary};
+use bollard::query_parameters::{
+    CommitContainerOptionsBuilder, CreateImageOptionsBuilder, ListImagesOptions, ListImagesOptionsBuilder,
+    RemoveImageOptionsBuilder,
+};
 use futures::StreamExt;
 use std::collections::HashMap;
 use tracing::{debug, error, info, warn};
 
 use super::client::PodmanClient;
 
+/// Repository checkpoints of environments are committed to
+pub const CHECKPOINT_REPOSITORY: &str = "localhost/cofer-checkpoint";
+
 /// Image management operations for Podman
 impl PodmanClient {
     /// Check if an image exists locally
@@ -18,15 +24,14 @@ impl PodmanClient {
 
         let filters = {
             let mut filters = HashMap::new();
-            filters.insert("reference".to_string(), vec![format!("{}:{}", name, tag)]);
+            filters.insert("reference", vec![format!("{}:{}", name, tag)]);
             filters
         };
 
-        let options = ListImagesOptions {
-            all: false,
-            filters,
-            ..Default::default()
-        };
+        let options = ListImagesOptionsBuilder::new()
+            .all(false)
+            .filters(&filters)
+            .build();
 
         let images = self.docker
             .list_images(Some(options))
@@ -44,7 +49,7 @@ impl PodmanClient {
         debug!("Listing all images");
 
         let images = self.docker
-            .list_images(None::<ListImagesOptions<String>>)
+            .list_images(None::<ListImagesOptions>)
             .await
             .context("Failed to list images")?;
 
@@ -54,40 +59,46 @@ impl PodmanClient {
 
     /// Pull an image from registry
     pub async fn pull_image(&self, image: &str) -> Result<()> {
+        self.pull_image_with_progress(image, |_| {}).await
+    }
+
+    /// Pull an image from registry, reporting aggregated layer progress
+    pub async fn pull_image_with_progress(
+        &self,
+        image: &str,
+        mut on_progress: impl FnMut(&PullProgress) + Send,
+    ) -> Result<()> {
         info!("Pulling image: {}", image);
 
         let (name, tag) = parse_image_tag(image);
 
-        let options = Some(CreateImageOptions {
-            from_image: name.clone(),
-            tag: tag.clone(),
-            ..Default::default()
-        });
+        let options = Some(CreateImageOptionsBuilder::new()
+            .from_image(&name)
+            .tag(&tag)
+            .build());
 
         let mut stream = self.docker.create_image(options, None, None);
+        let mut progress = PullProgress::default();
 
         // Process the stream to track progress
         while let Some(result) = stream.next().await {
             match result {
                 Ok(info) => {
-                    // Log progress information
-                    if let Some(status) = info.status {
-                        debug!("Pull progress: {}", status);
-                        if let Some(progress) = info.progress_detail {
-                            if let (Some(current), Some(total)) = (progress.current, progress.total) {
-                                if total > 0 {
-                                    let percent = (current as f64 / total as f64) * 100.0;
-                                    debug!("  Progress: {:.1}%", percent);
-                                }
-                            }
-                        }
-                    }
-
                     // Check for errors in the info
                     if let Some(error) = info.error {
                         warn!("Pull error: {}", error);
                         return Err(anyhow::anyhow!("Failed to pull image: {}", error));
                     }
+
+                    if let Some(status) = info.status {
+                        debug!("Pull progress: {}", status);
+                        let detail = info.progress_detail.unwrap_or_default();
+                        progress.update(info.id, status, detail.current, detail.total);
+                        if let Some(percent) = progress.percent() {
+                            debug!("  Progress: {:.1}%", percent);
+                        }
+                        on_progress(&progress);
+                    }
                 }
                 Err(e) => {
                     error!("Failed to pull image {}: {}", image, e);
@@ -102,12 +113,21 @@ impl PodmanClient {
 
     /// Pull image if it doesn't exist locally
     pub async fn ensure_image(&self, image: &str) -> Result<()> {
+        self.ensure_image_with_progress(image, |_| {}).await
+    }
+
+    /// Pull image if it doesn't exist locally, reporting pull progress
+    pub async fn ensure_image_with_progress(
+        &self,
+        image: &str,
+        on_progress: impl FnMut(&PullProgress) + Send,
+    ) -> Result<()> {
         if self.image_exists(image).await? {
             info!("Image {} already exists locally", image);
             Ok(())
         } else {
             info!("Image {} not found locally, pulling...", image);
-            self.pull_image(image).await
+            self.pull_image_with_progress(image, on_progress).await
         }
     }
 
@@ -115,10 +135,7 @@ impl PodmanClient {
     pub async fn remove_image(&self, image: &str, force: bool) -> Result<()> {
         info!("Removing image: {} (force: {})", image, force);
 
-        let options = bollard::image::RemoveImageOptions {
-            force,
-            ..Default::default()
-        };
+        let options = RemoveImageOptionsBuilder::new().force(force).build();
 
         let results = self.docker
             .remove_image(image, Some(options), None)
@@ -137,6 +154,109 @@ impl PodmanClient {
         info!("Successfully removed image: {}", image);
         Ok(())
     }
+
+    /// Commit a container's filesystem as the image `image`
+    ///
+    /// The container is paused while it is committed. `labels` are added to
+    /// those the image inherits from the container.
+    pub async fn commit_container(
+        &self,
+        container_id: &str,
+        image: &str,
+        labels: HashMap<String, String>,
+    ) -> Result<()> {
+        info!("Committing container {} as {}", container_id, image);
+
+        let (repo, tag) = parse_image_tag(image);
+        let options = CommitContainerOptionsBuilder::new()
+            .container(container_id)
+            .repo(&repo)
+            .tag(&tag)
+            .pause(true)
+            .build();
+        let config = ContainerConfig {
+            labels: Some(labels),
+            ..Default::default()
+        };
+
+        self.docker
+            .commit_container(options, config)
+            .await
+            .with_context(|| format!("Failed to commit container {}", container_id))?;
+
+        info!("Committed container {} as {}", container_id, image);
+        Ok(())
+    }
+}
+
+/// Image a checkpoint of `env_id` named `label` is committed as
+///
+/// Image names must be lowercase, so the env_id is lowercased and anything
+/// else a repository name cannot contain is replaced with `-`.
+pub fn checkpoint_image(env_id: &str, label: &str) -> String {
+    let name: String = env_id
+        .chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            c @ ('a'..='z' | '0'..='9' | '.' | '_' | '-') => c,
+            _ => '-',
+        })
+        .collect();
+    format!("{}/{}:{}", CHECKPOINT_REPOSITORY, name.trim_matches(|c| c == '.' || c == '-'), label)
+}
+
+/// Check that `tag` can be used as an image tag
+///
+/// All-digit tags are refused as well since they read as a registry port.
+pub fn is_valid_tag(tag: &str) -> bool {
+    let mut chars = tag.chars();
+    let Some(first) = chars.next() else { return false };
+    tag.len() <= 128
+        && (first.is_ascii_alphanumeric() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+        && !tag.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Aggregated progress of an image pull across all layers
+#[derive(Debug, Clone, Default)]
+pub struct PullProgress {
+    /// Bytes transferred so far, summed over layers
+    pub current: u64,
+    /// Total bytes of the layers seen so far
+    pub total: u64,
+    /// Latest status line reported by Podman
+    pub status: String,
+    layers: HashMap<String, (u64, u64)>,
+}
+
+impl PullProgress {
+    /// Record a progress event for a layer
+    ///
+    /// Events without a layer id or byte counts only update the status.
+    fn update(&mut self, layer: Option<String>, status: String, current: Option<i64>, total: Option<i64>) {
+        self.status = status;
+
+        let Some(layer) = layer else { return };
+        if let (Some(current), Some(total)) = (current, total) {
+            if total > 0 {
+                let total = total as u64;
+                let current = (current.max(0) as u64).min(total);
+                self.layers.insert(layer, (current, total));
+            }
+        } else if self.status.starts_with("Download complete") || self.status.starts_with("Pull complete") {
+            // Mark a finished layer as fully transferred
+            if let Some((current, total)) = self.layers.get_mut(&layer) {
+                *current = *total;
+            }
+        }
+
+        self.current = self.layers.values().map(|(current, _)| current).sum();
+        self.total = self.layers.values().map(|(_, total)| total).sum();
+    }
+
+    /// Overall completion in percent, once any layer size is known
+    pub fn percent(&self) -> Option<f64> {
+        (self.total > 0).then(|| self.current as f64 / self.total as f64 * 100.0)
+    }
 }
 
 /// Parse image name and tag from image string
@@ -167,6 +287,39 @@ mod tests {
         assert_eq!(parse_image_tag("localhost:5000/myimage:v1"), ("localhost:5000/myimage".to_string(), "v1".to_string()));
     }
 
+    #[test]
+    fn test_checkpoint_image() {
+        assert_eq!(checkpoint_image("env1", "cp-1"), "localhost/cofer-checkpoint/env1:cp-1");
+        assert_eq!(checkpoint_image("My Env", "before"), "localhost/cofer-checkpoint/my-env:before");
+        assert_eq!(parse_image_tag(&checkpoint_image("env1", "v2")).1, "v2");
+
+        assert!(is_valid_tag("before-upgrade.2"));
+        assert!(!is_valid_tag(""));
+        assert!(!is_valid_tag("-x"));
+        assert!(!is_valid_tag("a/b"));
+        assert!(!is_valid_tag("42"));
+        assert!(!is_valid_tag(&"a".repeat(129)));
+    }
+
+    #[test]
+    fn test_pull_progress_aggregates_layers() {
+        let mut progress = PullProgress::default();
+        assert_eq!(progress.percent(), None);
+
+        progress.update(None, "Pulling fs layer".to_string(), None, None);
+        assert_eq!(progress.status, "Pulling fs layer");
+        assert_eq!(progress.percent(), None);
+
+        progress.update(Some("a".to_string()), "Downloading".to_string(), Some(50), Some(100));
+        progress.update(Some("b".to_string()), "Downloading".to_string(), Some(0), Some(300));
+        assert_eq!((progress.current, progress.total), (50, 400));
+
+        progress.update(Some("a".to_string()), "Download complete".to_string(), None, None);
+        progress.update(Some("b".to_string()), "Downloading".to_string(), Some(100), Some(300));
+        assert_eq!((progress.current, progress.total), (200, 400));
+        assert_eq!(progress.percent(), Some(50.0));
+    }
+
     #[tokio::test]
     #[ignore] // Requires Podman
     async fn test_list_images() {
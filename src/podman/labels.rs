@@ -0,0 +1,149 @@
+use anyhow::{Context, Result};
+use bollard::query_parameters::ListContainersOptionsBuilder;
+use bollard::models::ContainerSummary;
+use std::collections::HashMap;
+use tracing::debug;
+
+use super::client::PodmanClient;
+use crate::git::EnvironmentWorktree;
+
+/// Marks a container as created by cofer
+pub const LABEL_MANAGED: &str = "io.cofer.managed";
+/// Environment the container belongs to
+pub const LABEL_ENV_ID: &str = "io.cofer.env_id";
+/// Host path bind-mounted into the container
+pub const LABEL_PROJECT_ROOT: &str = "io.cofer.project_root";
+/// Where the project is mounted inside the container
+pub const LABEL_MOUNT_PATH: &str = "io.cofer.mount_path";
+/// Server instance that created the container
+pub const LABEL_INSTANCE: &str = "io.cofer.instance";
+/// The environment's git worktree as JSON, if it has one
+pub const LABEL_WORKTREE: &str = "io.cofer.worktree";
+/// Label of the checkpoint an image was committed for
+pub const LABEL_CHECKPOINT: &str = "io.cofer.checkpoint";
+
+/// Ownership labels set on every container cofer creates
+///
+/// They carry enough of the environment to register it again if the
+/// registry lost track of the container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerLabels {
+    pub env_id: String,
+    pub project_root: String,
+    pub mount_path: String,
+    pub instance_id: String,
+    pub worktree: Option<EnvironmentWorktree>,
+}
+
+impl ContainerLabels {
+    /// Convert into the label map passed to Podman
+    pub fn to_map(&self) -> HashMap<String, String> {
+        let mut labels = HashMap::from([
+            (LABEL_MANAGED.to_string(), "true".to_string()),
+            (LABEL_ENV_ID.to_string(), self.env_id.clone()),
+            (LABEL_PROJECT_ROOT.to_string(), self.project_root.clone()),
+            (LABEL_MOUNT_PATH.to_string(), self.mount_path.clone()),
+            (LABEL_INSTANCE.to_string(), self.instance_id.clone()),
+        ]);
+        if let Some(worktree) = self.worktree.as_ref().and_then(|w| serde_json::to_string(w).ok()) {
+            labels.insert(LABEL_WORKTREE.to_string(), worktree);
+        }
+        labels
+    }
+
+    /// Read the labels back from a container, `None` if it is not cofer's
+    ///
+    /// The env_id and project root are required; the mount path falls back
+    /// to `/workdir`. A worktree label that does not parse is ignored.
+    pub fn from_map(labels: &HashMap<String, String>) -> Option<Self> {
+        if labels.get(LABEL_MANAGED).map(String::as_str) != Some("true") {
+            return None;
+        }
+
+        Some(Self {
+            env_id: labels.get(LABEL_ENV_ID).filter(|v| !v.is_empty())?.clone(),
+            project_root: labels.get(LABEL_PROJECT_ROOT).filter(|v| !v.is_empty())?.clone(),
+            mount_path: labels.get(LABEL_MOUNT_PATH).cloned().unwrap_or_else(|| "/workdir".to_string()),
+            instance_id: labels.get(LABEL_INSTANCE).cloned().unwrap_or_default(),
+            worktree: labels.get(LABEL_WORKTREE).and_then(|worktree| serde_json::from_str(worktree).ok()),
+        })
+    }
+}
+
+/// Lookup of cofer-owned containers
+impl PodmanClient {
+    /// List all containers labelled as created by cofer, running or not
+    pub async fn list_managed_containers(&self) -> Result<Vec<ContainerSummary>> {
+        debug!("Listing cofer containers");
+
+        let filters = HashMap::from([("label", vec![format!("{}=true", LABEL_MANAGED)])]);
+        let options = ListContainersOptionsBuilder::new()
+            .all(true)
+            .filters(&filters)
+            .build();
+
+        self.docker
+            .list_containers(Some(options))
+            .await
+            .context("Failed to list cofer containers")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> ContainerLabels {
+        ContainerLabels {
+            env_id: "env-1".to_string(),
+            project_root: "/home/user/project".to_string(),
+            mount_path: "/src".to_string(),
+            instance_id: "instance-1".to_string(),
+            worktree: None,
+        }
+    }
+
+    #[test]
+    fn test_labels_round_trip() {
+        let mut labels = sample();
+        let map = labels.to_map();
+
+        assert_eq!(map[LABEL_MANAGED], "true");
+        assert_eq!(map[LABEL_ENV_ID], "env-1");
+        assert!(!map.contains_key(LABEL_WORKTREE));
+        assert_eq!(ContainerLabels::from_map(&map), Some(labels.clone()));
+
+        labels.worktree = Some(EnvironmentWorktree {
+            path: "/data/worktrees/env-1".into(),
+            branch: "cofer/env-1".to_string(),
+            repository: "/home/user/project/.git".into(),
+            base_commit: "0123456789abcdef".to_string(),
+            subdir: Default::default(),
+            created_branch: false,
+        });
+        assert_eq!(ContainerLabels::from_map(&labels.to_map()), Some(labels));
+    }
+
+    #[test]
+    fn test_from_map_rejects_foreign_containers() {
+        assert_eq!(ContainerLabels::from_map(&HashMap::new()), None);
+
+        let mut map = sample().to_map();
+        map.remove(LABEL_PROJECT_ROOT);
+        assert_eq!(ContainerLabels::from_map(&map), None);
+
+        let mut map = sample().to_map();
+        map.insert(LABEL_MANAGED.to_string(), "false".to_string());
+        assert_eq!(ContainerLabels::from_map(&map), None);
+
+        // Older labels without a mount path still parse
+        let mut map = sample().to_map();
+        map.remove(LABEL_MOUNT_PATH);
+        map.remove(LABEL_INSTANCE);
+        map.insert(LABEL_WORKTREE.to_string(), "not json".to_string());
+        let labels = ContainerLabels::from_map(&map).unwrap();
+        assert_eq!(labels.mount_path, "/workdir");
+        assert_eq!(labels.instance_id, "");
+        assert_eq!(labels.worktree, None);
+    }
+}
@@ -1,7 +1,14 @@
+pub mod capture;
 pub mod client;
 pub mod diagnostics;
 pub mod image;
 pub mod container;
+pub mod exec;
+pub mod labels;
+pub mod ports;
+pub mod shared;
+pub mod stats;
 
 pub use client::PodmanClient;
-pub use diagnostics::PodmanDiagnostics;
\ No newline at end of file
+pub use diagnostics::PodmanDiagnostics;
+pub use shared::SharedPodmanClient;
\ No newline at end of file
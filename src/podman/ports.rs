@@ -0,0 +1,378 @@
+use anyhow::{anyhow, bail, Context, Result};
+use bollard::models::{ContainerInspectResponse, PortBinding, PortMap};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, TcpListener, UdpSocket};
+use std::str::FromStr;
+
+/// Transport protocol of a container port
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(protocol: &str) -> Result<Self> {
+        match protocol.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            other => bail!("Unknown protocol '{}', expected 'tcp' or 'udp'", other),
+        }
+    }
+}
+
+/// A container port to publish, written as `[[host_ip:]host_port:]container_port[/protocol]`
+///
+/// This is the syntax of `podman run -p`. Without a host port, or with host
+/// port 0, Podman picks a free one. Serialized in the same form.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortSpec {
+    pub container_port: u16,
+    /// Host port to bind, `None` to have one assigned
+    pub host_port: Option<u16>,
+    /// Host address to bind, `None` for all addresses
+    pub host_ip: Option<IpAddr>,
+    pub protocol: Protocol,
+}
+
+impl PortSpec {
+    /// Key Podman uses for the port in exposed ports and port bindings, e.g. `3000/tcp`
+    pub fn key(&self) -> String {
+        format!("{}/{}", self.container_port, self.protocol.as_str())
+    }
+
+    /// The same port with the host port left for Podman to assign
+    pub fn auto_assigned(&self) -> Self {
+        Self { host_port: None, ..self.clone() }
+    }
+
+    /// Check whether the host port can still be bound
+    ///
+    /// Ports to be assigned by Podman always can. The check binds the port
+    /// briefly, so it can still be taken before the container starts.
+    pub fn host_port_available(&self) -> bool {
+        let Some(port) = self.host_port else {
+            return true;
+        };
+        let ip = self.host_ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        match self.protocol {
+            Protocol::Tcp => TcpListener::bind((ip, port)).is_ok(),
+            Protocol::Udp => UdpSocket::bind((ip, port)).is_ok(),
+        }
+    }
+}
+
+impl FromStr for PortSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid port '{}', expected [[host_ip:]host_port:]container_port[/protocol]", spec);
+
+        let (ports, protocol) = match spec.rsplit_once('/') {
+            Some((ports, protocol)) => (ports, protocol.parse()?),
+            None => (spec, Protocol::Tcp),
+        };
+
+        // An IPv6 host address is bracketed, anything else has at most two colons
+        let (host_ip, ports) = if let Some(bracketed) = ports.strip_prefix('[') {
+            let (ip, ports) = bracketed.split_once("]:").ok_or_else(invalid)?;
+            (Some(ip), ports)
+        } else if ports.matches(':').count() == 2 {
+            let (ip, ports) = ports.split_once(':').ok_or_else(invalid)?;
+            (Some(ip), ports)
+        } else {
+            (None, ports)
+        };
+        let (host_port, container_port) = match ports.split_once(':') {
+            Some((host_port, container_port)) => (Some(host_port), container_port),
+            None => (None, ports),
+        };
+
+        let container_port: u16 = container_port.parse().map_err(|_| invalid())?;
+        if container_port == 0 {
+            return Err(invalid());
+        }
+        let host_port = match host_port {
+            None | Some("") => None,
+            Some(port) => Some(port.parse::<u16>().map_err(|_| invalid())?).filter(|&port| port != 0),
+        };
+        let host_ip = match host_ip {
+            None | Some("") => None,
+            Some(ip) => Some(ip.parse().with_context(|| format!("Invalid host address in port '{}'", spec))?),
+        };
+
+        Ok(Self { container_port, host_port, host_ip, protocol })
+    }
+}
+
+impl fmt::Display for PortSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host_ip {
+            Some(IpAddr::V6(ip)) => write!(f, "[{}]:", ip)?,
+            Some(ip) => write!(f, "{}:", ip)?,
+            None => {}
+        }
+        match self.host_port {
+            Some(port) => write!(f, "{}:", port)?,
+            None if self.host_ip.is_some() => write!(f, ":")?,
+            None => {}
+        }
+        write!(f, "{}", self.container_port)?;
+        if self.protocol != Protocol::Tcp {
+            write!(f, "/{}", self.protocol.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for PortSpec {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: String) -> Result<Self> {
+        spec.parse()
+    }
+}
+
+impl From<PortSpec> for String {
+    fn from(spec: PortSpec) -> Self {
+        spec.to_string()
+    }
+}
+
+/// Ports a container is created with
+///
+/// Published ports are bound on the host; internal ones are only exposed,
+/// reachable from the container network but not from the host.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContainerPorts {
+    pub published: Vec<PortSpec>,
+    pub internal: Vec<PortSpec>,
+}
+
+impl ContainerPorts {
+    /// Publish every port whose host port is free, keeping the rest internal
+    pub fn publish_available(specs: &[PortSpec]) -> Self {
+        let (published, internal) = specs.iter().cloned().partition(PortSpec::host_port_available);
+        Self { published, internal }
+    }
+
+    /// Keep every port internal
+    pub fn internal_only(specs: &[PortSpec]) -> Self {
+        Self { published: Vec::new(), internal: specs.to_vec() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.published.is_empty() && self.internal.is_empty()
+    }
+
+    /// Ports to declare as exposed in the container config
+    pub fn exposed_ports(&self) -> HashMap<String, HashMap<(), ()>> {
+        self.published.iter()
+            .chain(&self.internal)
+            .map(|spec| (spec.key(), HashMap::new()))
+            .collect()
+    }
+
+    /// Host bindings of the published ports
+    pub fn port_bindings(&self) -> PortMap {
+        let mut bindings = PortMap::new();
+        for spec in &self.published {
+            bindings.entry(spec.key())
+                .or_insert_with(|| Some(Vec::new()))
+                .get_or_insert_with(Vec::new)
+                .push(PortBinding {
+                    host_ip: spec.host_ip.map(|ip| ip.to_string()),
+                    host_port: Some(spec.host_port.map(|port| port.to_string()).unwrap_or_default()),
+                });
+        }
+        bindings
+    }
+}
+
+/// Where a container port can be reached from the host
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortMapping {
+    pub container_port: u16,
+    pub protocol: Protocol,
+    /// Host address the port is bound to, `None` if it is internal only
+    pub host_ip: Option<String>,
+    /// Host port the port is bound to, `None` if it is internal only
+    pub host_port: Option<u16>,
+}
+
+/// Read the host mappings of the ports in `specs` from a container inspect
+///
+/// Every requested port gets at least one mapping; one Podman did not bind
+/// gets a mapping without host address and port.
+pub fn host_mappings(inspect: &ContainerInspectResponse, specs: &[PortSpec]) -> Vec<PortMapping> {
+    let ports = inspect.network_settings.as_ref().and_then(|network| network.ports.as_ref());
+
+    let mut seen = Vec::new();
+    let mut mappings = Vec::new();
+    for spec in specs {
+        let key = spec.key();
+        if seen.contains(&key) {
+            continue;
+        }
+        let bindings = ports
+            .and_then(|ports| ports.get(&key))
+            .and_then(|bindings| bindings.as_ref())
+            .map(|bindings| bindings.as_slice())
+            .unwrap_or_default();
+
+        let bound: Vec<PortMapping> = bindings.iter()
+            .filter_map(|binding| {
+                let host_port = binding.host_port.as_deref()?.parse().ok()?;
+                Some(PortMapping {
+                    container_port: spec.container_port,
+                    protocol: spec.protocol,
+                    host_ip: binding.host_ip.clone().filter(|ip| !ip.is_empty()),
+                    host_port: Some(host_port),
+                })
+            })
+            .collect();
+        if bound.is_empty() {
+            mappings.push(PortMapping {
+                container_port: spec.container_port,
+                protocol: spec.protocol,
+                host_ip: None,
+                host_port: None,
+            });
+        }
+        mappings.extend(bound);
+        seen.push(key);
+    }
+    mappings
+}
+
+/// Check whether a container failed to start because a host port was taken
+pub fn is_port_conflict(error: &anyhow::Error) -> bool {
+    let message = format!("{:#}", error).to_ascii_lowercase();
+    ["address already in use", "port is already allocated"]
+        .iter()
+        .any(|needle| message.contains(needle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bollard::models::NetworkSettings;
+
+    fn spec(spec: &str) -> PortSpec {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_port_spec() {
+        assert_eq!(spec("3000"), PortSpec { container_port: 3000, host_port: None, host_ip: None, protocol: Protocol::Tcp });
+        assert_eq!(spec("8080:3000").host_port, Some(8080));
+        assert_eq!(spec("0:3000").host_port, None);
+        assert_eq!(spec("5353/UDP").protocol, Protocol::Udp);
+
+        let bound = spec("127.0.0.1:8080:80/tcp");
+        assert_eq!(bound.host_ip, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert_eq!((bound.host_port, bound.container_port), (Some(8080), 80));
+        assert_eq!(spec("[::1]:8080:80").host_ip, Some("::1".parse().unwrap()));
+        assert_eq!(spec("127.0.0.1::80").host_port, None);
+
+        for invalid in ["", "http", "0", "70000", "80/sctp", "a:b:c:80", "bad.ip:1:2", "[::1]80"] {
+            assert!(invalid.parse::<PortSpec>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_port_spec_round_trip() {
+        for text in ["3000", "8080:3000", "5353/udp", "127.0.0.1:8080:80", "127.0.0.1::80", "[::1]:8080:80/udp"] {
+            assert_eq!(spec(text).to_string(), text);
+        }
+        assert_eq!(spec("8080:3000/tcp").to_string(), "8080:3000");
+
+        let json = serde_json::to_value(spec("8080:3000")).unwrap();
+        assert_eq!(json, "8080:3000");
+        assert_eq!(serde_json::from_value::<PortSpec>(json).unwrap(), spec("8080:3000"));
+        assert!(serde_json::from_value::<PortSpec>(serde_json::json!("nope")).is_err());
+    }
+
+    #[test]
+    fn test_port_bindings() {
+        let ports = ContainerPorts {
+            published: vec![spec("8080:3000"), spec("127.0.0.1:8081:3000"), spec("5353/udp")],
+            internal: vec![spec("9229")],
+        };
+
+        let exposed = ports.exposed_ports();
+        let mut keys: Vec<&String> = exposed.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["3000/tcp", "5353/udp", "9229/tcp"]);
+
+        let bindings = ports.port_bindings();
+        assert_eq!(bindings.len(), 2);
+        let tcp = bindings["3000/tcp"].as_ref().unwrap();
+        assert_eq!(tcp.len(), 2);
+        assert_eq!(tcp[1].host_ip.as_deref(), Some("127.0.0.1"));
+        assert_eq!(bindings["5353/udp"].as_ref().unwrap()[0].host_port.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_publish_available() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken = listener.local_addr().unwrap().port();
+
+        let specs = vec![spec("3000"), spec(&format!("127.0.0.1:{}:3001", taken))];
+        let ports = ContainerPorts::publish_available(&specs);
+        assert_eq!(ports.published, vec![spec("3000")]);
+        assert_eq!(ports.internal, vec![specs[1].clone()]);
+
+        assert!(ContainerPorts::internal_only(&specs).published.is_empty());
+        assert!(ContainerPorts::default().is_empty());
+    }
+
+    #[test]
+    fn test_host_mappings() {
+        let mut ports = PortMap::new();
+        ports.insert("3000/tcp".to_string(), Some(vec![PortBinding {
+            host_ip: Some("0.0.0.0".to_string()),
+            host_port: Some("41234".to_string()),
+        }]));
+        ports.insert("9229/tcp".to_string(), None);
+        let inspect = ContainerInspectResponse {
+            network_settings: Some(NetworkSettings { ports: Some(ports), ..Default::default() }),
+            ..Default::default()
+        };
+
+        let mappings = host_mappings(&inspect, &[spec("3000"), spec("9229"), spec("8080:3000")]);
+        assert_eq!(mappings, vec![
+            PortMapping {
+                container_port: 3000,
+                protocol: Protocol::Tcp,
+                host_ip: Some("0.0.0.0".to_string()),
+                host_port: Some(41234),
+            },
+            PortMapping { container_port: 9229, protocol: Protocol::Tcp, host_ip: None, host_port: None },
+        ]);
+    }
+
+    #[test]
+    fn test_is_port_conflict() {
+        let error = anyhow!("rootlessport listen tcp 0.0.0.0:8080: bind: address already in use")
+            .context("Failed to start container");
+        assert!(is_port_conflict(&error));
+        assert!(!is_port_conflict(&anyhow!("no such image")));
+    }
+}
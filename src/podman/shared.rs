@@ -0,0 +1,177 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use super::client::PodmanClient;
+
+/// How long a verified connection is trusted before it is pinged again
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Function establishing a new Podman connection
+type Connector = Arc<dyn Fn() -> BoxFuture<'static, Result<PodmanClient>> + Send + Sync>;
+
+/// A connected client and when it was last known to work
+struct Connection {
+    client: PodmanClient,
+    verified_at: Option<Instant>,
+}
+
+/// Lazily connected Podman client shared by all requests
+///
+/// The first [`SharedPodmanClient::get`] runs the Podman diagnostics and
+/// connects; later calls reuse that connection. A connection that has not
+/// been verified recently is pinged first and replaced if the socket has
+/// gone away.
+#[derive(Clone)]
+pub struct SharedPodmanClient {
+    connection: Arc<Mutex<Option<Connection>>>,
+    connector: Connector,
+}
+
+impl Default for SharedPodmanClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SharedPodmanClient {
+    /// Create a shared client connecting with [`PodmanClient::new`]
+    pub fn new() -> Self {
+        Self::with_connector(Arc::new(|| Box::pin(PodmanClient::new())))
+    }
+
+    fn with_connector(connector: Connector) -> Self {
+        Self {
+            connection: Arc::new(Mutex::new(None)),
+            connector,
+        }
+    }
+
+    /// Get a working client, connecting or reconnecting as needed
+    pub async fn get(&self) -> Result<PodmanClient> {
+        // Holding the lock while connecting makes concurrent callers share
+        // one connection attempt
+        let mut connection = self.connection.lock().await;
+
+        if let Some(current) = connection.as_mut() {
+            let fresh = current.verified_at
+                .is_some_and(|verified_at| verified_at.elapsed() < HEALTH_CHECK_INTERVAL);
+            if fresh {
+                return Ok(current.client.clone());
+            }
+
+            match current.client.ping().await {
+                Ok(()) => {
+                    debug!("Podman connection still healthy");
+                    current.verified_at = Some(Instant::now());
+                    return Ok(current.client.clone());
+                }
+                Err(e) => {
+                    warn!("Lost connection to Podman, reconnecting: {}", e);
+                    *connection = None;
+                }
+            }
+        }
+
+        let client = (self.connector)().await?;
+        info!("Shared Podman client connected");
+        *connection = Some(Connection {
+            client: client.clone(),
+            verified_at: Some(Instant::now()),
+        });
+
+        Ok(client)
+    }
+
+    /// Have the next [`SharedPodmanClient::get`] check the connection first
+    ///
+    /// Call this after an operation failed in a way that may mean the
+    /// socket dropped.
+    pub async fn mark_unverified(&self) {
+        if let Some(current) = self.connection.lock().await.as_mut() {
+            current.verified_at = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::podman::diagnostics::PodmanStatus;
+    use bollard::Docker;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A client whose "socket" is a plain file, so every ping fails
+    fn unreachable_client() -> PodmanClient {
+        let path = std::env::temp_dir().join("cofer-unreachable-podman.sock");
+        std::fs::write(&path, b"").unwrap();
+        let docker = Docker::connect_with_socket(path.to_str().unwrap(), 1, bollard::API_DEFAULT_VERSION)
+            .expect("connecting is lazy");
+        PodmanClient {
+            docker,
+            status: PodmanStatus {
+                available: true,
+                version: None,
+                service_running: true,
+                socket_path: None,
+            },
+        }
+    }
+
+    fn counting_shared_client() -> (SharedPodmanClient, Arc<AtomicUsize>) {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = connects.clone();
+        let shared = SharedPodmanClient::with_connector(Arc::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok(unreachable_client()) })
+        }));
+        (shared, connects)
+    }
+
+    #[tokio::test]
+    async fn test_connects_lazily_once() {
+        let (shared, connects) = counting_shared_client();
+        assert_eq!(connects.load(Ordering::SeqCst), 0);
+
+        shared.get().await.unwrap();
+        shared.get().await.unwrap();
+        let (a, b) = tokio::join!(shared.get(), shared.get());
+        a.unwrap();
+        b.unwrap();
+
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_when_ping_fails() {
+        let (shared, connects) = counting_shared_client();
+        shared.get().await.unwrap();
+
+        // The next get pings the dead socket and reconnects
+        shared.mark_unverified().await;
+        shared.get().await.unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_connect_failure_is_retried() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let shared = SharedPodmanClient::with_connector(Arc::new(move || {
+            let attempt = counter.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                if attempt == 0 {
+                    anyhow::bail!("Podman is not running");
+                }
+                Ok(unreachable_client())
+            })
+        }));
+
+        assert!(shared.get().await.is_err());
+        assert!(shared.get().await.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::{Context, Result};
+use bollard::query_parameters::StatsOptionsBuilder;
+use bollard::models::ContainerStatsResponse;
+use futures::StreamExt;
+use serde::Serialize;
+use tracing::debug;
+
+use super::client::PodmanClient;
+
+/// Resource statistics for Podman containers
+impl PodmanClient {
+    /// Sample a container's current resource usage
+    ///
+    /// Takes two samples about a second apart so the CPU share can be
+    /// computed.
+    pub async fn container_stats(&self, container_id: &str) -> Result<ResourceUsage> {
+        debug!("Getting resource usage for container: {}", container_id);
+
+        let options = StatsOptionsBuilder::new()
+            .stream(false)
+            .one_shot(false)
+            .build();
+
+        let stats = self
+            .docker
+            .stats(container_id, Some(options))
+            .next()
+            .await
+            .context("Podman returned no stats")?
+            .context("Failed to get container stats")?;
+
+        Ok(ResourceUsage::from_stats(&stats))
+    }
+}
+
+/// Resource usage of a container at one point in time
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ResourceUsage {
+    /// CPU use since the previous sample, where 100 is one full core
+    pub cpu_percent: Option<f64>,
+    pub memory_usage_bytes: Option<u64>,
+    pub memory_limit_bytes: Option<u64>,
+    /// Number of processes in the container
+    pub pids: Option<u64>,
+}
+
+impl ResourceUsage {
+    /// Extract the interesting figures from a stats sample
+    pub fn from_stats(stats: &ContainerStatsResponse) -> Self {
+        let memory = stats.memory_stats.as_ref();
+
+        Self {
+            cpu_percent: cpu_percent(stats),
+            memory_usage_bytes: memory.and_then(|memory| memory.usage),
+            memory_limit_bytes: memory.and_then(|memory| memory.limit),
+            pids: stats.pids_stats.as_ref().and_then(|pids| pids.current),
+        }
+    }
+}
+
+/// CPU share between the previous and current sample, the way `docker stats` computes it
+fn cpu_percent(stats: &ContainerStatsResponse) -> Option<f64> {
+    let cpu = stats.cpu_stats.as_ref()?;
+    let precpu = stats.precpu_stats.as_ref()?;
+
+    let total = cpu.cpu_usage.as_ref()?.total_usage?;
+    let previous_total = precpu.cpu_usage.as_ref()?.total_usage?;
+    let system = cpu.system_cpu_usage?;
+    let previous_system = precpu.system_cpu_usage?;
+
+    let cpu_delta = total.checked_sub(previous_total)? as f64;
+    let system_delta = system.checked_sub(previous_system)? as f64;
+    if system_delta <= 0.0 {
+        return None;
+    }
+
+    let cpus = cpu.online_cpus.filter(|cpus| *cpus > 0).unwrap_or(1) as f64;
+    Some(cpu_delta / system_delta * cpus * 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bollard::models::{ContainerCpuStats, ContainerCpuUsage, ContainerMemoryStats, ContainerPidsStats};
+
+    fn cpu_stats(total_usage: u64, system_cpu_usage: u64) -> ContainerCpuStats {
+        ContainerCpuStats {
+            cpu_usage: Some(ContainerCpuUsage {
+                total_usage: Some(total_usage),
+                ..Default::default()
+            }),
+            system_cpu_usage: Some(system_cpu_usage),
+            online_cpus: Some(4),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resource_usage_from_stats() {
+        let stats = ContainerStatsResponse {
+            cpu_stats: Some(cpu_stats(3_000, 20_000)),
+            precpu_stats: Some(cpu_stats(1_000, 10_000)),
+            memory_stats: Some(ContainerMemoryStats {
+                usage: Some(1024),
+                limit: Some(4096),
+                ..Default::default()
+            }),
+            pids_stats: Some(ContainerPidsStats {
+                current: Some(3),
+                limit: None,
+            }),
+            ..Default::default()
+        };
+
+        let usage = ResourceUsage::from_stats(&stats);
+        assert_eq!(usage.cpu_percent, Some(80.0));
+        assert_eq!(usage.memory_usage_bytes, Some(1024));
+        assert_eq!(usage.memory_limit_bytes, Some(4096));
+        assert_eq!(usage.pids, Some(3));
+    }
+
+    #[test]
+    fn test_cpu_percent_needs_two_samples() {
+        let stats = ContainerStatsResponse {
+            cpu_stats: Some(cpu_stats(3_000, 20_000)),
+            ..Default::default()
+        };
+        assert_eq!(ResourceUsage::from_stats(&stats), ResourceUsage::default());
+
+        // A one-shot sample repeats the same counters
+        let stats = ContainerStatsResponse {
+            cpu_stats: Some(cpu_stats(3_000, 20_000)),
+            precpu_stats: Some(cpu_stats(3_000, 20_000)),
+            ..Default::default()
+        };
+        assert_eq!(ResourceUsage::from_stats(&stats).cpu_percent, None);
+    }
+}
@@ -0,0 +1,337 @@
+use anyhow::Result;
+use cofer::mcp::server::McpServer;
+use cofer::mcp::types::McpResponse;
+use cofer::podman::PodmanClient;
+use serde_json::{json, Value};
+
+/// Helper to make a request and parse response
+async fn make_request(server: &McpServer, request: Value) -> McpResponse {
+    let request_str = request.to_string();
+    server.handle_request(&request_str).await
+}
+
+/// Call a lifecycle method on an environment
+async fn lifecycle(server: &McpServer, method: &str, params: Value) -> Value {
+    let response = make_request(server, json!({
+        "jsonrpc": "2.0",
+        "id": 2,
+        "method": method,
+        "params": params
+    })).await;
+
+    match response.result {
+        Some(result) => result,
+        None => panic!("{} failed: {:?}", method, response.error),
+    }
+}
+
+/// Run a command, returning its trimmed stdout
+async fn run(server: &McpServer, env_id: &str, command: &str) -> Option<String> {
+    let response = make_request(server, json!({
+        "jsonrpc": "2.0",
+        "id": 3,
+        "method": "run_command",
+        "params": { "env_id": env_id, "command": command }
+    })).await;
+
+    response.result.map(|result| result["stdout"].as_str().unwrap().trim().to_string())
+}
+
+/// Test stopping and restarting an environment, then recreating it after removal
+#[tokio::test]
+async fn test_down_and_up() -> Result<()> {
+    let server = McpServer::new();
+    let temp_dir = tempfile::tempdir()?;
+    let env_id = format!("test-lifecycle-{}", uuid::Uuid::new_v4().simple());
+
+    let response = make_request(&server, json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "create_environment",
+        "params": {
+            "env_id": env_id,
+            "project_root": temp_dir.path().to_str().unwrap(),
+            "image": "docker.io/library/alpine:latest",
+            "env_vars": { "GREETING": "hello" }
+        }
+    })).await;
+
+    // Skip if Podman is not available or container creation fails
+    let Some(container_id) = response.result
+        .and_then(|result| result["container_id"].as_str().map(str::to_string))
+    else {
+        return Ok(());
+    };
+
+    let down = lifecycle(&server, "down", json!({ "env_id": env_id })).await;
+    assert_eq!(down["status"], "stopped");
+    assert_eq!(down["removed"], false);
+    assert!(run(&server, &env_id, "true").await.is_none(), "stopped environments refuse commands");
+
+    // Restarting keeps the container
+    let up = lifecycle(&server, "up", json!({ "env_id": env_id })).await;
+    assert_eq!(up["status"], "running");
+    assert_eq!(up["recreated"], false);
+    assert_eq!(up["container_id"], container_id);
+    assert_eq!(run(&server, &env_id, "echo $GREETING").await.as_deref(), Some("hello"));
+
+    // A removed container is recreated from the stored parameters
+    let down = lifecycle(&server, "down", json!({ "env_id": env_id, "remove": true })).await;
+    assert_eq!(down["removed"], true);
+    let client = PodmanClient::new().await?;
+    assert!(!client.container_exists(&container_id).await?);
+
+    let up = lifecycle(&server, "up", json!({ "env_id": env_id })).await;
+    assert_eq!(up["recreated"], true);
+    let new_container_id = up["container_id"].as_str().unwrap().to_string();
+    assert_ne!(new_container_id, container_id);
+    assert_eq!(run(&server, &env_id, "echo $GREETING").await.as_deref(), Some("hello"));
+
+    let _ = client.remove_container(&new_container_id, true).await;
+    Ok(())
+}
+
+/// Test bringing an environment up after its published host port was taken
+#[tokio::test]
+async fn test_up_with_taken_port() -> Result<()> {
+    let server = McpServer::new();
+    let temp_dir = tempfile::tempdir()?;
+    let env_id = format!("test-lifecycle-{}", uuid::Uuid::new_v4().simple());
+    let port = std::net::TcpListener::bind("0.0.0.0:0")?.local_addr()?.port();
+
+    let response = make_request(&server, json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "create_environment",
+        "params": {
+            "env_id": env_id,
+            "project_root": temp_dir.path().to_str().unwrap(),
+            "image": "docker.io/library/alpine:latest",
+            "ports": [format!("{}:8080", port)]
+        }
+    })).await;
+
+    // Skip if Podman is not available or container creation fails
+    let Some(container_id) = response.result
+        .and_then(|result| result["container_id"].as_str().map(str::to_string))
+    else {
+        return Ok(());
+    };
+
+    lifecycle(&server, "down", json!({ "env_id": env_id })).await;
+    let _listener = std::net::TcpListener::bind(("0.0.0.0", port))?;
+
+    // The stopped container cannot start, so it is replaced
+    let up = lifecycle(&server, "up", json!({ "env_id": env_id })).await;
+    assert_eq!(up["status"], "running");
+    assert_eq!(up["recreated"], true);
+    let new_container_id = up["container_id"].as_str().unwrap().to_string();
+    assert_ne!(new_container_id, container_id);
+    assert_eq!(run(&server, &env_id, "echo up").await.as_deref(), Some("up"));
+
+    let client = PodmanClient::new().await?;
+    assert!(!client.container_exists(&container_id).await?);
+    let _ = client.remove_container(&new_container_id, true).await;
+    Ok(())
+}
+
+/// Test listing and inspecting an environment after running a command
+#[tokio::test]
+async fn test_list_and_inspect() -> Result<()> {
+    let server = McpServer::new();
+
+    let empty = lifecycle(&server, "list_environments", json!({})).await;
+    assert_eq!(empty["count"], 0);
+
+    let temp_dir = tempfile::tempdir()?;
+    let env_id = format!("test-inspect-{}", uuid::Uuid::new_v4().simple());
+    let response = make_request(&server, json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "create_environment",
+        "params": {
+            "env_id": env_id,
+            "project_root": temp_dir.path().to_str().unwrap(),
+            "image": "docker.io/library/alpine:latest"
+        }
+    })).await;
+
+    // Skip if Podman is not available or container creation fails
+    let Some(container_id) = response.result
+        .and_then(|result| result["container_id"].as_str().map(str::to_string))
+    else {
+        return Ok(());
+    };
+
+    run(&server, &env_id, "echo hi").await;
+    let list = lifecycle(&server, "list_environments", json!({})).await;
+    let inspect = lifecycle(&server, "inspect_environment", json!({ "env_id": env_id })).await;
+    if let Ok(client) = PodmanClient::new().await {
+        let _ = client.remove_container(&container_id, true).await;
+    }
+
+    assert_eq!(list["count"], 1);
+    assert_eq!(list["environments"][0]["container_state"], "running");
+    assert!(list["environments"][0]["uptime_secs"].is_i64());
+
+    assert_eq!(inspect["container_id"], container_id);
+    assert_eq!(inspect["mounts"][0]["destination"], "/workdir");
+    assert!(inspect["resources"]["memory_usage_bytes"].is_u64());
+    assert_eq!(inspect["recent_commands"][0]["command"], "echo hi");
+
+    Ok(())
+}
+
+/// Test destroying an environment removes its container and registry entry
+#[tokio::test]
+async fn test_destroy_environment() -> Result<()> {
+    let server = McpServer::new();
+    let temp_dir = tempfile::tempdir()?;
+    let env_id = format!("test-destroy-{}", uuid::Uuid::new_v4().simple());
+    let response = make_request(&server, json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "create_environment",
+        "params": {
+            "env_id": env_id,
+            "project_root": temp_dir.path().to_str().unwrap(),
+            "image": "docker.io/library/alpine:latest"
+        }
+    })).await;
+
+    // Skip if Podman is not available or container creation fails
+    let Some(container_id) = response.result
+        .and_then(|result| result["container_id"].as_str().map(str::to_string))
+    else {
+        return Ok(());
+    };
+
+    let destroyed = lifecycle(&server, "destroy_environment", json!({ "env_id": env_id })).await;
+    assert_eq!(destroyed["destroyed"], true, "{}", destroyed);
+    assert_eq!(destroyed["steps"][0]["status"], "done");
+
+    let client = PodmanClient::new().await?;
+    assert!(!client.container_exists(&container_id).await?);
+
+    let list = lifecycle(&server, "list_environments", json!({})).await;
+    assert_eq!(list["count"], 0);
+
+    Ok(())
+}
+
+/// Test a second server adopting, then collecting, a container it did not create
+#[tokio::test]
+async fn test_adopt_and_gc_orphans() -> Result<()> {
+    let server = McpServer::new();
+    let temp_dir = tempfile::tempdir()?;
+    let env_id = format!("test-orphan-{}", uuid::Uuid::new_v4().simple());
+    let response = make_request(&server, json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "create_environment",
+        "params": {
+            "env_id": env_id,
+            "project_root": temp_dir.path().to_str().unwrap(),
+            "image": "docker.io/library/alpine:latest"
+        }
+    })).await;
+
+    // Skip if Podman is not available or container creation fails
+    let Some(container_id) = response.result
+        .and_then(|result| result["container_id"].as_str().map(str::to_string))
+    else {
+        return Ok(());
+    };
+
+    // The first server tracks its container, so it is not an orphan there
+    let own = lifecycle(&server, "adopt_orphans", json!({ "env_id": env_id, "dry_run": true })).await;
+    let is_listed = |report: &Value| {
+        report["orphans"].as_array().unwrap().iter().any(|orphan| orphan["container_id"] == container_id)
+    };
+    assert!(!is_listed(&own), "{}", own);
+
+    // A server with an empty registry sees it as orphaned
+    let other = McpServer::new();
+    let dry_run = lifecycle(&other, "gc", json!({ "env_id": env_id, "dry_run": true })).await;
+    assert!(is_listed(&dry_run), "{}", dry_run);
+    let client = PodmanClient::new().await?;
+    assert!(client.container_exists(&container_id).await?);
+
+    // A bare call is a dry run and leaves other instances' containers alone
+    let bare = lifecycle(&other, "gc", json!({})).await;
+    assert_eq!(bare["dry_run"], true);
+    let report = bare["orphans"].as_array().unwrap().iter()
+        .find(|orphan| orphan["container_id"] == container_id)
+        .cloned()
+        .unwrap_or_default();
+    assert_eq!(report["status"], "skipped", "{}", bare);
+    assert!(client.container_exists(&container_id).await?);
+
+    let adopted = lifecycle(&other, "adopt_orphans", json!({ "env_id": env_id })).await;
+    assert!(is_listed(&adopted), "{}", adopted);
+    assert_eq!(run(&other, &env_id, "echo adopted").await.as_deref(), Some("adopted"));
+
+    // Servers not tracking it can still collect it
+    let third = McpServer::new();
+    let collected = lifecycle(&third, "gc", json!({ "env_id": env_id })).await;
+    assert!(is_listed(&collected), "{}", collected);
+    assert!(!client.container_exists(&container_id).await?);
+
+    Ok(())
+}
+
+/// Create a repository with a single commit
+fn init_repo(dir: &std::path::Path) -> Result<()> {
+    let repo = git2::Repository::init(dir)?;
+    std::fs::write(dir.join("README.md"), "hello\n")?;
+    let mut index = repo.index()?;
+    index.add_path(std::path::Path::new("README.md"))?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let signature = git2::Signature::now("Test", "test@example.com")?;
+    repo.commit(Some("HEAD"), &signature, &signature, "Initial commit", &tree, &[])?;
+    Ok(())
+}
+
+/// Test edits in a git project landing in the environment's worktree, not the checkout
+#[tokio::test]
+async fn test_environment_worktree() -> Result<()> {
+    let project = tempfile::tempdir()?;
+    let worktrees = tempfile::tempdir()?;
+    init_repo(project.path())?;
+    let server = McpServer::new().with_worktrees_dir(worktrees.path());
+    let env_id = format!("test-worktree-{}", uuid::Uuid::new_v4().simple());
+
+    let response = make_request(&server, json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "create_environment",
+        "params": {
+            "env_id": env_id,
+            "project_root": project.path().to_str().unwrap(),
+            "image": "docker.io/library/alpine:latest"
+        }
+    })).await;
+
+    // Skip if Podman is not available or container creation fails
+    let Some(created) = response.result else {
+        return Ok(());
+    };
+    let worktree = worktrees.path().join(&env_id);
+    assert_eq!(created["worktree"], json!(worktree));
+    assert_eq!(created["branch"], format!("cofer/{}", env_id));
+
+    assert_eq!(run(&server, &env_id, "cat README.md").await.as_deref(), Some("hello"));
+
+    // The gitfile's absolute host path resolves inside the container too
+    let head = run(&server, &env_id, "cat \"$(sed -n 's/^gitdir: //p' .git)/HEAD\"").await;
+    assert_eq!(head, Some(format!("ref: refs/heads/cofer/{}", env_id)));
+    run(&server, &env_id, "echo agent > agent.txt").await.expect("write succeeds");
+    assert!(worktree.join("agent.txt").exists());
+    assert!(!project.path().join("agent.txt").exists());
+
+    let destroyed = lifecycle(&server, "destroy_environment", json!({ "env_id": env_id })).await;
+    assert_eq!(destroyed["steps"][1]["status"], "done", "{}", destroyed);
+    assert!(!worktree.exists());
+
+    Ok(())
+}
@@ -10,7 +10,7 @@ fn format_message(json: &Value) -> String {
 }
 
 /// Parse a message with Content-Length header
-fn parse_message(reader: &mut impl BufRead) -> Result<Value> {
+fn parse_message(reader: &mut dyn BufRead) -> Result<Value> {
     let mut header_line = String::new();
 
     // Read Content-Length header
@@ -180,4 +180,181 @@ fn test_mcp_server_method_not_found_with_headers() {
     assert_eq!(response["error"]["code"], -32601);
 
     child.kill().expect("Failed to kill process");
-}
\ No newline at end of file
+}
+
+/// Parse a newline-delimited JSON message
+fn parse_ndjson_message(reader: &mut dyn BufRead) -> Result<Value> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        anyhow::bail!("EOF while reading message");
+    }
+    Ok(serde_json::from_str(line.trim_end())?)
+}
+
+/// Read `count` responses, which may arrive in any order
+fn read_responses(
+    reader: &mut impl BufRead,
+    count: usize,
+    parse: fn(&mut dyn BufRead) -> Result<Value>,
+) -> Vec<Value> {
+    (0..count).map(|_| parse(reader).expect("Failed to parse response")).collect()
+}
+
+/// Find the response with the given id
+fn response_with_id(responses: &[Value], id: Value) -> &Value {
+    responses.iter().find(|r| r["id"] == id).expect("Missing response")
+}
+
+/// Start the server with extra command line arguments
+fn spawn_server(args: &[&str]) -> std::process::Child {
+    Command::new("cargo")
+        .args(["run", "--"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start server")
+}
+
+#[test]
+fn test_mcp_server_with_newline_delimited_json() {
+    let mut child = spawn_server(&[]);
+
+    let mut stdin = child.stdin.take().expect("Failed to get stdin");
+    let stdout = child.stdout.take().expect("Failed to get stdout");
+    let mut reader = BufReader::new(stdout);
+
+    // Send newline-delimited requests as the MCP stdio transport does
+    let initialize = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "initialize",
+        "params": {"protocolVersion": "2025-06-18", "capabilities": {}}
+    });
+    let list = json!({"jsonrpc": "2.0", "id": 2, "method": "tools/list"});
+    writeln!(stdin, "{}", initialize).expect("Failed to write");
+    writeln!(stdin, "{}", list).expect("Failed to write");
+    stdin.flush().expect("Failed to flush");
+
+    // Responses come back in the same framing
+    let responses = read_responses(&mut reader, 2, parse_ndjson_message);
+    let initialize = response_with_id(&responses, json!(1));
+    assert_eq!(initialize["result"]["protocolVersion"], "2025-06-18");
+    let list = response_with_id(&responses, json!(2));
+    assert!(list["result"]["tools"].is_array());
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+}
+
+#[test]
+fn test_mcp_server_recovers_from_garbled_ndjson() {
+    let mut child = spawn_server(&[]);
+
+    let mut stdin = child.stdin.take().expect("Failed to get stdin");
+    let stdout = child.stdout.take().expect("Failed to get stdout");
+    let mut reader = BufReader::new(stdout);
+
+    // First message fixes the framing, then garbage, then a valid request
+    let ping = json!({"jsonrpc": "2.0", "id": 1, "method": "ping"});
+    writeln!(stdin, "{}", ping).expect("Failed to write");
+    writeln!(stdin, "this is not json").expect("Failed to write");
+    writeln!(stdin, "{{\"jsonrpc\": \"2.0\", \"id\": 2,").expect("Failed to write");
+    let ping = json!({"jsonrpc": "2.0", "id": 3, "method": "ping"});
+    writeln!(stdin, "{}", ping).expect("Failed to write");
+    stdin.flush().expect("Failed to flush");
+
+    let responses = read_responses(&mut reader, 4, parse_ndjson_message);
+    assert!(response_with_id(&responses, json!(1)).get("result").is_some());
+    assert!(response_with_id(&responses, json!(3)).get("result").is_some());
+
+    // Each garbled line gets an error response without an id
+    let errors: Vec<&Value> = responses.iter().filter(|r| r["id"].is_null()).collect();
+    assert_eq!(errors.len(), 2);
+    assert!(errors.iter().all(|r| r.get("error").is_some()));
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+}
+
+#[test]
+fn test_mcp_server_recovers_from_garbled_headers() {
+    let mut child = spawn_server(&[]);
+
+    let mut stdin = child.stdin.take().expect("Failed to get stdin");
+    let stdout = child.stdout.take().expect("Failed to get stdout");
+    let mut reader = BufReader::new(stdout);
+
+    // Establish header framing, then send a broken header and a valid message
+    let ping = json!({"jsonrpc": "2.0", "id": 1, "method": "ping"});
+    stdin.write_all(format_message(&ping).as_bytes()).expect("Failed to write");
+    stdin.write_all(b"Content-Length: nope\r\n\r\n").expect("Failed to write");
+    let ping = json!({"jsonrpc": "2.0", "id": 2, "method": "ping"});
+    stdin.write_all(format_message(&ping).as_bytes()).expect("Failed to write");
+    stdin.flush().expect("Failed to flush");
+
+    let responses = read_responses(&mut reader, 2, parse_message);
+    assert!(response_with_id(&responses, json!(1)).get("result").is_some());
+    assert!(response_with_id(&responses, json!(2)).get("result").is_some());
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+}
+
+#[test]
+fn test_mcp_server_forced_framing_flag() {
+    let mut child = spawn_server(&["--framing", "ndjson"]);
+
+    let mut stdin = child.stdin.take().expect("Failed to get stdin");
+    let stdout = child.stdout.take().expect("Failed to get stdout");
+    let mut reader = BufReader::new(stdout);
+
+    // A header-framed request still gets a newline-delimited reply
+    let ping = json!({"jsonrpc": "2.0", "id": 1, "method": "ping"});
+    stdin.write_all(format_message(&ping).as_bytes()).expect("Failed to write");
+    stdin.flush().expect("Failed to flush");
+
+    let response = parse_ndjson_message(&mut reader).expect("Failed to parse response");
+    assert_eq!(response["id"], 1);
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+}
+
+#[test]
+fn test_mcp_server_parse_error_and_batch() {
+    let mut child = spawn_server(&[]);
+
+    let mut stdin = child.stdin.take().expect("Failed to get stdin");
+    let stdout = child.stdout.take().expect("Failed to get stdout");
+    let mut reader = BufReader::new(stdout);
+
+    // Malformed JSON gets a parse error
+    let garbage = "{ invalid json }";
+    write!(stdin, "Content-Length: {}\r\n\r\n{}", garbage.len(), garbage).expect("Failed to write");
+    stdin.flush().expect("Failed to flush");
+
+    let response = parse_message(&mut reader).expect("Failed to parse response");
+    assert_eq!(response["error"]["code"], -32700);
+    assert!(response["id"].is_null());
+
+    // A notification followed by a batch: only the batch is answered
+    let notification = json!({"jsonrpc": "2.0", "method": "notifications/initialized"});
+    stdin.write_all(format_message(&notification).as_bytes()).expect("Failed to write");
+    let batch = json!([
+        {"jsonrpc": "2.0", "id": 1, "method": "ping"},
+        {"jsonrpc": "2.0", "id": 2, "method": "tools/list"}
+    ]);
+    stdin.write_all(format_message(&batch).as_bytes()).expect("Failed to write");
+    stdin.flush().expect("Failed to flush");
+
+    let response = parse_message(&mut reader).expect("Failed to parse response");
+    let responses = response.as_array().expect("Batch response should be an array");
+    assert_eq!(responses.len(), 2);
+    assert!(response_with_id(responses, json!(1)).get("result").is_some());
+    assert!(response_with_id(responses, json!(2))["result"]["tools"].is_array());
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+}
@@ -154,23 +154,19 @@ fn test_missing_method_returns_error() {
 }
 
 #[test]
-fn test_unimplemented_method_returns_unimplemented_error() {
-    // Requirement 1.4: Unsupported methods should return Unimplemented error
+fn test_note_append_method_exists() {
+    // note-append used to be unimplemented; it now validates its parameters
     let request = json!({
         "jsonrpc": "2.0",
         "id": 3,
-        "method": "watch-commit",
+        "method": "note-append",
         "params": {}
     });
 
     let response = send_jsonrpc_request(request).unwrap();
 
-    // Should return error
-    assert!(response.get("error").is_some());
-
     let error = &response["error"];
-    assert_eq!(error["code"], -32601); // MethodNotFound or custom Unimplemented
-    assert!(error["message"].as_str().unwrap().to_lowercase().contains("not implemented"));
+    assert_eq!(error["code"], -32602); // InvalidParams, not MethodNotFound
 }
 
 #[test]
@@ -222,4 +218,28 @@ fn test_run_command_method_exists() {
     if let Some(error) = response.get("error") {
         assert_ne!(error["code"], -32601);
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_tools_list_advertises_tools_with_schemas() {
+    // Stock MCP clients discover tools through tools/list
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 6,
+        "method": "tools/list",
+        "params": {}
+    });
+
+    let response = send_jsonrpc_request(request).unwrap();
+
+    assert_eq!(response["id"], 6);
+    let tools = response["result"]["tools"].as_array().expect("tools array");
+
+    let names: Vec<&str> = tools.iter().filter_map(|t| t["name"].as_str()).collect();
+    assert!(names.contains(&"create_environment"));
+    assert!(names.contains(&"run_command"));
+
+    for tool in tools {
+        assert_eq!(tool["inputSchema"]["type"], "object");
+    }
+}
@@ -61,7 +61,7 @@ async fn test_container_lifecycle() -> Result<()> {
         None,
     ).await?;
 
-    assert_eq!(exec_result.stdout.trim(), "hello world");
+    assert_eq!(exec_result.stdout.head.trim(), "hello world");
     assert_eq!(exec_result.exit_code, Some(0));
 
     // Get logs
@@ -143,7 +143,7 @@ async fn test_exec_with_env() -> Result<()> {
         Some(env_vars),
     ).await?;
 
-    assert_eq!(exec_result.stdout.trim(), "custom_value");
+    assert_eq!(exec_result.stdout.head.trim(), "custom_value");
     assert_eq!(exec_result.exit_code, Some(0));
 
     // Clean up
@@ -188,7 +188,7 @@ async fn test_bind_mount() -> Result<()> {
         None,
     ).await?;
 
-    assert_eq!(exec_result.stdout.trim(), "Hello from host");
+    assert_eq!(exec_result.stdout.head.trim(), "Hello from host");
 
     // Write from container
     let exec_result = client.exec_command(
@@ -0,0 +1,242 @@
+use anyhow::Result;
+use cofer::mcp::server::McpServer;
+use cofer::mcp::types::McpResponse;
+use cofer::podman::PodmanClient;
+use serde_json::{json, Value};
+
+/// Helper to make a request and parse response
+async fn make_request(server: &McpServer, request: Value) -> McpResponse {
+    let request_str = request.to_string();
+    server.handle_request(&request_str).await
+}
+
+/// Create an alpine environment, returning its container id
+///
+/// Returns `None` when Podman is not available so the test can be skipped.
+async fn create_environment(server: &McpServer, env_id: &str, project_root: &str) -> Option<String> {
+    let response = make_request(server, json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "create_environment",
+        "params": {
+            "env_id": env_id,
+            "project_root": project_root,
+            "image": "docker.io/library/alpine:latest"
+        }
+    })).await;
+
+    // Skip if Podman is not available or container creation fails
+    response.result
+        .and_then(|result| result["container_id"].as_str().map(str::to_string))
+}
+
+/// Remove the container behind an environment
+async fn remove_container(container_id: &str) {
+    if let Ok(client) = PodmanClient::new().await {
+        let _ = client.remove_container(container_id, true).await;
+    }
+}
+
+/// Test a command finishing within its timeout
+#[tokio::test]
+async fn test_run_command_completes() -> Result<()> {
+    let server = McpServer::new();
+    let temp_dir = tempfile::tempdir()?;
+    let env_id = format!("test-run-{}", uuid::Uuid::new_v4().simple());
+    let Some(container_id) = create_environment(&server, &env_id, temp_dir.path().to_str().unwrap()).await else {
+        return Ok(());
+    };
+
+    let response = make_request(&server, json!({
+        "jsonrpc": "2.0",
+        "id": 2,
+        "method": "run_command",
+        "params": { "env_id": env_id, "command": "echo hello" }
+    })).await;
+    remove_container(&container_id).await;
+
+    let result = response.result.expect("run_command result");
+    assert_eq!(result["exit_code"], 0);
+    assert_eq!(result["stdout"].as_str().unwrap().trim(), "hello");
+    assert!(result["stdout_tail"].is_null());
+    assert_eq!(result["stdout_truncated"], false);
+    assert_eq!(result["stdout_bytes"], 6);
+    assert_eq!(result["timed_out"], false);
+    assert_eq!(result["killed"], false);
+    assert!(result["duration_ms"].is_u64());
+
+    Ok(())
+}
+
+/// Test a hung command being killed on timeout
+#[tokio::test]
+async fn test_run_command_timeout_kills_process() -> Result<()> {
+    let server = McpServer::new();
+    let temp_dir = tempfile::tempdir()?;
+    let env_id = format!("test-timeout-{}", uuid::Uuid::new_v4().simple());
+    let Some(container_id) = create_environment(&server, &env_id, temp_dir.path().to_str().unwrap()).await else {
+        return Ok(());
+    };
+
+    let response = make_request(&server, json!({
+        "jsonrpc": "2.0",
+        "id": 3,
+        "method": "run_command",
+        "params": { "env_id": env_id, "command": "echo started; sleep 300", "timeout_ms": 1000 }
+    })).await;
+
+    // Nothing from the command survives the kill
+    let leftover = make_request(&server, json!({
+        "jsonrpc": "2.0",
+        "id": 4,
+        "method": "run_command",
+        "params": { "env_id": env_id, "command": "ps -o args | grep -c '^sleep 300' || true" }
+    })).await;
+    remove_container(&container_id).await;
+
+    let result = response.result.expect("timeout is reported as a result");
+    assert_eq!(result["timed_out"], true);
+    assert_eq!(result["killed"], true);
+    let duration_ms = result["duration_ms"].as_u64().unwrap();
+    assert!((1000..10_000).contains(&duration_ms), "duration_ms = {}", duration_ms);
+    assert_eq!(result["stdout"].as_str().unwrap().trim(), "started");
+
+    let leftover = leftover.result.expect("ps result");
+    assert_eq!(leftover["stdout"].as_str().unwrap().trim(), "0");
+
+    Ok(())
+}
+
+/// Test verbose output being capped to its head and tail
+#[tokio::test]
+async fn test_run_command_output_is_bounded() -> Result<()> {
+    let server = McpServer::new();
+    let temp_dir = tempfile::tempdir()?;
+    let env_id = format!("test-output-{}", uuid::Uuid::new_v4().simple());
+    let Some(container_id) = create_environment(&server, &env_id, temp_dir.path().to_str().unwrap()).await else {
+        return Ok(());
+    };
+
+    let response = make_request(&server, json!({
+        "jsonrpc": "2.0",
+        "id": 6,
+        "method": "run_command",
+        "params": {
+            "env_id": env_id,
+            "command": "echo first; seq 1 200000; echo last",
+            "max_output_bytes": 4096
+        }
+    })).await;
+    remove_container(&container_id).await;
+
+    let result = response.result.expect("run_command result");
+    assert_eq!(result["stdout_truncated"], true);
+    assert!(result["stdout_bytes"].as_u64().unwrap() > 1_000_000);
+
+    let stdout = result["stdout"].as_str().unwrap();
+    let stdout_tail = result["stdout_tail"].as_str().unwrap();
+    assert!(stdout.len() + stdout_tail.len() <= 4096);
+    assert!(stdout.starts_with("first\n"));
+    assert!(!stdout.contains("last"));
+    assert!(stdout_tail.ends_with("200000\nlast\n"));
+
+    Ok(())
+}
+
+/// Test argv commands with per-exec env, workdir and user
+#[tokio::test]
+async fn test_run_command_argv_env_workdir_user() -> Result<()> {
+    let server = McpServer::new();
+    let temp_dir = tempfile::tempdir()?;
+    std::fs::create_dir(temp_dir.path().join("sub"))?;
+    let env_id = format!("test-argv-{}", uuid::Uuid::new_v4().simple());
+    let Some(container_id) = create_environment(&server, &env_id, temp_dir.path().to_str().unwrap()).await else {
+        return Ok(());
+    };
+
+    let response = make_request(&server, json!({
+        "jsonrpc": "2.0",
+        "id": 7,
+        "method": "run_command",
+        "params": {
+            "env_id": env_id,
+            "command": ["printenv", "GREETING"],
+            "env": { "GREETING": "it's \"quoted\" $HOME" }
+        }
+    })).await;
+    let printenv = response.result.expect("printenv result");
+
+    let response = make_request(&server, json!({
+        "jsonrpc": "2.0",
+        "id": 8,
+        "method": "run_command",
+        "params": { "env_id": env_id, "command": ["pwd"], "workdir": "sub" }
+    })).await;
+    let pwd = response.result.expect("pwd result");
+
+    let response = make_request(&server, json!({
+        "jsonrpc": "2.0",
+        "id": 9,
+        "method": "run_command",
+        "params": { "env_id": env_id, "command": ["id", "-u"], "user": "nobody" }
+    })).await;
+    let id = response.result.expect("id result");
+    remove_container(&container_id).await;
+
+    assert_eq!(printenv["stdout"].as_str().unwrap().trim_end(), "it's \"quoted\" $HOME");
+    assert_eq!(pwd["stdout"].as_str().unwrap().trim(), "/workdir/sub");
+    assert_eq!(pwd["workdir"], "/workdir/sub");
+    assert_eq!(id["stdout"].as_str().unwrap().trim(), "65534");
+
+    Ok(())
+}
+
+/// Test validation of the numeric parameters
+#[tokio::test]
+async fn test_run_command_invalid_numeric_params() -> Result<()> {
+    let server = McpServer::new();
+
+    for name in ["timeout_ms", "max_output_bytes", "max_output_lines"] {
+        for value in [json!(0), json!(-5), json!("soon")] {
+            let response = make_request(&server, json!({
+                "jsonrpc": "2.0",
+                "id": 5,
+                "method": "run_command",
+                "params": { "env_id": "missing", "command": "true", name: value }
+            })).await;
+
+            let error = response.error.expect("invalid value is rejected");
+            assert_eq!(error.code, -32602);
+            assert!(error.message.contains(name));
+        }
+    }
+
+    Ok(())
+}
+
+/// Test validation of command, env and workdir
+#[tokio::test]
+async fn test_run_command_invalid_command_params() -> Result<()> {
+    let server = McpServer::new();
+
+    for params in [
+        json!({ "env_id": "missing", "command": [] }),
+        json!({ "env_id": "missing", "command": ["ls", 1] }),
+        json!({ "env_id": "missing", "command": "true", "env": { "A": 1 } }),
+        json!({ "env_id": "missing", "command": "true", "workdir": "" }),
+        json!({ "env_id": "missing", "command": "true", "user": 1000 }),
+    ] {
+        let response = make_request(&server, json!({
+            "jsonrpc": "2.0",
+            "id": 10,
+            "method": "run_command",
+            "params": params
+        })).await;
+
+        let error = response.error.expect("invalid params are rejected");
+        assert_eq!(error.code, -32602);
+        assert!(!error.message.contains("not found"), "{}", error.message);
+    }
+
+    Ok(())
+}